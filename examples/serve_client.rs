@@ -0,0 +1,64 @@
+// A client for `serve`: starts one serve process, runs a handful of containers through it at
+// once, and prints how each one ended. The image is pulled first so every run finds it cached.
+//
+//   MYDOCKER_BIN=target/debug/docker-starter-rust cargo run --example serve_client -- alpine:3.19
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+const RUNS: usize = 4;
+
+fn main() -> anyhow::Result<()> {
+    let image = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "alpine:3.19".to_string());
+    let executable = std::env::var("MYDOCKER_BIN").unwrap_or_else(|_| "mydocker".to_string());
+    let mut serve = Command::new(executable)
+        .arg("serve")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut requests = serve.stdin.take().unwrap();
+    let responses = BufReader::new(serve.stdout.take().unwrap());
+
+    let mut send = |request: Value| writeln!(requests, "{}", request);
+    send(json!({"id": "pull", "op": "pull", "image": image}))?;
+    for run in 0..RUNS {
+        send(json!({
+            "id": run,
+            "op": "run",
+            "image": image,
+            "command": ["sh", "-c", "echo container $N on $(hostname)"],
+            "env": [format!("N={}", run)],
+        }))?;
+    }
+    // Nothing more to ask; serve answers everything in flight and exits
+    drop(requests);
+
+    let mut started = HashMap::new();
+    for line in responses.lines() {
+        let response: Value = serde_json::from_str(&line?)?;
+        let id = &response["id"];
+        if response["ok"] != true {
+            println!("{}: failed: {}", id, response["error"]);
+            continue;
+        }
+        match response["event"].as_str() {
+            Some("started") => {
+                started.insert(id.to_string(), response["pid"].clone());
+            }
+            Some("exited") => print!(
+                "{} (pid {}): exit code {}, said {}",
+                id,
+                started[&id.to_string()],
+                response["exit_code"],
+                response["stdout"].as_str().unwrap_or("")
+            ),
+            _ => println!("{}: {}", id, response),
+        }
+    }
+    serve.wait()?;
+    Ok(())
+}
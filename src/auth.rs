@@ -0,0 +1,791 @@
+use crate::http::HttpClient;
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+// The key Docker Hub credentials are stored under, in config.json and in helpers
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+// What credential helpers say for Username when Secret is an identity (refresh) token
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+// Sent as client_id in refresh token exchanges, which the OAuth2 flow requires
+const CLIENT_ID: &str = "mydocker";
+
+// A parsed `WWW-Authenticate` header, e.g.
+//   Bearer realm="https://auth.docker.io/token",service="registry.docker.io"
+pub struct Challenge {
+    pub scheme: String,
+    pub params: HashMap<String, String>,
+}
+
+impl Challenge {
+    pub fn parse(header: &str) -> Result<Challenge> {
+        let header = header.trim();
+        let (scheme, rest) = match header.split_once(char::is_whitespace) {
+            Some((scheme, rest)) => (scheme, rest),
+            None => (header, ""),
+        };
+        if scheme.is_empty() {
+            bail!("Empty WWW-Authenticate header");
+        }
+
+        let mut params = HashMap::new();
+        let mut chars = rest.chars().peekable();
+        loop {
+            // Skip separators between parameters
+            while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.peek().is_none() {
+                break;
+            }
+
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == '=' || c == ',' || c.is_whitespace() {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.next() != Some('=') {
+                bail!("Malformed WWW-Authenticate header '{}'", header);
+            }
+            while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+                chars.next();
+            }
+
+            // Values are either a token or a quoted string where backslash escapes the next char.
+            // Quoted values can contain commas (GHCR and Quay put several actions in the scope).
+            let mut value = String::new();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => bail!("Unterminated escape in WWW-Authenticate header"),
+                        },
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => bail!("Unterminated quoted string in WWW-Authenticate header"),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+
+            params.insert(name.to_ascii_lowercase(), value);
+        }
+
+        Ok(Challenge {
+            scheme: scheme.to_string(),
+            params,
+        })
+    }
+
+    pub fn is_bearer(&self) -> bool {
+        self.scheme.eq_ignore_ascii_case("bearer")
+    }
+}
+
+#[derive(Clone)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    // Exchanged at the token endpoint with the OAuth2 refresh token grant
+    IdentityToken(String),
+}
+
+// The parts of ~/.docker/config.json (or $DOCKER_CONFIG/config.json) that say where
+// credentials are: a helper for some registries, a default helper for the rest, and base64
+// user:password pairs for when there's no helper at all
+#[derive(Deserialize, Default)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct AuthEntry {
+    #[serde(default)]
+    auth: Option<String>,
+    #[serde(default, rename = "identitytoken")]
+    identity_token: Option<String>,
+}
+
+// A helper's answer to `get`
+#[derive(Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+// Credentials per registry for the lifetime of the process, so a helper (which may prompt or
+// hit a cloud API) runs at most once for each
+#[derive(Default)]
+pub struct CredentialStore {
+    found: Mutex<HashMap<String, Option<Credentials>>>,
+}
+
+impl CredentialStore {
+    // None means pull anonymously, which is what most public images need anyway
+    pub async fn get(&self, registry: &str) -> Result<Option<Credentials>> {
+        if let Some(found) = self.found.lock().unwrap().get(registry) {
+            return Ok(found.clone());
+        }
+        let found = lookup(registry).await?;
+        self.found
+            .lock()
+            .unwrap()
+            .insert(registry.to_string(), found.clone());
+        Ok(found)
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker/config.json"))
+        }
+    }
+}
+
+// docker's own precedence: a registry's credHelpers entry, then credsStore, then auths
+async fn lookup(registry: &str) -> Result<Option<Credentials>> {
+    let path = match config_path() {
+        Some(path) if path.is_file() => path,
+        _ => return Ok(None),
+    };
+    let data =
+        std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let config: DockerConfig = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let server = if registry == crate::registry::DOCKER_HUB {
+        DOCKER_HUB_SERVER
+    } else {
+        registry
+    };
+    if let Some(helper) = config
+        .cred_helpers
+        .get(registry)
+        .or_else(|| config.cred_helpers.get(server))
+    {
+        return run_helper(helper, server).await;
+    }
+    if let Some(helper) = config
+        .creds_store
+        .as_deref()
+        .filter(|helper| !helper.is_empty())
+    {
+        return run_helper(helper, server).await;
+    }
+
+    let entry = config
+        .auths
+        .get(server)
+        .or_else(|| config.auths.get(&format!("https://{}", registry)))
+        .or_else(|| config.auths.get(registry));
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    if let Some(token) = entry
+        .identity_token
+        .as_ref()
+        .filter(|token| !token.is_empty())
+    {
+        return Ok(Some(Credentials::IdentityToken(token.clone())));
+    }
+    let auth = match entry.auth.as_deref().filter(|auth| !auth.is_empty()) {
+        Some(auth) => auth,
+        None => return Ok(None),
+    };
+    let decoded = decode_base64(auth)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "The auth for {} in {} isn't valid base64",
+                server,
+                path.display()
+            )
+        })?;
+    match decoded.split_once(':') {
+        Some((username, password)) => Ok(Some(Credentials::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        })),
+        None => bail!(
+            "The auth for {} in {} isn't username:password",
+            server,
+            path.display()
+        ),
+    }
+}
+
+// The credential helper protocol: `docker-credential-<name> get` reads the server URL on stdin
+// and prints {"ServerURL", "Username", "Secret"}. Not having credentials for the server is an
+// error exit too, which we take as "pull anonymously".
+async fn run_helper(name: &str, server: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", name);
+    let failed = |message: String| anyhow!("credential helper {} failed: {}", program, message);
+
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| match error.kind() {
+            std::io::ErrorKind::NotFound => failed(format!("{} is not on PATH", program)),
+            _ => failed(error.to_string()),
+        })?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(server.as_bytes())
+        .await
+        .map_err(|error| failed(error.to_string()))?;
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|error| failed(error.to_string()))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        if stdout.contains("credentials not found") {
+            return Ok(None);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = [stdout.trim(), stderr.trim()]
+            .iter()
+            .filter(|text| !text.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(": ");
+        return Err(failed(if message.is_empty() {
+            format!("exited with {}", output.status)
+        } else {
+            message
+        }));
+    }
+    let found: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .map_err(|error| failed(format!("unexpected output: {}", error)))?;
+    if found.username == IDENTITY_TOKEN_USERNAME {
+        Ok(Some(Credentials::IdentityToken(found.secret)))
+    } else {
+        Ok(Some(Credentials::Basic {
+            username: found.username,
+            password: found.secret,
+        }))
+    }
+}
+
+// Standard base64 with or without padding, which is all config.json ever holds
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let data = text.trim_end_matches('=');
+    // One character left over is six bits, not a byte; and padding never runs past two
+    if data.len() % 4 == 1 || text.len() - data.len() > 2 {
+        return None;
+    }
+    let mut bytes = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// When the token server doesn't say, which is what the token spec prescribes
+const DEFAULT_EXPIRES_IN: u64 = 60;
+
+// Tokens are given up this long before they expire, so a request doesn't carry one that runs
+// out on the way
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    // Seconds from the response's issued_at. We count from when the response arrived instead,
+    // which can only be later and doesn't depend on our clock agreeing with the server's.
+    #[serde(default = "default_expires_in")]
+    expires_in: u64,
+}
+
+fn default_expires_in() -> u64 {
+    DEFAULT_EXPIRES_IN
+}
+
+// (realm, service, scopes), the scopes sorted so the same set is always the same key
+type TokenKey = (String, String, Vec<String>);
+
+// Bearer tokens until they expire
+#[derive(Default)]
+pub struct TokenCache {
+    tokens: Mutex<HashMap<TokenKey, (String, Instant)>>,
+    // Realms that turned down a token for several scopes at once
+    single_scope: Mutex<HashSet<String>>,
+}
+
+impl TokenCache {
+    // Answer a bearer challenge with one token for every scope in `scopes` (the first being
+    // the one the client can't do without) plus any the challenge names, which the initial
+    // `/v2/` probe's doesn't. They go in one exchange as repeated scope parameters, which the
+    // token spec allows for; a token server that refuses that gets asked for the first scope
+    // and the challenge's alone from then on. Without credentials the token is an anonymous
+    // one.
+    pub async fn token(
+        &self,
+        client: &HttpClient,
+        challenge: &Challenge,
+        scopes: &[String],
+        credentials: Option<&Credentials>,
+    ) -> Result<String> {
+        let realm = challenge
+            .params
+            .get("realm")
+            .ok_or_else(|| anyhow!("Bearer challenge without a realm"))?;
+        let service = challenge.params.get("service").cloned().unwrap_or_default();
+        // Several scopes in a challenge are separated by spaces
+        let challenged: Vec<String> = challenge
+            .params
+            .get("scope")
+            .map(|scope| scope.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        let mut wanted = challenged.clone();
+        for scope in scopes {
+            if !wanted.contains(scope) {
+                wanted.push(scope.clone());
+            }
+        }
+
+        let single = self.single_scope.lock().unwrap().contains(realm);
+        if wanted.len() > 1 && !single {
+            // Only a refusal says anything about the scopes. Anything else (no connection, a
+            // timeout, a server error) would go just the same for one scope, and is no reason
+            // to stop asking for several next time.
+            match self
+                .exchange(client, realm, &service, &wanted, credentials)
+                .await?
+            {
+                Exchanged::Token(token) => return Ok(token),
+                Exchanged::Refused(_) => {
+                    self.single_scope.lock().unwrap().insert(realm.clone());
+                }
+            }
+        }
+        let mut narrowed = challenged;
+        if let Some(scope) = scopes.first().filter(|scope| !narrowed.contains(scope)) {
+            narrowed.push(scope.clone());
+        }
+        match self
+            .exchange(client, realm, &service, &narrowed, credentials)
+            .await?
+        {
+            Exchanged::Token(token) => Ok(token),
+            Exchanged::Refused(error) => Err(error),
+        }
+    }
+
+    async fn exchange(
+        &self,
+        client: &HttpClient,
+        realm: &str,
+        service: &str,
+        scopes: &[String],
+        credentials: Option<&Credentials>,
+    ) -> Result<Exchanged> {
+        let mut sorted = scopes.to_vec();
+        sorted.sort();
+        let key = (realm.to_string(), service.to_string(), sorted);
+        if let Some((token, expires)) = self.tokens.lock().unwrap().get(&key) {
+            if Instant::now() < *expires {
+                return Ok(Exchanged::Token(token.clone()));
+            }
+        }
+
+        let mut query = vec![];
+        if !service.is_empty() {
+            query.push(("service", service));
+        }
+        for scope in scopes.iter().filter(|scope| !scope.is_empty()) {
+            query.push(("scope", scope.as_str()));
+        }
+        let request = match credentials {
+            None => client.get(realm).query(&query),
+            Some(Credentials::Basic { username, password }) => client
+                .get(realm)
+                .query(&query)
+                .basic_auth(username, Some(password)),
+            // https://docs.docker.com/registry/spec/auth/oauth/
+            Some(Credentials::IdentityToken(refresh_token)) => {
+                query.push(("grant_type", "refresh_token"));
+                query.push(("refresh_token", refresh_token.as_str()));
+                query.push(("client_id", CLIENT_ID));
+                client.post(realm).form(&query)
+            }
+        };
+        let received = Instant::now();
+        let response = client.send(request).await?;
+        let refused = matches!(
+            response.status(),
+            StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        );
+        let response = match client
+            .check(response)
+            .await
+            .with_context(|| format!("Token exchange with {} failed", realm))
+        {
+            Ok(response) => response,
+            Err(error) if refused => return Ok(Exchanged::Refused(error)),
+            Err(error) => return Err(error),
+        };
+        let response = response.json::<TokenResponse>().await?;
+
+        // The spec calls it `token`, OAuth2-flavoured servers say `access_token`
+        let token = match response.token.or(response.access_token) {
+            Some(token) => token,
+            None => bail!("Token response from {} has no token", realm),
+        };
+        let lifetime = Duration::from_secs(response.expires_in).saturating_sub(EXPIRY_MARGIN);
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(key, (token.clone(), received + lifetime));
+
+        Ok(Exchanged::Token(token))
+    }
+}
+
+// How a token exchange went, when the token server answered
+enum Exchanged {
+    Token(String),
+    // 400, 401 or 403: the server turned down the scopes or credentials we asked with. The
+    // error says what it told us.
+    Refused(anyhow::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_registry::{FakeRegistry, Request, Response};
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_a_docker_hub_challenge() {
+        let challenge = Challenge::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        )
+        .unwrap();
+        assert!(challenge.is_bearer());
+        assert_eq!(challenge.params["realm"], "https://auth.docker.io/token");
+        assert_eq!(challenge.params["service"], "registry.docker.io");
+        assert_eq!(challenge.params["scope"], "repository:library/alpine:pull");
+    }
+
+    #[test]
+    fn quoted_values_keep_commas_and_escapes() {
+        let challenge = Challenge::parse(
+            r#"bearer realm="https://ghcr.io/token", scope="repository:o/app:pull,push", service="a\"b""#,
+        )
+        .unwrap();
+        assert!(challenge.is_bearer());
+        assert_eq!(challenge.params["scope"], "repository:o/app:pull,push");
+        assert_eq!(challenge.params["service"], "a\"b");
+    }
+
+    #[test]
+    fn unquoted_values_and_parameter_names_in_any_case() {
+        let challenge =
+            Challenge::parse("Bearer Realm=https://r.example/token , service = reg").unwrap();
+        assert_eq!(challenge.params["realm"], "https://r.example/token");
+        assert_eq!(challenge.params["service"], "reg");
+    }
+
+    #[test]
+    fn a_scheme_on_its_own() {
+        let challenge = Challenge::parse("Basic").unwrap();
+        assert!(!challenge.is_bearer());
+        assert!(challenge.params.is_empty());
+    }
+
+    #[test]
+    fn malformed_challenges_are_errors() {
+        assert!(Challenge::parse("").is_err());
+        assert!(Challenge::parse("Bearer realm").is_err());
+        assert!(Challenge::parse(r#"Bearer realm="unterminated"#).is_err());
+        assert!(Challenge::parse(r#"Bearer realm="trailing\"#).is_err());
+    }
+
+    #[test]
+    fn token_responses_from_docker_hub_ghcr_and_quay() {
+        // Docker Hub sends both names for the token, and when it was issued
+        let hub: TokenResponse = serde_json::from_str(
+            r#"{"token":"eyJhbGciOi.hub","access_token":"eyJhbGciOi.hub","expires_in":300,
+                "issued_at":"2024-01-15T10:21:33.547830871Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(hub.token.as_deref(), Some("eyJhbGciOi.hub"));
+        assert_eq!(hub.expires_in, 300);
+
+        // GHCR and Quay send the token alone, which is good for the spec's 60 seconds
+        let ghcr: TokenResponse = serde_json::from_str(r#"{"token":"djE6Z2hjcg=="}"#).unwrap();
+        assert_eq!(ghcr.token.as_deref(), Some("djE6Z2hjcg=="));
+        assert_eq!(ghcr.access_token, None);
+        assert_eq!(ghcr.expires_in, 60);
+        let quay: TokenResponse =
+            serde_json::from_str(r#"{"token": "eyJ0eXAi.quay", "scope": "repository:a/b:pull"}"#)
+                .unwrap();
+        assert_eq!(quay.expires_in, 60);
+
+        // An OAuth2-style answer has only access_token
+        let oauth: TokenResponse =
+            serde_json::from_str(r#"{"access_token":"oauth","expires_in":3600,"scope":""}"#)
+                .unwrap();
+        assert_eq!((oauth.token, oauth.expires_in), (None, 3600));
+    }
+
+    // A token server that gives multi-scope requests `status` and everything else a token
+    async fn token_server(status: Arc<AtomicU16>) -> FakeRegistry {
+        FakeRegistry::start(move |request: &Request| {
+            if request.target.matches("scope=").count() > 1 {
+                let status = status.load(Ordering::SeqCst);
+                if status != 200 {
+                    return Response::new(status).body(r#"{"errors":[{"code":"DENIED"}]}"#);
+                }
+            }
+            Response::new(200).body(r#"{"token":"t"}"#)
+        })
+        .await
+    }
+
+    fn challenge(realm: &str) -> Challenge {
+        Challenge::parse(&format!(
+            r#"Bearer realm="{}",service="registry",scope="repository:a:pull""#,
+            realm
+        ))
+        .unwrap()
+    }
+
+    fn scopes() -> Vec<String> {
+        vec!["repository:a:pull".into(), "repository:b:pull".into()]
+    }
+
+    fn multi_scope(server: &FakeRegistry) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|request| request.matches("scope=").count() > 1)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn a_refusal_drops_to_one_scope_for_good() {
+        for refusal in [400, 401, 403] {
+            let server = token_server(Arc::new(AtomicU16::new(refusal))).await;
+            let realm = format!("http://{}/token", server.address);
+            let (cache, client) = (TokenCache::default(), HttpClient::new(false));
+
+            let token = cache
+                .token(&client, &challenge(&realm), &scopes(), None)
+                .await;
+            assert_eq!(token.unwrap(), "t");
+            assert!(cache.single_scope.lock().unwrap().contains(&realm));
+            assert_eq!(server.requests().len(), 2);
+            // The single-scope token is cached, and several scopes aren't asked for again
+            cache.tokens.lock().unwrap().clear();
+            cache
+                .token(&client, &challenge(&realm), &scopes(), None)
+                .await
+                .unwrap();
+            assert_eq!(multi_scope(&server), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_failures_are_errors_and_change_nothing() {
+        let status = Arc::new(AtomicU16::new(503));
+        let server = token_server(status.clone()).await;
+        let realm = format!("http://{}/token", server.address);
+        let (cache, client) = (TokenCache::default(), HttpClient::new(false));
+
+        let error = cache
+            .token(&client, &challenge(&realm), &scopes(), None)
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("503"), "{:#}", error);
+        assert!(cache.single_scope.lock().unwrap().is_empty());
+        assert_eq!(server.requests().len(), 1);
+
+        // Once the server is back, several scopes it is
+        status.store(200, Ordering::SeqCst);
+        cache
+            .token(&client, &challenge(&realm), &scopes(), None)
+            .await
+            .unwrap();
+        assert_eq!(multi_scope(&server), 2);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_token_server_is_an_error() {
+        // Bound and dropped, so nothing listens there
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let realm = format!("http://{}/token", address);
+        let (cache, client) = (TokenCache::default(), HttpClient::new(false));
+        assert!(cache
+            .token(&client, &challenge(&realm), &scopes(), None)
+            .await
+            .is_err());
+        assert!(cache.single_scope.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn base64_with_and_without_padding() {
+        let decode =
+            |text: &str| decode_base64(text).map(|bytes| String::from_utf8(bytes).unwrap());
+        assert_eq!(decode("dXNlcjpwYXNz").as_deref(), Some("user:pass"));
+        assert_eq!(decode("dXNlcjpwYXNzMQ==").as_deref(), Some("user:pass1"));
+        assert_eq!(decode("dXNlcjpwYXNzMQ").as_deref(), Some("user:pass1"));
+        assert_eq!(decode("dXNlcjpwYXNzMTI=").as_deref(), Some("user:pass12"));
+        assert_eq!(decode("dXNlcjpwYXNzMTI").as_deref(), Some("user:pass12"));
+        assert_eq!(decode("YT8+Lw==").as_deref(), Some("a?>/"));
+        assert_eq!(decode("YT8-Lw").as_deref(), None, "url-safe alphabet");
+        assert_eq!(decode("").as_deref(), Some(""));
+
+        for invalid in ["dXNl cjpw", "dXNlcjp!", "dXNlc", "dX=Nl", "dXM===", "YQ==="] {
+            assert_eq!(decode_base64(invalid), None, "{}", invalid);
+        }
+    }
+
+    // A directory of fake docker-credential-* helpers, put at the front of PATH once for all
+    // the tests that need one
+    fn helpers() {
+        static HELPERS: std::sync::Once = std::sync::Once::new();
+        HELPERS.call_once(|| {
+            use std::os::unix::fs::PermissionsExt;
+            let directory = tempfile::tempdir().unwrap().into_path();
+            let scripts = [
+                (
+                    "basic",
+                    r#"server=$(cat); printf '{"ServerURL":"%s","Username":"user","Secret":"secret for %s"}' "$server" "$server""#,
+                ),
+                (
+                    "token",
+                    r#"cat >/dev/null; echo '{"ServerURL":"x","Username":"<token>","Secret":"refresh"}'"#,
+                ),
+                (
+                    "missing",
+                    "cat >/dev/null; echo 'credentials not found in native keychain'; exit 1",
+                ),
+                (
+                    "broken",
+                    "cat >/dev/null; echo 'keychain locked' >&2; exit 3",
+                ),
+                ("garbage", "cat >/dev/null; echo 'not json'"),
+                ("args", r#"[ "$*" = get ] || exit 9; cat >/dev/null; echo '{"Username":"u","Secret":"s"}'"#),
+            ];
+            for (name, script) in scripts {
+                let path = directory.join(format!("docker-credential-test-{}", name));
+                std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            let path = std::env::var_os("PATH").unwrap_or_default();
+            let paths = std::iter::once(directory).chain(std::env::split_paths(&path));
+            std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+        });
+    }
+
+    #[tokio::test]
+    async fn credential_helpers_are_asked_about_the_server() {
+        helpers();
+        match run_helper("test-basic", "registry.example:5000")
+            .await
+            .unwrap()
+        {
+            Some(Credentials::Basic { username, password }) => {
+                assert_eq!(username, "user");
+                assert_eq!(password, "secret for registry.example:5000");
+            }
+            _ => panic!("expected a username and password"),
+        }
+        match run_helper("test-token", "ghcr.io").await.unwrap() {
+            Some(Credentials::IdentityToken(token)) => assert_eq!(token, "refresh"),
+            _ => panic!("expected an identity token"),
+        }
+        assert!(run_helper("test-args", "ghcr.io").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn helpers_without_credentials_mean_anonymous_and_failures_say_why() {
+        helpers();
+        assert!(run_helper("test-missing", "ghcr.io")
+            .await
+            .unwrap()
+            .is_none());
+
+        let error = run_helper("test-broken", "ghcr.io")
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("docker-credential-test-broken"), "{}", error);
+        assert!(error.contains("keychain locked"), "{}", error);
+        let error = run_helper("test-garbage", "ghcr.io")
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("unexpected output"), "{}", error);
+        let error = run_helper("test-absent", "ghcr.io")
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("is not on PATH"), "{}", error);
+    }
+}
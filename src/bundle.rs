@@ -0,0 +1,162 @@
+use crate::manifest::ContainerConfig;
+use crate::passwd;
+use crate::volume::copy_tree;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::{read, write};
+use std::path::Path;
+
+// The runtime spec version our config.json follows
+const OCI_VERSION: &str = "1.0.2";
+
+// An OCI runtime bundle is a directory with the root filesystem in rootfs/ and config.json
+// saying how to run it:
+// https://github.com/opencontainers/runtime-spec/blob/main/bundle.md
+//
+// The config we write is what `runc spec` generates, with the process filled in from the image.
+pub fn write_config(bundle: &Path, config: &ContainerConfig) -> Result<()> {
+    let rootfs = bundle.join("rootfs");
+
+    let mut args = config.entrypoint.clone().unwrap_or_default();
+    args.extend(config.cmd.clone().unwrap_or_default());
+    if args.is_empty() {
+        bail!("The image has no Entrypoint or Cmd, so the bundle would have nothing to run");
+    }
+    let mut env = config.env.clone().unwrap_or_default();
+    if !env.iter().any(|variable| variable.starts_with("PATH=")) {
+        env.insert(0, format!("PATH={}", crate::DEFAULT_PATH));
+    }
+    let cwd = config
+        .working_dir
+        .clone()
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "/".to_string());
+    let user = passwd::resolve(&rootfs, config.user.as_deref().unwrap_or(""))?;
+    let capabilities = ["CAP_AUDIT_WRITE", "CAP_KILL", "CAP_NET_BIND_SERVICE"];
+
+    let spec = json!({
+        "ociVersion": OCI_VERSION,
+        "process": {
+            "terminal": false,
+            "user": { "uid": user.uid, "gid": user.gid, "additionalGids": user.groups },
+            "args": args,
+            "env": env,
+            "cwd": cwd,
+            "capabilities": {
+                "bounding": capabilities,
+                "effective": capabilities,
+                "permitted": capabilities,
+                "ambient": capabilities,
+            },
+            "rlimits": [{ "type": "RLIMIT_NOFILE", "hard": 1024, "soft": 1024 }],
+            "noNewPrivileges": true,
+        },
+        "root": { "path": "rootfs", "readonly": false },
+        "hostname": "mydocker",
+        "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc" },
+            {
+                "destination": "/dev",
+                "type": "tmpfs",
+                "source": "tmpfs",
+                "options": ["nosuid", "strictatime", "mode=755", "size=65536k"],
+            },
+            {
+                "destination": "/dev/pts",
+                "type": "devpts",
+                "source": "devpts",
+                "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"],
+            },
+            {
+                "destination": "/dev/shm",
+                "type": "tmpfs",
+                "source": "shm",
+                "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"],
+            },
+            {
+                "destination": "/dev/mqueue",
+                "type": "mqueue",
+                "source": "mqueue",
+                "options": ["nosuid", "noexec", "nodev"],
+            },
+            {
+                "destination": "/sys",
+                "type": "sysfs",
+                "source": "sysfs",
+                "options": ["nosuid", "noexec", "nodev", "ro"],
+            },
+            {
+                "destination": "/sys/fs/cgroup",
+                "type": "cgroup",
+                "source": "cgroup",
+                "options": ["nosuid", "noexec", "nodev", "relatime", "ro"],
+            },
+        ],
+        "annotations": config.labels.clone().unwrap_or_default(),
+        "linux": {
+            "namespaces": [
+                { "type": "pid" },
+                { "type": "network" },
+                { "type": "ipc" },
+                { "type": "uts" },
+                { "type": "mount" },
+            ],
+            "maskedPaths": [
+                "/proc/acpi", "/proc/asound", "/proc/kcore", "/proc/keys", "/proc/latency_stats",
+                "/proc/timer_list", "/proc/timer_stats", "/proc/sched_debug", "/sys/firmware",
+                "/proc/scsi",
+            ],
+            "readonlyPaths": [
+                "/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger",
+            ],
+        },
+    });
+
+    let path = bundle.join("config.json");
+    write(&path, serde_json::to_vec_pretty(&spec)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// The parts of a bundle's config.json that `run --bundle` uses. Namespaces, mounts and
+// capabilities are ours to decide, as with any image.
+#[derive(Deserialize)]
+struct Spec {
+    process: Process,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Process {
+    args: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    cwd: String,
+}
+
+// Copy a bundle's rootfs into a container's and turn its config.json into the equivalent of an
+// image config. The bundle itself is left untouched, so it can be run any number of times.
+pub fn load(bundle: &Path, rootfs: &Path) -> Result<ContainerConfig> {
+    let path = bundle.join("config.json");
+    let data = read(&path).with_context(|| format!("{} is not an OCI bundle", bundle.display()))?;
+    let spec: Spec = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let source = bundle.join("rootfs");
+    if !source.is_dir() {
+        bail!("Bundle {} has no rootfs directory", bundle.display());
+    }
+    copy_tree(&source, rootfs)
+        .with_context(|| format!("Failed to copy the rootfs of bundle {}", bundle.display()))?;
+
+    Ok(ContainerConfig {
+        env: Some(spec.process.env),
+        cmd: Some(spec.process.args),
+        working_dir: Some(spec.process.cwd),
+        labels: Some(spec.annotations),
+        ..ContainerConfig::default()
+    })
+}
@@ -0,0 +1,242 @@
+use crate::privileges::write_proc;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::{create_dir_all, read_to_string, remove_dir, write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// cgroup v2 only: every container gets <root>/mydocker/<id>
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PARENT: &str = "mydocker";
+const CONTROLLERS: [&str; 3] = ["cpu", "memory", "pids"];
+
+// cpu.max's default period; --cpus sets the quota as a share of it
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+// Kernels before 5.19 don't have memory.peak, so we sample memory.current instead
+const PEAK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A unified hierarchy has cgroup.controllers at its root; v1 and hybrid setups don't
+pub fn available(root: &Path) -> bool {
+    root.join("cgroup.controllers").is_file()
+}
+
+pub struct Cgroup {
+    path: PathBuf,
+    // <path>/cgroup.procs, ready for the forked child to write itself into
+    procs: CString,
+}
+
+impl Cgroup {
+    pub fn create(
+        root: &Path,
+        id: &str,
+        memory_limit: Option<u64>,
+        cpu_limit: Option<f64>,
+    ) -> Result<Cgroup> {
+        let parent = root.join(PARENT);
+        create_dir_all(&parent)
+            .with_context(|| format!("Failed to create cgroup {}", parent.display()))?;
+        // Controllers have to be enabled in every ancestor's subtree_control before a child
+        // cgroup gets their files. Ask only for what the kernel offers.
+        enable_controllers(root)?;
+        enable_controllers(&parent)?;
+
+        let path = parent.join(id);
+        create_dir_all(&path)
+            .with_context(|| format!("Failed to create cgroup {}", path.display()))?;
+        let cgroup = Cgroup {
+            procs: CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?,
+            path,
+        };
+
+        if let Some(limit) = memory_limit {
+            write(cgroup.path.join("memory.max"), limit.to_string())
+                .context("Failed to set the memory limit, is the memory controller enabled?")?;
+            // Without this the limit only pushes the container into swap
+            let _ = write(cgroup.path.join("memory.swap.max"), "0");
+        }
+        if let Some(cpus) = cpu_limit {
+            let quota = (cpus * CPU_PERIOD_USEC as f64).round() as u64;
+            write(
+                cgroup.path.join("cpu.max"),
+                format!("{} {}", quota, CPU_PERIOD_USEC),
+            )
+            .context("Failed to set the CPU limit, is the cpu controller enabled?")?;
+        }
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // For the forked child to hand to join(), since pre_exec can't borrow from us
+    pub fn procs_file(&self) -> CString {
+        self.procs.clone()
+    }
+
+    // Keep a running maximum of memory.current when the kernel doesn't track the peak itself
+    pub fn track_peak(&self) -> Option<PeakTracker> {
+        if self.path.join("memory.peak").is_file() {
+            return None;
+        }
+        let current = self.path.join("memory.current");
+        if !current.is_file() {
+            return None;
+        }
+        let peak = Arc::new(AtomicU64::new(0));
+        let recorded = peak.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                if let Some(bytes) = read_number(&current) {
+                    recorded.fetch_max(bytes, Ordering::Relaxed);
+                }
+                tokio::time::sleep(PEAK_POLL_INTERVAL).await;
+            }
+        });
+        Some(PeakTracker { peak, task })
+    }
+
+    // Read right after the container exits, before the cgroup goes away
+    pub fn usage(&self, tracker: Option<&PeakTracker>) -> (Option<u64>, Option<u64>) {
+        let memory_peak = read_number(&self.path.join("memory.peak"))
+            .or_else(|| tracker.map(|tracker| tracker.peak.load(Ordering::Relaxed)));
+        (memory_peak, cpu_usage(&self.path))
+    }
+
+    // Whether the kernel's OOM killer took anything in the container down, going by
+    // memory.events (which only exists with the memory controller)
+    pub fn oom_killed(&self) -> bool {
+        read_to_string(self.path.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events
+                    .lines()
+                    .find_map(|line| line.strip_prefix("oom_kill "))
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+            })
+            .map_or(false, |kills| kills > 0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // The kernel refuses to remove a cgroup until its last process is fully gone, which can
+        // lag a little behind the exit status we already collected
+        for _ in 0..20 {
+            if remove_dir(&self.path).is_ok() {
+                return;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        eprintln!("warning: failed to remove cgroup {}", self.path.display());
+    }
+}
+
+// Runs in the forked child: "0" means the writing process itself
+pub fn join(procs_file: &CStr) -> std::io::Result<()> {
+    write_proc(procs_file.to_bytes_with_nul(), b"0")
+}
+
+// Runs in the forked child once it's in its cgroup and its own mount namespace. A cgroup
+// namespace makes that cgroup the root of all the container can see, and a cgroup2 mount shows
+// it only its own subtree at target, read-only like docker's. memory.max and cpu.max there are
+// what container-aware runtimes (the JVM, Go's) read their limits from.
+pub fn enter_namespace(target: &CStr) -> std::io::Result<()> {
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWCGROUP) != 0
+            || libc::mount(
+                b"cgroup2\0".as_ptr().cast(),
+                target.as_ptr(),
+                b"cgroup2\0".as_ptr().cast(),
+                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_RDONLY,
+                std::ptr::null(),
+            ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub struct PeakTracker {
+    peak: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for PeakTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn enable_controllers(cgroup: &Path) -> Result<()> {
+    let offered = read_to_string(cgroup.join("cgroup.controllers")).unwrap_or_default();
+    let wanted: Vec<String> = offered
+        .split_whitespace()
+        .filter(|controller| CONTROLLERS.contains(controller))
+        .map(|controller| format!("+{}", controller))
+        .collect();
+    if wanted.is_empty() {
+        return Ok(());
+    }
+    write(cgroup.join("cgroup.subtree_control"), wanted.join(" ")).with_context(|| {
+        format!(
+            "Failed to enable cgroup controllers in {}",
+            cgroup.display()
+        )
+    })
+}
+
+// Every process in the cgroup, daemons in sessions of their own included. Nothing once the
+// cgroup is gone.
+pub fn pids(cgroup: &Path) -> Vec<i32> {
+    read_to_string(cgroup.join("cgroup.procs"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect()
+}
+
+// usage_usec from cpu.stat, which exists even without the cpu controller
+pub fn cpu_usage(cgroup: &Path) -> Option<u64> {
+    let stat = read_to_string(cgroup.join("cpu.stat")).ok()?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+pub fn read_number(path: &Path) -> Option<u64> {
+    read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// What the container used, kept in its state file once it has exited
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResourceUsage {
+    pub wall_seconds: f64,
+    #[serde(default)]
+    pub cpu_usec: Option<u64>,
+    #[serde(default)]
+    pub memory_peak: Option<u64>,
+}
+
+impl ResourceUsage {
+    // "1.2s wall, 0.3s CPU, 12.5 MB peak memory", leaving out what we couldn't measure
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!("{:.1}s wall", self.wall_seconds)];
+        if let Some(usec) = self.cpu_usec {
+            parts.push(format!("{:.1}s CPU", usec as f64 / 1_000_000.0));
+        }
+        if let Some(bytes) = self.memory_peak {
+            parts.push(format!("{} peak memory", crate::pull::human_size(bytes)));
+        }
+        parts.join(", ")
+    }
+}
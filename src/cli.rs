@@ -0,0 +1,1636 @@
+use crate::completion::Shell;
+use crate::container::is_valid_name;
+use crate::data_root::DataRoot;
+use crate::health;
+use crate::logs::LogKind;
+use crate::manifest::Platform;
+use crate::ports::PortMapping;
+use crate::pull::PullPolicy;
+use crate::restart::RestartPolicy;
+use crate::rootfs::{check_host_conflicts, HostEntry, DEFAULT_UMASK};
+use crate::store::DEFAULT_LOCK_TIMEOUT;
+use crate::supervise::{parse_signal, DEFAULT_STOP_TIMEOUT};
+use crate::timezone::Timezone;
+use crate::volume::{check_targets, VolumeSpec};
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Usage:
+//   your_docker.sh [--data-root <dir>] <subcommand> ...
+//   your_docker.sh run [options] <image> [<command> <arg1> <arg2> ...]
+//   your_docker.sh run [options] --bundle <dir> [<command> <arg1> <arg2> ...]
+//   your_docker.sh bundle [--offline] [--max-layer-size <size>] [--reproducible]
+//                         --output <dir> <image>
+//   your_docker.sh pull [--dry-run] [--platform <os/arch>] [--cache-lock-timeout <secs>]
+//                       [--write-lockfile <file> [--update-lockfile]] [--oci-dir <dir>]
+//                       <image>...
+//   your_docker.sh push [--platform <os/arch>] <image> [<target>]
+//   your_docker.sh serve [--offline]
+//   your_docker.sh manifest inspect [--platform <os/arch>] [--raw] <image>
+//   your_docker.sh images
+//   your_docker.sh rootfs-digest [--platform <os/arch>] <image>
+//   your_docker.sh ps [-a] [--filter label=<key>[=<value>]] [--root <dir>]
+//   your_docker.sh inspect [--format <template>] [--root <dir>] <id>...
+//   your_docker.sh logs [--root <dir>] <id>
+//   your_docker.sh stats [--format table|json] [--root <dir>] <id>
+//   your_docker.sh stop [-t <secs>] [--root <dir>] <id>...
+//   your_docker.sh wait [--timeout <secs>] [--root <dir>] <id>...
+//   your_docker.sh rm [--root <dir>] <id>...
+//   your_docker.sh diff [--verify] [--root <dir>] <id>
+//   your_docker.sh export [-o <file>] [--exclude <pattern>]... [--root <dir>] <id>
+//   your_docker.sh cp [--root <dir>] <id>:<path> <host path> | <host path> <id>:<path>
+//   your_docker.sh up [-f <file>] [-p <project>] [--offline] [--root <dir>]
+//   your_docker.sh down [-f <file>] [-p <project>] [-t <secs>] [--root <dir>]
+//   your_docker.sh volume ls
+//   your_docker.sh volume rm <name>...
+//   your_docker.sh store repair
+//   your_docker.sh cache verify [--fix] [--jobs <n>] [--root <dir>]
+//   your_docker.sh system info
+//   your_docker.sh system prune [--dry-run] [--max-cache-size <size>] [--root <dir>]
+//   your_docker.sh completions <bash|zsh|fish>
+// run, pull, bundle, manifest and serve accept --offline (or MYDOCKER_OFFLINE=1), which never touches
+// the network and works purely from the local store, and --debug-http, which traces every
+// registry request and response on stderr with credentials redacted.
+// run --restart no|always|on-failure[:<max-retries>] has the run process start the command
+// again when it exits, with a doubling delay in between; `stop` puts an end to that. There is
+// no detached mode: run stays in the foreground to do this, and the policy only holds for as
+// long as that run process does. Killing it, or closing its terminal, ends the container for
+// good.
+// A container runs until its command has exited and so has everything the command left behind,
+// so an entrypoint that daemonizes keeps it up; --exit-on-main-process ends it with the command
+// instead. Rootless containers get no PID namespace to keep track of that in; their cgroup
+// does it instead, and without one they end with the command.
+// run --timeout <secs> stops the container once it has been up that long and exits with 124,
+// like timeout(1).
+// run --pull always|missing|never decides whether a cached image is checked against the
+// registry first; always (the default) costs a single HEAD request when the tag hasn't moved.
+// Every container gets its own hostname (the start of its id) and a random /etc/machine-id,
+// and DMI data under a bind-mounted /sys is masked; --no-identity-isolation keeps the last two
+// off.
+// run --tz host|<name> gives the container the host's timezone or a named one: TZ=<name> when
+// the image has tzdata with the zone, otherwise the zone's file mounted from the host, with
+// /etc/localtime pointing at it. -e TZ= still wins over it.
+// run -v <source>:<target>[:<options>] mounts a host path or a named volume; options are ro or
+// rw and rprivate (the default) or rslave, which lets mounts the host makes under the source
+// later show up in the container. Nested targets are mounted outer first, whatever the order
+// of the flags, and two mounts on one target are an error.
+// run probes the container with the image's Healthcheck for as long as that run process is
+// there to do it, and ps and inspect show it starting, healthy or unhealthy, or stale once the
+// run process is gone. --health-cmd '<command line>' (run by the image's shell),
+// --health-interval, --health-timeout and --health-start-period <secs> and --health-retries <n>
+// override the image's settings; --no-healthcheck turns it off.
+// run --sh (or -c) '<command line>' runs the line through the image's Shell, /bin/sh -c when
+// it declares none, or /bin/ash or /bin/bash when that isn't there. It goes before the image
+// or right after it, and nothing else may follow the image then.
+// The store, containers and volumes live under the data root: --data-root, MYDOCKER_DATA_ROOT,
+// or /var/lib/mydocker for root and $XDG_DATA_HOME/mydocker for everyone else. --root only
+// moves the containers.
+// run and bundle leave out layer entries they can't decode or create (a name that won't
+// parse, an entry type we don't support) with a warning; --strict-unpack fails instead. An
+// entry reaching outside the layer always fails it. Device nodes in layers are left out unless
+// --allow-devices, and even then only root can create them; a fifo that can't be created is
+// left out too. Neither fails the extraction, --strict-unpack or not.
+// bundle --reproducible gives every path the mtime of the layer entry that last wrote it (the
+// epoch for directories no layer has) and extracts under a fixed umask, so two extractions of
+// an image are identical. rootfs-digest extracts that way into a scratch directory and prints
+// a hash of the tree: paths, modes, owners, symlink targets and content, but not mtimes.
+// Cached blobs are hashed before they're reused; a corrupt one is moved to <data root>/quarantine
+// and downloaded again. cache verify hashes the whole store on --jobs threads and reports
+// corrupt blobs, stray files and blobs nothing needs; --fix deletes them.
+// completions prints a completion script for the shell: `source <(your_docker.sh completions
+// bash)`. It calls back into `__complete <words>...` on every TAB, which also offers container
+// names and ids, cached images and volume names where those go.
+// serve takes pull and run requests as JSON lines on stdin and answers on stdout, keeping
+// registry clients and their tokens from one request to the next (see serve.rs).
+// Options always come before the positional arguments, like docker's own CLI, so anything
+// after the image belongs to the container command.
+pub enum Subcommand {
+    // Boxed, it's bigger than everything else put together
+    Run(Box<RunOptions>),
+    Pull(PullOptions),
+    Push(PushOptions),
+    Serve(ServeOptions),
+    Bundle(BundleOptions),
+    ManifestInspect(ManifestOptions),
+    Images,
+    RootfsDigest(RootfsDigestOptions),
+    Ps(PsOptions),
+    Inspect(InspectOptions),
+    Logs(LogsOptions),
+    Stats(StatsOptions),
+    Stop(StopOptions),
+    Wait(WaitOptions),
+    Rm(RmOptions),
+    Cp(CpOptions),
+    Diff(DiffOptions),
+    Export(ExportOptions),
+    Up(UpOptions),
+    Down(DownOptions),
+    VolumeLs,
+    VolumeRm(Vec<String>),
+    StoreRepair,
+    CacheVerify(CacheVerifyOptions),
+    SystemInfo,
+    SystemPrune(PruneOptions),
+    Completions(Shell),
+    // The command line being completed, from the completion scripts
+    Complete(Vec<String>),
+}
+
+pub struct RunOptions {
+    // The bundle's path with --bundle
+    pub image: String,
+    // Run an OCI bundle's rootfs and process instead of pulling an image
+    pub bundle: Option<PathBuf>,
+    // KEY=VALUE from --env-file, then -e, applied over the image's Env in this order
+    pub env: Vec<String>,
+    // Command and its arguments; empty means use the image's Cmd
+    pub command: Vec<String>,
+    // --sh: a command line for the image's shell, in place of command
+    pub shell_command: Option<String>,
+    pub cache_lock_timeout: Duration,
+    pub add_hosts: Vec<HostEntry>,
+    // Grace period between the stop signal and SIGKILL when we shut the container down
+    pub stop_timeout: Duration,
+    // Overrides the image's StopSignal
+    pub stop_signal: Option<i32>,
+    pub umask: libc::mode_t,
+    pub offline: bool,
+    // Trace every registry request on stderr
+    pub debug_http: bool,
+    // Leave the container directory behind on exit so the rootfs can be inspected
+    pub keep_rootfs: bool,
+    // Base directory holding <id>/rootfs and <id>/state.json
+    pub root: PathBuf,
+    // --label key=value pairs in command line order
+    pub labels: Vec<(String, String)>,
+    pub publish: Vec<PortMapping>,
+    pub volumes: Vec<VolumeSpec>,
+    // Fail instead of warning when some isolation isn't available to us
+    pub strict: bool,
+    // memory.max for the container's cgroup, in bytes
+    pub memory: Option<u64>,
+    // How many CPUs' worth of time the container gets, as cpu.max
+    pub cpus: Option<f64>,
+    // Must be unique among containers under root
+    pub name: Option<String>,
+    // Print a resource usage summary when the container exits
+    pub stats: bool,
+    // Pick this platform out of an index, and run it even if the host isn't one
+    pub platform: Option<Platform>,
+    // Ceiling on a decompressed layer, instead of a multiple of its compressed size
+    pub max_layer_size: Option<u64>,
+    // Say how the container is isolated, not just what's missing
+    pub verbose: bool,
+    // Where to write the container's id once it has one; must not exist yet
+    pub cidfile: Option<PathBuf>,
+    // Extract layers as they download; --no-pipeline downloads everything first
+    pub pipeline: bool,
+    // Overrides the image's User: name, uid, name:group or uid:gid
+    pub user: Option<String>,
+    // Write /etc/passwd and /etc/group for the user if the image has none
+    pub synthesize_user: bool,
+    // The container ends with its command, whatever the command left running
+    pub exit_on_main_process: bool,
+    // Where the container's output is kept besides our own stdout and stderr
+    pub log_driver: LogKind,
+    // Whether run, attached and in the foreground, starts the command again when it exits
+    pub restart: RestartPolicy,
+    // Wall-clock limit, restarts included, after which the container is stopped
+    pub timeout: Option<Duration>,
+    // Only run the image if it resolves to the digests pinned in this file
+    pub lockfile: Option<PathBuf>,
+    // Whether a cached image is checked against the registry, --offline overrides it
+    pub pull: PullPolicy,
+    // A machine-id of the container's own and masks over the host's DMI data in /sys;
+    // --no-identity-isolation leaves both as the image and the mounts have them
+    pub identity_isolation: bool,
+    // Fail on layer entries that can't be decoded or created instead of leaving them out
+    pub strict_unpack: bool,
+    // Create the layers' device nodes (as root) instead of leaving them out
+    pub allow_devices: bool,
+    // The container's timezone instead of the image's, usually UTC
+    pub tz: Option<Timezone>,
+    // --health-* over the image's Healthcheck, or --no-healthcheck
+    pub health: health::Overrides,
+}
+
+pub struct BundleOptions {
+    pub image: String,
+    pub output: PathBuf,
+    pub max_layer_size: Option<u64>,
+    pub offline: bool,
+    pub debug_http: bool,
+    pub pipeline: bool,
+    pub strict_unpack: bool,
+    pub allow_devices: bool,
+    // Pin mtimes and the umask so the rootfs is the same wherever it's extracted
+    pub reproducible: bool,
+    pub cache_lock_timeout: Duration,
+}
+
+pub struct RootfsDigestOptions {
+    pub image: String,
+    pub platform: Option<Platform>,
+    pub max_layer_size: Option<u64>,
+    pub offline: bool,
+    pub debug_http: bool,
+    pub cache_lock_timeout: Duration,
+}
+
+pub struct PullOptions {
+    pub images: Vec<String>,
+    pub dry_run: bool,
+    // Record what each image resolved to here; pins already in the file must still match
+    pub write_lockfile: Option<PathBuf>,
+    // Replace pins that no longer match instead of failing
+    pub update_lockfile: bool,
+    // Also write each image into an OCI image layout here
+    pub oci_dir: Option<PathBuf>,
+    pub platform: Option<Platform>,
+    pub offline: bool,
+    pub debug_http: bool,
+    pub cache_lock_timeout: Duration,
+}
+
+pub struct PruneOptions {
+    pub dry_run: bool,
+    // Evict least recently used layers until the store is at most this big
+    pub max_cache_size: Option<u64>,
+    pub cache_lock_timeout: Duration,
+    // Containers whose images must stay
+    pub root: PathBuf,
+}
+
+pub struct CacheVerifyOptions {
+    // Delete what's corrupt, stray or orphaned instead of only reporting it
+    pub fix: bool,
+    // Blobs hashed at once
+    pub jobs: usize,
+    pub cache_lock_timeout: Duration,
+    // Containers whose images aren't orphaned
+    pub root: PathBuf,
+}
+
+pub struct PushOptions {
+    // An image in the local store
+    pub image: String,
+    // Where it goes, the image's own reference when not given
+    pub target: Option<String>,
+    pub platform: Option<Platform>,
+    pub debug_http: bool,
+    pub cache_lock_timeout: Duration,
+}
+
+pub struct ServeOptions {
+    pub offline: bool,
+    pub debug_http: bool,
+}
+
+pub struct ManifestOptions {
+    pub image: String,
+    pub platform: Option<Platform>,
+    pub raw: bool,
+    pub offline: bool,
+    pub debug_http: bool,
+}
+
+pub struct PsOptions {
+    // Include exited containers whose rootfs was kept
+    pub all: bool,
+    // label=... conditions, all of which must hold
+    pub label_filters: Vec<String>,
+    pub root: PathBuf,
+}
+
+pub struct InspectOptions {
+    pub ids: Vec<String>,
+    // A template like '{{.State.ExitCode}}' to print for each container instead of the JSON
+    pub format: Option<String>,
+    pub root: PathBuf,
+}
+
+pub struct LogsOptions {
+    pub id: String,
+    pub root: PathBuf,
+}
+
+pub struct StatsOptions {
+    pub id: String,
+    // Newline-delimited JSON instead of a table
+    pub json: bool,
+    pub root: PathBuf,
+}
+
+pub struct StopOptions {
+    pub ids: Vec<String>,
+    // Overrides the grace period each container was started with
+    pub timeout: Option<Duration>,
+    pub root: PathBuf,
+}
+
+pub struct WaitOptions {
+    pub ids: Vec<String>,
+    // Give up on containers still running after this long
+    pub timeout: Option<Duration>,
+    pub root: PathBuf,
+}
+
+pub struct RmOptions {
+    pub ids: Vec<String>,
+    pub root: PathBuf,
+}
+
+pub struct ExportOptions {
+    pub id: String,
+    // Standard output when there's none
+    pub output: Option<PathBuf>,
+    // .dockerignore-style patterns, see exclude.rs
+    pub excludes: Vec<String>,
+    pub root: PathBuf,
+}
+
+pub struct DiffOptions {
+    pub id: String,
+    // Also catch edits made with the timestamps put back
+    pub verify: bool,
+    pub root: PathBuf,
+}
+
+pub struct CpOptions {
+    pub source: String,
+    pub destination: String,
+    pub root: PathBuf,
+}
+
+pub struct UpOptions {
+    // compose.yaml and friends in the current directory when not given
+    pub file: Option<PathBuf>,
+    // Overrides the file's name and the directory it's in
+    pub project_name: Option<String>,
+    pub offline: bool,
+    pub root: PathBuf,
+}
+
+pub struct DownOptions {
+    pub file: Option<PathBuf>,
+    pub project_name: Option<String>,
+    // Overrides the grace period each container was started with
+    pub timeout: Option<Duration>,
+    pub root: PathBuf,
+}
+
+// --data-root is the one option that goes before the subcommand, it applies to all of them
+pub fn parse(args: &[String]) -> Result<(DataRoot, Subcommand)> {
+    let (flag, args) = match args {
+        [flag, path, rest @ ..] if flag == "--data-root" => (Some(PathBuf::from(path)), rest),
+        [flag, rest @ ..] if flag.starts_with("--data-root=") => (
+            Some(PathBuf::from(flag.trim_start_matches("--data-root="))),
+            rest,
+        ),
+        _ => (None, args),
+    };
+    let data_root = DataRoot::resolve(flag)?;
+    let subcommand = parse_subcommand(args, &data_root)?;
+    Ok((data_root, subcommand))
+}
+
+fn parse_subcommand(args: &[String], data_root: &DataRoot) -> Result<Subcommand> {
+    let (subcommand, rest) = match args.split_first() {
+        Some((subcommand, rest)) => (subcommand.as_str(), rest),
+        None => {
+            bail!("Usage: your_docker.sh [--data-root <dir>] <run|bundle|pull|manifest|images|rootfs-digest|ps|inspect|logs|stats|stop|wait|rm|cp|diff|export|up|down|volume|store|cache|system|completions> ...")
+        }
+    };
+
+    match subcommand {
+        "run" => parse_run(rest, data_root).map(|options| Subcommand::Run(Box::new(options))),
+        "bundle" => parse_bundle(rest).map(Subcommand::Bundle),
+        "pull" => parse_pull(rest).map(Subcommand::Pull),
+        "push" => parse_push(rest).map(Subcommand::Push),
+        "serve" => parse_serve(rest).map(Subcommand::Serve),
+        "manifest" => match rest.split_first() {
+            Some((action, rest)) if action == "inspect" => {
+                parse_manifest_inspect(rest).map(Subcommand::ManifestInspect)
+            }
+            _ => bail!("Usage: your_docker.sh manifest inspect [options] <image>"),
+        },
+        "images" => match rest {
+            [] => Ok(Subcommand::Images),
+            _ => bail!("Usage: your_docker.sh images"),
+        },
+        "rootfs-digest" => parse_rootfs_digest(rest).map(Subcommand::RootfsDigest),
+        "ps" => parse_ps(rest, data_root).map(Subcommand::Ps),
+        "inspect" => parse_inspect(rest, data_root).map(Subcommand::Inspect),
+        "logs" => parse_logs(rest, data_root).map(Subcommand::Logs),
+        "stats" => parse_stats(rest, data_root).map(Subcommand::Stats),
+        "stop" => parse_stop(rest, data_root).map(Subcommand::Stop),
+        "wait" => parse_wait(rest, data_root).map(Subcommand::Wait),
+        "rm" => parse_rm(rest, data_root).map(Subcommand::Rm),
+        "cp" => parse_cp(rest, data_root).map(Subcommand::Cp),
+        "diff" => parse_diff(rest, data_root).map(Subcommand::Diff),
+        "export" => parse_export(rest, data_root).map(Subcommand::Export),
+        "up" => parse_up(rest, data_root).map(Subcommand::Up),
+        "down" => parse_down(rest, data_root).map(Subcommand::Down),
+        "volume" => match rest.split_first() {
+            Some((action, [])) if action == "ls" => Ok(Subcommand::VolumeLs),
+            Some((action, names)) if action == "rm" && !names.is_empty() => {
+                Ok(Subcommand::VolumeRm(names.to_vec()))
+            }
+            _ => bail!("Usage: your_docker.sh volume <ls | rm <name>...>"),
+        },
+        "store" => match rest {
+            [action] if action == "repair" => Ok(Subcommand::StoreRepair),
+            _ => bail!("Usage: your_docker.sh store repair"),
+        },
+        "cache" => match rest.split_first() {
+            Some((action, args)) if action == "verify" => {
+                parse_cache_verify(args, data_root).map(Subcommand::CacheVerify)
+            }
+            _ => bail!("Usage: your_docker.sh cache verify [options]"),
+        },
+        "system" => match rest.split_first() {
+            Some((action, [])) if action == "info" => Ok(Subcommand::SystemInfo),
+            Some((action, args)) if action == "prune" => {
+                parse_prune(args, data_root).map(Subcommand::SystemPrune)
+            }
+            _ => bail!("Usage: your_docker.sh system <info | prune [options]>"),
+        },
+        "completions" => match rest {
+            [shell] => Shell::parse(shell).map(Subcommand::Completions),
+            _ => bail!("Usage: your_docker.sh completions <bash|zsh|fish>"),
+        },
+        "__complete" => Ok(Subcommand::Complete(rest.to_vec())),
+        other => bail!("Unknown subcommand '{}'", other),
+    }
+}
+
+fn parse_run(args: &[String], data_root: &DataRoot) -> Result<RunOptions> {
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+    let mut add_hosts = vec![];
+    let mut stop_timeout = DEFAULT_STOP_TIMEOUT;
+    let mut stop_signal = None;
+    let mut umask = DEFAULT_UMASK;
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+    let mut keep_rootfs = false;
+    let mut root = data_root.containers();
+    let mut labels = vec![];
+    let mut publish = vec![];
+    let mut volumes = vec![];
+    let mut strict = false;
+    let mut memory = None;
+    let mut cpus = None;
+    let mut name = None;
+    let mut stats = false;
+    let mut platform = None;
+    let mut max_layer_size = None;
+    let mut verbose = false;
+    let mut cidfile = None;
+    let mut pipeline = true;
+    let mut strict_unpack = false;
+    let mut allow_devices = false;
+    let mut user = None;
+    let mut synthesize_user = false;
+    let mut exit_on_main_process = false;
+    let mut tz = None;
+    let mut health = health::Overrides::default();
+    let mut shell_command = None;
+    let mut log_driver = LogKind::File;
+    let mut restart = RestartPolicy::No;
+    let mut timeout = None;
+    let mut lockfile = None;
+    let mut pull = PullPolicy::Always;
+    let mut identity_isolation = true;
+    let mut bundle = None;
+    let mut env_files = vec![];
+    let mut env = vec![];
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            "--add-host" => add_hosts.push(HostEntry::parse(&flags.value(flag)?)?),
+            "--tz" => tz = Some(Timezone::parse(&flags.value(flag)?)?),
+            "--health-cmd" => health.cmd = Some(flags.value(flag)?),
+            "--health-interval" => {
+                health.interval = Some(parse_health_period(
+                    &flags.value(flag)?,
+                    "--health-interval",
+                )?)
+            }
+            "--health-timeout" => {
+                health.timeout = Some(parse_health_period(
+                    &flags.value(flag)?,
+                    "--health-timeout",
+                )?)
+            }
+            "--health-start-period" => {
+                health.start_period = Some(parse_seconds(&flags.value(flag)?)?)
+            }
+            "--health-retries" => {
+                let value = flags.value(flag)?;
+                health.retries = match value.parse() {
+                    Ok(retries) if retries > 0 => Some(retries),
+                    _ => bail!(
+                        "Invalid --health-retries '{}', expected a positive number",
+                        value
+                    ),
+                }
+            }
+            "--no-healthcheck" => health.disabled = flag.switch()?,
+            "-c" | "--sh" => shell_command = Some(flags.value(flag)?),
+            "--stop-timeout" => stop_timeout = parse_seconds(&flags.value(flag)?)?,
+            "--stop-signal" => stop_signal = Some(parse_signal(&flags.value(flag)?)?),
+            "--umask" => umask = parse_umask(&flags.value(flag)?)?,
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            "--keep-rootfs" => keep_rootfs = flag.switch()?,
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            "-l" | "--label" => labels.push(parse_label(&flags.value(flag)?)?),
+            "-p" | "--publish" => publish.push(PortMapping::parse(&flags.value(flag)?)?),
+            "-v" | "--volume" => volumes.push(VolumeSpec::parse(&flags.value(flag)?)?),
+            // --require-isolation is the older name
+            "--strict" | "--require-isolation" => strict = flag.switch()?,
+            "-m" | "--memory" => memory = Some(parse_size(&flags.value(flag)?, "--memory")?),
+            "--stats" => stats = flag.switch()?,
+            "--verbose" => verbose = flag.switch()?,
+            "--cidfile" => cidfile = Some(PathBuf::from(flags.value(flag)?)),
+            "--no-pipeline" => pipeline = !flag.switch()?,
+            "--strict-unpack" => strict_unpack = flag.switch()?,
+            "--allow-devices" => allow_devices = flag.switch()?,
+            "-u" | "--user" => user = Some(parse_user(&flags.value(flag)?)?),
+            "--synthesize-user" => synthesize_user = flag.switch()?,
+            "--exit-on-main-process" => exit_on_main_process = flag.switch()?,
+            "--log-driver" => log_driver = LogKind::parse(&flags.value(flag)?)?,
+            "--restart" => restart = RestartPolicy::parse(&flags.value(flag)?)?,
+            "--timeout" => timeout = Some(parse_time_limit(&flags.value(flag)?)?),
+            "--lockfile" => lockfile = Some(PathBuf::from(flags.value(flag)?)),
+            "--pull" => pull = PullPolicy::parse(&flags.value(flag)?)?,
+            "--no-identity-isolation" => identity_isolation = !flag.switch()?,
+            "--platform" => platform = Some(Platform::parse(&flags.value(flag)?)?),
+            "--max-layer-size" => {
+                max_layer_size = Some(parse_size(&flags.value(flag)?, "--max-layer-size")?)
+            }
+            "--cpus" => cpus = Some(parse_cpus(&flags.value(flag)?)?),
+            "--name" => name = Some(parse_name(&flags.value(flag)?)?),
+            "--bundle" => bundle = Some(PathBuf::from(flags.value(flag)?)),
+            "--env-file" => env_files.extend(read_env_file(&flags.value(flag)?)?),
+            "-e" | "--env" => env.extend(parse_env(&flags.value(flag)?)),
+            _ => bail!("Unknown option '{}' for run", flag.name),
+        }
+    }
+    check_host_conflicts(&add_hosts)?;
+    if bundle.is_some() && lockfile.is_some() {
+        bail!("--lockfile pins images from a registry, it doesn't apply to --bundle");
+    }
+    // Files first whatever order the flags came in, so -e always wins
+    env_files.extend(env);
+    let env = env_files;
+    check_targets(&volumes)?;
+    let overridden = health.cmd.is_some()
+        || health.interval.is_some()
+        || health.timeout.is_some()
+        || health.start_period.is_some()
+        || health.retries.is_some();
+    if health.disabled && overridden {
+        bail!("--no-healthcheck conflicts with the --health-* options");
+    }
+
+    // A bundle takes the image's place, so every positional belongs to the command
+    let positional = flags.positional();
+    let mut image_and_command = match &bundle {
+        Some(bundle) => Some((bundle.display().to_string(), positional)),
+        None => positional
+            .split_first()
+            .map(|(image, command)| (image.clone(), command)),
+    };
+    // Options end at the image, but `run alpine --sh 'echo hi'` reads too naturally to exec a
+    // binary called --sh instead
+    if let Some((_, command)) = &mut image_and_command {
+        match command.split_first() {
+            Some((flag, rest)) if shell_command.is_none() && (flag == "--sh" || flag == "-c") => {
+                let (line, rest) = rest
+                    .split_first()
+                    .with_context(|| format!("Option '{}' requires a value", flag))?;
+                shell_command = Some(line.clone());
+                *command = rest;
+            }
+            Some((flag, rest)) if shell_command.is_none() && flag.starts_with("--sh=") => {
+                shell_command = Some(flag["--sh=".len()..].to_string());
+                *command = rest;
+            }
+            _ => {}
+        }
+    }
+    if let (Some(_), Some((_, [first, ..]))) = (&shell_command, &image_and_command) {
+        bail!(
+            "--sh takes the whole command line as one argument, but '{}' follows the image",
+            first
+        );
+    }
+    match image_and_command {
+        Some((image, command)) => Ok(RunOptions {
+            image,
+            bundle,
+            env,
+            command: command.to_vec(),
+            shell_command,
+            cache_lock_timeout,
+            add_hosts,
+            stop_timeout,
+            stop_signal,
+            umask,
+            offline,
+            debug_http,
+            keep_rootfs,
+            root,
+            labels,
+            publish,
+            volumes,
+            strict,
+            memory,
+            cpus,
+            name,
+            stats,
+            platform,
+            max_layer_size,
+            verbose,
+            cidfile,
+            pipeline,
+            strict_unpack,
+            allow_devices,
+            user,
+            synthesize_user,
+            exit_on_main_process,
+            log_driver,
+            restart,
+            timeout,
+            lockfile,
+            pull,
+            identity_isolation,
+            tz,
+            health,
+        }),
+        None => {
+            bail!("Usage: your_docker.sh run [options] <image> [<command> <arg1> <arg2> ...]")
+        }
+    }
+}
+
+fn parse_bundle(args: &[String]) -> Result<BundleOptions> {
+    let mut output = None;
+    let mut max_layer_size = None;
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+    let mut pipeline = true;
+    let mut strict_unpack = false;
+    let mut allow_devices = false;
+    let mut reproducible = false;
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(flags.value(flag)?)),
+            "--no-pipeline" => pipeline = !flag.switch()?,
+            "--strict-unpack" => strict_unpack = flag.switch()?,
+            "--allow-devices" => allow_devices = flag.switch()?,
+            "--reproducible" => reproducible = flag.switch()?,
+            "--max-layer-size" => {
+                max_layer_size = Some(parse_size(&flags.value(flag)?, "--max-layer-size")?)
+            }
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            _ => bail!("Unknown option '{}' for bundle", flag.name),
+        }
+    }
+
+    match (flags.positional(), output) {
+        ([image], Some(output)) => Ok(BundleOptions {
+            image: image.clone(),
+            output,
+            max_layer_size,
+            offline,
+            debug_http,
+            pipeline,
+            strict_unpack,
+            allow_devices,
+            reproducible,
+            cache_lock_timeout,
+        }),
+        _ => bail!("Usage: your_docker.sh bundle [--offline] --output <dir> <image>"),
+    }
+}
+
+fn parse_rootfs_digest(args: &[String]) -> Result<RootfsDigestOptions> {
+    let mut platform = None;
+    let mut max_layer_size = None;
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--platform" => platform = Some(Platform::parse(&flags.value(flag)?)?),
+            "--max-layer-size" => {
+                max_layer_size = Some(parse_size(&flags.value(flag)?, "--max-layer-size")?)
+            }
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            _ => bail!("Unknown option '{}' for rootfs-digest", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [image] => Ok(RootfsDigestOptions {
+            image: image.clone(),
+            platform,
+            max_layer_size,
+            offline,
+            debug_http,
+            cache_lock_timeout,
+        }),
+        _ => bail!("Usage: your_docker.sh rootfs-digest [--platform <os/arch>] <image>"),
+    }
+}
+
+fn parse_pull(args: &[String]) -> Result<PullOptions> {
+    let mut dry_run = false;
+    let mut write_lockfile = None;
+    let mut update_lockfile = false;
+    let mut oci_dir = None;
+    let mut platform = None;
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--dry-run" => dry_run = flag.switch()?,
+            "--write-lockfile" => write_lockfile = Some(PathBuf::from(flags.value(flag)?)),
+            "--update-lockfile" => update_lockfile = flag.switch()?,
+            "--oci-dir" => oci_dir = Some(PathBuf::from(flags.value(flag)?)),
+            "--platform" => platform = Some(Platform::parse(&flags.value(flag)?)?),
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            _ => bail!("Unknown option '{}' for pull", flag.name),
+        }
+    }
+
+    if update_lockfile && write_lockfile.is_none() {
+        bail!("--update-lockfile only makes sense with --write-lockfile");
+    }
+    // A dry run downloads nothing, so it has no business pinning anything either
+    if dry_run && write_lockfile.is_some() {
+        bail!("--write-lockfile can't be combined with --dry-run");
+    }
+    if dry_run && oci_dir.is_some() {
+        bail!("--oci-dir can't be combined with --dry-run");
+    }
+
+    match flags.positional() {
+        [] => bail!("Usage: your_docker.sh pull [options] <image>..."),
+        images => Ok(PullOptions {
+            images: images.to_vec(),
+            dry_run,
+            write_lockfile,
+            update_lockfile,
+            oci_dir,
+            platform,
+            offline,
+            debug_http,
+            cache_lock_timeout,
+        }),
+    }
+}
+
+fn parse_prune(args: &[String], data_root: &DataRoot) -> Result<PruneOptions> {
+    let mut dry_run = false;
+    let mut max_cache_size = None;
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--dry-run" => dry_run = flag.switch()?,
+            "--max-cache-size" => {
+                max_cache_size = Some(parse_size(&flags.value(flag)?, "--max-cache-size")?)
+            }
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for system prune", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => Ok(PruneOptions {
+            dry_run,
+            max_cache_size,
+            cache_lock_timeout,
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh system prune [options]"),
+    }
+}
+
+fn parse_cache_verify(args: &[String], data_root: &DataRoot) -> Result<CacheVerifyOptions> {
+    let mut fix = false;
+    // Past a handful the disk is the limit, not the hashing
+    let mut jobs = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(8);
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--fix" => fix = flag.switch()?,
+            "--jobs" => {
+                let value = flags.value(flag)?;
+                jobs = match value.parse() {
+                    Ok(jobs) if jobs > 0 => jobs,
+                    _ => bail!("Invalid --jobs '{}', expected a positive number", value),
+                }
+            }
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for cache verify", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => Ok(CacheVerifyOptions {
+            fix,
+            jobs,
+            cache_lock_timeout,
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh cache verify [options]"),
+    }
+}
+
+fn parse_push(args: &[String]) -> Result<PushOptions> {
+    let mut platform = None;
+    let mut debug_http = false;
+    let mut cache_lock_timeout = DEFAULT_LOCK_TIMEOUT;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--platform" => platform = Some(Platform::parse(&flags.value(flag)?)?),
+            "--debug-http" => debug_http = flag.switch()?,
+            "--cache-lock-timeout" => cache_lock_timeout = parse_seconds(&flags.value(flag)?)?,
+            _ => bail!("Unknown option '{}' for push", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [image, rest @ ..] if rest.len() <= 1 => Ok(PushOptions {
+            image: image.clone(),
+            target: rest.first().cloned(),
+            platform,
+            debug_http,
+            cache_lock_timeout,
+        }),
+        _ => bail!("Usage: your_docker.sh push [--platform <os/arch>] <image> [<target>]"),
+    }
+}
+
+fn parse_serve(args: &[String]) -> Result<ServeOptions> {
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            _ => bail!("Unknown option '{}' for serve", flag.name),
+        }
+    }
+    if !flags.positional().is_empty() {
+        bail!("Usage: your_docker.sh serve [--offline]");
+    }
+    Ok(ServeOptions {
+        offline,
+        debug_http,
+    })
+}
+
+fn parse_manifest_inspect(args: &[String]) -> Result<ManifestOptions> {
+    let mut platform = None;
+    let mut raw = false;
+    let mut offline = offline_from_env();
+    let mut debug_http = false;
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--platform" => platform = Some(Platform::parse(&flags.value(flag)?)?),
+            "--raw" => raw = flag.switch()?,
+            "--offline" => offline = flag.switch()?,
+            "--debug-http" => debug_http = flag.switch()?,
+            _ => bail!("Unknown option '{}' for manifest inspect", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [image] => Ok(ManifestOptions {
+            image: image.clone(),
+            platform,
+            raw,
+            offline,
+            debug_http,
+        }),
+        _ => bail!("Usage: your_docker.sh manifest inspect [--platform <os/arch>] [--raw] <image>"),
+    }
+}
+
+fn parse_ps(args: &[String], data_root: &DataRoot) -> Result<PsOptions> {
+    let mut all = false;
+    let mut label_filters = vec![];
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-a" | "--all" => all = flag.switch()?,
+            "-f" | "--filter" => {
+                let filter = flags.value(flag)?;
+                match filter.split_once('=') {
+                    Some(("label", condition)) if !condition.is_empty() => {
+                        label_filters.push(condition.to_string())
+                    }
+                    _ => bail!(
+                        "Unsupported filter '{}', expected label=<key>[=<value>]",
+                        filter
+                    ),
+                }
+            }
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for ps", flag.name),
+        }
+    }
+
+    if !flags.positional().is_empty() {
+        bail!("Usage: your_docker.sh ps [-a] [--filter label=<key>[=<value>]] [--root <dir>]");
+    }
+    Ok(PsOptions {
+        all,
+        label_filters,
+        root,
+    })
+}
+
+fn parse_inspect(args: &[String], data_root: &DataRoot) -> Result<InspectOptions> {
+    let mut format = None;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-f" | "--format" => format = Some(flags.value(flag)?),
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for inspect", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => bail!("Usage: your_docker.sh inspect [--format <template>] [--root <dir>] <id>..."),
+        ids => Ok(InspectOptions {
+            ids: ids.to_vec(),
+            format,
+            root,
+        }),
+    }
+}
+
+fn parse_logs(args: &[String], data_root: &DataRoot) -> Result<LogsOptions> {
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for logs", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [id] => Ok(LogsOptions {
+            id: id.clone(),
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh logs [--root <dir>] <id>"),
+    }
+}
+
+fn parse_stats(args: &[String], data_root: &DataRoot) -> Result<StatsOptions> {
+    let mut json = false;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--format" => match flags.value(flag)?.as_str() {
+                "json" => json = true,
+                "table" => json = false,
+                other => bail!("Unknown stats format '{}', expected table or json", other),
+            },
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for stats", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [id] => Ok(StatsOptions {
+            id: id.clone(),
+            json,
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh stats [--format table|json] [--root <dir>] <id>"),
+    }
+}
+
+fn parse_stop(args: &[String], data_root: &DataRoot) -> Result<StopOptions> {
+    let mut timeout = None;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-t" | "--time" => timeout = Some(parse_seconds(&flags.value(flag)?)?),
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for stop", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => bail!("Usage: your_docker.sh stop [-t <secs>] [--root <dir>] <id>..."),
+        ids => Ok(StopOptions {
+            ids: ids.to_vec(),
+            timeout,
+            root,
+        }),
+    }
+}
+
+fn parse_wait(args: &[String], data_root: &DataRoot) -> Result<WaitOptions> {
+    let mut timeout = None;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--timeout" => timeout = Some(parse_seconds(&flags.value(flag)?)?),
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for wait", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => bail!("Usage: your_docker.sh wait [--timeout <secs>] [--root <dir>] <id>..."),
+        ids => Ok(WaitOptions {
+            ids: ids.to_vec(),
+            timeout,
+            root,
+        }),
+    }
+}
+
+fn parse_rm(args: &[String], data_root: &DataRoot) -> Result<RmOptions> {
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for rm", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => bail!("Usage: your_docker.sh rm [--root <dir>] <id>..."),
+        ids => Ok(RmOptions {
+            ids: ids.to_vec(),
+            root,
+        }),
+    }
+}
+
+fn parse_diff(args: &[String], data_root: &DataRoot) -> Result<DiffOptions> {
+    let mut verify = false;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--verify" => verify = flag.switch()?,
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for diff", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [id] => Ok(DiffOptions {
+            id: id.clone(),
+            verify,
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh diff [--verify] [--root <dir>] <id>"),
+    }
+}
+
+fn parse_export(args: &[String], data_root: &DataRoot) -> Result<ExportOptions> {
+    let mut output = None;
+    let mut excludes = vec![];
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(flags.value(flag)?)),
+            "--exclude" => excludes.push(flags.value(flag)?),
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for export", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [id] => Ok(ExportOptions {
+            id: id.clone(),
+            output,
+            excludes,
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh export [-o <file>] [--exclude <pattern>]... <id>"),
+    }
+}
+
+fn parse_cp(args: &[String], data_root: &DataRoot) -> Result<CpOptions> {
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for cp", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [source, destination] => Ok(CpOptions {
+            source: source.clone(),
+            destination: destination.clone(),
+            root,
+        }),
+        _ => bail!("Usage: your_docker.sh cp [--root <dir>] <source> <destination>"),
+    }
+}
+
+fn parse_up(args: &[String], data_root: &DataRoot) -> Result<UpOptions> {
+    let mut file = None;
+    let mut project_name = None;
+    let mut offline = offline_from_env();
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-f" | "--file" => file = Some(PathBuf::from(flags.value(flag)?)),
+            "-p" | "--project-name" => project_name = Some(flags.value(flag)?),
+            "--offline" => offline = flag.switch()?,
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for up", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => Ok(UpOptions {
+            file,
+            project_name,
+            offline,
+            root,
+        }),
+        _ => {
+            bail!("Usage: your_docker.sh up [-f <file>] [-p <project>] [--offline] [--root <dir>]")
+        }
+    }
+}
+
+fn parse_down(args: &[String], data_root: &DataRoot) -> Result<DownOptions> {
+    let mut file = None;
+    let mut project_name = None;
+    let mut timeout = None;
+    let mut root = data_root.containers();
+
+    let mut flags = Flags::new(args);
+    while let Some(flag) = flags.next_flag() {
+        match flag.name.as_str() {
+            "-f" | "--file" => file = Some(PathBuf::from(flags.value(flag)?)),
+            "-p" | "--project-name" => project_name = Some(flags.value(flag)?),
+            "-t" | "--timeout" => timeout = Some(parse_seconds(&flags.value(flag)?)?),
+            "--root" => root = PathBuf::from(flags.value(flag)?),
+            _ => bail!("Unknown option '{}' for down", flag.name),
+        }
+    }
+
+    match flags.positional() {
+        [] => Ok(DownOptions {
+            file,
+            project_name,
+            timeout,
+            root,
+        }),
+        _ => bail!(
+            "Usage: your_docker.sh down [-f <file>] [-p <project>] [-t <secs>] [--root <dir>]"
+        ),
+    }
+}
+
+// MYDOCKER_OFFLINE=1 is the same as passing --offline everywhere, for air-gapped machines
+fn offline_from_env() -> bool {
+    matches!(
+        std::env::var("MYDOCKER_OFFLINE").as_deref(),
+        Ok("1") | Ok("true") | Ok("yes")
+    )
+}
+
+// key=value, or a bare key for a label with an empty value
+fn parse_label(value: &str) -> Result<(String, String)> {
+    let (key, label) = value.split_once('=').unwrap_or((value, ""));
+    if key.is_empty() {
+        bail!("Invalid --label '{}', expected key=value", value);
+    }
+    Ok((key.to_string(), label.to_string()))
+}
+
+fn parse_seconds(value: &str) -> Result<Duration> {
+    let seconds = value
+        .parse::<u64>()
+        .with_context(|| format!("Expected a number of seconds, got '{}'", value))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+// Probing all the time, or giving every probe no time at all, isn't a healthcheck
+fn parse_health_period(value: &str, name: &str) -> Result<Duration> {
+    match parse_seconds(value)? {
+        period if period.is_zero() => {
+            bail!("Invalid {} '{}', expected at least 1 second", name, value)
+        }
+        period => Ok(period),
+    }
+}
+
+// A time limit of nothing at all would stop the container before it starts
+fn parse_time_limit(value: &str) -> Result<Duration> {
+    match parse_seconds(value)? {
+        limit if limit.is_zero() => {
+            bail!("Invalid --timeout '{}', expected at least 1 second", value)
+        }
+        limit => Ok(limit),
+    }
+}
+
+// docker's size syntax: a number of bytes with an optional b/k/m/g suffix
+pub fn parse_size(value: &str, option: &str) -> Result<u64> {
+    let lower = value.to_ascii_lowercase();
+    let (number, multiplier) = match lower.chars().last() {
+        Some('b') => (&lower[..lower.len() - 1], 1),
+        Some('k') => (&lower[..lower.len() - 1], 1 << 10),
+        Some('m') => (&lower[..lower.len() - 1], 1 << 20),
+        Some('g') => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (lower.as_str(), 1),
+    };
+    match number.parse::<u64>() {
+        Ok(amount) if amount > 0 => Ok(amount * multiplier),
+        _ => bail!(
+            "Invalid {} '{}', expected something like 512m or 1g",
+            option,
+            value
+        ),
+    }
+}
+
+// -e KEY=VALUE, or -e KEY to pass the host's value through (skipped if it isn't set)
+fn parse_env(value: &str) -> Option<String> {
+    if value.contains('=') {
+        return Some(value.to_string());
+    }
+    std::env::var(value)
+        .ok()
+        .map(|host| format!("{}={}", value, host))
+}
+
+fn read_env_file(path: &str) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path))?;
+    parse_env_lines(&contents).with_context(|| format!("Invalid env file {}", path))
+}
+
+// docker's env file format: a KEY=VALUE per line, blank lines and # comments ignored. Values
+// are taken verbatim, quotes and backslashes included, and only the key loses leading
+// whitespace. A bare KEY takes the host's value and is an error if the host doesn't have one.
+fn parse_env_lines(contents: &str) -> Result<Vec<String>> {
+    let mut variables = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match trimmed.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (trimmed, None),
+        };
+        if key.is_empty() {
+            bail!("line {}: missing variable name", number + 1);
+        }
+        if key.contains(char::is_whitespace) {
+            bail!(
+                "line {}: variable '{}' contains whitespace",
+                number + 1,
+                key
+            );
+        }
+        match value {
+            Some(value) => variables.push(format!("{}={}", key, value)),
+            None => match std::env::var(key) {
+                Ok(host) => variables.push(format!("{}={}", key, host)),
+                Err(_) => bail!(
+                    "line {}: '{}' has no value and isn't set on the host",
+                    number + 1,
+                    key
+                ),
+            },
+        }
+    }
+    Ok(variables)
+}
+
+// Down to a hundredth of a CPU, the smallest quota cpu.max takes with the default period
+pub fn parse_cpus(value: &str) -> Result<f64> {
+    match value.parse::<f64>() {
+        Ok(cpus) if cpus.is_finite() && cpus >= 0.01 => Ok(cpus),
+        _ => bail!(
+            "Invalid --cpus '{}', expected a number of CPUs like 0.5 or 2",
+            value
+        ),
+    }
+}
+
+fn parse_name(value: &str) -> Result<String> {
+    if !is_valid_name(value) {
+        bail!(
+            "Invalid --name '{}', use letters, digits, '_', '.' and '-'",
+            value
+        );
+    }
+    Ok(value.to_string())
+}
+
+// Only the shape is checked here, names are looked up in the image once it's unpacked
+fn parse_user(value: &str) -> Result<String> {
+    let valid = match value.split_once(':') {
+        Some((user, group)) => !user.is_empty() && !group.is_empty() && !group.contains(':'),
+        None => !value.is_empty(),
+    };
+    if !valid {
+        bail!(
+            "Invalid --user '{}', expected name, uid, name:group or uid:gid",
+            value
+        );
+    }
+    Ok(value.to_string())
+}
+
+fn parse_umask(value: &str) -> Result<libc::mode_t> {
+    match libc::mode_t::from_str_radix(value, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        _ => bail!(
+            "Invalid --umask '{}', expected an octal value like 022",
+            value
+        ),
+    }
+}
+
+struct Flag {
+    name: String,
+    // Value given inline as --name=value
+    inline: Option<String>,
+}
+
+impl Flag {
+    // For boolean options, which don't accept --name=value
+    fn switch(&self) -> Result<bool> {
+        if self.inline.is_some() {
+            bail!("Option '{}' does not take a value", self.name);
+        }
+        Ok(true)
+    }
+}
+
+// Minimal getopt-style walker: yields leading "-x"/"--name[=value]" arguments and stops at the
+// first positional argument or at "--"
+struct Flags<'a> {
+    args: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Flags<'a> {
+    fn new(args: &'a [String]) -> Flags<'a> {
+        Flags { args, pos: 0 }
+    }
+
+    fn next_flag(&mut self) -> Option<Flag> {
+        let arg = self.args.get(self.pos)?;
+        if arg == "--" {
+            self.pos += 1;
+            return None;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            return None;
+        }
+        self.pos += 1;
+
+        match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => Some(Flag {
+                name: name.to_string(),
+                inline: Some(value.to_string()),
+            }),
+            _ => Some(Flag {
+                name: arg.clone(),
+                inline: None,
+            }),
+        }
+    }
+
+    // The value for a flag that takes one, either inline or as the following argument
+    fn value(&mut self, flag: Flag) -> Result<String> {
+        if let Some(value) = flag.inline {
+            return Ok(value);
+        }
+        match self.args.get(self.pos) {
+            Some(value) => {
+                self.pos += 1;
+                Ok(value.clone())
+            }
+            None => bail!("Option '{}' requires a value", flag.name),
+        }
+    }
+
+    fn positional(&self) -> &'a [String] {
+        &self.args[self.pos..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_file_values_are_taken_verbatim() {
+        let contents = "A=1\nTRAILING=two  \nURL=http://host/?a=b&c=d\nEMPTY=\n\
+                        QUOTED=\"x\"\nESCAPED=a\\nb\n  INDENTED= spaced\n";
+        assert_eq!(
+            parse_env_lines(contents).unwrap(),
+            [
+                "A=1",
+                "TRAILING=two  ",
+                "URL=http://host/?a=b&c=d",
+                "EMPTY=",
+                "QUOTED=\"x\"",
+                "ESCAPED=a\\nb",
+                "INDENTED= spaced",
+            ]
+        );
+    }
+
+    #[test]
+    fn env_file_skips_blank_lines_and_comments() {
+        let contents =
+            "# settings\r\n\r\n   \r\nA=1\r\n  # indented comment\r\nB=#not a comment\r\n";
+        assert_eq!(
+            parse_env_lines(contents).unwrap(),
+            ["A=1", "B=#not a comment"]
+        );
+        assert!(parse_env_lines("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn env_file_passes_host_variables_through() {
+        std::env::set_var("ENV_FILE_TEST_SET", "from host");
+        std::env::remove_var("ENV_FILE_TEST_UNSET");
+        assert_eq!(
+            parse_env_lines("ENV_FILE_TEST_SET\n").unwrap(),
+            ["ENV_FILE_TEST_SET=from host"]
+        );
+        let error = parse_env_lines("A=1\nENV_FILE_TEST_UNSET\n").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+    }
+
+    #[test]
+    fn env_file_rejects_broken_keys() {
+        for contents in ["=value", "MY VAR=1", "A\tB=1"] {
+            assert!(parse_env_lines(contents).is_err(), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn env_flag_passes_host_variables_through() {
+        std::env::set_var("ENV_FLAG_TEST_SET", "from host");
+        std::env::remove_var("ENV_FLAG_TEST_UNSET");
+        assert_eq!(parse_env("A=b=c").as_deref(), Some("A=b=c"));
+        assert_eq!(parse_env("A=").as_deref(), Some("A="));
+        assert_eq!(
+            parse_env("ENV_FLAG_TEST_SET").as_deref(),
+            Some("ENV_FLAG_TEST_SET=from host")
+        );
+        assert_eq!(parse_env("ENV_FLAG_TEST_UNSET"), None);
+    }
+
+    fn run_args(args: &[&str]) -> Result<RunOptions> {
+        let dir = tempfile::tempdir().unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_run(
+            &args,
+            &DataRoot::resolve(Some(dir.path().to_path_buf())).unwrap(),
+        )
+    }
+
+    #[test]
+    fn sh_goes_before_or_right_after_the_image() {
+        for args in [
+            &["--sh", "echo hi", "alpine"][..],
+            &["-c", "echo hi", "alpine"],
+            &["--sh=echo hi", "alpine"],
+            &["alpine", "--sh", "echo hi"],
+            &["alpine", "-c", "echo hi"],
+            &["alpine", "--sh=echo hi"],
+        ] {
+            let options = run_args(args).unwrap();
+            assert_eq!(options.image, "alpine", "{:?}", args);
+            assert_eq!(
+                options.shell_command.as_deref(),
+                Some("echo hi"),
+                "{:?}",
+                args
+            );
+            assert!(options.command.is_empty(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn nothing_else_follows_the_image_with_sh() {
+        for args in [
+            &["--sh", "echo hi", "alpine", "extra"][..],
+            &["alpine", "--sh", "echo hi", "extra"],
+            &["--sh", "echo hi", "alpine", "--sh", "echo again"],
+            &["alpine", "--sh"],
+        ] {
+            assert!(run_args(args).is_err(), "{:?}", args);
+        }
+        // Further along it's the command's own argument
+        let options = run_args(&["alpine", "bash", "-c", "echo hi"]).unwrap();
+        assert_eq!(options.command, ["bash", "-c", "echo hi"]);
+        assert_eq!(options.shell_command, None);
+    }
+
+    #[test]
+    fn env_files_come_before_env_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("first.env"), dir.path().join("second.env"));
+        std::fs::write(&first, "A=file\nB=first\n").unwrap();
+        std::fs::write(&second, "B=second\n").unwrap();
+        let args: Vec<String> = [
+            "-e",
+            "A=flag",
+            "--env-file",
+            first.to_str().unwrap(),
+            "--env-file",
+            second.to_str().unwrap(),
+            "alpine",
+            "env",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let data_root = DataRoot::resolve(Some(dir.path().to_path_buf())).unwrap();
+        // The container takes the last of each, so -e wins over the files and later files
+        // over earlier ones
+        assert_eq!(
+            parse_run(&args, &data_root).unwrap().env,
+            ["A=file", "B=first", "B=second", "A=flag"]
+        );
+    }
+}
@@ -0,0 +1,813 @@
+use crate::container;
+use crate::data_root::DataRoot;
+use crate::registry::DOCKER_HUB;
+use crate::store::Store;
+use crate::volume;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+// `completions <shell>` prints a script that hands every TAB back to us as
+// `<program> __complete <words>...`, so what completes where is only written down once, in
+// COMMANDS. That runs on every keypress: it reads the state directory, repositories.json and
+// the volumes directory, takes no locks and never touches the network.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn parse(value: &str) -> Result<Shell> {
+        match value {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => bail!("Unknown shell '{}', expected bash, zsh or fish", value),
+        }
+    }
+}
+
+// What a subcommand's positional arguments complete to
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Nothing,
+    Files,
+    Images,
+    Containers,
+    RunningContainers,
+    Volumes,
+    Words(&'static [&'static str]),
+}
+
+// A subcommand as completion sees it, nested ones by both their words. A flag that takes a
+// value ends in '=', followed by '*' when the value is a file, or the values there are to
+// choose from separated by '|'. Everything else is a switch.
+struct Command {
+    name: &'static str,
+    flags: &'static [&'static str],
+    // The first positional argument, then every one after it (run's image, then its command)
+    first: Operand,
+    rest: Operand,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "run",
+        flags: &[
+            "--add-host=",
+            "--allow-devices",
+            "--bundle=*",
+            "--cache-lock-timeout=",
+            "--cidfile=*",
+            "--cpus=",
+            "--debug-http",
+            "-e=",
+            "--env=",
+            "--env-file=*",
+            "--exit-on-main-process",
+            "--health-cmd=",
+            "--health-interval=",
+            "--health-retries=",
+            "--health-start-period=",
+            "--health-timeout=",
+            "--keep-rootfs",
+            "-l=",
+            "--label=",
+            "--lockfile=*",
+            "--log-driver=file|journald|none",
+            "-m=",
+            "--max-layer-size=",
+            "--memory=",
+            "--name=",
+            "--no-healthcheck",
+            "--no-identity-isolation",
+            "--no-pipeline",
+            "--offline",
+            "-p=",
+            "--platform=",
+            "--publish=",
+            "--pull=always|missing|never",
+            "--require-isolation",
+            "--restart=no|always|on-failure",
+            "--root=*",
+            "-c=",
+            "--sh=",
+            "--stats",
+            "--stop-signal=",
+            "--stop-timeout=",
+            "--strict",
+            "--strict-unpack",
+            "--synthesize-user",
+            "--timeout=",
+            "--tz=",
+            "-u=",
+            "--umask=",
+            "--user=",
+            "-v=",
+            "--verbose",
+            "--volume=",
+        ],
+        first: Operand::Images,
+        rest: Operand::Files,
+    },
+    Command {
+        name: "bundle",
+        flags: &[
+            "--allow-devices",
+            "--cache-lock-timeout=",
+            "--debug-http",
+            "--max-layer-size=",
+            "--no-pipeline",
+            "--offline",
+            "-o=*",
+            "--output=*",
+            "--reproducible",
+            "--strict-unpack",
+        ],
+        first: Operand::Images,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "pull",
+        flags: &[
+            "--cache-lock-timeout=",
+            "--debug-http",
+            "--dry-run",
+            "--oci-dir=*",
+            "--offline",
+            "--platform=",
+            "--update-lockfile",
+            "--write-lockfile=*",
+        ],
+        first: Operand::Images,
+        rest: Operand::Images,
+    },
+    Command {
+        name: "push",
+        flags: &["--cache-lock-timeout=", "--debug-http", "--platform="],
+        first: Operand::Images,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "serve",
+        flags: &["--debug-http", "--offline"],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "manifest inspect",
+        flags: &["--debug-http", "--offline", "--platform=", "--raw"],
+        first: Operand::Images,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "images",
+        flags: &[],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "rootfs-digest",
+        flags: &[
+            "--cache-lock-timeout=",
+            "--debug-http",
+            "--max-layer-size=",
+            "--offline",
+            "--platform=",
+        ],
+        first: Operand::Images,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "ps",
+        flags: &["-a", "--all", "-f=", "--filter=", "--root=*"],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "inspect",
+        flags: &["-f=", "--format=", "--root=*"],
+        first: Operand::Containers,
+        rest: Operand::Containers,
+    },
+    Command {
+        name: "logs",
+        flags: &["--root=*"],
+        first: Operand::Containers,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "stats",
+        flags: &["--format=table|json", "--root=*"],
+        first: Operand::RunningContainers,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "stop",
+        flags: &["--root=*", "-t=", "--time="],
+        first: Operand::RunningContainers,
+        rest: Operand::RunningContainers,
+    },
+    Command {
+        name: "wait",
+        flags: &["--root=*", "--timeout="],
+        first: Operand::Containers,
+        rest: Operand::Containers,
+    },
+    Command {
+        name: "rm",
+        flags: &["--root=*"],
+        first: Operand::Containers,
+        rest: Operand::Containers,
+    },
+    // <id>:<path> on either side; the host path is the one worth completing
+    Command {
+        name: "cp",
+        flags: &["--root=*"],
+        first: Operand::Files,
+        rest: Operand::Files,
+    },
+    Command {
+        name: "diff",
+        flags: &["--root=*", "--verify"],
+        first: Operand::Containers,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "export",
+        flags: &["--exclude=", "-o=*", "--output=*", "--root=*"],
+        first: Operand::Containers,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "up",
+        flags: &[
+            "-f=*",
+            "--file=*",
+            "--offline",
+            "-p=",
+            "--project-name=",
+            "--root=*",
+        ],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "down",
+        flags: &[
+            "-f=*",
+            "--file=*",
+            "-p=",
+            "--project-name=",
+            "--root=*",
+            "-t=",
+            "--timeout=",
+        ],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "volume ls",
+        flags: &[],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "volume rm",
+        flags: &[],
+        first: Operand::Volumes,
+        rest: Operand::Volumes,
+    },
+    Command {
+        name: "store repair",
+        flags: &[],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "cache verify",
+        flags: &["--cache-lock-timeout=", "--fix", "--jobs=", "--root=*"],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "system info",
+        flags: &[],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "system prune",
+        flags: &[
+            "--cache-lock-timeout=",
+            "--dry-run",
+            "--max-cache-size=",
+            "--root=*",
+        ],
+        first: Operand::Nothing,
+        rest: Operand::Nothing,
+    },
+    Command {
+        name: "completions",
+        flags: &[],
+        first: Operand::Words(&["bash", "zsh", "fish"]),
+        rest: Operand::Nothing,
+    },
+];
+
+pub enum Completion {
+    Words(Vec<String>),
+    // Leave it to the shell's own file name completion
+    Files,
+}
+
+// What can go where the cursor is. words is the command line after the program name, the
+// last one the word being completed, empty when the cursor is after a space. Lookups that
+// fail offer nothing rather than an error in the middle of the user's typing.
+pub fn complete(words: &[String]) -> Completion {
+    let (current, mut before) = match words.split_last() {
+        Some(split) => split,
+        None => return Completion::Words(vec![]),
+    };
+    let mut data_root = None;
+    match before {
+        [flag] if flag == "--data-root" => return Completion::Files,
+        [flag, path, rest @ ..] if flag == "--data-root" => {
+            data_root = Some(PathBuf::from(path));
+            before = rest;
+        }
+        [flag, rest @ ..] if flag.starts_with("--data-root=") => {
+            data_root = Some(PathBuf::from(flag.trim_start_matches("--data-root=")));
+            before = rest;
+        }
+        _ => {}
+    }
+
+    let (word, args) = match before.split_first() {
+        Some(split) => split,
+        None if data_root.is_none() && current.starts_with('-') => {
+            return matching(current, ["--data-root"]);
+        }
+        None => {
+            let mut names: Vec<&str> = COMMANDS
+                .iter()
+                .map(|command| command.name.split(' ').next().unwrap())
+                .collect();
+            names.dedup();
+            return matching(current, names);
+        }
+    };
+    let group = format!("{} ", word);
+    let (command, args) = if COMMANDS
+        .iter()
+        .any(|command| command.name.starts_with(&group))
+    {
+        match args.split_first() {
+            Some((action, args)) => (find(&format!("{}{}", group, action)), args),
+            None => {
+                let actions = COMMANDS
+                    .iter()
+                    .filter_map(|command| command.name.strip_prefix(&group));
+                return matching(current, actions);
+            }
+        }
+    } else {
+        (find(word), args)
+    };
+    let command = match command {
+        Some(command) => command,
+        None => return Completion::Words(vec![]),
+    };
+
+    // Walk the options the way cli's Flags does: they end at the first positional argument
+    // or at "--"
+    let mut root = None;
+    let mut pending: Option<&str> = None;
+    let mut options_done = false;
+    let mut positionals = 0;
+    for arg in args {
+        if let Some(flag) = pending.take() {
+            if flag == "--root" {
+                root = Some(PathBuf::from(arg));
+            }
+        } else if options_done || !arg.starts_with('-') || arg == "-" {
+            options_done = true;
+            positionals += 1;
+        } else if arg == "--" {
+            options_done = true;
+        } else if let Some((name, value)) = arg.split_once('=').filter(|_| arg.starts_with("--")) {
+            if name == "--root" {
+                root = Some(PathBuf::from(value));
+            }
+        } else if command.value_spec(arg).is_some() {
+            pending = Some(arg);
+        }
+    }
+    let lookups = Lookups { data_root, root };
+
+    if let Some(flag) = pending {
+        return match command.value_spec(flag) {
+            Some("*") => Completion::Files,
+            Some(spec) => matching(current, spec.split('|').filter(|value| !value.is_empty())),
+            None => Completion::Words(vec![]),
+        };
+    }
+    if !options_done && current.starts_with('-') {
+        if let Some((name, value)) = current
+            .split_once('=')
+            .filter(|_| current.starts_with("--"))
+        {
+            let choices = match command.value_spec(name) {
+                Some(spec) if spec != "*" => spec.split('|').filter(|value| !value.is_empty()),
+                _ => return Completion::Words(vec![]),
+            };
+            return matching(
+                current,
+                choices
+                    .filter(|choice| choice.starts_with(value))
+                    .map(|choice| format!("{}={}", name, choice)),
+            );
+        }
+        let names = command
+            .flags
+            .iter()
+            .map(|flag| flag.split_once('=').map_or(*flag, |(name, _)| name));
+        return matching(current, names);
+    }
+    let operand = if positionals == 0 {
+        command.first
+    } else {
+        command.rest
+    };
+    match operand {
+        Operand::Nothing => Completion::Words(vec![]),
+        Operand::Files => Completion::Files,
+        Operand::Images => matching(current, lookups.images()),
+        Operand::Containers => matching(current, lookups.containers(false)),
+        Operand::RunningContainers => matching(current, lookups.containers(true)),
+        Operand::Volumes => matching(current, lookups.volumes()),
+        Operand::Words(words) => matching(current, words.iter().copied()),
+    }
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+impl Command {
+    // What follows the '=' for a flag that takes a value, None for switches and flags we
+    // don't know
+    fn value_spec(&self, flag: &str) -> Option<&'static str> {
+        self.flags.iter().find_map(|spec| {
+            spec.split_once('=')
+                .filter(|(name, _)| *name == flag)
+                .map(|(_, values)| values)
+        })
+    }
+}
+
+fn matching<I, S>(current: &str, candidates: I) -> Completion
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    Completion::Words(
+        candidates
+            .into_iter()
+            .filter(|candidate| candidate.as_ref().starts_with(current))
+            .map(|candidate| candidate.as_ref().to_string())
+            .collect(),
+    )
+}
+
+// Where the dynamic candidates come from: the data root and --root the command line names,
+// or the defaults
+pub struct Lookups {
+    pub data_root: Option<PathBuf>,
+    pub root: Option<PathBuf>,
+}
+
+impl Lookups {
+    fn data_root(&self) -> Option<DataRoot> {
+        DataRoot::resolve(self.data_root.clone()).ok()
+    }
+
+    // Cached tags the way people type them
+    pub fn images(&self) -> Vec<String> {
+        let store = match self.data_root() {
+            Some(data_root) => Store::new(data_root.path()),
+            None => return vec![],
+        };
+        let tags = store.tags().unwrap_or_default();
+        tags.keys().map(|tag| familiar(tag).to_string()).collect()
+    }
+
+    // Names and short ids, newest container first
+    pub fn containers(&self, running: bool) -> Vec<String> {
+        let base = match (&self.root, self.data_root()) {
+            (Some(root), _) => root.clone(),
+            (None, Some(data_root)) => data_root.containers(),
+            (None, None) => return vec![],
+        };
+        let mut candidates = vec![];
+        for state in container::list(&base).unwrap_or_default() {
+            if running && !state.is_running() {
+                continue;
+            }
+            if let Some(name) = &state.name {
+                candidates.push(name.clone());
+            }
+            candidates.push(state.short_id().to_string());
+        }
+        candidates
+    }
+
+    pub fn volumes(&self) -> Vec<String> {
+        self.data_root()
+            .and_then(|data_root| volume::list(&data_root.volumes()).ok())
+            .unwrap_or_default()
+    }
+}
+
+// docker.io/library/alpine:3.19 is alpine:3.19, like docker shows it; other registries keep
+// their host
+pub fn familiar(tag: &str) -> &str {
+    match tag
+        .strip_prefix(DOCKER_HUB)
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        Some(name) => match name.strip_prefix("library/") {
+            Some(official) if !official.contains('/') => official,
+            _ => name,
+        },
+        None => tag,
+    }
+}
+
+const BASH: &str = r#"# bash completion for PROGRAM: source it, or save it as
+# /usr/share/bash-completion/completions/PROGRAM
+FUNCTION() {
+    # bash splits image references and --flag=value at ':' and '='; glue them back together
+    local words=() i
+    for ((i = 0; i <= COMP_CWORD; i++)); do
+        if ((i > 1)) && [[ ${COMP_WORDS[i]} == [:=] || ${COMP_WORDS[i - 1]} == [:=] ]]; then
+            words[${#words[@]} - 1]+=${COMP_WORDS[i]}
+        else
+            words+=("${COMP_WORDS[i]}")
+        fi
+    done
+    local cur=${words[${#words[@]} - 1]}
+    local IFS=$'\n'
+    local candidates=($("${words[0]}" __complete "${words[@]:1}" 2>/dev/null))
+    if [[ ${candidates[0]} == :files ]]; then
+        compopt -o filenames 2>/dev/null
+        COMPREPLY=($(compgen -f -- "${COMP_WORDS[COMP_CWORD]}"))
+    else
+        # What gets replaced is only bash's idea of the word
+        local prefix=${cur:0:${#cur}-${#COMP_WORDS[COMP_CWORD]}}
+        COMPREPLY=("${candidates[@]#"$prefix"}")
+    fi
+}
+complete -F FUNCTION PROGRAM
+"#;
+
+const ZSH: &str = r#"#compdef PROGRAM
+# zsh completion for PROGRAM: source it, or save it as _PROGRAM somewhere on $fpath
+FUNCTION() {
+    local -a candidates
+    candidates=(${(f)"$(${words[1]} __complete "${(@)words[2,CURRENT]}" 2>/dev/null)"})
+    if [[ $candidates[1] == :files ]]; then
+        _files
+    elif (( $#candidates )); then
+        compadd -Q -a candidates
+    fi
+}
+if [[ $funcstack[1] == _PROGRAM ]]; then
+    FUNCTION "$@"
+else
+    compdef FUNCTION PROGRAM
+fi
+"#;
+
+const FISH: &str = r#"# fish completion for PROGRAM: source it, or save it as
+# ~/.config/fish/completions/PROGRAM.fish
+function FUNCTION
+    set -l words (commandline -opc)
+    set -l current (commandline -ct)
+    set -l candidates ($words[1] __complete $words[2..-1] "$current" 2>/dev/null)
+    if test "$candidates[1]" = :files
+        __fish_complete_path "$current"
+    else
+        printf '%s\n' $candidates
+    end
+end
+complete -c PROGRAM -f -a '(FUNCTION)'
+"#;
+
+// The script for shell, for the program under the name it was run as
+pub fn script(shell: Shell, program: &str) -> String {
+    let template = match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+    };
+    let identifier: String = program
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    template
+        .replace("FUNCTION", &format!("__{}_complete", identifier))
+        .replace("PROGRAM", program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn words(line: &[&str]) -> Vec<String> {
+        let line: Vec<String> = line.iter().map(|word| word.to_string()).collect();
+        match complete(&line) {
+            Completion::Words(words) => words,
+            Completion::Files => panic!("{:?} completed to files", line),
+        }
+    }
+
+    fn is_files(line: &[&str]) -> bool {
+        let line: Vec<String> = line.iter().map(|word| word.to_string()).collect();
+        matches!(complete(&line), Completion::Files)
+    }
+
+    // The same line against the data root at root
+    fn words_in(root: &Path, line: &[&str]) -> Vec<String> {
+        let flag = format!("--data-root={}", root.display());
+        let mut full = vec![flag.as_str()];
+        full.extend_from_slice(line);
+        words(&full)
+    }
+
+    fn container(base: &Path, id: &str, name: Option<&str>, created: u64, running: bool) {
+        let dir = base.join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = serde_json::json!({
+            "id": id,
+            "name": name,
+            "image": "test",
+            "rootfs": dir.join("rootfs"),
+            "created": created,
+            "status": if running { "running" } else { "exited" },
+            "pid": if running { Some(std::process::id()) } else { None },
+        });
+        std::fs::write(dir.join("state.json"), state.to_string()).unwrap();
+    }
+
+    #[test]
+    fn commands_and_their_actions() {
+        assert_eq!(words(&["r"]), ["run", "rootfs-digest", "rm"]);
+        assert_eq!(words(&["vo"]), ["volume"]);
+        assert_eq!(words(&["volume", ""]), ["ls", "rm"]);
+        assert_eq!(words(&["system", "p"]), ["prune"]);
+        assert_eq!(words(&["-"]), ["--data-root"]);
+        assert!(words(&["nonsense", ""]).is_empty());
+        assert!(words(&[]).is_empty());
+    }
+
+    #[test]
+    fn flags_and_their_values() {
+        assert_eq!(
+            words(&["run", "--re"]),
+            ["--require-isolation", "--restart"]
+        );
+        assert_eq!(
+            words(&["run", "--restart", ""]),
+            ["no", "always", "on-failure"]
+        );
+        assert_eq!(words(&["run", "--restart=on"]), ["--restart=on-failure"]);
+        assert_eq!(words(&["completions", "z"]), ["zsh"]);
+        assert!(is_files(&["run", "--env-file", ""]));
+        assert!(is_files(&["--data-root", ""]));
+        // A value that's a file isn't ours to complete after the '='
+        assert!(words(&["run", "--cidfile=x"]).is_empty());
+    }
+
+    #[test]
+    fn options_end_at_the_first_positional_argument() {
+        assert!(is_files(&["run", "alpine", "--"]));
+        assert!(is_files(&["run", "-e", "A=1", "alpine", "-"]));
+        assert!(words(&["bundle", "alpine", ""]).is_empty());
+        // A switch takes no value, so what follows it is the image
+        let root = tempfile::tempdir().unwrap();
+        Store::new(root.path())
+            .set_tag("docker.io/library/alpine:3.19", "sha256:0")
+            .unwrap();
+        assert_eq!(
+            words_in(root.path(), &["run", "--keep-rootfs", "a"]),
+            ["alpine:3.19"]
+        );
+        assert!(words_in(root.path(), &["run", "--name", "a"]).is_empty());
+    }
+
+    #[test]
+    fn images_are_the_cached_tags_as_people_type_them() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        for tag in [
+            "docker.io/library/alpine:3.19",
+            "docker.io/someone/app:1",
+            "ghcr.io/someone/tool:2",
+        ] {
+            store.set_tag(tag, "sha256:0").unwrap();
+        }
+
+        assert_eq!(
+            words_in(root.path(), &["run", ""]),
+            ["alpine:3.19", "someone/app:1", "ghcr.io/someone/tool:2"]
+        );
+        assert_eq!(
+            words_in(root.path(), &["pull", "alpine", "s"]),
+            ["someone/app:1"]
+        );
+    }
+
+    #[test]
+    fn familiar_names() {
+        assert_eq!(familiar("docker.io/library/alpine:3.19"), "alpine:3.19");
+        assert_eq!(familiar("docker.io/library/a/b:1"), "library/a/b:1");
+        assert_eq!(familiar("docker.io/someone/app:1"), "someone/app:1");
+        assert_eq!(familiar("docker.iox/app:1"), "docker.iox/app:1");
+        assert_eq!(familiar("localhost:5000/app:1"), "localhost:5000/app:1");
+    }
+
+    #[test]
+    fn containers_by_name_and_short_id_newest_first() {
+        let root = tempfile::tempdir().unwrap();
+        let base = root.path().join("containers");
+        container(&base, "aaaaaaaaaaaaaaaa", Some("web"), 2, true);
+        container(&base, "bbbbbbbbbbbbbbbb", None, 1, false);
+
+        assert_eq!(
+            words_in(root.path(), &["rm", ""]),
+            ["web", "aaaaaaaaaaaa", "bbbbbbbbbbbb"]
+        );
+        assert_eq!(
+            words_in(root.path(), &["stop", ""]),
+            ["web", "aaaaaaaaaaaa"]
+        );
+        assert_eq!(words_in(root.path(), &["logs", "b"]), ["bbbbbbbbbbbb"]);
+    }
+
+    #[test]
+    fn root_picks_another_containers_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        container(
+            &root.path().join("containers"),
+            "aaaaaaaaaaaaaaaa",
+            None,
+            1,
+            false,
+        );
+        container(other.path(), "cccccccccccccccc", Some("db"), 1, false);
+        let flag = format!("--root={}", other.path().display());
+
+        assert_eq!(
+            words_in(root.path(), &["rm", &flag, ""]),
+            ["db", "cccccccccccc"]
+        );
+        let other = other.path().to_string_lossy();
+        assert_eq!(
+            words_in(root.path(), &["inspect", "--root", &other, "d"]),
+            ["db"]
+        );
+    }
+
+    #[test]
+    fn volumes_by_name() {
+        let root = tempfile::tempdir().unwrap();
+        for name in ["data", "cache"] {
+            std::fs::create_dir_all(root.path().join("volumes").join(name).join("_data")).unwrap();
+        }
+        // Not a volume without its _data
+        std::fs::create_dir_all(root.path().join("volumes/half")).unwrap();
+
+        assert_eq!(
+            words_in(root.path(), &["volume", "rm", ""]),
+            ["cache", "data"]
+        );
+        assert_eq!(
+            words_in(root.path(), &["volume", "rm", "data", "c"]),
+            ["cache"]
+        );
+    }
+
+    #[test]
+    fn lookups_in_a_missing_data_root_offer_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("missing");
+        assert!(words_in(&missing, &["run", ""]).is_empty());
+        assert!(words_in(&missing, &["rm", ""]).is_empty());
+        assert!(words_in(&missing, &["volume", "rm", ""]).is_empty());
+    }
+}
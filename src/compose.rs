@@ -0,0 +1,429 @@
+use crate::cli::{parse_cpus, parse_size};
+use crate::container::is_valid_name;
+use crate::volume::{check_targets, VolumeSpec};
+use crate::yaml;
+use anyhow::{bail, Context, Result};
+use docker_starter_rust::{Container, ContainerBuilder};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Looked for in the current directory when up and down aren't given -f, in this order
+const DEFAULT_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+// Labels on every container we start, so a stack's containers can be told apart in ps
+pub const PROJECT_LABEL: &str = "com.docker.compose.project";
+pub const SERVICE_LABEL: &str = "com.docker.compose.service";
+
+// The subset of the compose file format we understand. Anything else is a typo or a feature we
+// don't have, and either way it's better refused than silently ignored.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    name: Option<String>,
+    services: BTreeMap<String, Service>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Service {
+    image: String,
+    #[serde(default)]
+    command: Option<StringOrList>,
+    #[serde(default)]
+    environment: Option<Environment>,
+    #[serde(default)]
+    volumes: Vec<String>,
+    #[serde(default)]
+    mem_limit: Option<String>,
+    #[serde(default)]
+    cpus: Option<String>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    #[serde(default)]
+    network_mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+// Either list form ("KEY=value", or "KEY" to pass the host's value) or map form (a null value
+// passes the host's)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Environment {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<String>>),
+}
+
+pub struct Project {
+    pub name: String,
+    // In dependency order: everything a service depends on comes before it
+    pub services: Vec<ServiceRun>,
+}
+
+// One service as the `run` invocation that starts it
+pub struct ServiceRun {
+    pub service: String,
+    // <project>_<service>
+    pub container_name: String,
+    // Without the data root, --root or --offline, which are up's to add
+    pub run: ContainerBuilder,
+}
+
+pub fn find_file(file: Option<&Path>) -> Result<PathBuf> {
+    if let Some(file) = file {
+        return Ok(file.to_path_buf());
+    }
+    match DEFAULT_FILES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+    {
+        Some(path) => Ok(path),
+        None => bail!(
+            "No {} in the current directory, use -f to name the file",
+            DEFAULT_FILES.join(", ")
+        ),
+    }
+}
+
+// Read a compose file into the run invocations for its services. Everything that can be
+// checked up front is, so a bad service fails the whole stack before anything starts.
+pub fn load(path: &Path, project_name: Option<&str>) -> Result<Project> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let value =
+        yaml::parse(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+    let file: File = serde_json::from_value(value)
+        .with_context(|| format!("Invalid compose file {}", path.display()))?;
+
+    // Relative bind mounts are relative to the file, not to wherever we were run from
+    let directory = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))?
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let name = match project_name.map(str::to_string).or(file.name) {
+        Some(name) => validate_project_name(&name)?,
+        None => {
+            let base = directory
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            normalize_project_name(&base)?
+        }
+    };
+
+    let mut services = vec![];
+    for service in order(&file.services)? {
+        let run = service_run(&name, service, &file.services[service], &directory)
+            .with_context(|| format!("Invalid service '{}'", service))?;
+        services.push(run);
+    }
+    Ok(Project { name, services })
+}
+
+fn service_run(
+    project: &str,
+    service: &str,
+    spec: &Service,
+    directory: &Path,
+) -> Result<ServiceRun> {
+    if !is_valid_name(service) {
+        bail!("service names may only contain letters, digits, '_', '.' and '-'");
+    }
+    match spec.network_mode.as_deref() {
+        None | Some("host") => {}
+        Some(mode) => bail!(
+            "network_mode '{}' isn't supported, containers share the host's network",
+            mode
+        ),
+    }
+
+    let container_name = format!("{}_{}", project, service);
+    let mut run = Container::builder(&spec.image)
+        .name(&container_name)
+        .label(PROJECT_LABEL, project)
+        .label(SERVICE_LABEL, service);
+    let environment = match &spec.environment {
+        None => vec![],
+        Some(Environment::List(variables)) => variables.clone(),
+        Some(Environment::Map(variables)) => variables
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={}", key, value),
+                None => key.clone(),
+            })
+            .collect(),
+    };
+    for variable in environment {
+        if variable.starts_with('=') || variable.is_empty() {
+            bail!("environment entry '{}' has no variable name", variable);
+        }
+        run = match variable.split_once('=') {
+            Some((key, value)) => run.env(key, value),
+            None => run.env_from_host(&variable),
+        };
+    }
+    let mut specs = vec![];
+    for volume in &spec.volumes {
+        let volume = match volume.strip_prefix("./") {
+            Some(relative) => directory.join(relative).display().to_string(),
+            None if volume.starts_with("../") || volume.starts_with(".:") => {
+                directory.join(volume).display().to_string()
+            }
+            // Named volumes belong to the project, as in compose
+            None if !volume.starts_with('/') => format!("{}_{}", project, volume),
+            None => volume.clone(),
+        };
+        specs.push(VolumeSpec::parse(&volume)?);
+        run = run.volume(&volume);
+    }
+    check_targets(&specs)?;
+    if let Some(memory) = &spec.mem_limit {
+        run = run.memory_limit(parse_size(memory, "mem_limit")?);
+    }
+    if let Some(cpus) = &spec.cpus {
+        run = run.cpus(parse_cpus(cpus)?);
+    }
+
+    match &spec.command {
+        None => {}
+        Some(StringOrList::List(command)) => run = run.command(command),
+        Some(StringOrList::String(command)) => run = run.command(split_command(command)?),
+    }
+    Ok(ServiceRun {
+        service: service.to_string(),
+        container_name,
+        run,
+    })
+}
+
+// Services sorted so each comes after everything in its depends_on, ties broken by name
+fn order(services: &BTreeMap<String, Service>) -> Result<Vec<&str>> {
+    fn visit<'a>(
+        name: &'a str,
+        services: &'a BTreeMap<String, Service>,
+        path: &mut Vec<&'a str>,
+        ordered: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        if ordered.contains(&name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|&visiting| visiting == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            bail!(
+                "Services depend on each other in a cycle: {}",
+                cycle.join(" -> ")
+            );
+        }
+        path.push(name);
+        for dependency in &services[name].depends_on {
+            match services.get_key_value(dependency) {
+                Some((dependency, _)) => visit(dependency, services, path, ordered)?,
+                None => bail!(
+                    "Service '{}' depends on '{}', which isn't defined",
+                    name,
+                    dependency
+                ),
+            }
+        }
+        path.pop();
+        ordered.push(name);
+        Ok(())
+    }
+
+    let mut ordered = vec![];
+    for name in services.keys() {
+        visit(name, services, &mut vec![], &mut ordered)?;
+    }
+    Ok(ordered)
+}
+
+// command: as a string is split like a shell would split words, quotes included, but nothing
+// is expanded
+fn split_command(command: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some('"'), '\\') => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => bail!("command ends in a backslash"),
+            },
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '\\') => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => bail!("command ends in a backslash"),
+            },
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("command has an unterminated quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn validate_project_name(name: &str) -> Result<String> {
+    let valid = name
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        bail!(
+            "Invalid project name '{}', use lowercase letters, digits, '_' and '-'",
+            name
+        );
+    }
+    Ok(name.to_string())
+}
+
+// A directory name made into a project name the way compose does: lowercased, with anything
+// that isn't allowed dropped
+fn normalize_project_name(base: &str) -> Result<String> {
+    let name: String = base
+        .to_ascii_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '_' || *c == '-')
+        .collect();
+    let name = name.trim_start_matches(['_', '-']).to_string();
+    if name.is_empty() {
+        bail!(
+            "Can't make a project name out of directory '{}', use -p to give one",
+            base
+        );
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compose_file(text: &str) -> Result<File> {
+        Ok(serde_json::from_value(yaml::parse(text)?)?)
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(compose_file("services:\n  web:\n    image: nginx\n").is_ok());
+        assert!(
+            compose_file("services:\n  web:\n    image: nginx\n    ports: ['80:80']\n").is_err()
+        );
+        assert!(compose_file("version: '3'\nservices:\n  web:\n    image: nginx\n").is_err());
+        assert!(compose_file("services:\n  web:\n    command: true\n").is_err());
+    }
+
+    #[test]
+    fn services_start_after_what_they_depend_on() {
+        let file = compose_file(concat!(
+            "services:\n",
+            "  app:\n",
+            "    image: app\n",
+            "    depends_on: [db, cache]\n",
+            "  cache:\n",
+            "    image: redis\n",
+            "    depends_on: [db]\n",
+            "  db:\n",
+            "    image: postgres\n",
+            "  admin:\n",
+            "    image: admin\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            order(&file.services).unwrap(),
+            ["admin", "db", "cache", "app"]
+        );
+    }
+
+    #[test]
+    fn dependency_cycles_and_missing_services_are_refused() {
+        let file = compose_file(concat!(
+            "services:\n",
+            "  a:\n",
+            "    image: a\n",
+            "    depends_on: [b]\n",
+            "  b:\n",
+            "    image: b\n",
+            "    depends_on: [c]\n",
+            "  c:\n",
+            "    image: c\n",
+            "    depends_on: [a]\n",
+        ))
+        .unwrap();
+        let error = order(&file.services).unwrap_err().to_string();
+        assert!(error.contains("a -> b -> c -> a"), "{}", error);
+
+        let file = compose_file("services:\n  a:\n    image: a\n    depends_on: [a]\n").unwrap();
+        assert!(order(&file.services).is_err());
+        let file = compose_file("services:\n  a:\n    image: a\n    depends_on: [b]\n").unwrap();
+        assert!(order(&file.services).is_err());
+    }
+
+    #[test]
+    fn load_names_the_project_and_orders_its_services() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("compose.yaml");
+        std::fs::write(
+            &path,
+            concat!(
+                "name: stack\n",
+                "services:\n",
+                "  web:\n",
+                "    image: nginx\n",
+                "    command: nginx -g 'daemon off;'\n",
+                "    depends_on: [db]\n",
+                "  db:\n",
+                "    image: postgres\n",
+            ),
+        )
+        .unwrap();
+        let project = load(&path, None).unwrap();
+        assert_eq!(project.name, "stack");
+        let names: Vec<_> = project
+            .services
+            .iter()
+            .map(|service| service.container_name.as_str())
+            .collect();
+        assert_eq!(names, ["stack_db", "stack_web"]);
+        assert_eq!(load(&path, Some("other")).unwrap().name, "other");
+        assert!(load(&path, Some("Bad Name")).is_err());
+    }
+
+    #[test]
+    fn commands_split_like_shell_words() {
+        assert_eq!(
+            split_command(r#"sh -c "echo \"hi\" there" 'a b'\ c"#).unwrap(),
+            ["sh", "-c", "echo \"hi\" there", "a b c"]
+        );
+        assert_eq!(split_command("x ''").unwrap(), ["x", ""]);
+        assert!(split_command("echo 'open").is_err());
+        assert!(split_command("echo \\").is_err());
+    }
+}
@@ -0,0 +1,635 @@
+use crate::cgroup::{self, ResourceUsage};
+use crate::health::{self, Health};
+use crate::lock::pid_alive;
+use crate::logs::LogKind;
+use crate::privileges::{self, Ownership};
+use crate::restart::RestartPolicy;
+use crate::supervise::{signal_name, DEFAULT_STOP_TIMEOUT};
+use crate::volume::{self, MountPoint};
+use crate::wait::WaitLock;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::{read, read_dir, remove_dir_all, rename, write, DirBuilder, File};
+use std::io::{ErrorKind, Read};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How much of the id ps prints and what people usually type back at us
+pub const SHORT_ID_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Running,
+    // Exited, and waiting out the backoff before its restart policy starts it again
+    Restarting,
+    Exited,
+}
+
+// Persisted as state.json next to the rootfs so other invocations (ps, rm) can see us, and
+// printed as it is by inspect. Scripts read it, so fields get added but never renamed or
+// repurposed, and anything added later needs a serde default for older state files. Times are
+// seconds since the epoch.
+#[derive(Serialize, Deserialize)]
+pub struct ContainerState {
+    pub id: String,
+    // --name, which finds the container as well as its id does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub image: String,
+    // The argv actually executed, filled in once the image config is known
+    #[serde(default)]
+    pub command: Vec<String>,
+    pub rootfs: PathBuf,
+    // Seconds since the epoch
+    pub created: u64,
+    pub status: Status,
+    #[serde(default)]
+    pub pid: Option<u32>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    // The image's labels overlaid with --label, the command line winning on conflicts
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    // Published ports as "hostip:hostport->containerport/tcp"
+    #[serde(default)]
+    pub ports: Vec<String>,
+    // Named volumes mounted into the container
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    // Every -v mount, bind or volume
+    #[serde(default)]
+    pub mounts: Vec<MountPoint>,
+    // Volumes created for the image's declared Volumes, which go when the container does
+    #[serde(default)]
+    pub anonymous_volumes: Vec<String>,
+    // What the image config declares, for inspect
+    #[serde(default)]
+    pub exposed_ports: Vec<String>,
+    #[serde(default)]
+    pub on_build: Vec<String>,
+    // The digest the image reference resolved to (the index's for multi-platform images), None
+    // for bundles
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    // When the command was started and when it exited
+    #[serde(default)]
+    pub started_at: Option<u64>,
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+    // Recorded when the container exits
+    #[serde(default)]
+    pub usage: Option<ResourceUsage>,
+    // Whether the OOM killer struck in the container's cgroup, false without one
+    #[serde(default)]
+    pub oom_killed: bool,
+    // The container's cgroup directory, if it got one
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+    // How `stop` shuts the container down: this signal, then SIGKILL after the timeout
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: String,
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: u64,
+    // How the rootfs got the ownership its layers ask for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ownership: Option<Ownership>,
+    // Where the container's output was kept, for `logs`
+    #[serde(default)]
+    pub log_driver: LogKind,
+    // --restart, and how many times it has started the command again so far
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub restart_count: u32,
+    // --timeout in seconds, when it runs out, and whether it did; a Ctrl-C to the supervisor
+    // before then clears the deadline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit: Option<u64>,
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    #[serde(default)]
+    pub timed_out: bool,
+    // The run process, which owns the container and is what health checks it. None in state
+    // files from before it was recorded.
+    #[serde(default)]
+    pub supervisor: Option<u32>,
+    // From the image's Healthcheck or --health-cmd. Not part of state.json: the checker
+    // keeps it in a file of its own, read in with the state.
+    #[serde(skip)]
+    pub health: Option<Health>,
+}
+
+fn default_stop_signal() -> String {
+    "SIGTERM".to_string()
+}
+
+fn default_stop_timeout() -> u64 {
+    DEFAULT_STOP_TIMEOUT.as_secs()
+}
+
+impl ContainerState {
+    pub fn short_id(&self) -> &str {
+        &self.id[..SHORT_ID_LEN.min(self.id.len())]
+    }
+
+    // A supervisor that was SIGKILLed never gets to record the exit, so trust the pid over
+    // the recorded status. Without a PID namespace, what the command left behind in its
+    // cgroup keeps the container up once the command itself has exited.
+    pub fn is_running(&self) -> bool {
+        self.status == Status::Running
+            && (self.pid.map_or(false, |pid| pid_alive(pid as i32))
+                || self
+                    .cgroup
+                    .as_deref()
+                    .map_or(false, |cgroup| !cgroup::pids(cgroup).is_empty()))
+    }
+
+    // Nothing checks the container's health any more, whatever health.json last said
+    pub fn supervisor_gone(&self) -> bool {
+        self.supervisor.map_or(false, |pid| !pid_alive(pid as i32))
+    }
+
+    // A ps --filter label=... condition: "key" needs the label to exist, "key=value" to match
+    pub fn has_label(&self, filter: &str) -> bool {
+        match filter.split_once('=') {
+            Some((key, value)) => self.labels.get(key).map_or(false, |found| found == value),
+            None => self.labels.contains_key(filter),
+        }
+    }
+
+    pub fn describe_status(&self) -> String {
+        match (self.status, self.exit_code) {
+            _ if self.is_running() => match &self.health {
+                Some(health) => format!("Up ({})", health.describe()),
+                None => "Up".to_string(),
+            },
+            (Status::Created, _) => "Created".to_string(),
+            (Status::Restarting, Some(code)) => format!("Restarting ({})", code),
+            (_, Some(code)) => format!("Exited ({})", code),
+            (_, None) => "Exited (unknown)".to_string(),
+        }
+    }
+}
+
+// A container directory owned by this process. Dropping it removes the directory, rootfs and
+// all, unless the container was started with --keep-rootfs.
+pub struct Container {
+    dir: PathBuf,
+    state: ContainerState,
+    // Where its anonymous volumes go when it does
+    volumes_dir: PathBuf,
+    keep_rootfs: bool,
+}
+
+impl Container {
+    pub fn create(
+        base: &Path,
+        image: &str,
+        name: Option<&str>,
+        volumes: Vec<String>,
+        volumes_dir: &Path,
+        keep_rootfs: bool,
+    ) -> Result<Container> {
+        if let Some(name) = name {
+            if let Some(other) = list(base)?
+                .iter()
+                .find(|state| state.name.as_deref() == Some(name))
+            {
+                bail!(
+                    "The name {} is already in use by container {}, remove it first",
+                    name,
+                    other.short_id()
+                );
+            }
+        }
+        let id = generate_id()?;
+        let dir = base.join(&id);
+        let rootfs = dir.join("rootfs");
+        // 0755 all the way down: the rootfs becomes /, which must be readable by anyone
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(&rootfs)
+            .with_context(|| format!("Failed to create container directory {}", dir.display()))?;
+
+        let container = Container {
+            dir,
+            state: ContainerState {
+                id,
+                name: name.map(str::to_string),
+                image: image.to_string(),
+                command: vec![],
+                rootfs,
+                created: now(),
+                status: Status::Created,
+                pid: None,
+                exit_code: None,
+                labels: BTreeMap::new(),
+                ports: vec![],
+                volumes,
+                mounts: vec![],
+                anonymous_volumes: vec![],
+                exposed_ports: vec![],
+                on_build: vec![],
+                image_digest: None,
+                started_at: None,
+                finished_at: None,
+                usage: None,
+                oom_killed: false,
+                cgroup: None,
+                stop_signal: default_stop_signal(),
+                stop_timeout: default_stop_timeout(),
+                ownership: None,
+                log_driver: LogKind::None,
+                restart_policy: RestartPolicy::No,
+                restart_count: 0,
+                time_limit: None,
+                deadline: None,
+                timed_out: false,
+                supervisor: Some(std::process::id()),
+                health: None,
+            },
+            volumes_dir: volumes_dir.to_path_buf(),
+            keep_rootfs,
+        };
+        container.save()?;
+        Ok(container)
+    }
+
+    pub fn rootfs(&self) -> &Path {
+        &self.state.rootfs
+    }
+
+    pub fn set_stop_config(&mut self, signal: i32, timeout: Duration) -> Result<()> {
+        self.state.stop_signal = signal_name(signal);
+        self.state.stop_timeout = timeout.as_secs();
+        self.save()
+    }
+
+    pub fn set_ownership(&mut self, ownership: Ownership) -> Result<()> {
+        self.state.ownership = Some(ownership);
+        self.save()
+    }
+
+    pub fn set_log_driver(&mut self, driver: LogKind) -> Result<()> {
+        self.state.log_driver = driver;
+        self.save()
+    }
+
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) -> Result<()> {
+        self.state.restart_policy = policy;
+        self.save()
+    }
+
+    // Counted from now, which is when the command first starts
+    pub fn set_time_limit(&mut self, limit: Duration) -> Result<()> {
+        self.state.time_limit = Some(limit.as_secs());
+        self.state.deadline = Some(now() + limit.as_secs());
+        self.save()
+    }
+
+    pub fn clear_deadline(&mut self) -> Result<()> {
+        self.state.deadline = None;
+        self.save()
+    }
+
+    pub fn set_timed_out(&mut self) -> Result<()> {
+        self.state.timed_out = true;
+        self.save()
+    }
+
+    pub fn set_image_digest(&mut self, digest: &str) -> Result<()> {
+        self.state.image_digest = Some(digest.to_string());
+        self.save()
+    }
+
+    pub fn set_cgroup(&mut self, cgroup: &Path) -> Result<()> {
+        self.state.cgroup = Some(cgroup.to_path_buf());
+        self.save()
+    }
+
+    pub fn set_mounts(&mut self, mounts: Vec<MountPoint>) -> Result<()> {
+        self.state.mounts = mounts;
+        self.save()
+    }
+
+    pub fn set_declared(
+        &mut self,
+        exposed_ports: Vec<String>,
+        on_build: Vec<String>,
+    ) -> Result<()> {
+        self.state.exposed_ports = exposed_ports;
+        self.state.on_build = on_build;
+        self.save()
+    }
+
+    pub fn add_anonymous_volumes(&mut self, names: &[String]) -> Result<()> {
+        self.state.volumes.extend(names.iter().cloned());
+        self.state.anonymous_volumes.extend(names.iter().cloned());
+        self.save()
+    }
+
+    pub fn set_labels(&mut self, labels: BTreeMap<String, String>) -> Result<()> {
+        self.state.labels = labels;
+        self.save()
+    }
+
+    pub fn set_running(
+        &mut self,
+        command: &[String],
+        ports: Vec<String>,
+        pid: Option<u32>,
+    ) -> Result<()> {
+        self.state.command = command.to_vec();
+        self.state.ports = ports;
+        self.state.status = Status::Running;
+        self.state.pid = pid;
+        self.state.started_at = Some(now());
+        self.save()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.state.id
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn state(&self) -> &ContainerState {
+        &self.state
+    }
+
+    pub fn set_exited(
+        &mut self,
+        exit_code: i32,
+        usage: ResourceUsage,
+        oom_killed: bool,
+    ) -> Result<()> {
+        self.state.status = Status::Exited;
+        self.state.exit_code = Some(exit_code);
+        self.state.finished_at = Some(now());
+        self.state.usage = Some(usage);
+        self.state.oom_killed = oom_killed;
+        self.save()
+    }
+
+    // The command exited and will be started again: what it exited with stays visible in the
+    // meantime, like docker's "Restarting (1)"
+    pub fn set_restarting(&mut self, exit_code: i32) -> Result<()> {
+        self.state.status = Status::Restarting;
+        self.state.pid = None;
+        self.state.exit_code = Some(exit_code);
+        self.state.finished_at = Some(now());
+        self.state.restart_count += 1;
+        self.save()
+    }
+
+    // Write-then-rename so a concurrent ps never reads half a file
+    fn save(&self) -> Result<()> {
+        let path = self.dir.join("state.json");
+        let staging = self.dir.join("state.json.partial");
+        write(&staging, serde_json::to_vec_pretty(&self.state)?)?;
+        rename(&staging, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if self.keep_rootfs {
+            eprintln!("Kept rootfs at {}", self.state.rootfs.display());
+            return;
+        }
+        // Let anyone in `wait` read the exit code before the state file goes
+        let _lock = WaitLock::exclusive(&self.dir);
+        if let Err(error) = remove_container_dir(&self.state, &self.dir) {
+            eprintln!(
+                "warning: failed to remove container directory {}: {}",
+                self.dir.display(),
+                error
+            );
+        }
+        remove_anonymous_volumes(&self.state, &self.volumes_dir);
+    }
+}
+
+// Every container under base with a readable state file, newest first like docker ps
+pub fn list(base: &Path) -> Result<Vec<ContainerState>> {
+    let entries = match read_dir(base) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to list {}", base.display()))
+        }
+    };
+
+    let mut containers = vec![];
+    for entry in entries {
+        let dir = entry?.path();
+        // A directory without state is mid-creation or not ours; skip it rather than fail ps
+        if let Ok(data) = read(dir.join("state.json")) {
+            if let Ok(mut state) = serde_json::from_slice::<ContainerState>(&data) {
+                state.health = health::load(&dir);
+                let stale = state.supervisor_gone();
+                if let Some(health) = state.health.as_mut().filter(|_| stale) {
+                    health.status = health::Status::Stale;
+                }
+                containers.push(state);
+            }
+        }
+    }
+    containers.sort_by_key(|state| Reverse(state.created));
+    Ok(containers)
+}
+
+// Look a container up by its name, full id or any unambiguous prefix of the id, like docker
+pub fn find(base: &Path, id: &str) -> Result<ContainerState> {
+    if id.is_empty() {
+        bail!("Container id must not be empty");
+    }
+    let containers = list(base)?;
+    if let Some(index) = containers
+        .iter()
+        .position(|state| state.name.as_deref() == Some(id))
+    {
+        return Ok(containers.into_iter().nth(index).unwrap());
+    }
+    let mut matches: Vec<ContainerState> = containers
+        .into_iter()
+        .filter(|state| state.id.starts_with(id))
+        .collect();
+    match matches.len() {
+        0 => bail!("No such container: {}", id),
+        1 => Ok(matches.remove(0)),
+        _ => bail!("Container id '{}' is ambiguous, use more characters", id),
+    }
+}
+
+// Delete a retained container directory. Running containers still own theirs.
+pub fn remove(base: &Path, id: &str, volumes_dir: &Path) -> Result<String> {
+    let state = find(base, id)?;
+    if state.status == Status::Restarting {
+        bail!(
+            "Container {} is restarting, stop it first",
+            state.short_id()
+        );
+    }
+    if state.is_running() {
+        bail!(
+            "Container {} is still running (pid {})",
+            state.short_id(),
+            state.pid.unwrap_or_default()
+        );
+    }
+    let dir = base.join(&state.id);
+    remove_container_dir(&state, &dir)
+        .with_context(|| format!("Failed to remove {}", dir.display()))?;
+    remove_anonymous_volumes(&state, volumes_dir);
+    Ok(state.id)
+}
+
+// A rootfs chowned to subordinate ids has directories we can't write to ourselves, so it goes
+// from inside the same mapping
+fn remove_container_dir(state: &ContainerState, dir: &Path) -> Result<()> {
+    let ids = state
+        .ownership
+        .and_then(|ownership| ownership.subordinate());
+    if let (Some(ids), true) = (ids, state.rootfs.exists()) {
+        let mut command = std::process::Command::new("rm");
+        command.arg("-rf").arg("--").arg(&state.rootfs);
+        let status = privileges::run_mapped(&mut command, ids)?;
+        if !status.success() {
+            bail!("rm -rf {} failed", state.rootfs.display());
+        }
+    }
+    Ok(remove_dir_all(dir)?)
+}
+
+// Nobody can name an anonymous volume to use it again, so it's gone with its container
+fn remove_anonymous_volumes(state: &ContainerState, volumes_dir: &Path) {
+    for name in &state.anonymous_volumes {
+        if let Err(error) = volume::remove(volumes_dir, name) {
+            eprintln!("warning: failed to remove volume {}: {:#}", name, error);
+        }
+    }
+}
+
+// docker's rule for container names: letters, digits, '_', '.' and '-', starting with one of
+// the first two
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+// "5 seconds ago" style age for listings
+pub fn ago(timestamp: u64) -> String {
+    let seconds = now().saturating_sub(timestamp);
+    let (amount, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{} {}{} ago", amount, unit, plural)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// 64 hex characters, same shape as docker's ids
+pub fn generate_id() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Failed to generate a container id")?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{Probe, Status as HealthStatus};
+
+    // A pid that was just reaped, which nothing else is likely to have picked up yet
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    // A running container, us as far as its pid goes, checked by supervisor and last found
+    // healthy
+    fn checked_container(base: &Path, id: &str, supervisor: Option<u32>) {
+        let dir = base.join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = serde_json::json!({
+            "id": id,
+            "image": "test",
+            "rootfs": dir.join("rootfs"),
+            "created": 0,
+            "status": "running",
+            "pid": std::process::id(),
+            "supervisor": supervisor,
+        });
+        write(dir.join("state.json"), state.to_string()).unwrap();
+        let mut health = Health::new();
+        let probe = Probe {
+            start: 0,
+            end: 0,
+            exit_code: 0,
+            output: String::new(),
+        };
+        health.record(probe, 3, false);
+        write(
+            dir.join("health.json"),
+            serde_json::to_vec(&health).unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn status_of(base: &Path, id: &str) -> (HealthStatus, String) {
+        let state = find(base, id).unwrap();
+        (
+            state.health.as_ref().unwrap().status,
+            state.describe_status(),
+        )
+    }
+
+    #[test]
+    fn health_holds_while_the_supervisor_is_there() {
+        let base = tempfile::tempdir().unwrap();
+        checked_container(base.path(), "aaaa", Some(std::process::id()));
+        assert_eq!(
+            status_of(base.path(), "aaaa"),
+            (HealthStatus::Healthy, "Up (healthy)".to_string())
+        );
+    }
+
+    #[test]
+    fn health_is_stale_once_the_supervisor_is_gone() {
+        let base = tempfile::tempdir().unwrap();
+        checked_container(base.path(), "bbbb", Some(dead_pid()));
+        assert_eq!(
+            status_of(base.path(), "bbbb"),
+            (HealthStatus::Stale, "Up (health: stale)".to_string())
+        );
+    }
+
+    #[test]
+    fn health_from_before_supervisors_were_recorded_is_taken_as_it_is() {
+        let base = tempfile::tempdir().unwrap();
+        checked_container(base.path(), "cccc", None);
+        assert_eq!(status_of(base.path(), "cccc").0, HealthStatus::Healthy);
+    }
+}
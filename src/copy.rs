@@ -0,0 +1,531 @@
+use crate::container::ContainerState;
+use crate::volume::MountPoint;
+use anyhow::{bail, Context, Result};
+use std::ffi::{CString, OsString};
+use std::fs::{
+    copy, create_dir, read_dir, read_link, remove_file, set_permissions, symlink_metadata, File,
+    Metadata,
+};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+// Same limit as the kernel's
+const MAX_SYMLINKS: usize = 40;
+
+// One side of `cp`: "<id>:<path>" names a path in a container, anything else is on the host.
+// Like docker, a host path with a colon in it has to start with / or ./ to count as one.
+pub enum Endpoint {
+    Container { id: String, path: String },
+    Host(PathBuf),
+}
+
+impl Endpoint {
+    pub fn parse(value: &str) -> Endpoint {
+        if !value.starts_with('/') && !value.starts_with('.') {
+            if let Some((id, path)) = value.split_once(':') {
+                if !id.is_empty() {
+                    return Endpoint::Container {
+                        id: id.to_string(),
+                        path: path.to_string(),
+                    };
+                }
+            }
+        }
+        Endpoint::Host(PathBuf::from(value))
+    }
+}
+
+// A container's filesystem as the container sees it: the rootfs with its -v mounts on top.
+// Paths are resolved the way the kernel would inside the container, so absolute symlinks start
+// from the container's / and nothing (symlink or "..") leads out of it.
+pub struct ContainerFs {
+    rootfs: PathBuf,
+    mounts: Vec<MountPoint>,
+}
+
+impl ContainerFs {
+    pub fn new(state: &ContainerState) -> Result<ContainerFs> {
+        if !state.rootfs.is_dir() {
+            bail!(
+                "The rootfs of container {} is gone, only containers run with --keep-rootfs keep it",
+                state.short_id()
+            );
+        }
+        Ok(ContainerFs {
+            rootfs: state.rootfs.clone(),
+            mounts: state.mounts.clone(),
+        })
+    }
+
+    // A rootfs with nothing mounted on it yet, for setting a container up
+    pub fn bare(rootfs: &Path) -> ContainerFs {
+        ContainerFs {
+            rootfs: rootfs.to_path_buf(),
+            mounts: vec![],
+        }
+    }
+
+    // Where path is on the host, found the way the container would find it. Nothing on the
+    // way there is a symlink (nor the last component, if follow_last), so creating or writing
+    // it from out here can't end up outside the container's filesystem.
+    pub fn host(&self, path: &str, follow_last: bool) -> Result<PathBuf> {
+        let (host, _) = self.host_path(&self.resolve(path, follow_last)?);
+        Ok(host)
+    }
+
+    // Where the files behind an absolute, already resolved container path really are, and the
+    // mount they're on if any. The innermost mount wins.
+    fn host_path(&self, path: &Path) -> (PathBuf, Option<&MountPoint>) {
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|mount| path.starts_with(&mount.destination))
+            .max_by_key(|mount| mount.destination.components().count());
+        match mount {
+            Some(mount) => (
+                mount
+                    .source
+                    .join(path.strip_prefix(&mount.destination).unwrap()),
+                Some(mount),
+            ),
+            None => (self.rootfs.join(path.strip_prefix("/").unwrap()), None),
+        }
+    }
+
+    // Whether path is a file, or a symlink to one, as the container would find it
+    pub fn is_file(&self, path: &str) -> bool {
+        match self.resolve(path, true) {
+            Ok(resolved) => self.host_path(&resolved).0.is_file(),
+            Err(_) => false,
+        }
+    }
+
+    // Up to len bytes from the start of the file at path, following symlinks
+    pub fn head(&self, path: &str, len: usize) -> Result<Vec<u8>> {
+        let resolved = self.resolve(path, true)?;
+        let mut bytes = vec![];
+        File::open(self.host_path(&resolved).0)?
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Resolve every symlink on the way to path, and the last component too if follow_last.
+    // The result is an absolute container path free of symlinks (bar the last) and "..".
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<PathBuf> {
+        let mut pending = components(Path::new(path));
+        pending.reverse();
+        let mut resolved = PathBuf::from("/");
+        let mut links = 0;
+
+        while let Some(component) = pending.pop() {
+            if component == ".." {
+                // At / this is a no-op, as it is in the kernel
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&component);
+            if pending.is_empty() && !follow_last {
+                resolved = candidate;
+                break;
+            }
+            let (host, _) = self.host_path(&candidate);
+            match symlink_metadata(&host) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        bail!("Too many levels of symbolic links in {}", path);
+                    }
+                    let target = read_link(&host)?;
+                    if target.is_absolute() {
+                        resolved = PathBuf::from("/");
+                    }
+                    let mut target = components(&target);
+                    target.reverse();
+                    pending.extend(target);
+                }
+                _ => resolved = candidate,
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+// "a/./b/../c" as ["a", "b", "..", "c"]; ".." is kept because it has to be applied after
+// any symlink before it is resolved
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+// What docker's trailing "/" and "/." mean
+struct Spec<'a> {
+    path: &'a str,
+    // "dir/": must be a directory
+    trailing_slash: bool,
+    // "dir/.": copy what's in the directory rather than the directory itself
+    contents_only: bool,
+}
+
+impl Spec<'_> {
+    fn new(path: &str) -> Spec<'_> {
+        Spec {
+            path,
+            trailing_slash: path.ends_with('/'),
+            contents_only: path.ends_with("/.") || path == ".",
+        }
+    }
+
+    // The name a copy gets inside a destination directory
+    fn name(&self) -> Result<OsString> {
+        match Path::new(self.path).file_name() {
+            Some(name) => Ok(name.to_os_string()),
+            None => bail!("Can't work out a name to copy {} under", self.path),
+        }
+    }
+}
+
+// Work out the final path of the copy from what's at the destination:
+//   missing           -> the copy is created under that name (a slash means it had to be a dir)
+//   a directory       -> the copy goes inside it, or with "src/." its contents merge into it
+//   anything else     -> overwritten, unless we're copying a directory
+fn place(
+    source: &Spec,
+    source_is_dir: bool,
+    destination: &Spec,
+    resolved: PathBuf,
+    existing: Option<Metadata>,
+) -> Result<PathBuf> {
+    match existing {
+        None if destination.trailing_slash && !source_is_dir => {
+            bail!("Destination directory {} does not exist", destination.path)
+        }
+        None => Ok(resolved),
+        Some(metadata) if metadata.is_dir() => {
+            if source_is_dir && source.contents_only {
+                Ok(resolved)
+            } else {
+                Ok(resolved.join(source.name()?))
+            }
+        }
+        Some(_) if source_is_dir => bail!(
+            "Can't copy directory {} onto file {}",
+            source.path,
+            destination.path
+        ),
+        Some(_) if destination.trailing_slash => {
+            bail!("{} is not a directory", destination.path)
+        }
+        Some(_) => Ok(resolved),
+    }
+}
+
+// Copy a path out of a container onto the host. Symlinks inside the copied tree come out as
+// symlinks (they point at container paths, following them here would be wrong), and mounts
+// inside it are copied from their source.
+pub fn copy_out(fs: &ContainerFs, path: &str, destination: &Path) -> Result<u64> {
+    let source = Spec::new(path);
+    let resolved = fs.resolve(path, source.contents_only || source.trailing_slash)?;
+    let metadata = symlink_metadata(fs.host_path(&resolved).0)
+        .with_context(|| format!("No such file or directory in the container: {}", path))?;
+    if source.trailing_slash && !metadata.is_dir() {
+        bail!("{} is not a directory", path);
+    }
+
+    let destination_text = destination.to_string_lossy();
+    let target = Spec::new(&destination_text);
+    let target = place(
+        &source,
+        metadata.is_dir(),
+        &target,
+        destination.to_path_buf(),
+        symlink_metadata(destination).ok(),
+    )?;
+    check_parent(&target)?;
+    copy_out_tree(fs, &resolved, &target)
+}
+
+fn copy_out_tree(fs: &ContainerFs, path: &Path, target: &Path) -> Result<u64> {
+    let host = fs.host_path(path).0;
+    let metadata = symlink_metadata(&host)?;
+    let file_type = metadata.file_type();
+    let mut copied = 0;
+
+    if file_type.is_dir() {
+        make_dir(target)?;
+        for entry in read_dir(&host)? {
+            let name = entry?.file_name();
+            copied += copy_out_tree(fs, &path.join(&name), &target.join(&name))?;
+        }
+    } else if file_type.is_symlink() {
+        replace_entry(target)?;
+        symlink(read_link(&host)?, target)?;
+    } else if file_type.is_file() {
+        replace_entry(target)?;
+        copied +=
+            copy(&host, target).with_context(|| format!("Failed to copy {}", path.display()))?;
+    } else {
+        eprintln!("warning: skipping special file {}", path.display());
+        return Ok(0);
+    }
+    preserve(&metadata, target)?;
+    Ok(copied)
+}
+
+// Copy a host path into a container. Everything written is checked against the container's
+// mounts (read-only ones refuse) and existing symlinks in the container are replaced, never
+// written through.
+pub fn copy_in(fs: &ContainerFs, source: &Path, path: &str) -> Result<u64> {
+    let source_text = source.to_string_lossy();
+    let source_spec = Spec::new(&source_text);
+    let metadata = if source_spec.contents_only || source_spec.trailing_slash {
+        source.metadata()
+    } else {
+        symlink_metadata(source)
+    }
+    .with_context(|| format!("No such file or directory: {}", source.display()))?;
+    if source_spec.trailing_slash && !metadata.is_dir() {
+        bail!("{} is not a directory", source.display());
+    }
+
+    let destination = Spec::new(path);
+    let resolved = fs.resolve(path, true)?;
+    let existing = symlink_metadata(fs.host_path(&resolved).0).ok();
+    let target = place(
+        &source_spec,
+        metadata.is_dir(),
+        &destination,
+        resolved,
+        existing,
+    )?;
+    let parent = target.parent().unwrap_or_else(|| Path::new("/"));
+    if !fs.host_path(parent).0.is_dir() {
+        bail!(
+            "Directory {} does not exist in the container",
+            parent.display()
+        );
+    }
+    copy_in_tree(fs, source, &target)
+}
+
+fn copy_in_tree(fs: &ContainerFs, source: &Path, path: &Path) -> Result<u64> {
+    let (target, mount) = fs.host_path(path);
+    if let Some(mount) = mount.filter(|mount| mount.read_only) {
+        bail!(
+            "Can't copy to {}, {} is mounted read-only",
+            path.display(),
+            mount.destination.display()
+        );
+    }
+    let metadata = symlink_metadata(source)?;
+    let file_type = metadata.file_type();
+    let mut copied = 0;
+
+    if file_type.is_dir() {
+        make_dir(&target)?;
+        for entry in read_dir(source)? {
+            let name = entry?.file_name();
+            copied += copy_in_tree(fs, &source.join(&name), &path.join(&name))?;
+        }
+    } else if file_type.is_symlink() {
+        replace_entry(&target)?;
+        symlink(read_link(source)?, &target)?;
+    } else if file_type.is_file() {
+        replace_entry(&target)?;
+        copied += copy(source, &target)
+            .with_context(|| format!("Failed to copy {}", source.display()))?;
+    } else {
+        eprintln!("warning: skipping special file {}", source.display());
+        return Ok(0);
+    }
+    preserve(&metadata, &target)?;
+    Ok(copied)
+}
+
+fn check_parent(target: &Path) -> Result<()> {
+    let parent = target.parent().unwrap_or_else(|| Path::new("/"));
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    if !parent.is_dir() {
+        bail!("Directory {} does not exist", parent.display());
+    }
+    Ok(())
+}
+
+// A directory to copy into: an existing real directory is merged into, anything else in the
+// way (including a symlink to a directory) is an error rather than something to follow
+fn make_dir(target: &Path) -> Result<()> {
+    match symlink_metadata(target) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => bail!(
+            "Can't copy a directory over non-directory {}",
+            target.display()
+        ),
+        Err(_) => create_dir(target)
+            .with_context(|| format!("Failed to create directory {}", target.display())),
+    }
+}
+
+// Clear whatever is at target before a file or symlink goes there. Removing a symlink instead
+// of opening it is what keeps writes inside the tree we mean to write to.
+fn replace_entry(target: &Path) -> Result<()> {
+    match symlink_metadata(target) {
+        Ok(metadata) if metadata.is_dir() => bail!(
+            "Can't copy a non-directory over directory {}",
+            target.display()
+        ),
+        Ok(_) => {
+            remove_file(target).with_context(|| format!("Failed to replace {}", target.display()))
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+// Modes always, ownership only when we're allowed to give files away (i.e. as root)
+fn preserve(metadata: &Metadata, target: &Path) -> Result<()> {
+    if !metadata.file_type().is_symlink() {
+        set_permissions(target, metadata.permissions())?;
+    }
+    if unsafe { libc::geteuid() } == 0 {
+        let path = CString::new(target.as_os_str().as_bytes())?;
+        if unsafe { libc::lchown(path.as_ptr(), metadata.uid(), metadata.gid()) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to set the owner of {}", target.display()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    fn rootfs(links: &[(&str, &str)]) -> tempfile::TempDir {
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir_all(rootfs.path().join("b/c")).unwrap();
+        write(rootfs.path().join("b/c/file"), "").unwrap();
+        for (path, target) in links {
+            let path = rootfs.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(target, path).unwrap();
+        }
+        rootfs
+    }
+
+    fn resolve(fs: &ContainerFs, path: &str) -> String {
+        fs.resolve(path, true)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn absolute_links_start_from_the_container_root() {
+        let rootfs = rootfs(&[("a/abs", "/b/c"), ("host", "/etc")]);
+        let fs = ContainerFs::bare(rootfs.path());
+        assert_eq!(resolve(&fs, "/a/abs/file"), "/b/c/file");
+        // Not the host's /etc, whatever is at /etc in the container
+        assert_eq!(resolve(&fs, "host/passwd"), "/etc/passwd");
+        assert_eq!(
+            fs.host_path(&fs.resolve("/host/passwd", true).unwrap()).0,
+            rootfs.path().join("etc/passwd")
+        );
+    }
+
+    #[test]
+    fn relative_links_start_from_their_directory() {
+        let rootfs = rootfs(&[("a/rel", "../b/c"), ("a/deeper/up", "../../b")]);
+        let fs = ContainerFs::bare(rootfs.path());
+        assert_eq!(resolve(&fs, "/a/rel/file"), "/b/c/file");
+        assert_eq!(resolve(&fs, "/a/deeper/up/c"), "/b/c");
+    }
+
+    #[test]
+    fn dot_dot_stops_at_the_root() {
+        let rootfs = rootfs(&[("escape", "../../../../b"), ("a/out", "/../../etc")]);
+        let fs = ContainerFs::bare(rootfs.path());
+        assert_eq!(resolve(&fs, "../../b/./c"), "/b/c");
+        assert_eq!(resolve(&fs, "/escape/c"), "/b/c");
+        assert_eq!(resolve(&fs, "/a/out/shadow"), "/etc/shadow");
+    }
+
+    #[test]
+    fn the_last_link_is_only_followed_when_asked() {
+        let rootfs = rootfs(&[("a/link", "/b/c"), ("b/dir", "c")]);
+        let fs = ContainerFs::bare(rootfs.path());
+        assert_eq!(fs.resolve("/a/link", false).unwrap(), Path::new("/a/link"));
+        // Links before the last are followed either way
+        assert_eq!(
+            fs.resolve("/a/link/file", false).unwrap(),
+            Path::new("/b/c/file")
+        );
+        assert_eq!(fs.resolve("/b/dir", true).unwrap(), Path::new("/b/c"));
+    }
+
+    #[test]
+    fn forty_links_are_the_most_followed() {
+        let chain = |length: usize| {
+            let mut links: Vec<(String, String)> = (0..length - 1)
+                .map(|n| (format!("l{}", n), format!("l{}", n + 1)))
+                .collect();
+            links.push((format!("l{}", length - 1), "b".to_string()));
+            links
+        };
+        let build = |links: &[(String, String)]| {
+            let links: Vec<(&str, &str)> = links
+                .iter()
+                .map(|(path, target)| (path.as_str(), target.as_str()))
+                .collect();
+            rootfs(&links)
+        };
+
+        let rootfs = build(&chain(MAX_SYMLINKS));
+        assert_eq!(resolve(&ContainerFs::bare(rootfs.path()), "/l0/c"), "/b/c");
+        let rootfs = build(&chain(MAX_SYMLINKS + 1));
+        let error = ContainerFs::bare(rootfs.path())
+            .resolve("/l0/c", true)
+            .unwrap_err();
+        assert!(error.to_string().contains("Too many levels"), "{}", error);
+
+        let rootfs = self::rootfs(&[("loop", "loop")]);
+        assert!(ContainerFs::bare(rootfs.path())
+            .resolve("/loop", true)
+            .is_err());
+    }
+
+    #[test]
+    fn the_innermost_mount_wins() {
+        let fs = ContainerFs {
+            rootfs: PathBuf::from("/rootfs"),
+            mounts: vec![
+                MountPoint {
+                    source: PathBuf::from("/outer"),
+                    destination: PathBuf::from("/data"),
+                    read_only: false,
+                },
+                MountPoint {
+                    source: PathBuf::from("/inner"),
+                    destination: PathBuf::from("/data/cache"),
+                    read_only: false,
+                },
+            ],
+        };
+        let host = |path: &str| fs.host_path(Path::new(path)).0;
+        assert_eq!(host("/data/cache/x"), Path::new("/inner/x"));
+        assert_eq!(host("/data/x"), Path::new("/outer/x"));
+        assert_eq!(host("/etc/x"), Path::new("/rootfs/etc/x"));
+        // A prefix of the name isn't the mount
+        assert_eq!(host("/database"), Path::new("/rootfs/database"));
+    }
+}
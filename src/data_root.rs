@@ -0,0 +1,117 @@
+use anyhow::{bail, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// Where root keeps everything, like docker's /var/lib/docker
+const SYSTEM_DATA_ROOT: &str = "/var/lib/mydocker";
+
+// The one directory the store, containers and volumes all live under: the store at the top,
+// <root>/containers/<id>/rootfs and <root>/volumes/<name>/_data. Keeping them together puts the
+// rootfs on the same filesystem as the layers it's built from, and keeps image extraction off
+// /tmp, which is often a small tmpfs.
+#[derive(Clone)]
+pub struct DataRoot {
+    path: PathBuf,
+}
+
+impl DataRoot {
+    // --data-root, then MYDOCKER_DATA_ROOT, then /var/lib/mydocker for root and
+    // $XDG_DATA_HOME/mydocker (~/.local/share/mydocker) for everyone else
+    pub fn resolve(flag: Option<PathBuf>) -> Result<DataRoot> {
+        let path = match flag.or_else(|| std::env::var_os("MYDOCKER_DATA_ROOT").map(PathBuf::from))
+        {
+            Some(path) => path,
+            None if unsafe { libc::geteuid() } == 0 => PathBuf::from(SYSTEM_DATA_ROOT),
+            None => user_data_home()?.join("mydocker"),
+        };
+        if !path.is_absolute() {
+            bail!("The data root {} must be an absolute path", path.display());
+        }
+        Ok(DataRoot { path })
+    }
+
+    // The store: blobs, refs and repositories.json
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn containers(&self) -> PathBuf {
+        self.path.join("containers")
+    }
+
+    pub fn volumes(&self) -> PathBuf {
+        self.path.join("volumes")
+    }
+}
+
+fn user_data_home() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("XDG_DATA_HOME").filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+    match std::env::var_os("HOME").filter(|home| !home.is_empty()) {
+        Some(home) => Ok(PathBuf::from(home).join(".local/share")),
+        None => bail!("Neither XDG_DATA_HOME nor HOME is set, pass --data-root"),
+    }
+}
+
+// Bytes available to us on the filesystem holding path, or None when that can't be told
+pub fn available_space(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// ENOSPC anywhere in the chain, or EDQUOT, which comes to the same thing
+pub fn is_out_of_space(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+            .map_or(false, |code| code == libc::ENOSPC || code == libc::EDQUOT)
+    })
+}
+
+// The filesystem holding path as "<type> at <mount point>", from the deepest mount in
+// /proc/self/mountinfo that contains it
+pub fn filesystem(path: &Path) -> Option<String> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            // <id> <parent> <major:minor> <root> <mount point> <options> ... - <type> <source> ...
+            let (before, after) = line.split_once(" - ")?;
+            let mount_point = unescape(before.split(' ').nth(4)?);
+            let kind = after.split(' ').next()?;
+            Some((PathBuf::from(mount_point), kind.to_string()))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(mount_point, kind)| format!("{} at {}", kind, mount_point.display()))
+}
+
+// mountinfo writes spaces, tabs, newlines and backslashes in paths as octal escapes
+pub fn unescape(field: &str) -> String {
+    let mut text = String::new();
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        text.push_str(&rest[..index]);
+        let escape = rest.get(index + 1..index + 4).unwrap_or("");
+        match u8::from_str_radix(escape, 8) {
+            Ok(byte) if escape.len() == 3 => {
+                text.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            _ => {
+                text.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
@@ -0,0 +1,173 @@
+use crate::container::ContainerState;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read, read_dir, read_link, symlink_metadata, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Written next to the rootfs right before the container starts
+const SNAPSHOT: &str = "rootfs.snapshot.json";
+
+// What we remember about each path in the freshly assembled rootfs. There's no overlay upper
+// dir to walk, so changes are found by comparing against this.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: (i64, i64),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link: Option<PathBuf>,
+    // Only compared with --verify. A process can set mtime back with utimensat, but it can't
+    // touch ctime, so these catch edits that were made to look untouched.
+    inode: u64,
+    ctime: (i64, i64),
+}
+
+impl Entry {
+    fn new(path: &Path, metadata: &Metadata) -> Result<Entry> {
+        Ok(Entry {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            // A directory's size depends on the filesystem's bookkeeping, not its content
+            size: if metadata.is_dir() {
+                0
+            } else {
+                metadata.size()
+            },
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            link: if metadata.file_type().is_symlink() {
+                Some(read_link(path)?)
+            } else {
+                None
+            },
+            inode: metadata.ino(),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+
+    fn changed(&self, other: &Entry, verify: bool) -> bool {
+        let visible = (
+            self.mode, self.uid, self.gid, self.size, self.mtime, &self.link,
+        ) != (
+            other.mode,
+            other.uid,
+            other.gid,
+            other.size,
+            other.mtime,
+            &other.link,
+        );
+        visible || (verify && (self.inode, self.ctime) != (other.inode, other.ctime))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl Kind {
+    // docker diff's prefixes
+    pub fn letter(self) -> char {
+        match self {
+            Kind::Added => 'A',
+            Kind::Changed => 'C',
+            Kind::Deleted => 'D',
+        }
+    }
+}
+
+// Record the rootfs as the container is about to see it. What's under a mount point goes to
+// the mount rather than the rootfs, so it's left out here and in changes(), like docker does.
+pub fn snapshot(state: &ContainerState) -> Result<()> {
+    let mut entries = BTreeMap::new();
+    walk(
+        &state.rootfs,
+        Path::new("/"),
+        &mount_points(state),
+        &mut entries,
+    )?;
+    let path = state.rootfs.parent().unwrap().join(SNAPSHOT);
+    std::fs::write(&path, serde_json::to_vec(&entries)?)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// Everything the container has added, changed or deleted since it started, sorted by path
+pub fn changes(state: &ContainerState, verify: bool) -> Result<Vec<(Kind, String)>> {
+    let snapshot = state.rootfs.parent().unwrap().join(SNAPSHOT);
+    if !state.rootfs.is_dir() {
+        bail!(
+            "The rootfs of container {} is gone, only containers run with --keep-rootfs keep it",
+            state.short_id()
+        );
+    }
+    let data = read(&snapshot).with_context(|| {
+        format!(
+            "Container {} has no snapshot of its rootfs to compare with",
+            state.short_id()
+        )
+    })?;
+    let before: BTreeMap<String, Entry> =
+        serde_json::from_slice(&data).context("Failed to parse the rootfs snapshot")?;
+
+    let mut after = BTreeMap::new();
+    walk(
+        &state.rootfs,
+        Path::new("/"),
+        &mount_points(state),
+        &mut after,
+    )?;
+
+    let mut changes = vec![];
+    for (path, entry) in &after {
+        match before.get(path) {
+            None => changes.push((Kind::Added, path.clone())),
+            Some(old) if old.changed(entry, verify) => changes.push((Kind::Changed, path.clone())),
+            Some(_) => {}
+        }
+    }
+    for path in before.keys() {
+        if !after.contains_key(path) {
+            changes.push((Kind::Deleted, path.clone()));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(changes)
+}
+
+fn mount_points(state: &ContainerState) -> Vec<PathBuf> {
+    state
+        .mounts
+        .iter()
+        .map(|mount| mount.destination.clone())
+        .collect()
+}
+
+// Collect every path below root (but not root itself) without following symlinks. Mount
+// points themselves are recorded, what's below them isn't.
+fn walk(
+    root: &Path,
+    path: &Path,
+    excluded: &[PathBuf],
+    entries: &mut BTreeMap<String, Entry>,
+) -> Result<()> {
+    let host = root.join(path.strip_prefix("/").unwrap());
+    for child in read_dir(&host).with_context(|| format!("Failed to read {}", host.display()))? {
+        let child = child?;
+        let container_path = path.join(child.file_name());
+        let metadata = symlink_metadata(child.path())?;
+        entries.insert(
+            container_path.to_string_lossy().into_owned(),
+            Entry::new(&child.path(), &metadata)?,
+        );
+        if metadata.is_dir() && !excluded.contains(&container_path) {
+            walk(root, &container_path, excluded, entries)?;
+        }
+    }
+    Ok(())
+}
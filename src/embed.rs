@@ -0,0 +1,343 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::{OsStr, OsString};
+use std::io::Read;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Where to find mydocker when the program embedding us isn't mydocker itself
+const EXECUTABLE_VARIABLE: &str = "MYDOCKER_BIN";
+
+// A `run` of a container, put together the way std::process::Command puts together a process.
+// Nothing happens until run() or spawn(), and both go through a `run` of the mydocker
+// executable: the same pull cache, supervision and cleanup as from the command line, and a
+// container can't take the embedding program down with it.
+#[derive(Clone)]
+pub struct ContainerBuilder {
+    executable: Option<PathBuf>,
+    data_root: Option<PathBuf>,
+    root: Option<PathBuf>,
+    offline: bool,
+    // `run` options in the order they were given; the image and command come after them
+    options: Vec<OsString>,
+    image: String,
+    command: Vec<OsString>,
+}
+
+// A container started by ContainerBuilder::spawn(). Its pipes are taken out with stdin(),
+// stdout() and stderr(); whatever isn't taken is read by wait_with_output().
+pub struct Container {
+    child: Child,
+    started: Instant,
+    exited: Option<Exited>,
+}
+
+// How a container ended, as `run` reported it
+#[derive(Clone, Copy, Debug)]
+pub struct Exited {
+    // The command's exit status, 128 + the signal if one ended it
+    pub exit_code: i32,
+    pub stats: RunStats,
+}
+
+// What Command::output() gives, for a container
+#[derive(Debug)]
+pub struct RunOutput {
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub stats: RunStats,
+}
+
+// What the container cost, pulling and unpacking the image included. The CPU times and peak
+// memory are the kernel's accounting for `run` and everything it waited for.
+#[derive(Clone, Copy, Debug)]
+pub struct RunStats {
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    // The largest resident set of any one of those processes
+    pub max_rss_bytes: u64,
+}
+
+impl Container {
+    // image is anything `run` takes: a reference like alpine:3.19, or a digest
+    pub fn builder(image: &str) -> ContainerBuilder {
+        ContainerBuilder {
+            executable: None,
+            data_root: None,
+            root: None,
+            offline: false,
+            options: vec![],
+            image: image.to_string(),
+            command: vec![],
+        }
+    }
+
+    // The supervising `run` process, not the command inside the container
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Dropping it closes the container's stdin
+    pub fn stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    pub fn stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    pub fn stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    // Stops the container like `stop` would: the stop signal, SIGKILL once the grace period is
+    // up, and then the container is removed. wait() collects how it ended.
+    pub fn kill(&mut self) -> Result<()> {
+        if self.exited.is_some() {
+            return Ok(());
+        }
+        if unsafe { libc::kill(self.child.id() as i32, libc::SIGTERM) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to stop the container");
+        }
+        Ok(())
+    }
+
+    // Blocks until the container has exited and `run` has cleaned up after it
+    pub fn wait(&mut self) -> Result<Exited> {
+        if let Some(exited) = self.exited {
+            return Ok(exited);
+        }
+        // wait4 rather than Child::wait, for the resource usage
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            let pid = unsafe { libc::wait4(self.child.id() as i32, &mut status, 0, &mut usage) };
+            if pid >= 0 {
+                break;
+            }
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error).context("Failed to wait for the container");
+            }
+        }
+        let exited = Exited {
+            exit_code: exit_code(ExitStatus::from_raw(status)),
+            stats: RunStats {
+                wall_time: self.started.elapsed(),
+                user_time: duration(usage.ru_utime),
+                system_time: duration(usage.ru_stime),
+                // Linux counts it in kilobytes
+                max_rss_bytes: usage.ru_maxrss as u64 * 1024,
+            },
+        };
+        self.exited = Some(exited);
+        Ok(exited)
+    }
+
+    // The same, on a blocking thread so an async caller's executor isn't held up
+    pub async fn wait_async(mut self) -> Result<Exited> {
+        tokio::task::spawn_blocking(move || self.wait())
+            .await
+            .context("Waiting for the container panicked")?
+    }
+
+    // Closes stdin if it's still ours, reads stdout and stderr to the end, then waits
+    pub fn wait_with_output(mut self) -> Result<RunOutput> {
+        drop(self.child.stdin.take());
+        let stdout = read_all(self.child.stdout.take());
+        let stderr = read_all(self.child.stderr.take());
+        let exited = self.wait()?;
+        Ok(RunOutput {
+            exit_code: exited.exit_code,
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+            stats: exited.stats,
+        })
+    }
+}
+
+impl ContainerBuilder {
+    // What runs in the container instead of the image's default command
+    pub fn command<I, S>(mut self, command: I) -> ContainerBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command = command
+            .into_iter()
+            .map(|arg| arg.as_ref().to_os_string())
+            .collect();
+        self
+    }
+
+    pub fn env(self, key: &str, value: &str) -> ContainerBuilder {
+        self.run_option("--env")
+            .run_option(format!("{}={}", key, value))
+    }
+
+    // The variable's value in our own environment, left out if we don't have it
+    pub fn env_from_host(self, key: &str) -> ContainerBuilder {
+        self.run_option("--env").run_option(key)
+    }
+
+    pub fn name(self, name: &str) -> ContainerBuilder {
+        self.run_option("--name").run_option(name)
+    }
+
+    pub fn label(self, key: &str, value: &str) -> ContainerBuilder {
+        self.run_option("--label")
+            .run_option(format!("{}={}", key, value))
+    }
+
+    // -v syntax: <host path or volume name>:<container path>[:ro]
+    pub fn volume(self, spec: &str) -> ContainerBuilder {
+        self.run_option("--volume").run_option(spec)
+    }
+
+    pub fn memory_limit(self, bytes: u64) -> ContainerBuilder {
+        self.run_option(format!("--memory={}", bytes))
+    }
+
+    pub fn cpus(self, cpus: f64) -> ContainerBuilder {
+        self.run_option(format!("--cpus={}", cpus))
+    }
+
+    // Any other `run` option, for what has no method of its own: .run_option("--read-only")
+    pub fn run_option<S: AsRef<OsStr>>(mut self, option: S) -> ContainerBuilder {
+        self.options.push(option.as_ref().to_os_string());
+        self
+    }
+
+    // The store, containers and volumes, as --data-root; the usual default otherwise
+    pub fn data_root(mut self, path: &Path) -> ContainerBuilder {
+        self.data_root = Some(path.to_path_buf());
+        self
+    }
+
+    // Where the container's directory goes, as run --root
+    pub fn root(mut self, path: &Path) -> ContainerBuilder {
+        self.root = Some(path.to_path_buf());
+        self
+    }
+
+    // Only use what the store already has, as --offline
+    pub fn offline(mut self, offline: bool) -> ContainerBuilder {
+        self.offline = offline;
+        self
+    }
+
+    // The mydocker executable; MYDOCKER_BIN, or the running program when that isn't set
+    pub fn executable(mut self, path: &Path) -> ContainerBuilder {
+        self.executable = Some(path.to_path_buf());
+        self
+    }
+
+    // The `run` invocation as a Command, for a caller that wants to spawn it its own way
+    pub fn to_command(&self) -> Result<Command> {
+        let executable = match (&self.executable, std::env::var_os(EXECUTABLE_VARIABLE)) {
+            (Some(executable), _) => executable.clone(),
+            (None, Some(executable)) => PathBuf::from(executable),
+            (None, None) => std::env::current_exe().context("Failed to find our own executable")?,
+        };
+        if self.image.is_empty() {
+            bail!("No image to run");
+        }
+        let mut command = Command::new(executable);
+        if let Some(data_root) = &self.data_root {
+            command.arg("--data-root").arg(data_root);
+        }
+        command.arg("run");
+        if let Some(root) = &self.root {
+            command.arg("--root").arg(root);
+        }
+        if self.offline {
+            command.arg("--offline");
+        }
+        command
+            .args(&self.options)
+            .arg("--")
+            .arg(&self.image)
+            .args(&self.command);
+        Ok(command)
+    }
+
+    // Like Command::output(): runs the container to completion with stdin closed, and
+    // captures what it wrote
+    pub fn run(self) -> Result<RunOutput> {
+        let mut command = self.to_command()?;
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        start(command)?.wait_with_output()
+    }
+
+    // The same, on a blocking thread so an async caller's executor isn't held up
+    pub async fn run_async(self) -> Result<RunOutput> {
+        tokio::task::spawn_blocking(move || self.run())
+            .await
+            .context("Running the container panicked")?
+    }
+
+    // Starts the container with stdin, stdout and stderr all piped. As with any pipe, a
+    // container writing more than the pipe holds blocks until someone reads.
+    pub fn spawn(self) -> Result<Container> {
+        let mut command = self.to_command()?;
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        start(command)
+    }
+}
+
+fn start(mut command: Command) -> Result<Container> {
+    let child = command.spawn().with_context(|| {
+        format!(
+            "Failed to start {}",
+            command.get_program().to_string_lossy()
+        )
+    })?;
+    Ok(Container {
+        child,
+        started: Instant::now(),
+        exited: None,
+    })
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+fn duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+// Each stream on a thread of its own, so neither pipe can fill up while we read the other
+fn read_all<R: Read + Send + 'static>(
+    stream: Option<R>,
+) -> Option<JoinHandle<std::io::Result<Vec<u8>>>> {
+    stream.map(|mut stream| {
+        std::thread::spawn(move || {
+            let mut bytes = vec![];
+            stream.read_to_end(&mut bytes).map(|_| bytes)
+        })
+    })
+}
+
+fn collect(reader: Option<JoinHandle<std::io::Result<Vec<u8>>>>) -> Result<Vec<u8>> {
+    match reader {
+        Some(reader) => Ok(reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Reading the container's output panicked"))??),
+        None => Ok(vec![]),
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{bail, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+// What a container's filesystem has that's never worth archiving: the kernel's views, which
+// are empty directories in the rootfs anyway, and /dev, which we populate ourselves. The
+// directories themselves stay. These come before --exclude, so "!dev/null" can still put a
+// path back.
+const DEFAULTS: &[&str] = &["proc/**", "sys/**", "dev/**"];
+
+// .dockerignore-style patterns, matched against paths relative to the root of the rootfs:
+//   *       any run of characters within one path component
+//   ?       one character, never /
+//   [a-z]   one character from a class, [!a-z] or [^a-z] for one outside it
+//   **      any number of whole components: **/x at any depth, x/** everything below x
+//   \c      c itself
+//   !p      puts back what an earlier pattern excluded
+//   p/      only directories
+// Every pattern is anchored at the root, with or without a leading /: "cache" is /cache, not
+// /var/cache. A pattern that matches a directory excludes everything below it too. The last
+// pattern to match a path decides.
+pub struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    negated: bool,
+    dir_only: bool,
+    segments: Vec<Segment>,
+}
+
+#[derive(PartialEq)]
+enum Segment {
+    AnyDepth,
+    Glob(Vec<u8>),
+}
+
+impl Excludes {
+    // The built-in exclusions followed by the given patterns
+    pub fn new(patterns: &[String]) -> Result<Excludes> {
+        let defaults = DEFAULTS.iter().map(|pattern| pattern.to_string());
+        let patterns = defaults
+            .chain(patterns.iter().cloned())
+            .map(|pattern| Pattern::parse(&pattern))
+            .collect::<Result<_>>()?;
+        Ok(Excludes { patterns })
+    }
+
+    // Whether there's any point looking inside an excluded directory
+    pub fn has_negations(&self) -> bool {
+        self.patterns.iter().any(|pattern| pattern.negated)
+    }
+
+    // path is relative to the root of the rootfs, "usr/bin/env"
+    pub fn excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let components: Vec<&[u8]> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        let mut excluded = false;
+        for pattern in &self.patterns {
+            if pattern.negated == excluded && pattern.matches(&components, is_dir) {
+                excluded = !pattern.negated;
+            }
+        }
+        excluded
+    }
+}
+
+impl Pattern {
+    fn parse(value: &str) -> Result<Pattern> {
+        let (negated, rest) = match value.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let dir_only = rest.ends_with('/');
+        let rest = rest.trim_matches('/');
+        if rest.is_empty() {
+            bail!("Invalid --exclude '{}', the pattern is empty", value);
+        }
+
+        let mut segments = vec![];
+        for part in rest
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            if part == ".." {
+                bail!("Invalid --exclude '{}', patterns can't contain ..", value);
+            }
+            let segment = if part == "**" {
+                Segment::AnyDepth
+            } else {
+                check_glob(part.as_bytes()).map_err(|problem| {
+                    anyhow::anyhow!("Invalid --exclude '{}', {}", value, problem)
+                })?;
+                Segment::Glob(part.as_bytes().to_vec())
+            };
+            // a/**/**/b is a/**/b
+            if segment == Segment::AnyDepth && segments.last() == Some(&Segment::AnyDepth) {
+                continue;
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            bail!("Invalid --exclude '{}', the pattern is empty", value);
+        }
+        Ok(Pattern {
+            negated,
+            dir_only,
+            segments,
+        })
+    }
+
+    // The path itself, or any directory above it
+    fn matches(&self, components: &[&[u8]], is_dir: bool) -> bool {
+        (1..=components.len()).any(|len| {
+            let whole = len == components.len();
+            (!self.dir_only || is_dir || !whole)
+                && match_segments(&self.segments, &components[..len])
+        })
+    }
+}
+
+fn match_segments(segments: &[Segment], components: &[&[u8]]) -> bool {
+    match segments.split_first() {
+        None => components.is_empty(),
+        // A trailing ** is everything below, not the directory itself
+        Some((Segment::AnyDepth, [])) => !components.is_empty(),
+        Some((Segment::AnyDepth, rest)) => {
+            (0..=components.len()).any(|skip| match_segments(rest, &components[skip..]))
+        }
+        Some((Segment::Glob(glob), rest)) => match components.split_first() {
+            Some((first, others)) => match_glob(glob, first) && match_segments(rest, others),
+            None => false,
+        },
+    }
+}
+
+// Only the class syntax can be malformed
+fn check_glob(glob: &[u8]) -> std::result::Result<(), &'static str> {
+    let mut i = 0;
+    while i < glob.len() {
+        match glob[i] {
+            b'\\' if i + 1 == glob.len() => return Err("it ends with a lone \\"),
+            b'\\' => i += 2,
+            b'[' => match class_end(glob, i) {
+                Some(end) => i = end + 1,
+                None => return Err("a [ is never closed"),
+            },
+            _ => i += 1,
+        }
+    }
+    Ok(())
+}
+
+// The index of the ] closing the class that opens at start. A ] right after [ or [! is part
+// of the class, like in fnmatch.
+fn class_end(glob: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if matches!(glob.get(i), Some(b'!') | Some(b'^')) {
+        i += 1;
+    }
+    if glob.get(i) == Some(&b']') {
+        i += 1;
+    }
+    while i < glob.len() {
+        match glob[i] {
+            b'\\' => i += 2,
+            b']' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn match_glob(glob: &[u8], name: &[u8]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some((b'\\', rest)) => match (rest.split_first(), name.split_first()) {
+            (Some((wanted, rest)), Some((found, name))) => {
+                wanted == found && match_glob(rest, name)
+            }
+            _ => false,
+        },
+        Some((b'[', _)) => {
+            let end = class_end(glob, 0).unwrap();
+            match name.split_first() {
+                Some((&found, name)) => {
+                    in_class(&glob[1..end], found) && match_glob(&glob[end + 1..], name)
+                }
+                None => false,
+            }
+        }
+        Some((wanted, rest)) => match name.split_first() {
+            Some((found, name)) => wanted == found && match_glob(rest, name),
+            None => false,
+        },
+    }
+}
+
+// class is what's between the brackets
+fn in_class(class: &[u8], byte: u8) -> bool {
+    let (negated, mut class) = match class.split_first() {
+        Some((b'!', rest)) | Some((b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while let Some((&start, rest)) = class.split_first() {
+        let (start, rest) = match (start, rest.split_first()) {
+            (b'\\', Some((&escaped, rest))) => (escaped, rest),
+            _ => (start, rest),
+        };
+        // a-z, unless the - is the last thing in the class
+        match rest {
+            [b'-', end, rest @ ..] => {
+                let (end, rest) = match (end, rest.split_first()) {
+                    (b'\\', Some((&escaped, rest))) => (escaped, rest),
+                    _ => (*end, rest),
+                };
+                found |= (start..=end).contains(&byte);
+                class = rest;
+            }
+            _ => {
+                found |= start == byte;
+                class = rest;
+            }
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (patterns, path, is_dir, excluded)
+    type Case = (&'static [&'static str], &'static str, bool, bool);
+
+    fn check(cases: &[Case]) {
+        for &(patterns, path, is_dir, expected) in cases {
+            let patterns: Vec<String> =
+                patterns.iter().map(|pattern| pattern.to_string()).collect();
+            let excludes = Excludes::new(&patterns).unwrap();
+            assert_eq!(
+                excludes.excluded(Path::new(path), is_dir),
+                expected,
+                "{:?} on {}{}",
+                patterns,
+                path,
+                if is_dir { "/" } else { "" }
+            );
+        }
+    }
+
+    #[test]
+    fn defaults_keep_the_kernel_directories_but_not_their_contents() {
+        check(&[
+            (&[], "proc", true, false),
+            (&[], "proc/1/status", false, true),
+            (&[], "sys/kernel", true, true),
+            (&[], "dev/null", false, true),
+            (&[], "dev", true, false),
+            (&[], "devices", false, false),
+            (&[], "usr/dev/x", false, false),
+            (&["!dev/null"], "dev/null", false, false),
+            (&["!dev/null"], "dev/zero", false, true),
+        ]);
+    }
+
+    #[test]
+    fn patterns_are_anchored_at_the_root() {
+        check(&[
+            (&["cache"], "cache", true, true),
+            (&["cache"], "cache/apt/lists", false, true),
+            (&["cache"], "var/cache", true, false),
+            (&["/var/cache"], "var/cache/apt", true, true),
+            (&["./var/cache"], "var/cache", true, true),
+            (&["var/cache"], "var/cached", true, false),
+            (&["var//cache/"], "var/cache", true, true),
+        ]);
+    }
+
+    #[test]
+    fn globs_stay_within_a_component() {
+        check(&[
+            (&["*.log"], "app.log", false, true),
+            (&["*.log"], "var/app.log", false, false),
+            (&["var/*.log"], "var/app.log", false, true),
+            (&["var/*"], "var/log/app.log", false, true),
+            (&["*"], "anything/at/all", false, true),
+            (&["tmp/?"], "tmp/a", false, true),
+            (&["tmp/?"], "tmp/ab", false, false),
+            (&["tmp/?"], "tmp", true, false),
+            (&["tmp*"], "tmp", true, true),
+            (&["a*b*c"], "aXbYc", false, true),
+            (&["a*b*c"], "aXcYb", false, false),
+        ]);
+    }
+
+    #[test]
+    fn double_stars_cross_components() {
+        check(&[
+            (&["**/*.pyc"], "x.pyc", false, true),
+            (&["**/*.pyc"], "usr/lib/python3/x.pyc", false, true),
+            (&["**/*.pyc"], "usr/lib/x.py", false, false),
+            (&["**/__pycache__"], "a/b/__pycache__/x", false, true),
+            (&["var/**"], "var", true, false),
+            (&["var/**"], "var/lib", true, true),
+            (&["usr/**/doc"], "usr/doc", true, true),
+            (&["usr/**/doc"], "usr/share/doc/README", false, true),
+            (&["usr/**/**/doc"], "usr/a/b/doc", true, true),
+            (&["usr/**/doc"], "usr/share/docs", true, false),
+            (&["**"], "etc", true, true),
+        ]);
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        check(&[
+            (&["log[0-9]"], "log7", false, true),
+            (&["log[0-9]"], "logx", false, false),
+            (&["log[!0-9]"], "logx", false, true),
+            (&["log[^0-9]"], "log7", false, false),
+            (&["[]]x"], "]x", false, true),
+            (&["[!]]x"], "]x", false, false),
+            (&["[a-]"], "-", false, true),
+            (&["[\\]]"], "]", false, true),
+            (&["[a\\-z]"], "b", false, false),
+            (&["\\*"], "*", false, true),
+            (&["\\*"], "x", false, false),
+            (&["what\\?"], "what?", false, true),
+            (&["what\\?"], "whatx", false, false),
+        ]);
+    }
+
+    #[test]
+    fn the_last_matching_pattern_decides() {
+        check(&[
+            (&["var/log", "!var/log/keep"], "var/log/keep", false, false),
+            (&["var/log", "!var/log/keep"], "var/log/drop", false, true),
+            (&["!var/log/keep", "var/log"], "var/log/keep", false, true),
+            (&["*.txt", "!important.txt"], "important.txt", false, false),
+            (
+                &["*.txt", "!important.txt", "*.txt"],
+                "important.txt",
+                false,
+                true,
+            ),
+            // Nothing to put back
+            (&["!etc"], "etc", true, false),
+        ]);
+    }
+
+    #[test]
+    fn a_trailing_slash_only_matches_directories() {
+        check(&[
+            (&["build/"], "build", true, true),
+            (&["build/"], "build", false, false),
+            (&["build/"], "build/out.o", false, true),
+            (&["**/node_modules/"], "app/node_modules", true, true),
+            (&["**/node_modules/"], "app/node_modules", false, false),
+        ]);
+    }
+
+    #[test]
+    fn non_utf8_names_can_be_matched() {
+        use std::ffi::OsStr;
+        let excludes = Excludes::new(&["data/*".to_string()]).unwrap();
+        let name = Path::new(OsStr::from_bytes(b"data/caf\xe9"));
+        assert!(excludes.excluded(name, false));
+    }
+
+    #[test]
+    fn negations_are_noticed() {
+        assert!(!Excludes::new(&["a".to_string()]).unwrap().has_negations());
+        assert!(Excludes::new(&["a".to_string(), "!a/b".to_string()])
+            .unwrap()
+            .has_negations());
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for pattern in ["", "/", "!", "./", "a/../b", "..", "[abc", "x\\", "a/[!"] {
+            assert!(
+                Excludes::new(&[pattern.to_string()]).is_err(),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::container::ContainerState;
+use crate::exclude::Excludes;
+use crate::ownership::{self, Owners};
+use crate::privileges::{Ownership, SubordinateIds};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{read_dir, read_link, symlink_metadata, File, Metadata};
+use std::io::{empty, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use tar::{Builder, EntryType, Header, HeaderMode};
+
+// What the kernel uses for ids that have no mapping, "nobody"
+const OVERFLOW_ID: u32 = 65534;
+
+pub struct Summary {
+    // Unix sockets, which tar has no way to represent
+    pub sockets: u64,
+}
+
+// Ids as the container sees them. How they're stored on the host depends on how the rootfs
+// got its ownership (see privileges::Ownership), and the archive should read the same whichever
+// it was.
+enum Ids {
+    // The host's ids are the container's
+    Direct,
+    // Our own ids are the container's root, the subordinate ranges are 1 and up
+    Subordinate {
+        ids: SubordinateIds,
+        uid: u32,
+        gid: u32,
+    },
+    // Everything is ours on the host, what the layers asked for is in ownership.json
+    Recorded(Owners),
+}
+
+impl Ids {
+    fn of(state: &ContainerState) -> Result<Ids> {
+        Ok(match state.ownership {
+            None | Some(Ownership::Chown) => Ids::Direct,
+            Some(Ownership::Subordinate { uids, gids }) => Ids::Subordinate {
+                ids: SubordinateIds { uids, gids },
+                uid: unsafe { libc::geteuid() },
+                gid: unsafe { libc::getegid() },
+            },
+            Some(Ownership::Recorded) => Ids::Recorded(ownership::load(&state.rootfs)?),
+        })
+    }
+
+    fn get(&self, path: &Path, metadata: &Metadata) -> (u32, u32) {
+        match self {
+            Ids::Direct => (metadata.uid(), metadata.gid()),
+            Ids::Subordinate { ids, uid, gid } => {
+                let map = |id: u32, ours: u32, start: u32, count: u32| match id {
+                    _ if id == ours => 0,
+                    _ if id >= start && id - start < count => id - start + 1,
+                    _ => OVERFLOW_ID,
+                };
+                (
+                    map(metadata.uid(), *uid, ids.uids.start, ids.uids.count),
+                    map(metadata.gid(), *gid, ids.gids.start, ids.gids.count),
+                )
+            }
+            Ids::Recorded(owners) => owners
+                .get(&ownership::container_path(path))
+                .copied()
+                .unwrap_or((0, 0)),
+        }
+    }
+}
+
+// The container's filesystem as an uncompressed tar, like `docker export`: the rootfs without
+// its -v mounts, minus whatever excludes says. Entries are sorted, so the same tree gives the
+// same archive.
+pub fn export(state: &ContainerState, excludes: &Excludes, output: impl Write) -> Result<Summary> {
+    if !state.rootfs.is_dir() {
+        bail!(
+            "The rootfs of container {} is gone, only containers run with --keep-rootfs keep it",
+            state.short_id()
+        );
+    }
+    let mut exporter = Exporter {
+        rootfs: &state.rootfs,
+        excludes,
+        ids: Ids::of(state)?,
+        builder: Builder::new(output),
+        links: HashMap::new(),
+        summary: Summary { sockets: 0 },
+    };
+    exporter.walk(Path::new(""))?;
+    exporter.builder.into_inner()?.flush()?;
+    Ok(exporter.summary)
+}
+
+struct Exporter<'a, W: Write> {
+    rootfs: &'a Path,
+    excludes: &'a Excludes,
+    ids: Ids,
+    builder: Builder<W>,
+    // (device, inode) of files with more than one link, and the path they went in as first
+    links: HashMap<(u64, u64), PathBuf>,
+    summary: Summary,
+}
+
+impl<W: Write> Exporter<'_, W> {
+    // dir is relative to the rootfs, "" for the rootfs itself
+    fn walk(&mut self, dir: &Path) -> Result<()> {
+        let host = self.rootfs.join(dir);
+        let mut names = read_dir(&host)
+            .with_context(|| format!("Failed to list {}", host.display()))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+
+        for name in names {
+            let path = dir.join(&name);
+            let host = self.rootfs.join(&path);
+            let metadata = symlink_metadata(&host)
+                .with_context(|| format!("Failed to stat {}", host.display()))?;
+            let is_dir = metadata.is_dir();
+            if self.excludes.excluded(&path, is_dir) {
+                // Something further down may be put back
+                if is_dir && self.excludes.has_negations() {
+                    self.walk(&path)?;
+                }
+                continue;
+            }
+            if metadata.file_type().is_socket() {
+                self.summary.sockets += 1;
+                continue;
+            }
+            self.append(&path, &host, &metadata)
+                .with_context(|| format!("Failed to archive /{}", path.display()))?;
+            if is_dir {
+                self.walk(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, path: &Path, host: &Path, metadata: &Metadata) -> Result<()> {
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+        let (uid, gid) = self.ids.get(path, metadata);
+        header.set_uid(uid as u64);
+        header.set_gid(gid as u64);
+        let file_type = metadata.file_type();
+
+        if file_type.is_symlink() {
+            let target = read_link(host)?;
+            self.builder.append_link(&mut header, path, target)?;
+        } else if file_type.is_file() {
+            if metadata.nlink() > 1 {
+                let key = (metadata.dev(), metadata.ino());
+                if let Some(first) = self.links.get(&key) {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    let first = first.clone();
+                    self.builder.append_link(&mut header, path, first)?;
+                    return Ok(());
+                }
+                self.links.insert(key, path.to_path_buf());
+            }
+            self.builder
+                .append_data(&mut header, path, File::open(host)?)?;
+        } else {
+            if file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device() {
+                header.set_entry_type(if file_type.is_fifo() {
+                    EntryType::Fifo
+                } else if file_type.is_char_device() {
+                    EntryType::Char
+                } else {
+                    EntryType::Block
+                });
+                let device = metadata.rdev();
+                let (major, minor) = unsafe { (libc::major(device), libc::minor(device)) };
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+            }
+            header.set_size(0);
+            self.builder.append_data(&mut header, path, empty())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    fn state(rootfs: &Path) -> ContainerState {
+        serde_json::from_value(serde_json::json!({
+            "id": "0123456789abcdef",
+            "image": "test",
+            "rootfs": rootfs,
+            "created": 0,
+            "status": "exited",
+        }))
+        .unwrap()
+    }
+
+    fn entries(archive: &[u8]) -> Vec<String> {
+        tar::Archive::new(archive)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().to_str().unwrap().to_string();
+                match entry.header().entry_type() {
+                    EntryType::Directory => format!("{}/", path),
+                    _ => path,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_everything_but_what_is_excluded() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        for path in [
+            "proc/1/status",
+            "dev/null",
+            "etc/hostname",
+            "var/cache/apt/pkgcache.bin",
+            "var/cache/keep/me",
+        ] {
+            create_dir_all(rootfs.join(path).parent().unwrap()).unwrap();
+            write(rootfs.join(path), "x").unwrap();
+        }
+        create_dir_all(rootfs.join("sys")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(rootfs.join("etc/sock")).unwrap();
+
+        let excludes =
+            Excludes::new(&["var/cache".to_string(), "!var/cache/keep".to_string()]).unwrap();
+        let mut archive = vec![];
+        let summary = export(&state(&rootfs), &excludes, &mut archive).unwrap();
+        assert_eq!(summary.sockets, 1);
+        assert_eq!(
+            entries(&archive),
+            [
+                "dev/",
+                "etc/",
+                "etc/hostname",
+                "proc/",
+                "sys/",
+                "var/",
+                "var/cache/keep/",
+                "var/cache/keep/me",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_same_tree_gives_the_same_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        for name in ["b", "a", "c/d", "c/a"] {
+            create_dir_all(rootfs.join(name).parent().unwrap()).unwrap();
+            write(rootfs.join(name), name).unwrap();
+        }
+        let excludes = Excludes::new(&[]).unwrap();
+        let (mut first, mut second) = (vec![], vec![]);
+        export(&state(&rootfs), &excludes, &mut first).unwrap();
+        export(&state(&rootfs), &excludes, &mut second).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(entries(&first), ["a", "b", "c/", "c/a", "c/d"]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct Request {
+    pub method: String,
+    // With the query string, if any
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Close the connection after this much of the body, for a download that breaks off
+    pub cut_off: Option<usize>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+            cut_off: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    pub fn cut_off(mut self, after: usize) -> Response {
+        self.cut_off = Some(after);
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+// A registry on the loopback interface for tests, speaking just enough plain HTTP/1.1 for our
+// client: one request per connection, bodies only with a Content-Length. The client tries
+// HTTPS first; those connections are dropped, which sends it on to plain HTTP the way it would
+// for a registry:2 container.
+pub struct FakeRegistry {
+    // host:port, for an image reference
+    pub address: String,
+    // "METHOD target" of every request so far
+    seen: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeRegistry {
+    pub async fn start(
+        respond: impl Fn(&Request) -> Response + Send + Sync + 'static,
+    ) -> FakeRegistry {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let respond: Arc<Handler> = Arc::new(respond);
+        let log = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (respond, log) = (respond.clone(), log.clone());
+                tokio::spawn(async move {
+                    let _ = serve(stream, &*respond, &log).await;
+                });
+            }
+        });
+        FakeRegistry { address, seen }
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.seen.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    respond: &Handler,
+    seen: &Mutex<Vec<String>>,
+) -> std::io::Result<()> {
+    let mut data = vec![];
+    let mut buffer = [0; 4096];
+    let end = loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        data.extend_from_slice(&buffer[..read]);
+        // A TLS ClientHello
+        if data[0] == 0x16 {
+            return Ok(());
+        }
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: data[end + 4..].to_vec(),
+    };
+    let length: usize = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    while request.body.len() < length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.body.extend_from_slice(&buffer[..read]);
+    }
+    seen.lock()
+        .unwrap()
+        .push(format!("{} {}", request.method, request.target));
+
+    let response = respond(&request);
+    let mut head = format!("HTTP/1.1 {} Fake\r\n", response.status);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+    stream.write_all(head.as_bytes()).await?;
+    if request.method != "HEAD" {
+        let sent = response.cut_off.unwrap_or(response.body.len());
+        stream.write_all(&response.body[..sent]).await?;
+    }
+    stream.shutdown().await
+}
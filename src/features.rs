@@ -0,0 +1,269 @@
+use crate::cgroup;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// The first kernel with pidfd_open
+const PIDFD_KERNEL: (u32, u32) = (5, 3);
+// And with clone3, which came in the same release
+const CLONE3_KERNEL: (u32, u32) = (5, 3);
+
+pub enum CgroupVersion {
+    // A unified hierarchy, with the controllers our own cgroup was given
+    V2 { controllers: Vec<String> },
+    // v1 or a hybrid setup, which we don't drive
+    V1,
+    None,
+}
+
+// What the kernel offers, as far as the files under /proc and /sys tell. Everything is read
+// from the two directories we're given, so a copied or hand-made tree answers just like the
+// real ones do.
+pub struct FeatureSet {
+    // /proc/sys/kernel/osrelease, "5.15.0-91-generic"
+    pub kernel: String,
+    pub cgroup: CgroupVersion,
+    pub cgroup_namespace: bool,
+    // Whether an unprivileged process may create a user namespace, or why not
+    pub user_namespaces: Result<(), String>,
+    pub overlayfs: bool,
+    pub seccomp: bool,
+    pub pidfd: bool,
+    pub clone3: bool,
+}
+
+impl FeatureSet {
+    pub fn probe(proc: &Path, sys: &Path) -> FeatureSet {
+        let kernel = read_to_string(proc.join("sys/kernel/osrelease"))
+            .map(|release| release.trim().to_string())
+            .unwrap_or_default();
+
+        let cgroup = if cgroup::available(&sys.join("fs/cgroup")) {
+            CgroupVersion::V2 {
+                controllers: delegated_controllers(proc, sys),
+            }
+        } else if read_to_string(proc.join("self/cgroup"))
+            .unwrap_or_default()
+            .lines()
+            // v1 lines name their controllers, "4:memory:/user.slice", v2's is "0::/..."
+            .any(|line| {
+                line.split(':')
+                    .nth(1)
+                    .map_or(false, |names| !names.is_empty())
+            })
+        {
+            CgroupVersion::V1
+        } else {
+            CgroupVersion::None
+        };
+
+        let sysctl = |name: &str| {
+            read_to_string(proc.join("sys").join(name))
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        // Debian and Ubuntu have their own switch; everyone has the namespace limit
+        let user_namespaces = if !proc.join("self/ns/user").exists() {
+            Err("the kernel was built without user namespaces".to_string())
+        } else if sysctl("kernel/unprivileged_userns_clone") == Some(0) {
+            Err("kernel.unprivileged_userns_clone is 0".to_string())
+        } else if sysctl("user/max_user_namespaces") == Some(0) {
+            Err("user.max_user_namespaces is 0".to_string())
+        } else {
+            Ok(())
+        };
+
+        let overlayfs = read_to_string(proc.join("filesystems"))
+            .unwrap_or_default()
+            .lines()
+            .any(|line| line.split_whitespace().last() == Some("overlay"));
+        let seccomp = read_to_string(proc.join("self/status"))
+            .unwrap_or_default()
+            .lines()
+            .any(|line| line.starts_with("Seccomp:"));
+        let version = kernel_version(&kernel);
+        let pidfd = version.map_or(false, |version| version >= PIDFD_KERNEL);
+        let clone3 = version.map_or(false, |version| version >= CLONE3_KERNEL);
+
+        FeatureSet {
+            cgroup,
+            cgroup_namespace: proc.join("self/ns/cgroup").exists(),
+            user_namespaces,
+            overlayfs,
+            seccomp,
+            pidfd,
+            clone3,
+            kernel,
+        }
+    }
+
+    pub fn cgroup_v2(&self) -> bool {
+        matches!(self.cgroup, CgroupVersion::V2 { .. })
+    }
+
+    // What `system info` prints, as (name, value)
+    pub fn report(&self) -> Vec<(&'static str, String)> {
+        let yes_no = |available: bool| if available { "yes" } else { "no" }.to_string();
+        let cgroup = match &self.cgroup {
+            CgroupVersion::V2 { controllers } if controllers.is_empty() => {
+                "v2 (no controllers delegated to us)".to_string()
+            }
+            CgroupVersion::V2 { controllers } => {
+                format!("v2 (controllers: {})", controllers.join(" "))
+            }
+            CgroupVersion::V1 => "v1 or hybrid (not supported, no resource limits)".to_string(),
+            CgroupVersion::None => "none".to_string(),
+        };
+        vec![
+            ("Kernel", self.kernel.clone()),
+            ("cgroup", cgroup),
+            ("cgroup namespace", yes_no(self.cgroup_namespace)),
+            (
+                "User namespaces",
+                match &self.user_namespaces {
+                    Ok(()) => "yes".to_string(),
+                    Err(reason) => format!("no ({})", reason),
+                },
+            ),
+            ("overlayfs", yes_no(self.overlayfs)),
+            ("seccomp", yes_no(self.seccomp)),
+            ("pidfd", yes_no(self.pidfd)),
+            ("clone3", yes_no(self.clone3)),
+        ]
+    }
+}
+
+// The controllers available in the cgroup we were started in, which are the ones a delegated
+// subtree (systemd's user@.service, a container's namespace) lets us use
+fn delegated_controllers(proc: &Path, sys: &Path) -> Vec<String> {
+    let own = read_to_string(proc.join("self/cgroup"))
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+        .unwrap_or_else(|| "/".to_string());
+    let directory = sys.join("fs/cgroup").join(own.trim_start_matches('/'));
+    read_to_string(directory.join("cgroup.controllers"))
+        .or_else(|_| read_to_string(sys.join("fs/cgroup/cgroup.controllers")))
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+// "5.15.0-91-generic" as (5, 15)
+fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>());
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::TempDir;
+
+    // A /proc and /sys made of the given files, relative to the tempdir
+    fn tree(files: &[(&str, &str)]) -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        create_dir_all(root.path().join("proc")).unwrap();
+        create_dir_all(root.path().join("sys")).unwrap();
+        for (path, contents) in files {
+            let path = root.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, contents).unwrap();
+        }
+        root
+    }
+
+    fn probe(root: &TempDir) -> FeatureSet {
+        FeatureSet::probe(&root.path().join("proc"), &root.path().join("sys"))
+    }
+
+    #[test]
+    fn a_current_kernel_has_everything() {
+        let root = tree(&[
+            ("proc/sys/kernel/osrelease", "6.1.0-18-amd64\n"),
+            ("proc/self/cgroup", "0::/user.slice/user-1000.slice\n"),
+            ("proc/self/ns/user", ""),
+            ("proc/self/ns/cgroup", ""),
+            ("proc/sys/user/max_user_namespaces", "63413\n"),
+            ("proc/filesystems", "nodev\tproc\n\text4\nnodev\toverlay\n"),
+            ("proc/self/status", "Name:\tcat\nSeccomp:\t0\n"),
+            (
+                "sys/fs/cgroup/cgroup.controllers",
+                "cpuset cpu io memory pids\n",
+            ),
+            (
+                "sys/fs/cgroup/user.slice/user-1000.slice/cgroup.controllers",
+                "memory pids\n",
+            ),
+        ]);
+        let features = probe(&root);
+        assert_eq!(features.kernel, "6.1.0-18-amd64");
+        match &features.cgroup {
+            CgroupVersion::V2 { controllers } => assert_eq!(controllers, &["memory", "pids"]),
+            _ => panic!("not cgroup v2"),
+        }
+        assert!(features.cgroup_namespace);
+        assert_eq!(features.user_namespaces, Ok(()));
+        assert!(features.overlayfs && features.seccomp && features.pidfd && features.clone3);
+    }
+
+    #[test]
+    fn an_old_kernel_degrades_feature_by_feature() {
+        let root = tree(&[
+            ("proc/sys/kernel/osrelease", "4.19.0-27-amd64\n"),
+            (
+                "proc/self/cgroup",
+                "12:memory:/user.slice\n1:name=systemd:/user.slice\n0::/user.slice\n",
+            ),
+            ("proc/self/ns/user", ""),
+            ("proc/sys/kernel/unprivileged_userns_clone", "0\n"),
+            ("proc/filesystems", "nodev\tproc\n\text4\n"),
+            ("proc/self/status", "Name:\tcat\n"),
+        ]);
+        let features = probe(&root);
+        assert!(matches!(features.cgroup, CgroupVersion::V1));
+        assert!(!features.cgroup_namespace);
+        assert_eq!(
+            features.user_namespaces,
+            Err("kernel.unprivileged_userns_clone is 0".to_string())
+        );
+        assert!(!features.overlayfs && !features.seccomp && !features.pidfd && !features.clone3);
+    }
+
+    #[test]
+    fn an_empty_tree_has_nothing() {
+        let features = probe(&tree(&[]));
+        assert!(matches!(features.cgroup, CgroupVersion::None));
+        assert!(features.user_namespaces.is_err());
+        assert!(!features.pidfd && !features.clone3);
+        let report = features.report();
+        assert_eq!(report.last().unwrap(), &("clone3", "no".to_string()));
+    }
+
+    #[test]
+    fn the_namespace_limit_counts_too() {
+        let root = tree(&[
+            ("proc/self/ns/user", ""),
+            ("proc/sys/user/max_user_namespaces", "0\n"),
+        ]);
+        assert_eq!(
+            probe(&root).user_namespaces,
+            Err("user.max_user_namespaces is 0".to_string())
+        );
+    }
+
+    #[test]
+    fn kernel_versions_come_from_the_release() {
+        assert_eq!(kernel_version("5.3.0"), Some((5, 3)));
+        assert_eq!(kernel_version("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(kernel_version("6.18.44-fc-v130"), Some((6, 18)));
+        assert_eq!(kernel_version("4"), None);
+        assert_eq!(kernel_version(""), None);
+    }
+}
@@ -0,0 +1,523 @@
+use crate::manifest::Healthcheck;
+use crate::supervise;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fs::{read, rename, write, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Next to state.json. The checker writes it while the supervisor owns state.json, so the two
+// never overwrite each other's changes.
+const HEALTH_FILE: &str = "health.json";
+
+// docker's defaults for whatever neither the image nor the command line sets
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+
+// What a probe leaves in the log, and how many probes the log keeps, as docker does
+const MAX_OUTPUT: usize = 4096;
+const MAX_LOG: usize = 5;
+
+// What the monitor's thread got told, or that its Monitor is gone
+type Request = std::result::Result<Option<Arc<Namespaces>>, RecvError>;
+
+// How often a running probe is checked on: finished, out of time, or the container gone
+const PROBE_POLL: Duration = Duration::from_millis(50);
+
+// --health-* and --no-healthcheck, laid over the image's Healthcheck
+#[derive(Default, Clone)]
+pub struct Overrides {
+    // A command line for the shell, like HEALTHCHECK CMD-SHELL
+    pub cmd: Option<String>,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub start_period: Option<Duration>,
+    pub retries: Option<u32>,
+    pub disabled: bool,
+}
+
+// A healthcheck with every setting decided
+#[derive(Clone)]
+pub struct Check {
+    // The argv the probe execs in the container
+    pub test: Vec<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    // Failures this soon after the start don't count, until the first probe passes
+    pub start_period: Duration,
+    // Failures in a row that make the container unhealthy
+    pub retries: u32,
+}
+
+impl Overrides {
+    // None when there's nothing to run: no healthcheck anywhere, HEALTHCHECK NONE, or
+    // --no-healthcheck. A shell-form test goes through shell, the image's Shell or /bin/sh -c.
+    pub fn apply(&self, image: Option<&Healthcheck>, shell: &[String]) -> Option<Check> {
+        if self.disabled {
+            return None;
+        }
+        let image = image.cloned().unwrap_or_default();
+        let nanoseconds = |value: Option<u64>| value.filter(|&n| n > 0).map(Duration::from_nanos);
+        let shell_form = |line: &str| {
+            let mut argv = shell.to_vec();
+            argv.push(line.to_string());
+            argv
+        };
+        let test = match (&self.cmd, image.test.as_deref()) {
+            (Some(line), _) => shell_form(line),
+            (None, Some([kind, argv @ ..])) if kind == "CMD" && !argv.is_empty() => argv.to_vec(),
+            (None, Some([kind, line])) if kind == "CMD-SHELL" => shell_form(line),
+            _ => return None,
+        };
+        Some(Check {
+            test,
+            interval: self
+                .interval
+                .or_else(|| nanoseconds(image.interval))
+                .unwrap_or(DEFAULT_INTERVAL),
+            timeout: self
+                .timeout
+                .or_else(|| nanoseconds(image.timeout))
+                .unwrap_or(DEFAULT_TIMEOUT),
+            start_period: self
+                .start_period
+                .or_else(|| nanoseconds(image.start_period))
+                .unwrap_or_default(),
+            retries: self
+                .retries
+                .or(image.retries.filter(|&retries| retries > 0))
+                .unwrap_or(DEFAULT_RETRIES),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    // No probe has passed yet, and not enough have failed to say otherwise
+    Starting,
+    Healthy,
+    Unhealthy,
+    // Never recorded, only set on load: the run process doing the checks is gone, so whatever
+    // the last probe said no longer holds
+    Stale,
+}
+
+// One probe, as docker's State.Health.Log has it. Times are seconds since the epoch, and a
+// probe that ran out of time has exit code -1.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Probe {
+    pub start: u64,
+    pub end: u64,
+    pub exit_code: i32,
+    pub output: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Health {
+    pub status: Status,
+    pub failing_streak: u32,
+    // The last few probes, oldest first
+    pub log: Vec<Probe>,
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            status: Status::Starting,
+            failing_streak: 0,
+            log: vec![],
+        }
+    }
+
+    // The whole state machine, kept free of processes and files like restart::decide. A pass
+    // makes the container healthy whenever it comes; a failure counts towards retries, except
+    // in the start period while the container has never been healthy, which is the time it
+    // gets to come up.
+    pub fn record(&mut self, probe: Probe, retries: u32, in_start_period: bool) {
+        if probe.exit_code == 0 {
+            self.status = Status::Healthy;
+            self.failing_streak = 0;
+        } else if !(in_start_period && self.status == Status::Starting) {
+            self.failing_streak += 1;
+            if self.failing_streak >= retries {
+                self.status = Status::Unhealthy;
+            }
+        }
+        self.log.push(probe);
+        if self.log.len() > MAX_LOG {
+            self.log.remove(0);
+        }
+    }
+
+    // For ps: "healthy", or "health: starting" like docker
+    pub fn describe(&self) -> &'static str {
+        match self.status {
+            Status::Starting => "health: starting",
+            Status::Healthy => "healthy",
+            Status::Unhealthy => "unhealthy",
+            Status::Stale => "health: stale",
+        }
+    }
+}
+
+pub fn load(dir: &Path) -> Option<Health> {
+    serde_json::from_slice(&read(dir.join(HEALTH_FILE)).ok()?).ok()
+}
+
+fn save(dir: &Path, health: &Health) -> Result<()> {
+    let path = dir.join(HEALTH_FILE);
+    let staging = dir.join(format!("{}.partial", HEALTH_FILE));
+    write(&staging, serde_json::to_vec_pretty(health)?)?;
+    rename(&staging, &path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+// The namespaces of the container's first process that aren't ours, and its root, opened
+// while that process is there to open them from. A probe enters them all, so it sees the
+// container's mounts, hostname and processes, then chroots to the same root.
+pub struct Namespaces {
+    // In the order setns needs them: the user namespace first, it grants the others
+    entered: Vec<(File, libc::c_int)>,
+    pid: Option<File>,
+    root: File,
+}
+
+impl Namespaces {
+    pub fn open(pid: u32) -> Result<Namespaces> {
+        let proc = PathBuf::from(format!("/proc/{}", pid));
+        let differs = |name: &str| -> Result<Option<File>> {
+            let theirs = proc.join("ns").join(name);
+            let ours = Path::new("/proc/self/ns").join(name);
+            match (theirs.metadata(), ours.metadata()) {
+                (Ok(theirs_meta), Ok(ours_meta)) if theirs_meta.ino() == ours_meta.ino() => {
+                    Ok(None)
+                }
+                // Kernels without the namespace type have no file for it either way
+                (Err(_), Err(_)) => Ok(None),
+                _ => File::open(&theirs)
+                    .map(Some)
+                    .with_context(|| format!("Failed to open {}", theirs.display())),
+            }
+        };
+        let mut entered = vec![];
+        for (name, kind) in [
+            ("user", libc::CLONE_NEWUSER),
+            ("cgroup", libc::CLONE_NEWCGROUP),
+            ("uts", libc::CLONE_NEWUTS),
+            ("ipc", libc::CLONE_NEWIPC),
+            ("net", libc::CLONE_NEWNET),
+            ("mnt", libc::CLONE_NEWNS),
+        ] {
+            if let Some(file) = differs(name)? {
+                entered.push((file, kind));
+            }
+        }
+        let root = proc.join("root");
+        Ok(Namespaces {
+            entered,
+            pid: differs("pid")?,
+            root: File::open(&root)
+                .with_context(|| format!("Failed to open {}", root.display()))?,
+        })
+    }
+
+    // Only affects the children of the calling thread, which makes it the checker thread's to
+    // call: the supervisor's own threads stay where they are
+    fn enter_pid(&self) -> std::io::Result<()> {
+        if let Some(pid) = &self.pid {
+            if unsafe { libc::setns(pid.as_raw_fd(), libc::CLONE_NEWPID) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // Runs in the forked probe, before it switches to the container's user. Only
+    // async-signal-safe calls belong here.
+    pub fn enter(&self, working_dir: &CStr) -> std::io::Result<()> {
+        unsafe {
+            for (file, kind) in &self.entered {
+                if libc::setns(file.as_raw_fd(), *kind) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            let current = b".\0";
+            if libc::fchdir(self.root.as_raw_fd()) != 0
+                || libc::chroot(current.as_ptr() as *const libc::c_char) != 0
+                || libc::chdir(working_dir.as_ptr()) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Probes the container every interval on a thread of its own, from when it's told which
+// process to watch until it's paused. A probe that outlives its timeout is killed, process
+// group and all, before the next one can start, so a hanging check never piles up behind
+// itself.
+pub struct Monitor {
+    // Some to watch a process's namespaces, None to pause
+    requests: Sender<Option<Arc<Namespaces>>>,
+    paused: Receiver<()>,
+}
+
+impl Monitor {
+    // Has to come before the supervisor unshares its PID namespace: a process whose children
+    // go into another PID namespace than its own can't create threads any more. probe makes
+    // a fresh Command for each probe, one that enters the namespaces itself and puts the probe
+    // in a session of its own.
+    pub fn start(
+        check: Check,
+        dir: &Path,
+        probe: impl Fn(&Arc<Namespaces>) -> Command + Send + 'static,
+    ) -> Monitor {
+        let (requests, received) = channel::<Option<Arc<Namespaces>>>();
+        let (pause, paused) = channel();
+        let dir = dir.to_path_buf();
+        std::thread::spawn(move || {
+            let mut next = received.recv();
+            loop {
+                next = match next {
+                    Ok(Some(namespaces)) => watch(&check, &dir, &namespaces, &probe, &received),
+                    Ok(None) => {
+                        let _ = pause.send(());
+                        received.recv()
+                    }
+                    Err(_) => return,
+                };
+            }
+        });
+        Monitor { requests, paused }
+    }
+
+    // Starts over on the container's first process, which is pid. One we can't get into isn't
+    // worth failing the run over, it just goes unchecked.
+    pub fn watch(&self, pid: u32) {
+        match Namespaces::open(pid) {
+            Ok(namespaces) => {
+                let _ = self.requests.send(Some(Arc::new(namespaces)));
+            }
+            Err(error) => eprintln!(
+                "warning: {:#}, the container won't be health checked",
+                error
+            ),
+        }
+    }
+
+    // Returns once a probe in flight is killed. The last health recorded stays for inspect.
+    pub fn pause(&self) {
+        if self.requests.send(None).is_ok() {
+            let _ = self.paused.recv();
+        }
+    }
+}
+
+// One process's checks, until the next request comes in, which it returns
+fn watch(
+    check: &Check,
+    dir: &Path,
+    namespaces: &Arc<Namespaces>,
+    probe: &impl Fn(&Arc<Namespaces>) -> Command,
+    requests: &Receiver<Option<Arc<Namespaces>>>,
+) -> Request {
+    if let Err(error) = namespaces.enter_pid() {
+        eprintln!(
+            "warning: health checks run outside the container's PID namespace: {}",
+            error
+        );
+    }
+    let started = Instant::now();
+    let mut health = Health::new();
+    let _ = save(dir, &health);
+    loop {
+        match requests.recv_timeout(check.interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(request) => return Ok(request),
+            Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+        }
+        let in_start_period = started.elapsed() < check.start_period;
+        let start = unix_now();
+        let (exit_code, output) = match run_probe(probe(namespaces), check.timeout, requests) {
+            Ok(result) => result,
+            Err(request) => return request,
+        };
+        let probe = Probe {
+            start,
+            end: unix_now(),
+            exit_code,
+            output,
+        };
+        health.record(probe, check.retries, in_start_period);
+        let _ = save(dir, &health);
+    }
+}
+
+// The probe's exit code and what it printed, or the request that came in meanwhile, which
+// kills it
+fn run_probe(
+    mut command: Command,
+    timeout: Duration,
+    requests: &Receiver<Option<Arc<Namespaces>>>,
+) -> std::result::Result<(i32, String), Request> {
+    let failed =
+        |error: std::io::Error| Ok((-1, format!("Failed to start the health check: {}", error)));
+    let output = match tempfile::tempfile() {
+        Ok(output) => output,
+        Err(error) => return failed(error),
+    };
+    let (stdout, stderr) = match (output.try_clone(), output.try_clone()) {
+        (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+        (Err(error), _) | (_, Err(error)) => return failed(error),
+    };
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(stdout))
+        .stderr(Stdio::from(stderr));
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(error) => return failed(error),
+    };
+    let deadline = Instant::now() + timeout;
+    let kill = |child: &mut Child| {
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+        let _ = child.wait();
+    };
+    let exit_code = loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            break supervise::exit_code(status);
+        }
+        if Instant::now() >= deadline {
+            kill(&mut child);
+            let message = format!("Health check exceeded timeout ({}s)", timeout.as_secs_f64());
+            return Ok((-1, message));
+        }
+        match requests.recv_timeout(PROBE_POLL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(request) => {
+                kill(&mut child);
+                return Err(Ok(request));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                kill(&mut child);
+                return Err(Err(RecvError));
+            }
+        }
+    };
+    Ok((exit_code, read_output(output)))
+}
+
+fn read_output(mut output: File) -> String {
+    let mut bytes = vec![];
+    let _ = output.seek(SeekFrom::Start(0));
+    let _ = output.take(MAX_OUTPUT as u64).read_to_end(&mut bytes);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(exit_code: i32) -> Probe {
+        Probe {
+            start: 0,
+            end: 0,
+            exit_code,
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn failures_in_the_start_period_leave_it_starting() {
+        let mut health = Health::new();
+        for _ in 0..5 {
+            health.record(probe(1), 3, true);
+        }
+        assert_eq!(health.status, Status::Starting);
+        assert_eq!(health.failing_streak, 0);
+        assert_eq!(health.describe(), "health: starting");
+    }
+
+    #[test]
+    fn a_pass_makes_it_healthy() {
+        let mut health = Health::new();
+        health.record(probe(1), 3, false);
+        assert_eq!(health.status, Status::Starting);
+        assert_eq!(health.failing_streak, 1);
+        health.record(probe(0), 3, true);
+        assert_eq!(health.status, Status::Healthy);
+        assert_eq!(health.failing_streak, 0);
+        assert_eq!(health.describe(), "healthy");
+    }
+
+    #[test]
+    fn retries_failures_in_a_row_make_it_unhealthy() {
+        let mut health = Health::new();
+        health.record(probe(0), 3, false);
+        health.record(probe(1), 3, false);
+        health.record(probe(-1), 3, false);
+        assert_eq!(health.status, Status::Healthy);
+        health.record(probe(1), 3, false);
+        assert_eq!(health.status, Status::Unhealthy);
+        assert_eq!(health.failing_streak, 3);
+        assert_eq!(health.describe(), "unhealthy");
+
+        health.record(probe(0), 3, false);
+        assert_eq!(health.status, Status::Healthy);
+        assert_eq!(health.failing_streak, 0);
+    }
+
+    #[test]
+    fn the_start_period_only_covers_a_container_that_was_never_healthy() {
+        let mut health = Health::new();
+        health.record(probe(0), 1, true);
+        health.record(probe(1), 1, true);
+        assert_eq!(health.status, Status::Unhealthy);
+    }
+
+    #[test]
+    fn the_log_keeps_the_last_few_probes() {
+        let mut health = Health::new();
+        for exit_code in 0..MAX_LOG as i32 + 2 {
+            health.record(probe(exit_code), 100, false);
+        }
+        let kept: Vec<i32> = health.log.iter().map(|probe| probe.exit_code).collect();
+        assert_eq!(kept, (2..MAX_LOG as i32 + 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn saved_health_loads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(dir.path()).is_none());
+        let mut health = Health::new();
+        health.record(probe(0), 3, false);
+        save(dir.path(), &health).unwrap();
+        let loaded = load(dir.path()).unwrap();
+        assert_eq!(loaded.status, Status::Healthy);
+        assert_eq!(loaded.log.len(), 1);
+    }
+}
@@ -0,0 +1,400 @@
+use anyhow::{bail, Result};
+use reqwest::header::{
+    HeaderMap, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION,
+};
+use reqwest::redirect::Policy;
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+
+// How much of an error response's body --debug-http shows
+const BODY_PREVIEW: usize = 1024;
+
+// reqwest's own default
+const MAX_REDIRECTS: usize = 10;
+
+// Query parameters whose values are as good as a password: signed CDN URLs and token requests
+const SECRET_PARAMETERS: [&str; 7] = [
+    "sig",
+    "token",
+    "credential",
+    "secret",
+    "key",
+    "auth",
+    "password",
+];
+
+// Every registry and token request goes through here. Redirects are followed by hand rather
+// than by reqwest so that:
+//   - credentials only go where they were meant to: Authorization (and cookies) are kept on
+//     hops to the host the request started at and dropped as soon as a hop leaves it, which is
+//     what blob downloads that bounce to a CDN need
+//   - --debug-http sees every hop as a request and response of its own: the request line and
+//     headers, the response status and headers, and (through check()) the start of the body
+//     when it's an error. Authorization shows only its scheme, and cookies and secret-looking
+//     query parameters (signed CDN URLs are full of them) not even that.
+pub struct HttpClient {
+    client: reqwest::Client,
+    debug: bool,
+}
+
+impl HttpClient {
+    pub fn new(debug: bool) -> HttpClient {
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .expect("the TLS backend failed to initialize");
+        HttpClient { client, debug }
+    }
+
+    pub fn get(&self, url: &str) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.build()?;
+        let origin = request.url().clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let retry = request.try_clone();
+            let response = self.execute(request).await?;
+            let target = match redirect_target(&response) {
+                Some(target) => target,
+                None => return Ok(response),
+            };
+            request = match retry {
+                Some(previous) => redirected(previous, response.status(), target, &origin),
+                // A streamed body can't be sent twice, so the caller gets the redirect itself
+                None => return Ok(response),
+            };
+        }
+        bail!(
+            "Gave up on {} after {} redirects",
+            redact_url(&origin),
+            MAX_REDIRECTS
+        )
+    }
+
+    async fn execute(&self, request: Request) -> Result<Response> {
+        if !self.debug {
+            return Ok(self.client.execute(request).await?);
+        }
+        for line in request_lines(&request) {
+            eprintln!("{}", line);
+        }
+
+        let response = self.client.execute(request).await.map_err(|error| {
+            eprintln!("! {}", error);
+            error
+        })?;
+        for line in response_lines(&response) {
+            eprintln!("{}", line);
+        }
+        Ok(response)
+    }
+
+    // error_for_status, but with what the server said in the error: registries send JSON
+    // errors and object stores XML ones, and either beats a bare status code
+    pub async fn check(&self, response: Response) -> Result<Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let url = redact_url(response.url());
+        let body = response.bytes().await.unwrap_or_default();
+        if self.debug {
+            eprintln!(
+                "< {}",
+                String::from_utf8_lossy(&body[..body.len().min(BODY_PREVIEW)])
+            );
+        }
+        let text = String::from_utf8_lossy(&body);
+        match xml_error(&text).or_else(|| registry_error(&body)) {
+            Some(detail) => bail!("{} from {}: {}", status, url, detail),
+            None => bail!("{} from {}", status, url),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RegistryErrors {
+    errors: Vec<RegistryError>,
+}
+
+#[derive(Deserialize)]
+struct RegistryError {
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+// The distribution spec's {"errors": [{"code": "MANIFEST_UNKNOWN", "message": "..."}]}
+fn registry_error(body: &[u8]) -> Option<String> {
+    let errors = serde_json::from_slice::<RegistryErrors>(body).ok()?;
+    let described: Vec<String> = errors
+        .errors
+        .iter()
+        .map(
+            |error| match (error.code.is_empty(), error.message.is_empty()) {
+                (false, false) => format!("{}: {}", error.code, error.message),
+                (false, true) => error.code.clone(),
+                _ => error.message.clone(),
+            },
+        )
+        .filter(|text| !text.is_empty())
+        .collect();
+    if described.is_empty() {
+        None
+    } else {
+        Some(described.join("; "))
+    }
+}
+
+fn redirect_target(response: &Response) -> Option<Url> {
+    if !matches!(
+        response.status(),
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    ) {
+        return None;
+    }
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    // Location may be relative to the URL that sent it
+    response.url().join(location).ok()
+}
+
+// The next hop of a redirect, with the method rules browsers and reqwest follow (303, and 301
+// or 302 after a POST, turn into a GET without a body)
+fn redirected(mut request: Request, status: StatusCode, target: Url, origin: &Url) -> Request {
+    let switch_to_get = status == StatusCode::SEE_OTHER
+        || (request.method() == Method::POST
+            && (status == StatusCode::MOVED_PERMANENTLY || status == StatusCode::FOUND));
+    if switch_to_get {
+        *request.method_mut() = Method::GET;
+        *request.body_mut() = None;
+        request.headers_mut().remove(CONTENT_TYPE);
+        request.headers_mut().remove(CONTENT_LENGTH);
+    }
+    let same_host = target.host_str() == origin.host_str()
+        && target.port_or_known_default() == origin.port_or_known_default();
+    if !same_host {
+        for header in [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE] {
+            request.headers_mut().remove(header);
+        }
+    }
+    *request.url_mut() = target;
+    request
+}
+
+// What --debug-http prints for a request about to go out and for the response to it
+fn request_lines(request: &Request) -> Vec<String> {
+    let first = format!("> {} {}", request.method(), redact_url(request.url()));
+    std::iter::once(first)
+        .chain(header_lines('>', request.headers()))
+        .collect()
+}
+
+fn response_lines(response: &Response) -> Vec<String> {
+    let first = format!("< {} {}", response.status(), redact_url(response.url()));
+    std::iter::once(first)
+        .chain(header_lines('<', response.headers()))
+        .collect()
+}
+
+fn header_lines(direction: char, headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers.iter().map(move |(name, value)| {
+        let value = value.to_str().unwrap_or("<binary>");
+        format!(
+            "{} {}: {}",
+            direction,
+            name,
+            redact_header(name.as_str(), value)
+        )
+    })
+}
+
+fn redact_header(name: &str, value: &str) -> String {
+    match name.to_ascii_lowercase().as_str() {
+        "authorization" | "proxy-authorization" => match value.split_once(' ') {
+            Some((scheme, _)) => format!("{} <redacted>", scheme),
+            None => "<redacted>".to_string(),
+        },
+        "cookie" | "set-cookie" => "<redacted>".to_string(),
+        "location" => match Url::parse(value) {
+            Ok(url) => redact_url(&url),
+            Err(_) => value.to_string(),
+        },
+        _ => value.to_string(),
+    }
+}
+
+fn redact_url(url: &Url) -> String {
+    if url.query().is_none() {
+        return url.to_string();
+    }
+    let pairs: Vec<String> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let lower = name.to_ascii_lowercase();
+            if SECRET_PARAMETERS
+                .iter()
+                .any(|secret| lower.contains(secret))
+            {
+                format!("{}=<redacted>", name)
+            } else {
+                format!("{}={}", name, value)
+            }
+        })
+        .collect();
+    let mut shown = url.clone();
+    shown.set_query(None);
+    format!("{}?{}", shown, pairs.join("&"))
+}
+
+// What object stores (S3, GCS and the CDNs in front of them) send instead of the blob when
+// they refuse: <Error><Code>AccessDenied</Code><Message>Request has expired</Message></Error>
+fn xml_error(body: &str) -> Option<String> {
+    if !body.contains("<Error>") {
+        return None;
+    }
+    let element = |name: &str| {
+        let start = body.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = body[start..].find(&format!("</{}>", name))?;
+        Some(body[start..start + end].trim().to_string())
+    };
+    match (element("Code"), element("Message")) {
+        (Some(code), Some(message)) => Some(format!("{}: {}", code, message)),
+        (code, message) => code.or(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_registry::{self, FakeRegistry};
+    use std::sync::{Arc, Mutex};
+
+    const SECRETS: [&str; 4] = ["s3cr3t", "c00kie", "t0ken", "s1gnature"];
+
+    fn assert_no_secrets(lines: &[String]) {
+        let text = lines.join("\n");
+        for secret in SECRETS {
+            assert!(!text.contains(secret), "{} in\n{}", secret, text);
+        }
+    }
+
+    #[test]
+    fn credentials_only_show_their_scheme() {
+        assert_eq!(
+            redact_header("Authorization", "Bearer s3cr3t"),
+            "Bearer <redacted>"
+        );
+        assert_eq!(redact_header("proxy-authorization", "s3cr3t"), "<redacted>");
+        assert_eq!(redact_header("Set-Cookie", "id=c00kie"), "<redacted>");
+        assert_eq!(
+            redact_header(
+                "location",
+                "https://cdn.example/b?X-Amz-Signature=s1gnature&n=1"
+            ),
+            "https://cdn.example/b?X-Amz-Signature=<redacted>&n=1"
+        );
+        assert_eq!(redact_header("content-type", "text/plain"), "text/plain");
+
+        let url =
+            Url::parse("https://auth.example/token?scope=pull&access_token=t0ken&KEY=k").unwrap();
+        assert_eq!(
+            redact_url(&url),
+            "https://auth.example/token?scope=pull&access_token=<redacted>&KEY=<redacted>"
+        );
+        let plain = Url::parse("https://registry.example/v2/").unwrap();
+        assert_eq!(redact_url(&plain), "https://registry.example/v2/");
+    }
+
+    #[test]
+    fn a_cross_host_redirect_drops_and_never_logs_credentials() {
+        let origin =
+            Url::parse("https://registry.example/v2/app/blobs/sha256:1?token=t0ken").unwrap();
+        let request = reqwest::Client::new()
+            .get(origin.clone())
+            .header(AUTHORIZATION, "Bearer s3cr3t")
+            .header(COOKIE, "session=c00kie")
+            .build()
+            .unwrap();
+        let lines = request_lines(&request);
+        assert_no_secrets(&lines);
+        assert!(lines.contains(&"> authorization: Bearer <redacted>".to_string()));
+
+        let target = Url::parse("https://cdn.example/blob?X-Amz-Signature=s1gnature").unwrap();
+        let hop = redirected(request, StatusCode::TEMPORARY_REDIRECT, target, &origin);
+        assert!(hop.headers().get(AUTHORIZATION).is_none());
+        assert!(hop.headers().get(COOKIE).is_none());
+        let lines = request_lines(&hop);
+        assert_no_secrets(&lines);
+        assert_eq!(
+            lines,
+            ["> GET https://cdn.example/blob?X-Amz-Signature=<redacted>"]
+        );
+    }
+
+    #[test]
+    fn a_same_host_redirect_keeps_credentials() {
+        let origin = Url::parse("https://registry.example/v2/a").unwrap();
+        let request = reqwest::Client::new()
+            .post(origin.clone())
+            .header(AUTHORIZATION, "Bearer s3cr3t")
+            .body("data")
+            .build()
+            .unwrap();
+        let target = origin.join("/v2/b").unwrap();
+        let hop = redirected(request, StatusCode::SEE_OTHER, target, &origin);
+        assert_eq!(hop.method(), Method::GET);
+        assert!(hop.body().is_none());
+        assert!(hop.headers().get(AUTHORIZATION).is_some());
+    }
+
+    #[tokio::test]
+    async fn send_leaves_authorization_behind_at_another_host() {
+        let seen = Arc::new(Mutex::new(None));
+        let record = seen.clone();
+        let cdn = FakeRegistry::start(move |request| {
+            *record.lock().unwrap() = Some(request.headers.clone());
+            fake_registry::Response::new(200).body("blob")
+        })
+        .await;
+        // Another port is another host as far as credentials go
+        let location = format!("http://{}/blob?sig=s1gnature", cdn.address);
+        let registry = FakeRegistry::start(move |request| {
+            assert_eq!(request.header("authorization"), Some("Bearer s3cr3t"));
+            fake_registry::Response::new(307).header("Location", &location)
+        })
+        .await;
+
+        let client = HttpClient::new(true);
+        let request = client
+            .get(&format!(
+                "http://{}/v2/app/blobs/sha256:1",
+                registry.address
+            ))
+            .header(AUTHORIZATION, "Bearer s3cr3t");
+        let response = client.send(request).await.unwrap();
+        assert_no_secrets(&response_lines(&response));
+        assert_eq!(response.bytes().await.unwrap(), "blob");
+
+        let headers = seen.lock().unwrap().clone().unwrap();
+        assert!(headers
+            .iter()
+            .all(|(name, _)| !name.eq_ignore_ascii_case("authorization")));
+        assert_eq!(registry.requests().len(), 1);
+        assert_eq!(cdn.requests(), ["GET /blob?sig=s1gnature"]);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::copy::ContainerFs;
+use crate::rootfs::create_dir;
+use crate::volume::{Mount, MountPoint, Propagation};
+use anyhow::{Context, Result};
+use std::ffi::{CStr, CString};
+use std::fs::{metadata, remove_file, set_permissions, symlink_metadata, write, File, Permissions};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// What software reads to tell one machine from another beyond the hostname: DMI serials and
+// UUIDs, the firmware tables they come from, and the Xen domain UUID. Only reachable when /sys
+// is mounted into the container.
+const IDENTITY_PATHS: &[&str] = &[
+    "/sys/class/dmi/id",
+    "/sys/devices/virtual/dmi/id",
+    "/sys/firmware",
+    "/sys/hypervisor/uuid",
+];
+
+// Like docker: the first 12 characters of the id
+pub fn hostname(id: &str) -> &str {
+    &id[..id.len().min(12)]
+}
+
+// /etc/hostname in the rootfs, for whatever reads the file instead of calling uname
+pub fn write_hostname(rootfs: &Path, hostname: &str) -> Result<()> {
+    replace(
+        &rootfs.join("etc/hostname"),
+        &format!("{}\n", hostname),
+        0o644,
+    )
+}
+
+// A random machine-id of the container's own in /etc/machine-id, so two containers never look
+// like the same machine to D-Bus, systemd or licence checks, and neither looks like the host.
+// The D-Bus copy gets the same id when the image has one of its own; usually it's a symlink
+// to /etc/machine-id, which is taken care of already.
+pub fn write_machine_id(rootfs: &Path) -> Result<()> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Failed to generate a machine-id")?;
+    let id: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let id = format!("{}\n", id);
+
+    // Found the way the container would, so a /var -> /var in the image is the rootfs's
+    let fs = ContainerFs::bare(rootfs);
+    replace(&fs.host("/etc/machine-id", false)?, &id, 0o444)?;
+    let dbus = fs.host("/var/lib/dbus/machine-id", false)?;
+    let is_file = symlink_metadata(&dbus)
+        .map(|metadata| metadata.file_type().is_file())
+        .unwrap_or(false);
+    if is_file {
+        replace(&dbus, &id, 0o444)?;
+    }
+    Ok(())
+}
+
+// Never writes through a symlink, which would resolve against the host from out here
+fn replace(path: &Path, content: &str, mode: u32) -> Result<()> {
+    create_dir(path.parent().unwrap())?;
+    if symlink_metadata(path).is_ok() {
+        remove_file(path)?;
+    }
+    write(path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+    set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+// Read-only mounts hiding the identity paths a -v mount brings into the container: /dev/null
+// over files and the empty directory over directories. They go after the volumes, which have
+// to be mounted for the paths to exist.
+pub fn sysfs_masks(mounts: &[Mount], rootfs: &Path, empty: &Path) -> Result<Vec<Mount>> {
+    let mut masks = vec![];
+    for path in IDENTITY_PATHS {
+        // The last mount covering the path is the one the container sees
+        let on_host = mounts.iter().rev().find_map(|mount| {
+            Path::new(path)
+                .strip_prefix(&mount.point.destination)
+                .ok()
+                .map(|rest| mount.point.source.join(rest))
+        });
+        let is_dir = match on_host.map(metadata) {
+            Some(Ok(metadata)) => metadata.is_dir(),
+            _ => continue,
+        };
+        let source = if is_dir {
+            create_dir(empty)?;
+            empty.to_path_buf()
+        } else {
+            PathBuf::from("/dev/null")
+        };
+        let target = rootfs.join(path.trim_start_matches('/'));
+        masks.push(Mount {
+            source: CString::new(source.as_os_str().as_bytes())?,
+            target: CString::new(target.as_os_str().as_bytes())?,
+            read_only: true,
+            propagation: Propagation::Private,
+            submounts: vec![],
+            point: MountPoint {
+                source,
+                destination: PathBuf::from(path),
+                read_only: true,
+            },
+        });
+    }
+    Ok(masks)
+}
+
+// Runs in the forked child: a UTS namespace of its own, so the hostname is the container's and
+// changing it inside doesn't rename the host
+pub fn enter_uts_namespace(hostname: &CStr) -> std::io::Result<()> {
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWUTS) != 0
+            || libc::sethostname(hostname.as_ptr(), hostname.to_bytes().len()) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn the_dbus_copy_is_found_through_the_images_symlinks() {
+        let (rootfs, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let dbus = Path::new("lib/dbus/machine-id");
+        create_dir_all(outside.path().join("lib/dbus")).unwrap();
+        write(outside.path().join(dbus), "host\n").unwrap();
+        // var -> <outside> in the image, which is <rootfs>/<outside> to the container
+        let image_var = rootfs
+            .path()
+            .join(outside.path().strip_prefix("/").unwrap());
+        create_dir_all(image_var.join("lib/dbus")).unwrap();
+        write(image_var.join(dbus), "image\n").unwrap();
+        symlink(outside.path(), rootfs.path().join("var")).unwrap();
+
+        write_machine_id(rootfs.path()).unwrap();
+        assert_eq!(read_to_string(outside.path().join(dbus)).unwrap(), "host\n");
+        let id = read_to_string(rootfs.path().join("etc/machine-id")).unwrap();
+        assert_eq!(id.len(), 33);
+        assert_eq!(read_to_string(image_var.join(dbus)).unwrap(), id);
+    }
+
+    #[test]
+    fn a_dbus_symlink_is_left_alone() {
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir_all(rootfs.path().join("var/lib/dbus")).unwrap();
+        symlink(
+            "/etc/machine-id",
+            rootfs.path().join("var/lib/dbus/machine-id"),
+        )
+        .unwrap();
+
+        write_machine_id(rootfs.path()).unwrap();
+        assert!(
+            symlink_metadata(rootfs.path().join("var/lib/dbus/machine-id"))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert!(rootfs.path().join("etc/machine-id").is_file());
+    }
+}
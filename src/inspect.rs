@@ -0,0 +1,201 @@
+use crate::container::{ContainerState, Status};
+use crate::identity;
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+
+// What docker prints for a time that never happened, such as FinishedAt while still running
+const ZERO_TIME: &str = "0001-01-01T00:00:00Z";
+
+// The container as `inspect --format` sees it: docker's field names and nesting, so lookups
+// written against docker such as {{.State.ExitCode}} or {{.State.OOMKilled}} work unchanged.
+// The JSON inspect prints without --format stays our own state file.
+pub fn view(state: &ContainerState) -> Value {
+    let running = state.is_running();
+    let status = match state.status {
+        _ if running => "running",
+        Status::Created => "created",
+        Status::Restarting => "restarting",
+        // A supervisor that was killed never got to record the exit
+        Status::Running | Status::Exited => "exited",
+    };
+    json!({
+        "Id": state.id,
+        "Name": state.name.as_ref().map(|name| format!("/{}", name)).unwrap_or_default(),
+        "Created": rfc3339(Some(state.created)),
+        "Image": state.image_digest.clone().unwrap_or_default(),
+        "Path": state.command.first().cloned().unwrap_or_default(),
+        "Args": state.command.iter().skip(1).collect::<Vec<_>>(),
+        "State": {
+            "Status": status,
+            "Running": running,
+            "Restarting": state.status == Status::Restarting,
+            "Pid": if running { state.pid.unwrap_or_default() } else { 0 },
+            "ExitCode": state.exit_code.unwrap_or_default(),
+            "OOMKilled": state.oom_killed,
+            "StartedAt": rfc3339(state.started_at),
+            "FinishedAt": rfc3339(state.finished_at),
+            // Ours, docker has no --timeout
+            "Deadline": rfc3339(state.deadline),
+            "TimedOut": state.timed_out,
+            "Health": state.health.as_ref().map(|health| json!({
+                "Status": health.status,
+                "FailingStreak": health.failing_streak,
+                "Log": health.log.iter().map(|probe| json!({
+                    "Start": rfc3339(Some(probe.start)),
+                    "End": rfc3339(Some(probe.end)),
+                    "ExitCode": probe.exit_code,
+                    "Output": probe.output,
+                })).collect::<Vec<_>>(),
+            })),
+        },
+        "Config": {
+            "Hostname": identity::hostname(&state.id),
+            "Image": state.image,
+            "Labels": state.labels,
+            "StopSignal": state.stop_signal,
+            "StopTimeout": state.stop_timeout,
+        },
+        "RestartCount": state.restart_count,
+        "HostConfig": {
+            "RestartPolicy": {
+                "Name": state.restart_policy.name(),
+                "MaximumRetryCount": state.restart_policy.max_retries(),
+            },
+        },
+        "Mounts": state.mounts,
+    })
+}
+
+enum Part {
+    Text(String),
+    // {{.A.B}}, or {{json .A.B}} for the value as JSON
+    Field { path: Vec<String>, json: bool },
+}
+
+// The little of Go's text/template that scripts use with inspect: literal text and field
+// lookups, optionally through `json`
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => bail!("Unclosed action in template '{}'", template),
+            };
+            parts.push(action(&rest[start + 2..end])?);
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn render(&self, value: &Value) -> Result<String> {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Field { path, json } => {
+                    let mut found = value;
+                    for name in path {
+                        found = match found.get(name) {
+                            Some(field) => field,
+                            None => bail!("Template can't evaluate field {}", name),
+                        };
+                    }
+                    if *json {
+                        output.push_str(&serde_json::to_string(found)?);
+                    } else {
+                        output.push_str(&go_format(found));
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+fn action(text: &str) -> Result<Part> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let (json, field) = match words.as_slice() {
+        [field] => (false, *field),
+        ["json", field] => (true, *field),
+        _ => bail!(
+            "Unsupported template action '{{{{{}}}}}', only fields and json are",
+            text.trim()
+        ),
+    };
+    let path = match field {
+        "." => vec![],
+        _ => match field.strip_prefix('.') {
+            Some(path) => path.split('.').map(str::to_string).collect(),
+            None => bail!("Template field '{}' must start with '.'", field),
+        },
+    };
+    if path.iter().any(|name| name.is_empty()) {
+        bail!("Invalid template field '{}'", field);
+    }
+    Ok(Part::Field { path, json })
+}
+
+// How text/template prints a value that isn't run through json
+fn go_format(value: &Value) -> String {
+    match value {
+        Value::Null => "<no value>".to_string(),
+        Value::String(text) => text.clone(),
+        Value::Bool(_) | Value::Number(_) => value.to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(go_format).collect();
+            format!("[{}]", items.join(" "))
+        }
+        Value::Object(entries) => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| format!("{}:{}", key, go_format(value)))
+                .collect();
+            format!("map[{}]", entries.join(" "))
+        }
+    }
+}
+
+// Seconds since the epoch as UTC in RFC 3339, the way docker shows its times
+pub fn rfc3339(seconds: Option<u64>) -> String {
+    let seconds = match seconds {
+        Some(seconds) => seconds,
+        None => return ZERO_TIME.to_string(),
+    };
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Howard Hinnant's civil_from_days, for days since 1970-01-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
@@ -0,0 +1,76 @@
+//! Running containers from other programs, test harnesses in particular: a container put
+//! together like a std::process::Command, with output captured the same way. It goes through
+//! the mydocker executable, found through `MYDOCKER_BIN` unless given with `executable()`.
+//!
+//! ```no_run
+//! use docker_starter_rust::Container;
+//!
+//! let output = Container::builder("alpine:3.19")
+//!     .command(["sh", "-c", "echo $GREETING"])
+//!     .env("GREETING", "hello")
+//!     .memory_limit(64 << 20)
+//!     .run()?;
+//! assert_eq!(output.exit_code, 0);
+//! assert_eq!(output.stdout, b"hello\n");
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! A container that has to be talked to while it runs:
+//!
+//! ```no_run
+//! use docker_starter_rust::Container;
+//! use std::io::{Read, Write};
+//!
+//! let mut container = Container::builder("alpine:3.19").command(["cat"]).spawn()?;
+//! container.stdin().unwrap().write_all(b"ping")?;
+//! let mut echoed = String::new();
+//! container.stdout().unwrap().read_to_string(&mut echoed)?;
+//! assert_eq!(echoed, "ping");
+//! assert_eq!(container.wait()?.exit_code, 0);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! From async code, `run_async()` and `Container::wait_async()` do the waiting on a blocking
+//! thread.
+//!
+//! A container some other mydocker invocation started can be waited for with
+//! `wait_for_exit`, given the data root's containers directory and the container's id.
+//! `Lookups` lists the cached images, containers and volumes, which is what shell completion
+//! offers for them.
+
+mod embed;
+
+// What the mydocker binary is built from
+pub mod auth;
+pub mod cgroup;
+pub mod completion;
+pub mod container;
+pub mod copy;
+pub mod data_root;
+pub mod digest;
+pub mod features;
+pub mod health;
+pub mod http;
+pub mod identity;
+pub mod inspect;
+pub mod lock;
+pub mod logs;
+pub mod manifest;
+pub mod ownership;
+pub mod privileges;
+pub mod pull;
+pub mod registry;
+pub mod restart;
+pub mod rootfs;
+pub mod store;
+pub mod supervise;
+pub mod unpack;
+pub mod volume;
+pub mod wait;
+
+#[cfg(test)]
+mod fake_registry;
+
+pub use completion::{familiar, Lookups};
+pub use embed::{Container, ContainerBuilder, Exited, RunOutput, RunStats};
+pub use wait::wait_for_exit;
@@ -0,0 +1,130 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// An exclusive flock(2) on a lock file, released when dropped (or when the process dies).
+// The holder writes its pid into the file so a waiter that gives up can say who it was
+// waiting for.
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    pub fn acquire(path: &Path, timeout: Duration) -> Result<FileLock> {
+        FileLock::lock(path, timeout, libc::LOCK_EX)
+    }
+
+    // Held by any number of processes at once, keeping out only exclusive holders
+    pub fn acquire_shared(path: &Path, timeout: Duration) -> Result<FileLock> {
+        FileLock::lock(path, timeout, libc::LOCK_SH)
+    }
+
+    fn lock(path: &Path, timeout: Duration, operation: i32) -> Result<FileLock> {
+        let started = Instant::now();
+        let mut cleared_stale = false;
+
+        loop {
+            let file = open_lock_file(path)?;
+            if try_lock(&file, operation)? {
+                let mut lock = FileLock { file };
+                if operation == libc::LOCK_EX {
+                    lock.record_owner()?;
+                } else {
+                    // Shared holders have no single pid to record, but a leftover one from an
+                    // earlier exclusive holder must not look stale to the next exclusive waiter
+                    lock.file.set_len(0)?;
+                }
+                return Ok(lock);
+            }
+
+            if started.elapsed() >= timeout {
+                let holder = read_owner(path);
+                // flock dies with its holder on local filesystems, but on network filesystems
+                // the lock can outlive a crashed process. A dead owner means it's safe to
+                // start over with a fresh lock file.
+                if let Some(pid) = holder {
+                    if !cleared_stale && !pid_alive(pid) {
+                        eprintln!(
+                            "warning: removing stale lock {} left by exited process {}",
+                            path.display(),
+                            pid
+                        );
+                        let _ = remove_file(path);
+                        cleared_stale = true;
+                        continue;
+                    }
+                }
+                bail!(
+                    "Timed out after {}s waiting for lock {} (held by {})",
+                    timeout.as_secs(),
+                    path.display(),
+                    holder
+                        .map(|pid| format!("pid {}", pid))
+                        .unwrap_or_else(|| "an unknown process".to_string())
+                );
+            }
+            sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn record_owner(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        write!(self.file, "{}", std::process::id())?;
+        Ok(())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+fn open_lock_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))
+}
+
+fn try_lock(file: &File, operation: i32) -> Result<bool> {
+    let result = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if result == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(false);
+    }
+    Err(error.into())
+}
+
+fn read_owner(path: &Path) -> Option<i32> {
+    let mut contents = String::new();
+    File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+pub fn pid_alive(pid: i32) -> bool {
+    // Signal 0 only checks whether the process exists; EPERM means it does but isn't ours
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
@@ -0,0 +1,227 @@
+use crate::pull::ResolvedImage;
+use crate::store::write_atomic;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::read;
+use std::path::Path;
+
+// Bumped whenever a change would make an older binary misread the file
+const VERSION: u32 = 1;
+
+// What `pull --write-lockfile` records and `run --lockfile` holds images to: per reference,
+// every digest it resolved to. Keys are references written out in full
+// (registry/repository:tag), so "alpine" and "docker.io/library/alpine:latest" share an entry.
+// Sorted keys and a fixed layout keep the file byte-for-byte the same for the same images, so
+// it diffs cleanly in version control.
+#[derive(Serialize, Deserialize)]
+pub struct Lockfile {
+    pub version: u32,
+    pub images: BTreeMap<String, Pin>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Pin {
+    // What the tag pointed at: the index for multi-platform images, otherwise the manifest
+    pub digest: String,
+    // The platform picked out of the index, absent for single-platform manifests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    pub manifest: String,
+    pub config: String,
+    pub layers: Vec<String>,
+}
+
+impl Pin {
+    pub fn of(image: &ResolvedImage) -> Pin {
+        Pin {
+            digest: image.digest.clone(),
+            platform: image.platform.as_ref().map(|platform| platform.to_string()),
+            manifest: image.manifest_digest.clone(),
+            config: image.manifest.config.digest.clone(),
+            layers: image
+                .manifest
+                .layers
+                .iter()
+                .map(|layer| layer.digest.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Lockfile {
+    // A missing file is an empty lockfile, for --write-lockfile to start from
+    pub fn load_or_default(path: &Path) -> Result<Lockfile> {
+        if !path.exists() {
+            return Ok(Lockfile {
+                version: VERSION,
+                images: BTreeMap::new(),
+            });
+        }
+        Lockfile::load(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Lockfile> {
+        let data =
+            read(path).with_context(|| format!("Failed to read lockfile {}", path.display()))?;
+        let lockfile: Lockfile = serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse lockfile {}", path.display()))?;
+        if lockfile.version != VERSION {
+            bail!(
+                "Lockfile {} has version {}, this build only understands version {}",
+                path.display(),
+                lockfile.version,
+                VERSION
+            );
+        }
+        Ok(lockfile)
+    }
+
+    // Write-then-rename, so an interrupted write never leaves half a lockfile
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+        write_atomic(path, &data)
+            .with_context(|| format!("Failed to write lockfile {}", path.display()))
+    }
+
+    // Record what the image resolved to. An entry that's already there only changes with update;
+    // otherwise a tag that moved is an error, like it is for run.
+    pub fn record(&mut self, image: &ResolvedImage, update: bool) -> Result<()> {
+        let key = image.reference.to_string();
+        let pin = Pin::of(image);
+        if let Some(locked) = self.images.get(&key) {
+            if !update {
+                check(&key, locked, &pin).map_err(|error| {
+                    anyhow!("{:#}; pass --update-lockfile to pin the new digests", error)
+                })?;
+            }
+        }
+        self.images.insert(key, pin);
+        Ok(())
+    }
+
+    // Refuse an image unless the lockfile has it and every digest matches
+    pub fn verify(&self, image: &ResolvedImage) -> Result<()> {
+        let key = image.reference.to_string();
+        match self.images.get(&key) {
+            Some(locked) => check(&key, locked, &Pin::of(image)),
+            None => bail!("{} is not in the lockfile", key),
+        }
+    }
+}
+
+fn check(key: &str, locked: &Pin, found: &Pin) -> Result<()> {
+    let digests = [
+        ("digest", &locked.digest, &found.digest),
+        ("manifest", &locked.manifest, &found.manifest),
+        ("config", &locked.config, &found.config),
+    ];
+    for (what, locked, found) in digests {
+        if locked != found {
+            bail!(
+                "{} no longer matches the lockfile: its {} is {}, the lockfile pins {}",
+                key,
+                what,
+                found,
+                locked
+            );
+        }
+    }
+    if locked.layers != found.layers {
+        bail!(
+            "{} no longer matches the lockfile: its layers differ from the {} pinned",
+            key,
+            locked.layers.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{Descriptor, Manifest, Platform};
+    use crate::registry::Reference;
+
+    fn descriptor(digest: &str) -> Descriptor {
+        Descriptor {
+            media_type: "application/vnd.oci.image.layer.v1.tar+gzip".to_string(),
+            digest: digest.to_string(),
+            size: 1,
+            urls: vec![],
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    fn image(reference: &str, manifest: &str) -> ResolvedImage {
+        ResolvedImage {
+            reference: Reference::parse(reference).unwrap(),
+            digest: "sha256:index".to_string(),
+            manifest_digest: manifest.to_string(),
+            platform: Some(Platform::parse("linux/arm/v7").unwrap()),
+            manifest: Manifest {
+                media_type: None,
+                config: descriptor("sha256:config"),
+                layers: vec![descriptor("sha256:one"), descriptor("sha256:two")],
+                annotations: BTreeMap::new(),
+            },
+            documents: vec![],
+        }
+    }
+
+    #[test]
+    fn a_saved_lockfile_loads_back_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.lock");
+        let mut lockfile = Lockfile::load_or_default(&path).unwrap();
+        lockfile
+            .record(&image("alpine", "sha256:manifest"), false)
+            .unwrap();
+        lockfile.save(&path).unwrap();
+        let first = read(&path).unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir.path()).unwrap().count(),
+            1,
+            "the staging file is gone"
+        );
+
+        let loaded = Lockfile::load(&path).unwrap();
+        let pin = &loaded.images["docker.io/library/alpine:latest"];
+        assert_eq!(pin, &Pin::of(&image("alpine", "sha256:manifest")));
+        assert_eq!(pin.platform.as_deref(), Some("linux/arm/v7"));
+        loaded
+            .verify(&image("docker.io/library/alpine:latest", "sha256:manifest"))
+            .unwrap();
+        // Nothing about the layout changes between saves
+        loaded.save(&path).unwrap();
+        assert_eq!(read(&path).unwrap(), first);
+    }
+
+    #[test]
+    fn a_moved_manifest_is_caught() {
+        let mut lockfile = Lockfile::load_or_default(Path::new("/nonexistent/lock")).unwrap();
+        lockfile
+            .record(&image("alpine", "sha256:manifest"), false)
+            .unwrap();
+        let moved = image("alpine", "sha256:moved");
+
+        let error = format!("{:#}", lockfile.verify(&moved).unwrap_err());
+        assert!(error.contains("its manifest is sha256:moved"), "{}", error);
+        assert!(lockfile.record(&moved, false).is_err());
+        assert!(lockfile
+            .verify(&image("busybox", "sha256:manifest"))
+            .is_err());
+
+        lockfile.record(&moved, true).unwrap();
+        lockfile.verify(&moved).unwrap();
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.lock");
+        std::fs::write(&path, r#"{"version": 2, "images": {}}"#).unwrap();
+        assert!(Lockfile::load(&path).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::container::ContainerState;
+use crate::inspect::rfc3339;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::JoinHandle;
+
+// Next to the rootfs, so it goes with the container directory
+const LOG_FILE: &str = "container.log";
+
+// systemd's native protocol: one datagram per entry
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// How long output may keep coming once the container has exited, from whatever it left
+// running with our pipes open
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where a container's output is kept besides the terminal, chosen with --log-driver and
+// recorded in its state so `logs` knows where to look
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogKind {
+    // JSON lines in the container directory
+    File,
+    Journald,
+    // Not kept at all: the container writes straight to our stdout and stderr. Also what
+    // containers from before there were logs have.
+    #[default]
+    None,
+}
+
+impl LogKind {
+    pub fn parse(value: &str) -> Result<LogKind> {
+        match value {
+            "file" | "json-file" => Ok(LogKind::File),
+            "journald" => Ok(LogKind::Journald),
+            "none" => Ok(LogKind::None),
+            _ => bail!(
+                "Unknown --log-driver '{}', expected file, journald or none",
+                value
+            ),
+        }
+    }
+
+    // None for the none driver, which needs no relaying
+    pub fn open(self, state: &ContainerState) -> Result<Option<Box<dyn LogDriver + Send>>> {
+        Ok(match self {
+            LogKind::File => Some(Box::new(FileLog::create(&file_path(state))?)),
+            LogKind::Journald => Some(Box::new(Journald::connect(state)?)),
+            LogKind::None => None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+pub type SharedDriver = Arc<Mutex<Box<dyn LogDriver + Send>>>;
+
+// Takes the container's output a line at a time, the newline left off. The last line of a
+// stream may be missing one.
+pub trait LogDriver {
+    fn write(&mut self, stream: Stream, line: &[u8]) -> Result<()>;
+}
+
+// One JSON object per line: {"stream": "stdout", "time": "...", "log": "..."}
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    stream: Stream,
+    time: String,
+    log: String,
+}
+
+pub struct FileLog {
+    file: File,
+}
+
+impl FileLog {
+    pub fn create(path: &Path) -> Result<FileLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to create log file {}", path.display()))?;
+        Ok(FileLog { file })
+    }
+}
+
+impl LogDriver for FileLog {
+    fn write(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let entry = Entry {
+            stream,
+            time: rfc3339(Some(now())),
+            log: String::from_utf8_lossy(line).into_owned(),
+        };
+        let mut data = serde_json::to_vec(&entry)?;
+        data.push(b'\n');
+        Ok(self.file.write_all(&data)?)
+    }
+}
+
+// Entries go to the journal with the fields docker's journald driver uses, so
+// `journalctl CONTAINER_ID=<short id>` finds them
+pub struct Journald {
+    socket: UnixDatagram,
+    // Every field but MESSAGE and PRIORITY, already encoded
+    fields: Vec<u8>,
+}
+
+impl Journald {
+    pub fn connect(state: &ContainerState) -> Result<Journald> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET).with_context(|| {
+            format!(
+                "Failed to reach journald at {}, is systemd-journald running?",
+                JOURNAL_SOCKET
+            )
+        })?;
+        let mut fields = vec![];
+        journal_field(&mut fields, "CONTAINER_ID", state.short_id().as_bytes());
+        journal_field(&mut fields, "CONTAINER_ID_FULL", state.id.as_bytes());
+        if let Some(name) = &state.name {
+            journal_field(&mut fields, "CONTAINER_NAME", name.as_bytes());
+        }
+        journal_field(&mut fields, "IMAGE_NAME", state.image.as_bytes());
+        journal_field(
+            &mut fields,
+            "SYSLOG_IDENTIFIER",
+            state.short_id().as_bytes(),
+        );
+        Ok(Journald { socket, fields })
+    }
+}
+
+impl LogDriver for Journald {
+    fn write(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let mut entry = self.fields.clone();
+        // info for stdout and err for stderr, as docker does
+        let priority = match stream {
+            Stream::Stdout => b"6",
+            Stream::Stderr => b"3",
+        };
+        journal_field(&mut entry, "PRIORITY", priority);
+        journal_field(&mut entry, "MESSAGE", line);
+        self.socket
+            .send(&entry)
+            .context("Failed to send a log entry to journald")?;
+        Ok(())
+    }
+}
+
+// "KEY\n", the value's length as 64-bit little endian, the value and "\n": the form that
+// allows any bytes in the value
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &[u8]) {
+    entry.extend_from_slice(key.as_bytes());
+    entry.push(b'\n');
+    entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+pub fn file_path(state: &ContainerState) -> PathBuf {
+    state.rootfs.with_file_name(LOG_FILE)
+}
+
+// Copy one of the container's streams to ours as it arrives, a chunk at a time so it's never
+// held back, and hand it to the driver line by line. Neither a terminal that went away nor a
+// driver that fails stops the reading, or the container would block on a full pipe.
+pub fn relay<R>(stream: R, kind: Stream, driver: SharedDriver) -> JoinHandle<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut stream = stream;
+        let mut chunk = [0; 4096];
+        let mut line = vec![];
+        let (mut terminal, mut logging) = (true, true);
+        loop {
+            let length = match stream.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(length) => length,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            if terminal {
+                let written = match kind {
+                    Stream::Stdout => {
+                        let stdout = std::io::stdout();
+                        let mut stdout = stdout.lock();
+                        stdout
+                            .write_all(&chunk[..length])
+                            .and_then(|_| stdout.flush())
+                    }
+                    Stream::Stderr => std::io::stderr().write_all(&chunk[..length]),
+                };
+                terminal = written.is_ok();
+            }
+            for &byte in &chunk[..length] {
+                if !logging {
+                    break;
+                } else if byte == b'\n' {
+                    logging = log(&driver, kind, &line);
+                    line.clear();
+                } else {
+                    line.push(byte);
+                }
+            }
+        }
+        if logging && !line.is_empty() {
+            log(&driver, kind, &line);
+        }
+    })
+}
+
+// Whether to keep logging
+fn log(driver: &SharedDriver, kind: Stream, line: &[u8]) -> bool {
+    match driver.lock().unwrap().write(kind, line) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!(
+                "warning: {:#}, no longer logging the container's output",
+                error
+            );
+            false
+        }
+    }
+}
+
+// `logs`: print what the file driver kept, each line back on the stream it came from
+pub fn print(state: &ContainerState) -> Result<()> {
+    match state.log_driver {
+        LogKind::File => {}
+        LogKind::Journald => bail!(
+            "Container {} logs to journald, read them with journalctl CONTAINER_ID={}",
+            state.short_id(),
+            state.short_id()
+        ),
+        LogKind::None => bail!(
+            "Container {} doesn't keep logs (--log-driver none)",
+            state.short_id()
+        ),
+    }
+    let path = file_path(state);
+    let file =
+        File::open(&path).with_context(|| format!("Failed to open log file {}", path.display()))?;
+    let stdout = std::io::stdout();
+    let stderr = std::io::stderr();
+    let (mut stdout, mut stderr) = (stdout.lock(), stderr.lock());
+    for line in BufReader::new(file).lines() {
+        // A line cut short by a crash mid-write is skipped, not fatal
+        let entry: Entry = match serde_json::from_str(&line?) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        match entry.stream {
+            Stream::Stdout => writeln!(stdout, "{}", entry.log)?,
+            Stream::Stderr => writeln!(stderr, "{}", entry.log)?,
+        }
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
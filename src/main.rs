@@ -1,27 +1,137 @@
-use anyhow::{Context, Result};
-use bytes::Buf;
-use flate2::read::GzDecoder;
-use serde::Deserialize;
-use std::env::{args, set_current_dir};
-use std::fs::{copy, create_dir, create_dir_all, set_permissions, File, Permissions};
-use std::io::{Cursor, Write};
+use anyhow::{anyhow, bail, Context, Result};
+use std::env::args;
+use std::ffi::{CStr, CString};
+use std::io::{stdout, Write};
 #[cfg(target_os = "linux")]
-use std::os::unix::fs::{chroot, PermissionsExt};
-use std::process::{exit, Command, Stdio};
-use tar::Archive;
-use tempfile::TempDir;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{exit, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
 
-// Usage: your_docker.sh run <image> <command> <arg1> <arg2> ...
+mod bundle;
+mod cli;
+mod compose;
+mod diff;
+mod exclude;
+mod export;
+mod lockfile;
+mod oci_layout;
+mod passwd;
+mod ports;
+mod prune;
+mod push;
+mod serve;
+mod stats;
+mod timezone;
+mod tree_digest;
+mod yaml;
+
+use docker_starter_rust::{
+    cgroup, completion, container, copy, data_root, digest, health, identity, inspect, lock, logs,
+    manifest, ownership, privileges, pull, registry, restart, rootfs, store, supervise, unpack,
+    volume, wait,
+};
+
+use cgroup::{ResourceUsage, CGROUP_ROOT};
+use cli::{
+    BundleOptions, CacheVerifyOptions, CpOptions, DiffOptions, DownOptions, ExportOptions,
+    InspectOptions, LogsOptions, ManifestOptions, PruneOptions, PsOptions, PullOptions,
+    PushOptions, RmOptions, RootfsDigestOptions, RunOptions, ServeOptions, StatsOptions,
+    StopOptions, Subcommand, UpOptions, WaitOptions,
+};
+use container::{Container, ContainerState};
+use data_root::DataRoot;
+use lockfile::Lockfile;
+use logs::Stream;
+use manifest::{document_media_type, is_index, ImageConfig, Index, Platform};
+use privileges::{Ownership, SubordinateIds};
+use pull::PullPolicy;
+use registry::{RawManifest, Reference, RegistryClient};
+use restart::RestartPolicy;
+use store::Store;
+use volume::VolumeSpec;
+
+// What docker gives containers whose image doesn't set PATH itself
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// Usage: your_docker.sh [--data-root <dir>] <subcommand> ...
+//        your_docker.sh run [--tz host|<name>] [--health-cmd <command line>] <image>
+//                           [<command> <arg1> <arg2> ...]
+//        your_docker.sh bundle [--reproducible] --output <dir> <image>
+//        your_docker.sh pull [--dry-run] [--write-lockfile <file>] [--oci-dir <dir>] <image>...
+//        your_docker.sh push <image> [<target>]
+//        your_docker.sh serve
+//        your_docker.sh manifest inspect [--platform <os/arch>] [--raw] <image>
+//        your_docker.sh rootfs-digest <image>
+//        your_docker.sh ps [-a]
+//        your_docker.sh inspect [--format <template>] <id>...
+//        your_docker.sh logs <id>
+//        your_docker.sh stats [--format json] <id>
+//        your_docker.sh stop [-t <secs>] <id>...
+//        your_docker.sh wait [--timeout <secs>] <id>...
+//        your_docker.sh rm <id>...
+//        your_docker.sh diff [--verify] <id>
+//        your_docker.sh export [-o <file>] [--exclude <pattern>]... <id>
+//        your_docker.sh cp <id>:<path> <host path> | <host path> <id>:<path>
+//        your_docker.sh up [-f <file>] [-p <project>]
+//        your_docker.sh down [-f <file>] [-p <project>]
+//        your_docker.sh volume <ls | rm <name>...>
+//        your_docker.sh store repair
+//        your_docker.sh cache verify [--fix]
+//        your_docker.sh system info
+//        your_docker.sh system prune [--dry-run] [--max-cache-size <size>]
+//        your_docker.sh completions <bash|zsh|fish>
 #[cfg(target_os = "linux")]
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<_> = args().collect();
-    let command = &args[3];
-    let command_args = &args[4..];
-    let image = &args[2];
+    let args: Vec<_> = args().skip(1).collect();
 
-    let exit_code = run_child(command, command_args, image).await?;
-    exit(exit_code);
+    let (data_root, subcommand) = cli::parse(&args)?;
+    match subcommand {
+        Subcommand::Run(options) => {
+            let exit_code = run_child(&options, &data_root).await?;
+            // exit() skips the flush that returning from main would do. A reader that went
+            // away (`run ... | head -1`) makes it fail, and mustn't change the exit code.
+            let _ = stdout().flush();
+            exit(exit_code);
+        }
+        Subcommand::Pull(options) => pull_command(&options, &data_root).await,
+        Subcommand::Push(options) => push_command(&options, &data_root).await,
+        Subcommand::Serve(ServeOptions {
+            offline,
+            debug_http,
+        }) => serve::serve(&data_root, offline, debug_http).await,
+        Subcommand::Bundle(options) => bundle_command(&options, &data_root).await,
+        Subcommand::ManifestInspect(options) => manifest_command(&options, &data_root).await,
+        Subcommand::Ps(options) => ps_command(&options),
+        Subcommand::Inspect(options) => inspect_command(&options),
+        Subcommand::Logs(options) => logs_command(&options),
+        Subcommand::Stats(options) => stats_command(&options),
+        Subcommand::Stop(options) => stop_command(&options),
+        Subcommand::Wait(options) => {
+            let exit_code = wait_command(&options).await?;
+            exit(exit_code);
+        }
+        Subcommand::Rm(options) => rm_command(&options, &data_root),
+        Subcommand::Cp(options) => cp_command(&options),
+        Subcommand::Diff(options) => diff_command(&options),
+        Subcommand::Export(options) => export_command(&options),
+        Subcommand::Up(options) => up_command(&options, &data_root).await,
+        Subcommand::Down(options) => down_command(&options, &data_root).await,
+        Subcommand::Images => images_command(&data_root),
+        Subcommand::RootfsDigest(options) => rootfs_digest_command(&options, &data_root).await,
+        Subcommand::VolumeLs => volume_ls_command(&data_root),
+        Subcommand::VolumeRm(names) => volume_rm_command(&names, &data_root),
+        Subcommand::StoreRepair => store_repair_command(&data_root),
+        Subcommand::CacheVerify(options) => cache_verify_command(&options, &data_root),
+        Subcommand::SystemInfo => system_info_command(&data_root),
+        Subcommand::SystemPrune(options) => system_prune_command(&options, &data_root),
+        Subcommand::Completions(shell) => completions_command(shell),
+        Subcommand::Complete(words) => complete_command(&words),
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -30,126 +140,2288 @@ fn main() -> Result<()> {
     exit(1);
 }
 
+// The supervising process never chroots itself: it owns the container directory, the child
+// enters its rootfs just before exec, and once the child is gone the directory is removed from
+// the host's view of the filesystem. Dropping `container` on every return path (including
+// errors) does the cleanup, or leaves everything in place with --keep-rootfs.
 #[cfg(target_os = "linux")]
-async fn run_child(command: &String, command_args: &[String], image: &String) -> Result<i32> {
-    // Need the destructor to run so the directory is removed after use. See https://docs.rs/tempfile/3.3.0/tempfile/struct.TempDir.html#resource-leaking
-    let temp_dir = tempfile::tempdir()?;
+async fn run_child(options: &RunOptions, data_root: &DataRoot) -> Result<i32> {
+    supervise::ignore_sigpipe();
+    // Work out up front what isolation we can offer, rather than failing halfway with EPERM
+    let snapshot = privileges::Snapshot::probe(&[data_root.path(), &options.root]);
+    let plan = privileges::plan(
+        &snapshot,
+        options.memory.is_some() || options.cpus.is_some(),
+    );
+    plan.report(options.strict, options.verbose)?;
+    let rootless = plan.rootless;
 
-    copy_command(command, &temp_dir)?;
-    create_dev_null(&temp_dir)?;
+    // Pin the umask so the rootfs doesn't depend on the caller's; the child inherits it too
+    unsafe {
+        libc::umask(options.umask);
+    }
 
-    pull_image(image, &temp_dir.path().to_str().unwrap().to_string()).await?;
+    let volumes = options
+        .volumes
+        .iter()
+        .filter_map(|spec| spec.volume_name().map(str::to_string))
+        .collect();
+    // Checked before anything is created, like docker, so a stale file from an earlier run
+    // doesn't leave a container behind
+    if let Some(cidfile) = &options.cidfile {
+        if cidfile.exists() {
+            bail!(
+                "Container ID file {} already exists, remove it or pick another",
+                cidfile.display()
+            );
+        }
+    }
+    // Read before anything is created, so a missing or broken lockfile leaves nothing behind
+    let lockfile = match &options.lockfile {
+        Some(path) => Some(Lockfile::load(path)?),
+        None => None,
+    };
+    let mut container = Container::create(
+        &options.root,
+        &options.image,
+        options.name.as_deref(),
+        volumes,
+        &data_root.volumes(),
+        options.keep_rootfs,
+    )?;
+    if let Some(cidfile) = &options.cidfile {
+        write_cidfile(cidfile, container.id())?;
+    }
+    let rootfs = container.rootfs().to_path_buf();
+
+    let config = match &options.bundle {
+        // A bundle is complete as it is, host binaries aren't copied in
+        Some(bundle) => bundle::load(bundle, &rootfs)?,
+        None => {
+            if let Some(command) = options.command.first() {
+                rootfs::copy_command(command, &rootfs)?;
+            }
+            let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+            let settings = PullSettings {
+                platform: options.platform.as_ref(),
+                max_layer_size: options.max_layer_size,
+                policy: if options.offline {
+                    PullPolicy::Never
+                } else {
+                    options.pull
+                },
+                debug_http: options.debug_http,
+                pipeline: options.pipeline,
+                strict_unpack: options.strict_unpack,
+                allow_devices: options.allow_devices,
+                // run pins the umask itself, and the container changes the tree anyway
+                reproducible: false,
+                lockfile: lockfile.as_ref(),
+            };
+            let (digest, image_config) =
+                pull_image(&options.image, &settings, &store, &rootfs).await?;
+            pull::check_platform(&image_config, options.platform.as_ref())?;
+            container.set_image_digest(&digest)?;
+            image_config.config.unwrap_or_default()
+        }
+    };
+
+    // Give the files to the owners the layers name, as far as our privileges allow
+    let owners = ownership::load(&rootfs)?;
+    match plan.ownership {
+        Ownership::Chown => ownership::chown_all(&rootfs, &owners)?,
+        Ownership::Subordinate { uids, gids } => {
+            let skipped = ownership::chown_mapped(&rootfs, &owners, SubordinateIds { uids, gids })?;
+            if skipped > 0 {
+                eprintln!(
+                    "warning: {} paths belong to ids beyond our subordinate ranges, they stay root's",
+                    skipped
+                );
+            }
+        }
+        Ownership::Recorded => {}
+    }
+    container.set_ownership(plan.ownership)?;
+
+    let mut labels = config.labels.clone().unwrap_or_default();
+    labels.extend(options.labels.iter().cloned());
+    container.set_labels(labels)?;
+
+    // --stop-signal beats the image's StopSignal, which beats SIGTERM
+    let stop_signal = match (options.stop_signal, &config.stop_signal) {
+        (Some(signal), _) => signal,
+        (None, Some(name)) => supervise::parse_signal(name)
+            .with_context(|| format!("Invalid StopSignal in the config of {}", options.image))?,
+        (None, None) => libc::SIGTERM,
+    };
+    container.set_stop_config(stop_signal, options.stop_timeout)?;
+    // Shell-form tests go through the image's Shell, like --sh does
+    let probe_shell = config
+        .shell
+        .clone()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| vec!["/bin/sh".to_string(), "-c".to_string()]);
+    let health_check = options
+        .health
+        .apply(config.healthcheck.as_ref(), &probe_shell);
+
+    let exposed = config.exposed_ports.unwrap_or_default();
+    container.set_declared(
+        exposed.keys().cloned().collect(),
+        config.on_build.unwrap_or_default(),
+    )?;
+    ports::check_exposed(&options.publish, &exposed);
+    ports::hint_unpublished(&options.publish, &exposed);
+    let proxy = ports::PortProxy::start(&options.publish).await?;
+
+    // Paths in the image's Volumes get a fresh volume each, so state the image expects to keep
+    // out of the rootfs stays out of it. A -v for the same path takes its place.
+    let mut volume_specs = options.volumes.clone();
+    let mut anonymous = vec![];
+    for target in config.volumes.unwrap_or_default().keys() {
+        if !target.starts_with('/') {
+            eprintln!(
+                "warning: ignoring relative path {} in the image's Volumes",
+                target
+            );
+            continue;
+        }
+        let covered = options
+            .volumes
+            .iter()
+            .any(|spec| spec.target.trim_end_matches('/') == target.trim_end_matches('/'));
+        if !covered {
+            let name = container::generate_id()?;
+            volume_specs.push(VolumeSpec::anonymous(name.clone(), target));
+            anonymous.push(name);
+        }
+    }
+    container.add_anonymous_volumes(&anonymous)?;
 
-    chroot(temp_dir.path())?;
-    // Move working directory to the new root at the chroot dir
-    set_current_dir("/")?;
+    rootfs::create_dev(&rootfs)?;
+    rootfs::write_hosts(&rootfs, &options.add_hosts)?;
+    let hostname = identity::hostname(container.id()).to_string();
+    identity::write_hostname(&rootfs, &hostname)?;
+    if options.identity_isolation {
+        identity::write_machine_id(&rootfs)?;
+    }
+    let mut mounts = volume::prepare(&volume_specs, &data_root.volumes(), &rootfs)?;
+    let timezone = match &options.tz {
+        Some(tz) => timezone::plan(tz, &rootfs)?,
+        None => timezone::Plan {
+            env: None,
+            mount: None,
+        },
+    };
+    if let Some(mount) = timezone.mount.clone() {
+        mounts.push(mount);
+    }
+    container.set_mounts(mounts.iter().map(|mount| mount.point.clone()).collect())?;
+    // Not part of the state, there's nothing behind them for cp to find
+    if options.identity_isolation {
+        let masks = identity::sysfs_masks(&mounts, &rootfs, &container.dir().join("masked"))?;
+        mounts.extend(masks);
+    }
+    volume::arrange(&mut mounts)?;
+    let hostname = CString::new(hostname)?;
+
+    let cgroup = if plan.cgroup {
+        Some(cgroup::Cgroup::create(
+            Path::new(CGROUP_ROOT),
+            container.id(),
+            options.memory,
+            options.cpus,
+        )?)
+    } else {
+        None
+    };
+    if let Some(cgroup) = &cgroup {
+        container.set_cgroup(cgroup.path())?;
+    }
+    let procs_file = cgroup.as_ref().map(|cgroup| cgroup.procs_file());
+    // The container's own cgroup, as its /sys/fs/cgroup
+    let cgroup_mount = match &cgroup {
+        Some(_) if plan.cgroup_namespace => {
+            let target = rootfs.join(CGROUP_ROOT.trim_start_matches('/'));
+            rootfs::create_dir(&target)?;
+            Some(CString::new(target.as_os_str().as_bytes())?)
+        }
+        _ => None,
+    };
+
+    // --user beats the image's User, which beats root
+    let user_spec = options
+        .user
+        .clone()
+        .or_else(|| config.user.clone())
+        .unwrap_or_default();
+    let user = passwd::resolve(&rootfs, &user_spec)
+        .with_context(|| format!("Can't run as user '{}'", user_spec))?;
+    if rootless {
+        // Our own user namespace maps container root to us and nothing else, or ids 1 and up to
+        // our subordinate ranges
+        let (uids, gids) = plan
+            .ownership
+            .subordinate()
+            .map_or((0, 0), |ids| (ids.uids.count, ids.gids.count));
+        let gid = user
+            .groups
+            .iter()
+            .copied()
+            .chain([user.gid])
+            .max()
+            .unwrap_or(0);
+        if user.uid > uids || gid > gids {
+            bail!(
+                "Can't run as uid {} (gid {}) rootless, the user namespace only maps uids up to {} \
+                 and gids up to {}; add ranges to /etc/subuid and /etc/subgid for more",
+                user.uid,
+                user.gid,
+                uids,
+                gids
+            );
+        }
+    }
+    if options.synthesize_user {
+        for path in passwd::synthesize(&rootfs, user.uid, user.gid)? {
+            if options.verbose {
+                eprintln!("Synthesized {} for uid {}", path.display(), user.uid);
+            }
+        }
+    }
+
+    // Same rules as docker: the entrypoint always runs, and the command line (or the image's Cmd
+    // when none was given) becomes its arguments
+    let mut argv = config.entrypoint.unwrap_or_default();
+    if let Some(line) = &options.shell_command {
+        let fs = copy::ContainerFs::new(container.state())?;
+        argv.extend(shell_form(config.shell.as_deref(), &fs, &options.image)?);
+        argv.push(line.clone());
+    } else if options.command.is_empty() {
+        argv.extend(config.cmd.unwrap_or_default());
+    } else {
+        argv.extend(options.command.iter().cloned());
+    }
+    let (command, command_args) = match argv.split_first() {
+        Some(split) => split,
+        None => bail!(
+            "No command given and {} has no Entrypoint or Cmd, name one after the image",
+            options.image
+        ),
+    };
+
+    // Everything the child needs after fork is prepared up front, allocating in pre_exec isn't safe
+    let root = CString::new(rootfs.as_os_str().as_bytes())?;
+    let working_dir = config
+        .working_dir
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or_else(|| "/".to_string());
+    rootfs::create_working_dir(&rootfs, &working_dir)?;
+    let working_dir = CString::new(working_dir)?;
+
+    let subordinate = plan.ownership.subordinate();
+    // A user namespace we map ourselves denies setgroups, and it has no other groups anyway
+    let groups = if rootless && subordinate.is_none() {
+        None
+    } else {
+        Some(user.groups.clone())
+    };
+    let (uid, gid) = (user.uid, user.gid);
+    let home = user
+        .home
+        .filter(|home| !home.is_empty())
+        .unwrap_or_else(|| "/".to_string());
+    let env: Vec<String> = config
+        .env
+        .unwrap_or_default()
+        .into_iter()
+        .chain(timezone.env)
+        .chain(options.env.iter().cloned())
+        .collect();
+
+    // Opened before the container starts, so a journald that isn't there fails the run rather
+    // than losing its output. Every restart writes to the same one.
+    let driver = options.log_driver.open(container.state())?;
+    container.set_log_driver(options.log_driver)?;
+    let driver: Option<logs::SharedDriver> = driver.map(|driver| Arc::new(Mutex::new(driver)));
+    container.set_restart_policy(options.restart)?;
+
+    // The baseline for `diff`, which is a debugging aid and not worth failing the run over
+    if let Err(error) = diff::snapshot(container.state()) {
+        eprintln!("warning: {:#}, diff won't work for this container", error);
+    }
+
+    // Started before the unshare below, see health::Monitor::start
+    let monitor = health_check.map(|check| {
+        let probe = HealthProbe {
+            test: check.test.clone(),
+            env: env.clone(),
+            home: home.clone(),
+            working_dir: working_dir.clone(),
+            procs_file: procs_file.clone(),
+            uid,
+            gid,
+            groups: groups.clone(),
+        };
+        health::Monitor::start(check, container.dir(), move |namespaces| {
+            probe.command(namespaces)
+        })
+    });
+
+    // Taken before the unshare below, to get back to for the next restart's fresh namespace
+    let pid_namespaces = if plan.pid_namespace && options.restart != RestartPolicy::No {
+        Some(restart::PidNamespaces::open()?)
+    } else {
+        None
+    };
+    // The child stays behind as the namespace's init and the command runs under it, see
+    // supervise::become_init
+    let init = plan.pid_namespace && !options.exit_on_main_process;
+    // Without one, the cgroup keeps track of what the command leaves behind, see
+    // supervise::linger
+    let leftovers = cgroup
+        .as_ref()
+        .filter(|_| !plan.pid_namespace && !options.exit_on_main_process)
+        .map(|cgroup| cgroup.path().to_path_buf());
+    // Only affects processes we create from here on, so the child becomes PID 1 of a new namespace
+    if plan.pid_namespace && unsafe { libc::unshare(libc::CLONE_NEWPID) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create a PID namespace");
+    }
 
+    let published: Vec<String> = proxy
+        .published
+        .iter()
+        .map(|port| port.to_string())
+        .collect();
+    let peak_tracker = cgroup.as_ref().and_then(|cgroup| cgroup.track_peak());
+    let started = Instant::now();
+    if let Some(limit) = options.timeout {
+        container.set_time_limit(limit)?;
+    }
+    let mut deadline = options
+        .timeout
+        .map(|limit| tokio::time::Instant::now() + limit);
+
+    // Each pass starts the command once; a restart reuses the rootfs, cgroup, published ports
+    // and log driver, and only the process (with its namespaces) is new
+    let (exit_code, ending) = loop {
+        let (user_mapping, mapper) = match subordinate {
+            Some(ids) => {
+                let (mapping, mapper) = privileges::UserMapping::subordinate(ids)?;
+                (mapping, Some(mapper))
+            }
+            None => (privileges::UserMapping::new(&snapshot), None),
+        };
+
+        let mut child = Command::new(command);
+        child
+            .args(command_args)
+            .stdin(Stdio::null())
+            .env_clear()
+            .env("PATH", DEFAULT_PATH)
+            // The image's Env and -e can still say otherwise
+            .env("HOME", &home);
+        for variable in &env {
+            if let Some((key, value)) = variable.split_once('=') {
+                child.env(key, value);
+            }
+        }
+        if driver.is_some() {
+            child.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let (procs_file, hostname, mounts, cgroup_mount, root, working_dir, groups) = (
+            procs_file.clone(),
+            hostname.clone(),
+            mounts.clone(),
+            cgroup_mount.clone(),
+            root.clone(),
+            working_dir.clone(),
+            groups.clone(),
+        );
+        unsafe {
+            child.pre_exec(move || {
+                // Own session and process group: signals we forward reach every process in the
+                // container, and terminal-generated signals meant for us don't reach it directly
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                supervise::reset_signal_dispositions();
+                // Join the cgroup before anything else so no process escapes its limits
+                if let Some(procs_file) = &procs_file {
+                    cgroup::join(procs_file)?;
+                }
+                // The user namespace comes first, it's what grants the rest in rootless mode
+                if rootless {
+                    user_mapping.enter()?;
+                }
+                identity::enter_uts_namespace(&hostname)?;
+                if !mounts.is_empty() || cgroup_mount.is_some() {
+                    rootfs::unshare_mounts()?;
+                }
+                volume::mount_all(&mounts)?;
+                if let Some(target) = &cgroup_mount {
+                    cgroup::enter_namespace(target)?;
+                }
+                enter_rootfs(&root, &working_dir)?;
+                // Forked while we're still root in the container, so init can signal anything
+                if init {
+                    supervise::become_init()?;
+                }
+                switch_user(uid, gid, groups.as_deref())
+            });
+        }
+
+        // The child's output reaches our stdout directly or through a relay, either way nothing
+        // of ours may still be sitting in the buffer for it to overtake. With the reader gone
+        // there's nothing to overtake, and the container still gets to run.
+        let _ = stdout().flush();
+        let spawned = child.spawn();
+        if let Some(mapper) = mapper {
+            mapper
+                .finish()
+                .context("Failed to map the container's user namespace")?;
+        }
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(error) => {
+                let mut message = format!(
+                    "Tried to run '{}' with arguments {:?}",
+                    command, command_args
+                );
+                // What exec says about a binary for another CPU, and all it says
+                if error.raw_os_error() == Some(libc::ENOEXEC) {
+                    message.push_str(&format!(
+                        " (not an executable for this {} host, is the image built for another \
+                         architecture?)",
+                        Platform::host()
+                    ));
+                }
+                if error.raw_os_error() == Some(libc::ENOENT) {
+                    let fs = copy::ContainerFs::new(container.state())?;
+                    message.push_str(&explain_not_found(command, &env, &fs));
+                }
+                return Err(anyhow::Error::new(error).context(message));
+            }
+        };
+
+        container.set_running(&argv, published.clone(), child.id())?;
+        if let (Some(monitor), Some(pid)) = (&monitor, child.id()) {
+            monitor.watch(pid);
+        }
+        let relays = match &driver {
+            Some(driver) => vec![
+                logs::relay(child.stdout.take().unwrap(), Stream::Stdout, driver.clone()),
+                logs::relay(child.stderr.take().unwrap(), Stream::Stderr, driver.clone()),
+            ],
+            None => vec![],
+        };
+
+        let had_deadline = deadline.is_some();
+        let (status, mut ending) =
+            supervise::supervise(&mut child, stop_signal, options.stop_timeout, &mut deadline)
+                .await?;
+        if let Some(cgroup) = &leftovers {
+            ending = match ending {
+                supervise::Ending::Exited => {
+                    supervise::linger(cgroup, stop_signal, options.stop_timeout, &mut deadline)
+                        .await?
+                }
+                ending => {
+                    supervise::stop_leftovers(cgroup, stop_signal, options.stop_timeout).await?;
+                    ending
+                }
+            };
+        }
+        let exit_code = supervise::exit_code(status);
+        if let Some(monitor) = &monitor {
+            monitor.pause();
+        }
+        if had_deadline && deadline.is_none() {
+            container.clear_deadline()?;
+        }
+        // The pipes stay open as long as something the container started in the background lives
+        for mut relay in relays {
+            if tokio::time::timeout(logs::DRAIN_TIMEOUT, &mut relay)
+                .await
+                .is_err()
+            {
+                relay.abort();
+            }
+        }
+
+        // `stop` from another invocation only reaches the container, so it leaves word first
+        let stopped = ending != supervise::Ending::Exited || restart::disabled(container.dir());
+        let restarts = container.state().restart_count;
+        match restart::decide(options.restart, exit_code, restarts, stopped) {
+            restart::Action::Exit => break (exit_code, ending),
+            restart::Action::Restart(delay) => {
+                // The time limit covers the restarts too, so the wait for one ends with it
+                let delay = deadline.map_or(delay, |deadline| {
+                    delay.min(deadline.saturating_duration_since(tokio::time::Instant::now()))
+                });
+                container.set_restarting(exit_code)?;
+                eprintln!(
+                    "Container exited with code {}, restarting in {:.1}s ({} of {})",
+                    exit_code,
+                    delay.as_secs_f64(),
+                    restarts + 1,
+                    match options.restart.max_retries() {
+                        0 => "unlimited".to_string(),
+                        count => count.to_string(),
+                    }
+                );
+                if !supervise::backoff(delay, container.dir()).await? {
+                    break (exit_code, supervise::Ending::Stopped);
+                }
+                if deadline.map_or(false, |deadline| tokio::time::Instant::now() >= deadline) {
+                    break (exit_code, supervise::Ending::TimedOut);
+                }
+                if let Some(pid_namespaces) = &pid_namespaces {
+                    pid_namespaces.fresh()?;
+                }
+            }
+        }
+    };
+    drop(proxy);
+
+    let (memory_peak, cpu_usec) = match &cgroup {
+        Some(cgroup) => cgroup.usage(peak_tracker.as_ref()),
+        None => (None, None),
+    };
+    let usage = ResourceUsage {
+        wall_seconds: started.elapsed().as_secs_f64(),
+        cpu_usec,
+        memory_peak,
+    };
+    if options.stats {
+        eprintln!("Resource usage: {}", usage.summary());
+    }
+    let oom_killed = cgroup.as_ref().map_or(false, |cgroup| cgroup.oom_killed());
+    container.set_exited(exit_code, usage, oom_killed)?;
+    // The state keeps what the command itself exited with, only our own exit status says
+    // the limit was hit
+    if ending == supervise::Ending::TimedOut {
+        container.set_timed_out()?;
+        eprintln!(
+            "Container exceeded its time limit of {}s (exit code {}), exiting with {}",
+            options.timeout.unwrap_or_default().as_secs(),
+            exit_code,
+            supervise::TIMED_OUT
+        );
+        return Ok(supervise::TIMED_OUT);
+    }
+    Ok(exit_code)
+}
+
+// What the container's healthcheck probes share with its command: the environment, user and
+// working directory, and the cgroup they're counted in
+struct HealthProbe {
+    // The argv from the healthcheck
+    test: Vec<String>,
+    env: Vec<String>,
+    home: String,
+    working_dir: CString,
+    procs_file: Option<CString>,
+    uid: u32,
+    gid: u32,
+    groups: Option<Vec<libc::gid_t>>,
+}
+
+impl HealthProbe {
+    fn command(&self, namespaces: &Arc<health::Namespaces>) -> std::process::Command {
+        use std::os::unix::process::CommandExt;
+
+        let mut probe = std::process::Command::new(&self.test[0]);
+        probe
+            .args(&self.test[1..])
+            .env_clear()
+            .env("PATH", DEFAULT_PATH)
+            .env("HOME", &self.home);
+        for variable in &self.env {
+            if let Some((key, value)) = variable.split_once('=') {
+                probe.env(key, value);
+            }
+        }
+        let (namespaces, procs_file, working_dir, groups) = (
+            namespaces.clone(),
+            self.procs_file.clone(),
+            self.working_dir.clone(),
+            self.groups.clone(),
+        );
+        let (uid, gid) = (self.uid, self.gid);
+        unsafe {
+            probe.pre_exec(move || {
+                // A session of its own, so a probe out of time is killed with all it started
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                supervise::reset_signal_dispositions();
+                if let Some(procs_file) = &procs_file {
+                    cgroup::join(procs_file)?;
+                }
+                namespaces.enter(&working_dir)?;
+                switch_user(uid, gid, groups.as_deref())
+            });
+        }
+        probe
+    }
+}
+
+// Runs in the forked child, last: after chroot, which needs the privileges this gives up.
+// groups replaces our supplementary groups when it's given.
+#[cfg(target_os = "linux")]
+fn switch_user(uid: u32, gid: u32, groups: Option<&[libc::gid_t]>) -> std::io::Result<()> {
     unsafe {
-        libc::unshare(libc::CLONE_NEWPID);
+        if let Some(groups) = groups {
+            if libc::setgroups(groups.len(), groups.as_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
     }
+    Ok(())
+}
+
+// create_new closes the gap between the existence check and the write, should two runs be
+// given the same file
+fn write_cidfile(path: &Path, id: &str) -> Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(id.as_bytes()))
+        .with_context(|| format!("Failed to write container ID file {}", path.display()))
+}
 
-    let mut child = Command::new(command)
-        .args(command_args)
-        .stdin(Stdio::null())
-        .spawn()
-        .with_context(|| {
+// What --sh puts in front of the command line: the image's Shell, else /bin/sh -c, else
+// whichever of the usual others the image has. A shell given by name rather than path is left
+// to the container's PATH.
+fn shell_form(
+    declared: Option<&[String]>,
+    fs: &copy::ContainerFs,
+    image: &str,
+) -> Result<Vec<String>> {
+    let fallbacks = ["/bin/sh", "/bin/ash", "/bin/bash"]
+        .iter()
+        .map(|shell| vec![shell.to_string(), "-c".to_string()]);
+    let candidates: Vec<Vec<String>> = declared
+        .filter(|shell| !shell.is_empty())
+        .map(<[String]>::to_vec)
+        .into_iter()
+        .chain(fallbacks)
+        .collect();
+    let mut tried: Vec<&str> = vec![];
+    for candidate in &candidates {
+        let shell = candidate[0].as_str();
+        if !shell.starts_with('/') || fs.is_file(shell) {
+            if !tried.is_empty() {
+                eprintln!("{} has no {}, using {}", image, tried.join(" or "), shell);
+            }
+            return Ok(candidate.clone());
+        }
+        if !tried.contains(&shell) {
+            tried.push(shell);
+        }
+    }
+    bail!(
+        "--sh needs a shell, but {} has none of {}",
+        image,
+        tried.join(", ")
+    )
+}
+
+// What exec's ENOENT means here: the command isn't in the image at all, or it is and what it
+// needs to start isn't. The second is what a scratch image given a host binary runs into, a
+// dynamically linked binary without its loader and libraries.
+fn explain_not_found(command: &str, env: &[String], fs: &copy::ContainerFs) -> String {
+    let found = if command.contains('/') {
+        Some(command.to_string()).filter(|path| fs.is_file(path))
+    } else {
+        // The container's PATH, the last one given winning like it does in the environment
+        let path = env
+            .iter()
+            .rev()
+            .find_map(|variable| variable.strip_prefix("PATH="))
+            .unwrap_or(DEFAULT_PATH);
+        path.split(':')
+            .filter(|dir| dir.starts_with('/'))
+            .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), command))
+            .find(|candidate| fs.is_file(candidate))
+    };
+    let found = match found {
+        Some(found) => found,
+        None if command.contains('/') => return format!(" ({} isn't in the image)", command),
+        None => return format!(" ({} isn't on the image's PATH)", command),
+    };
+    let head = fs.head(&found, 256).unwrap_or_default();
+    match head.strip_prefix(b"#!") {
+        Some(line) => {
+            let line = String::from_utf8_lossy(line);
+            let interpreter = line.split_whitespace().next().unwrap_or_default();
             format!(
-                "Tried to run '{}' with arguments {:?}",
-                command, command_args
+                " ({} is a script for {}, which isn't in the image)",
+                found, interpreter
             )
-        })?;
+        }
+        None => format!(
+            " ({} is there, but not the dynamic loader it was linked against; scratch \
+             images need statically linked binaries)",
+            found
+        ),
+    }
+}
 
-    Ok(child.wait()?.code().unwrap_or(1))
+// Runs in the forked child right before exec. Only async-signal-safe calls belong here.
+#[cfg(target_os = "linux")]
+fn enter_rootfs(root: &CStr, working_dir: &CStr) -> std::io::Result<()> {
+    unsafe {
+        // working_dir is absolute, so this also moves us off the old root
+        if libc::chroot(root.as_ptr()) != 0 || libc::chdir(working_dir.as_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
 }
 
-fn copy_command(command: &String, temp_dir: &TempDir) -> Result<()> {
-    // Don't want '/usr/local/bin/docker-explorer' sending us back to the root of the file system.
-    // i.e. outside the temp dir we just created. So try to get a relative path
-    let command_path_relative = command.trim_start_matches("/");
-    let target_command = temp_dir.path().join(command_path_relative);
-    let target_path = target_command.parent().unwrap();
-    create_dir_all(target_path)?;
-    copy(command, target_command)?;
+fn ps_command(options: &PsOptions) -> Result<()> {
+    println!(
+        "{:<14}{:<24}{:<24}{:<18}{:<18}{:<10}{:<40}ROOTFS",
+        "CONTAINER ID", "IMAGE", "COMMAND", "CREATED", "STATUS", "RESTARTS", "USAGE"
+    );
+    for state in container::list(&options.root)? {
+        // One waiting out a restart's backoff is as alive as a running one
+        if !options.all && !state.is_running() && state.status != container::Status::Restarting {
+            continue;
+        }
+        if !options
+            .label_filters
+            .iter()
+            .all(|filter| state.has_label(filter))
+        {
+            continue;
+        }
+        println!(
+            "{:<14}{:<24}{:<24}{:<18}{:<18}{:<10}{:<40}{}",
+            state.short_id(),
+            state.image,
+            format!("\"{}\"", truncate(&state.command.join(" "), 20)),
+            container::ago(state.created),
+            state.describe_status(),
+            state.restart_count,
+            state
+                .usage
+                .as_ref()
+                .map(|usage| usage.summary())
+                .unwrap_or_default(),
+            state.rootfs.display()
+        );
+    }
+    Ok(())
+}
 
+// Shorten a column value like docker does, marking the cut with an ellipsis
+fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let mut shortened: String = value.chars().take(width - 1).collect();
+    shortened.push('…');
+    shortened
+}
+
+// Container state as JSON, an array like docker's so it can be piped into jq either way
+fn inspect_command(options: &InspectOptions) -> Result<()> {
+    let states = options
+        .ids
+        .iter()
+        .map(|id| container::find(&options.root, id))
+        .collect::<Result<Vec<_>>>()?;
+    match &options.format {
+        Some(template) => {
+            let template = inspect::Template::parse(template)?;
+            for state in &states {
+                println!("{}", template.render(&inspect::view(state))?);
+            }
+        }
+        None => println!("{}", serde_json::to_string_pretty(&states)?),
+    }
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-fn create_dev_null(temp_dir: &TempDir) -> Result<()> {
-    create_dir(temp_dir.path().join("dev"))?;
-    set_permissions(temp_dir.path().join("dev"), Permissions::from_mode(0o555))?;
-    File::create(temp_dir.path().join("dev/null"))?;
-    set_permissions(
-        temp_dir.path().join("dev/null"),
-        Permissions::from_mode(0o555),
-    )?;
+// Sample the container's cgroup once a second until it exits
+fn stats_command(options: &StatsOptions) -> Result<()> {
+    let state = container::find(&options.root, &options.id)?;
+    if !state.is_running() {
+        bail!("Container {} is not running", state.short_id());
+    }
+    let cgroup = match &state.cgroup {
+        Some(cgroup) => cgroup.clone(),
+        None => bail!(
+            "Container {} has no cgroup (cgroups were unavailable when it started)",
+            state.short_id()
+        ),
+    };
+    // Redraw in place for a person watching, append lines for anything else
+    let redraw = !options.json && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    if !options.json && !redraw {
+        println!("{}", stats::header());
+    }
+
+    let mut previous = stats::sample(&cgroup)?;
+    let mut state = state;
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        // Read again every time: the container may have exited (or been removed) since, and
+        // one waiting out a restart's backoff still has its cgroup
+        state = match container::find(&options.root, &state.id) {
+            Ok(state) if state.is_running() || state.status == container::Status::Restarting => {
+                state
+            }
+            _ => return Ok(()),
+        };
+        // The cgroup disappears with the container, either way we're done
+        let current = match stats::sample(&cgroup) {
+            Ok(current) => current,
+            Err(_) => return Ok(()),
+        };
+        if options.json {
+            println!("{}", stats::format_json(&state, &previous, &current)?);
+        } else {
+            if redraw {
+                println!("\x1b[2J\x1b[H{}", stats::header());
+            }
+            println!("{}", stats::format_row(&state, &previous, &current));
+        }
+        stdout().flush()?;
+        previous = current;
+    }
+}
+
+// Stop containers run by other invocations. Their supervisors notice the exit and clean up.
+fn stop_command(options: &StopOptions) -> Result<()> {
+    let mut failed = false;
+    for id in &options.ids {
+        let result = container::find(&options.root, id).and_then(|state| {
+            stop_container(&options.root, &state, options.timeout)?;
+            Ok(state.id)
+        });
+        match result {
+            Ok(stopped) => println!("{}", stopped),
+            Err(error) => {
+                eprintln!("Error: {:#}", error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!("Failed to stop some containers");
+    }
+    Ok(())
+}
+
+// The container's stop signal, then SIGKILL once its grace period (or timeout) is up. Its
+// restart policy is called off first, so the supervisor doesn't start it right back up; one
+// waiting to restart needs nothing more than that.
+fn stop_container(root: &Path, state: &ContainerState, timeout: Option<Duration>) -> Result<()> {
+    let restarting = state.status == container::Status::Restarting;
+    if state.is_running() || restarting {
+        restart::disable(&root.join(&state.id))?;
+    }
+    if !state.is_running() {
+        if restarting {
+            return Ok(());
+        }
+        bail!("Container {} is not running", state.short_id());
+    }
+    let signal = supervise::parse_signal(&state.stop_signal)?;
+    let timeout = timeout.unwrap_or_else(|| Duration::from_secs(state.stop_timeout));
+    let mut killed = match state.pid {
+        Some(pid) if lock::pid_alive(pid as i32) => supervise::stop_pid(pid, signal, timeout),
+        _ => false,
+    };
+    // Without a PID namespace, what the command left behind is only to be found in its cgroup
+    if let Some(cgroup) = &state.cgroup {
+        killed |= supervise::stop_cgroup(cgroup, signal, timeout);
+    }
+    if killed {
+        eprintln!(
+            "Container {} didn't stop within {}s of {}, sent SIGKILL",
+            state.short_id(),
+            timeout.as_secs(),
+            state.stop_signal
+        );
+    }
+    Ok(())
+}
+
+// Exit status of wait when a container outlives --timeout, the same as timeout(1)
+const WAIT_TIMED_OUT: i32 = 124;
 
+// One exit code per line in argument order. The timeout covers the whole command, not each
+// container.
+async fn wait_command(options: &WaitOptions) -> Result<i32> {
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    for id in &options.ids {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match wait::wait_for_exit(&options.root, id, remaining).await? {
+            Some(code) => println!("{}", code),
+            None => {
+                eprintln!("Timed out waiting for {}", id);
+                return Ok(WAIT_TIMED_OUT);
+            }
+        }
+    }
+    Ok(0)
+}
+
+// Keep going past a bad id so one typo doesn't stop the rest from being removed
+fn rm_command(options: &RmOptions, data_root: &DataRoot) -> Result<()> {
+    let mut failed = false;
+    for id in &options.ids {
+        match container::remove(&options.root, id, &data_root.volumes()) {
+            Ok(removed) => println!("{}", removed),
+            Err(error) => {
+                eprintln!("Error: {:#}", error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!("Failed to remove some containers");
+    }
     Ok(())
 }
 
-async fn pull_image(image_name: &String, target_dir: &String) -> Result<()> {
-    let image_tag: Vec<&str> = image_name.as_str().split(':').collect();
-    let image = image_tag[0];
-    let tag = image_tag.get(1).unwrap_or(&"latest");
+// Only the file driver keeps anything we can read back, and only while the container directory
+// is there: while it runs, or after it exited with --keep-rootfs
+fn logs_command(options: &LogsOptions) -> Result<()> {
+    let state = container::find(&options.root, &options.id)?;
+    logs::print(&state)
+}
 
-    let client = reqwest::Client::new();
+fn diff_command(options: &DiffOptions) -> Result<()> {
+    let state = container::find(&options.root, &options.id)?;
+    for (kind, path) in diff::changes(&state, options.verify)? {
+        println!("{} {}", kind.letter(), path);
+    }
+    Ok(())
+}
 
-    let access_token = client
-        .get(format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:library/{}:pull",
-        image
-    ))
-        .send()
-        .await?
-        .json::<Auth>()
-        .await?
-        .access_token;
+fn export_command(options: &ExportOptions) -> Result<()> {
+    let excludes = exclude::Excludes::new(&options.excludes)?;
+    let state = container::find(&options.root, &options.id)?;
+    let summary = match &options.output {
+        Some(path) => {
+            // Renamed into place once complete, so a failed export leaves no half an archive
+            let mut staging = path.as_os_str().to_owned();
+            staging.push(".partial");
+            let file = std::fs::File::create(&staging)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            let summary = export::export(&state, &excludes, std::io::BufWriter::new(file))
+                .and_then(|summary| {
+                    std::fs::rename(&staging, path)?;
+                    Ok(summary)
+                });
+            if summary.is_err() {
+                let _ = std::fs::remove_file(&staging);
+            }
+            summary?
+        }
+        None => {
+            if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+                bail!("Refusing to write a tar archive to a terminal, use -o or redirect stdout");
+            }
+            export::export(&state, &excludes, stdout().lock())?
+        }
+    };
+    if summary.sockets > 0 {
+        eprintln!(
+            "warning: left out {} unix socket(s), tar can't represent them",
+            summary.sockets
+        );
+    }
+    Ok(())
+}
 
-    let manifest = client
-        .get(format!(
-            "https://registry.hub.docker.com/v2/library/{}/manifests/{}",
-            image, tag
-        ))
-        .header("Authorization", format!("Bearer {}", access_token))
-        .header(
-            "Accept",
-            "application/vnd.docker.distribution.manifest.v2+json",
-        )
-        .send()
-        .await?
-        .json::<Manifest>()
-        .await?;
-
-    for layer in manifest.layers {
-        let data = client
-            .get(format!(
-                "https://registry.hub.docker.com/v2/library/{}/blobs/{}",
-                image, layer.digest
-            ))
-            .header("Authorization", format!("Bearer {}", access_token))
-            .send()
-            .await?
-            .bytes()
+// Start a compose file's services in dependency order, each as a `run` of ourselves, and stay
+// attached like `docker compose up`: their output is prefixed with the service name, and
+// Ctrl-C (or SIGTERM) stops them all in reverse order. A service that fails to start stops
+// the ones already running.
+async fn up_command(options: &UpOptions, data_root: &DataRoot) -> Result<()> {
+    use compose::ServiceRun;
+    use tokio::process::Child;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let file = compose::find_file(options.file.as_deref())?;
+    let project = compose::load(&file, options.project_name.as_deref())?;
+    for service in &project.services {
+        if let Some(state) = find_named(&options.root, &service.container_name) {
+            bail!(
+                "Container {} ({}) already exists, run `down` first",
+                service.container_name,
+                state.short_id()
+            );
+        }
+    }
+
+    // Registered up front so an interrupt during startup still stops what has started
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let executable = std::env::current_exe().context("Failed to find our own executable")?;
+    let width = project
+        .services
+        .iter()
+        .map(|service| service.service.len())
+        .max()
+        .unwrap_or(0);
+    let mut started: Vec<(&ServiceRun, Child)> = vec![];
+    let mut output = vec![];
+
+    let start = async {
+        for service in &project.services {
+            eprintln!("Starting {}", service.container_name);
+            let run = service
+                .run
+                .clone()
+                .executable(&executable)
+                .data_root(data_root.path())
+                .root(&options.root)
+                .offline(options.offline);
+            let mut command = Command::from(run.to_command()?);
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            unsafe {
+                // Our own process group keeps the terminal's Ctrl-C away from the services, so
+                // we get to stop them in order
+                command.pre_exec(|| {
+                    if libc::setpgid(0, 0) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let mut child = command
+                .spawn()
+                .with_context(|| format!("Failed to start service {}", service.service))?;
+            let prefix = format!("{:<width$} | ", service.service, width = width);
+            output.push(forward_lines(child.stdout.take(), prefix.clone(), stdout()));
+            output.push(forward_lines(
+                child.stderr.take(),
+                prefix,
+                std::io::stderr(),
+            ));
+
+            let failed = wait_started(&options.root, service, &mut child).await?;
+            started.push((service, child));
+            if let Some(code) = failed {
+                bail!(
+                    "Service {} failed to start (exit code {})",
+                    service.service,
+                    code
+                );
+            }
+        }
+        Ok(())
+    };
+    let stopping = tokio::select! {
+        result = start => result.err(),
+        _ = interrupt.recv() => Some(anyhow!("Interrupted while starting services")),
+        _ = terminate.recv() => Some(anyhow!("Terminated while starting services")),
+    };
+
+    let interrupted = match stopping {
+        Some(_) => true,
+        None => {
+            let all_exited = async {
+                for (_, child) in started.iter_mut() {
+                    let _ = child.wait().await;
+                }
+            };
+            tokio::select! {
+                _ = all_exited => false,
+                _ = interrupt.recv() => true,
+                _ = terminate.recv() => true,
+            }
+        }
+    };
+    if interrupted {
+        // SIGTERM makes each supervisor stop its container with the right signal and grace
+        // period, the same as it would for a container run by hand
+        for (service, child) in started.iter_mut().rev() {
+            if let (Ok(None), Some(pid)) = (child.try_wait(), child.id()) {
+                eprintln!("Stopping {}", service.container_name);
+                unsafe {
+                    libc::kill(pid as i32, libc::SIGTERM);
+                }
+                let _ = child.wait().await;
+            }
+        }
+    }
+    for task in output {
+        let _ = task.await;
+    }
+    for (service, child) in started.iter_mut() {
+        if let Ok(status) = child.wait().await {
+            eprintln!(
+                "{} exited with code {}",
+                service.container_name,
+                supervise::exit_code(status)
+            );
+        }
+    }
+    match stopping {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+// Stop and remove a compose file's containers in reverse dependency order
+async fn down_command(options: &DownOptions, data_root: &DataRoot) -> Result<()> {
+    let file = compose::find_file(options.file.as_deref())?;
+    let project = compose::load(&file, options.project_name.as_deref())?;
+    let mut failed = false;
+    for service in project.services.iter().rev() {
+        let state = match find_named(&options.root, &service.container_name) {
+            Some(state) => state,
+            None => continue,
+        };
+        let result = if state.is_running() || state.status == container::Status::Restarting {
+            // Its supervisor removes the container once it has recorded the exit, which may
+            // well have happened by the time we look
+            eprintln!("Stopping {}", service.container_name);
+            match stop_container(&options.root, &state, options.timeout) {
+                Ok(()) => match wait::wait_for_exit(&options.root, &state.id, None).await {
+                    Err(_) if find_named(&options.root, &service.container_name).is_none() => {
+                        Ok(())
+                    }
+                    result => result.map(|_| ()),
+                },
+                Err(error) => Err(error),
+            }
+        } else {
+            container::remove(&options.root, &state.id, &data_root.volumes()).map(|_| ())
+        };
+        match result {
+            Ok(()) => eprintln!("Removed {}", service.container_name),
+            Err(error) => {
+                eprintln!("Error: {}: {:#}", service.container_name, error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!(
+            "Failed to remove some containers of project {}",
+            project.name
+        );
+    }
+    Ok(())
+}
+
+// A container by exact name, not by id prefix like find()
+fn find_named(base: &Path, name: &str) -> Option<ContainerState> {
+    container::list(base)
+        .ok()?
+        .into_iter()
+        .find(|state| state.name.as_deref() == Some(name))
+}
+
+// Whether a service got going: None once its container is running (or it ran to completion),
+// its exit code if `run` gave up before that
+async fn wait_started(
+    root: &Path,
+    service: &compose::ServiceRun,
+    child: &mut tokio::process::Child,
+) -> Result<Option<i32>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(match supervise::exit_code(status) {
+                0 => None,
+                code => Some(code),
+            });
+        }
+        if let Some(state) = find_named(root, &service.container_name) {
+            if state.is_running() {
+                return Ok(None);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Copy a service's output with its name in front of every line. Whatever arrives is written
+// out (and flushed) right away, a partial line included, so the service's output keeps its
+// order and a prompt without a newline still shows up. Once our own stdout or stderr is gone
+// (`up | head`) the stream is still read to the end, or the service would block on a full
+// pipe or die writing to a closed one.
+fn forward_lines<R, W>(stream: Option<R>, prefix: String, mut out: W) -> tokio::task::JoinHandle<()>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    W: Write + Send + 'static,
+{
+    use tokio::io::AsyncReadExt;
+
+    tokio::spawn(async move {
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut chunk = [0; 4096];
+        let mut line_start = true;
+        let mut forwarding = true;
+        loop {
+            let length = match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => break,
+                Ok(length) => length,
+            };
+            if !forwarding {
+                continue;
+            }
+            let mut text = Vec::with_capacity(length + prefix.len());
+            for &byte in &chunk[..length] {
+                if line_start {
+                    text.extend_from_slice(prefix.as_bytes());
+                }
+                text.push(byte);
+                line_start = byte == b'\n';
+            }
+            forwarding = out.write_all(&text).and_then(|_| out.flush()).is_ok();
+        }
+    })
+}
+
+// Works on the rootfs recorded in the container's state, so the container may be running or
+// have exited with --keep-rootfs
+fn cp_command(options: &CpOptions) -> Result<()> {
+    use copy::Endpoint;
+
+    let copied = match (
+        Endpoint::parse(&options.source),
+        Endpoint::parse(&options.destination),
+    ) {
+        (Endpoint::Container { id, path }, Endpoint::Host(destination)) => {
+            let fs = copy::ContainerFs::new(&container::find(&options.root, &id)?)?;
+            copy::copy_out(&fs, &path, &destination)?
+        }
+        (Endpoint::Host(source), Endpoint::Container { id, path }) => {
+            let fs = copy::ContainerFs::new(&container::find(&options.root, &id)?)?;
+            copy::copy_in(&fs, &source, &path)?
+        }
+        (Endpoint::Container { .. }, Endpoint::Container { .. }) => {
+            bail!("Copying between containers isn't supported, go through the host")
+        }
+        (Endpoint::Host(_), Endpoint::Host(_)) => {
+            bail!("One side of cp must be a container path as <id>:<path>")
+        }
+    };
+    println!(
+        "Successfully copied {} to {}",
+        pull::human_size(copied),
+        options.destination
+    );
+    Ok(())
+}
+
+// Every tag in the store, and how long ago the registry last confirmed where it points
+fn images_command(data_root: &DataRoot) -> Result<()> {
+    let store = Store::new(data_root.path());
+    let checked = store.checked()?;
+    println!("{:<50}{:<15}CHECKED", "IMAGE", "DIGEST");
+    for (tag, digest) in store.tags()? {
+        let short = digest
+            .split_once(':')
+            .map_or(digest.as_str(), |(_, hex)| hex);
+        let when = checked
+            .get(&tag)
+            .map_or_else(|| "never".to_string(), |&time| container::ago(time));
+        println!("{:<50}{:<15}{}", tag, &short[..short.len().min(12)], when);
+    }
+    Ok(())
+}
+
+// Registered for the name we were run as, so it works for your_docker.sh, mydocker or a
+// cargo build alike
+fn completions_command(shell: completion::Shell) -> Result<()> {
+    let program = args().next().unwrap_or_default();
+    let program = Path::new(&program)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "mydocker".to_string());
+    print!("{}", completion::script(shell, &program));
+    Ok(())
+}
+
+// One candidate per line, or just ":files" for the shell to complete file names itself
+fn complete_command(words: &[String]) -> Result<()> {
+    match completion::complete(words) {
+        completion::Completion::Words(words) => {
+            for word in words {
+                println!("{}", word);
+            }
+        }
+        completion::Completion::Files => println!(":files"),
+    }
+    Ok(())
+}
+
+fn volume_ls_command(data_root: &DataRoot) -> Result<()> {
+    println!("{:<8}VOLUME NAME", "DRIVER");
+    for name in volume::list(&data_root.volumes())? {
+        println!("{:<8}{}", "local", name);
+    }
+    Ok(())
+}
+
+// Like docker, a volume a running container has mounted can't be removed out from under it
+fn volume_rm_command(names: &[String], data_root: &DataRoot) -> Result<()> {
+    let running: Vec<_> = container::list(&data_root.containers())?
+        .into_iter()
+        .filter(|state| state.is_running())
+        .collect();
+    let mut failed = false;
+    for name in names {
+        let user = running
+            .iter()
+            .find(|state| state.volumes.iter().any(|volume| volume == name));
+        let result = match user {
+            Some(state) => Err(anyhow!(
+                "Volume {} is in use by container {}",
+                name,
+                state.short_id()
+            )),
+            None => volume::remove(&data_root.volumes(), name),
+        };
+        match result {
+            Ok(()) => println!("{}", name),
+            Err(error) => {
+                eprintln!("Error: {:#}", error);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        bail!("Failed to remove some volumes");
+    }
+    Ok(())
+}
+
+fn store_repair_command(data_root: &DataRoot) -> Result<()> {
+    let report = Store::new(data_root.path()).repair()?;
+    if report.index_was_damaged {
+        println!("repositories.json was damaged, rebuilt it from refs");
+    }
+    if report.removed_partial > 0 {
+        println!(
+            "Removed {} leftover partial file(s)",
+            report.removed_partial
+        );
+    }
+    for digest in &report.corrupt_blobs {
+        println!("Removed corrupt blob {}", digest);
+    }
+    for tag in &report.dropped_tags {
+        println!("Dropped tag {}, its image is no longer in the store", tag);
+    }
+    println!("{} tag(s) in the store", report.tags.len());
+    Ok(())
+}
+
+// What the kernel offers, then what `run` would make of it with our privileges
+fn system_info_command(data_root: &DataRoot) -> Result<()> {
+    let snapshot = privileges::Snapshot::probe(&[data_root.path(), &data_root.containers()]);
+    for (name, value) in snapshot.features.report() {
+        println!("{:<18}{}", format!("{}:", name), value);
+    }
+    let plan = privileges::plan(&snapshot, false);
+    println!();
+    if !plan.fatal.is_empty() {
+        println!("run would fail: {}", plan.fatal.join("; "));
+    } else if plan.skipped.is_empty() {
+        println!("run would get full isolation");
+    } else {
+        println!(
+            "run would warn (or fail with --strict){}:",
+            if plan.rootless {
+                " in rootless mode"
+            } else {
+                ""
+            }
+        );
+        for (feature, reason) in &plan.skipped {
+            println!("  {}: {}", feature, reason);
+        }
+    }
+    Ok(())
+}
+
+// Hashes every blob in the store and reports the corrupt ones, files that aren't blobs at all
+// (dead partials, stray names, quarantined blobs) and blobs nothing tagged or running needs.
+// --fix deletes all three, holding the cache lock the way prune does.
+fn cache_verify_command(options: &CacheVerifyOptions, data_root: &DataRoot) -> Result<()> {
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    let _cache = if options.fix {
+        store.lock_cache()?
+    } else {
+        store.share_cache()?
+    };
+
+    let report = store.verify(options.jobs, options.fix)?;
+    let seconds = report.elapsed.as_secs_f64().max(0.001);
+    println!(
+        "Verified {} blob(s), {} in {:.1}s ({}/s on {} thread(s))",
+        report.blobs,
+        pull::human_size(report.bytes),
+        seconds,
+        pull::human_size((report.bytes as f64 / seconds) as u64),
+        options.jobs
+    );
+    let verb = if options.fix { "Deleted" } else { "Found" };
+    for digest in &report.corrupt {
+        println!("{} corrupt blob {}", verb, digest);
+    }
+    for path in &report.stray {
+        println!("{} stray file {}", verb, path.display());
+    }
+
+    let pinned = running_images(&store, &options.root)?;
+    let orphans: Vec<_> = prune::plan(&store, &pinned, None)?
+        .into_iter()
+        // Stray names are reported as such already
+        .filter(|candidate| {
+            store.blob_path(&candidate.digest).is_ok()
+                && !report.corrupt.contains(&candidate.digest)
+        })
+        .collect();
+    for orphan in &orphans {
+        println!(
+            "{} orphaned blob {} ({})",
+            verb,
+            orphan.digest,
+            pull::human_size(orphan.size)
+        );
+    }
+    if options.fix {
+        let digests: Vec<&str> = orphans
+            .iter()
+            .map(|orphan| orphan.digest.as_str())
+            .collect();
+        store.remove_blobs(&digests)?;
+    }
+
+    let problems = report.corrupt.len() + report.stray.len() + orphans.len();
+    if problems == 0 {
+        println!("The cache is clean");
+    } else if !options.fix {
+        println!("{} problem(s), --fix deletes them", problems);
+    }
+    Ok(())
+}
+
+// Images of containers that haven't exited yet, which prune and verify leave alone
+fn running_images(store: &Store, root: &Path) -> Result<prune::Reachable> {
+    let mut pinned = prune::Reachable::default();
+    for state in container::list(root)? {
+        if state.status == container::Status::Exited {
+            continue;
+        }
+        if let Ok(reference) = Reference::parse(&state.image) {
+            let digest = match reference.digest {
+                Some(digest) => Some(digest),
+                None => store.tag_digest(&reference.tag_key())?,
+            };
+            if let Some(digest) = digest {
+                pinned.add(store, &digest);
+            }
+        }
+    }
+    Ok(pinned)
+}
+
+// Blobs go once nothing tagged needs them, and with --max-cache-size the least recently used
+// layers too. Images of containers that haven't exited yet are left alone.
+fn system_prune_command(options: &PruneOptions, data_root: &DataRoot) -> Result<()> {
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    // A dry run only looks, so it needn't hold up pulls
+    let _cache = if options.dry_run {
+        store.share_cache()?
+    } else {
+        store.lock_cache()?
+    };
+
+    let pinned = running_images(&store, &options.root)?;
+    let candidates = prune::plan(&store, &pinned, options.max_cache_size)?;
+    let verb = if options.dry_run {
+        "Would delete"
+    } else {
+        "Deleted"
+    };
+    for candidate in &candidates {
+        println!(
+            "{} {} ({}, {})",
+            verb,
+            candidate.digest,
+            pull::human_size(candidate.size),
+            match candidate.reason {
+                prune::Reason::Unreferenced => "unreferenced",
+                prune::Reason::Evicted => "least recently used",
+            }
+        );
+    }
+    if !options.dry_run {
+        let digests: Vec<&str> = candidates
+            .iter()
+            .map(|candidate| candidate.digest.as_str())
+            .collect();
+        store.remove_blobs(&digests)?;
+    }
+
+    let reclaimed = candidates.iter().map(|candidate| candidate.size).sum();
+    println!(
+        "{} {} in {} blob(s)",
+        if options.dry_run {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        },
+        pull::human_size(reclaimed),
+        candidates.len()
+    );
+    Ok(())
+}
+
+// Assemble an image's rootfs into <output>/rootfs next to an OCI config.json, for runc or crun
+// to run (or `run --bundle`)
+async fn bundle_command(options: &BundleOptions, data_root: &DataRoot) -> Result<()> {
+    if options.reproducible {
+        pin_umask();
+    }
+    let rootfs = options.output.join("rootfs");
+    if rootfs.exists() {
+        bail!("{} already exists", rootfs.display());
+    }
+    std::fs::create_dir_all(&rootfs)
+        .with_context(|| format!("Failed to create {}", rootfs.display()))?;
+
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    let settings = PullSettings {
+        platform: None,
+        max_layer_size: options.max_layer_size,
+        policy: if options.offline {
+            PullPolicy::Never
+        } else {
+            PullPolicy::Always
+        },
+        debug_http: options.debug_http,
+        pipeline: options.pipeline,
+        strict_unpack: options.strict_unpack,
+        allow_devices: options.allow_devices,
+        reproducible: options.reproducible,
+        lockfile: None,
+    };
+    let pulled = pull_image(&options.image, &settings, &store, &rootfs).await;
+    let result = match pulled {
+        Ok((_, image_config)) => chown_bundle(&rootfs).and_then(|_| {
+            bundle::write_config(&options.output, &image_config.config.unwrap_or_default())
+        }),
+        Err(error) => Err(error),
+    };
+    unpack::remove_bookkeeping(&rootfs)?;
+    // Don't leave half a bundle behind for a retry to trip over
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&rootfs);
+    }
+    result?;
+    println!(
+        "Wrote bundle for {} to {}",
+        options.image,
+        options.output.display()
+    );
+    Ok(())
+}
+
+// Extract the image as bundle --reproducible would, into a scratch directory under the data
+// root (on the store's filesystem, unlike /tmp), and print the hash of the tree
+async fn rootfs_digest_command(options: &RootfsDigestOptions, data_root: &DataRoot) -> Result<()> {
+    pin_umask();
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    std::fs::create_dir_all(data_root.path())
+        .with_context(|| format!("Failed to create {}", data_root.path().display()))?;
+    let scratch = tempfile::Builder::new()
+        .prefix("rootfs-digest.")
+        .tempdir_in(data_root.path())?;
+    let rootfs = scratch.path().join("rootfs");
+    rootfs::create_dir(&rootfs)?;
+
+    let settings = PullSettings {
+        platform: options.platform.as_ref(),
+        max_layer_size: options.max_layer_size,
+        policy: if options.offline {
+            PullPolicy::Never
+        } else {
+            PullPolicy::Always
+        },
+        debug_http: options.debug_http,
+        pipeline: true,
+        // A tree that's missing entries hashes as something the image isn't
+        strict_unpack: true,
+        // The same tree whoever extracts it, root or not
+        allow_devices: false,
+        reproducible: true,
+        lockfile: None,
+    };
+    let (_, image_config) = pull_image(&options.image, &settings, &store, &rootfs).await?;
+    pull::check_platform(&image_config, options.platform.as_ref())?;
+    println!("{}", tree_digest::digest(&rootfs)?);
+    Ok(())
+}
+
+// For --reproducible: the rootfs and the directories the layers don't have entries for get
+// their modes from it
+fn pin_umask() {
+    unsafe {
+        libc::umask(rootfs::DEFAULT_UMASK);
+    }
+}
+
+// Whatever runs the bundle gets the rootfs as it is, so this is the only chance to chown it
+fn chown_bundle(rootfs: &Path) -> Result<()> {
+    let owners = ownership::load(rootfs)?;
+    if unsafe { libc::geteuid() } == 0 {
+        return ownership::chown_all(rootfs, &owners);
+    }
+    if !owners.is_empty() {
+        eprintln!(
+            "warning: not running as root, so the {} paths the image gives to other users are yours",
+            owners.len()
+        );
+    }
+    Ok(())
+}
+
+async fn pull_command(options: &PullOptions, data_root: &DataRoot) -> Result<()> {
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    // Keeps prune from deleting blobs between our finding and tagging them. A dry run tags
+    // nothing and leaves the store exactly as it was, lock file included.
+    let _cache = if options.dry_run {
+        None
+    } else {
+        Some(store.share_cache()?)
+    };
+    let mut lockfile = match &options.write_lockfile {
+        Some(path) => Some(Lockfile::load_or_default(path)?),
+        None => None,
+    };
+
+    for image in &options.images {
+        let image = pull_one(options, &store, data_root.path(), image).await?;
+        if let (Some(lockfile), Some(image)) = (&mut lockfile, &image) {
+            lockfile.record(image, options.update_lockfile)?;
+        }
+        if let (Some(dir), Some(image)) = (&options.oci_dir, &image) {
+            oci_layout::write(dir, image, &store)?;
+            println!("Wrote {} to {}", image.reference, dir.display());
+        }
+    }
+    // Only once everything pulled, so a failure leaves the lockfile as it was
+    if let (Some(lockfile), Some(path)) = (&lockfile, &options.write_lockfile) {
+        lockfile.save(path)?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+// The image as resolved, None for a dry run
+async fn pull_one(
+    options: &PullOptions,
+    store: &Store,
+    store_dir: &Path,
+    image: &str,
+) -> Result<Option<pull::ResolvedImage>> {
+    let reference = Reference::parse(image)?;
+
+    if options.offline {
+        // Nothing to download, but this still proves the image is usable without a network
+        let image = pull::resolve_local(
+            store,
+            reference,
+            options.platform.as_ref(),
+            !options.dry_run,
+        )?;
+        if options.dry_run {
+            pull::print_plan(&image, store);
+            return Ok(None);
+        }
+        println!("{} ({}) is cached", image.reference, image.digest);
+        return Ok(Some(image));
+    }
+
+    let client = RegistryClient::connect(&reference, options.debug_http).await?;
+    // A tag that hasn't moved costs one HEAD request
+    if let Some(image) = pull::resolve_unchanged(
+        &client,
+        store,
+        &reference,
+        options.platform.as_ref(),
+        !options.dry_run,
+    )
+    .await?
+    {
+        if options.dry_run {
+            pull::print_plan(&image, store);
+            return Ok(None);
+        }
+        println!("{} ({}) is up to date", image.reference, image.digest);
+        return Ok(Some(image));
+    }
+    let image = pull::resolve(&client, reference, options.platform.as_ref()).await?;
+
+    if options.dry_run {
+        pull::print_plan(&image, store);
+        return Ok(None);
+    }
+
+    let space = Space::check(store_dir, &image, store, false);
+    pull::pull(&client, &image, store)
+        .await
+        .map_err(|error| space.explain(error, store_dir, &image))?;
+    println!("Pulled {} ({})", image.reference, image.digest);
+    Ok(Some(image))
+}
+
+// An image from the store to a registry, under its own reference or the target's. Only one
+// platform's manifest is ever in the store, so that's what goes up even for an image pulled
+// through an index.
+async fn push_command(options: &PushOptions, data_root: &DataRoot) -> Result<()> {
+    let store = Store::new(data_root.path()).with_lock_timeout(options.cache_lock_timeout);
+    // Keeps prune from deleting blobs while they're uploaded
+    let _cache = store.share_cache()?;
+    let image = pull::resolve_local(
+        &store,
+        Reference::parse(&options.image)?,
+        options.platform.as_ref(),
+        true,
+    )?;
+    let target = Reference::parse(options.target.as_deref().unwrap_or(&options.image))?;
+    if let Some(platform) = image
+        .platform
+        .as_ref()
+        .filter(|_| image.digest != image.manifest_digest)
+    {
+        eprintln!(
+            "{} is a multi-platform image, pushing only its {} manifest",
+            image.reference, platform
+        );
+    }
+
+    // Layers the image shares with the repository it was pulled from can be mounted from there
+    let client =
+        RegistryClient::connect_for_push(&target, Some(&image.reference), options.debug_http)
             .await?;
+    let pushed = push::push(&client, &store, &image, &target).await?;
+    let mut summary = format!("{} blobs uploaded", pushed.uploaded);
+    if pushed.mounted > 0 {
+        summary += &format!(
+            ", {} mounted from {}",
+            pushed.mounted, image.reference.repository
+        );
+    }
+    println!(
+        "Pushed {} ({}): {}, {} already there",
+        target, image.manifest_digest, summary, pushed.skipped
+    );
+    Ok(())
+}
+
+// Print a manifest or index as the registry sent it. This deliberately skips the typed models
+// (which drop fields we don't use) and never touches blobs. With --offline the documents come
+// from the store instead, as saved by an earlier pull.
+async fn manifest_command(options: &ManifestOptions, data_root: &DataRoot) -> Result<()> {
+    let reference = Reference::parse(&options.image)?;
+    let store = Store::new(data_root.path());
+    let client = if options.offline {
+        None
+    } else {
+        Some(RegistryClient::connect(&reference, options.debug_http).await?)
+    };
+
+    let target = match (&client, &reference.digest) {
+        (None, None) => store
+            .tag_digest(&reference.tag_key())?
+            .ok_or_else(|| anyhow!("{} is not in the local store", reference))?,
+        _ => reference.target().to_string(),
+    };
+    let mut raw = fetch_manifest(client.as_ref(), &store, &target).await?;
+
+    if let Some(platform) = &options.platform {
+        if is_index(&raw.content_type) {
+            let index = serde_json::from_slice::<Index>(&raw.bytes)
+                .with_context(|| format!("Failed to parse image index for {}", reference))?;
+            let entry = index
+                .select(platform)
+                .ok_or_else(|| anyhow!("{} has no image for platform {}", reference, platform))?;
+            raw = fetch_manifest(client.as_ref(), &store, &entry.digest).await?;
+        } else {
+            eprintln!(
+                "{} is a single-platform manifest, ignoring --platform {}",
+                reference, platform
+            );
+        }
+    }
 
-        let tar = GzDecoder::new(Cursor::new(data).reader());
-        let mut archive = Archive::new(tar);
-        archive.unpack(target_dir)?;
+    // Headers go to stderr so stdout stays valid JSON for piping into other tools
+    eprintln!("Content-Type: {}", raw.content_type);
+    eprintln!(
+        "Docker-Content-Digest: {}",
+        raw.header_digest.as_deref().unwrap_or("(not sent)")
+    );
+
+    if options.raw {
+        stdout().write_all(&raw.bytes)?;
+    } else {
+        let document = serde_json::from_slice::<serde_json::Value>(&raw.bytes)
+            .context("Registry returned a manifest that isn't valid JSON, try --raw")?;
+        println!("{}", serde_json::to_string_pretty(&document)?);
     }
 
     Ok(())
 }
 
-#[derive(Deserialize)]
-struct Auth {
-    access_token: String,
+async fn fetch_manifest(
+    client: Option<&RegistryClient>,
+    store: &Store,
+    target: &str,
+) -> Result<RawManifest> {
+    if let Some(client) = client {
+        return client.manifest(target).await;
+    }
+    let bytes = store.read_blob(target)?;
+    Ok(RawManifest {
+        content_type: document_media_type(&bytes).unwrap_or_default(),
+        digest: target.to_string(),
+        header_digest: None,
+        bytes,
+    })
+}
+
+// How pull_image gets hold of an image, out of run's or bundle's options
+struct PullSettings<'a> {
+    platform: Option<&'a Platform>,
+    max_layer_size: Option<u64>,
+    // Whether to ask the registry about an image the store already has; Never is --offline
+    policy: PullPolicy,
+    debug_http: bool,
+    // Extract each layer while the next one downloads, rather than all of them afterwards
+    pipeline: bool,
+    // Refuse the image unless it resolves to what this pins
+    lockfile: Option<&'a Lockfile>,
+    // Fail on layer entries that can't be decoded or created, instead of leaving them out
+    strict_unpack: bool,
+    // Create the layers' device nodes, which are left out otherwise
+    allow_devices: bool,
+    // Pin the umask and every mtime, see unpack::pin_mtimes
+    reproducible: bool,
+}
+
+impl PullSettings<'_> {
+    fn entry_policy(&self) -> unpack::EntryPolicy {
+        unpack::EntryPolicy {
+            strict: self.strict_unpack,
+            allow_devices: self.allow_devices,
+        }
+    }
+}
+
+// Pull the image through the local store, then extract its layers in order into target_dir.
+// Returns the digest the reference resolved to along with the image's config.
+async fn pull_image(
+    image_name: &str,
+    settings: &PullSettings<'_>,
+    store: &Store,
+    target_dir: &Path,
+) -> Result<(String, ImageConfig)> {
+    let reference = Reference::parse(image_name)?;
+    // Held until the layers are unpacked, so prune can't delete them under us
+    let _cache = store.share_cache()?;
+    let cached = match settings.policy {
+        PullPolicy::Never => Some(pull::resolve_local(
+            store,
+            reference.clone(),
+            settings.platform,
+            true,
+        )?),
+        PullPolicy::Missing => {
+            pull::resolve_local(store, reference.clone(), settings.platform, true).ok()
+        }
+        PullPolicy::Always => None,
+    };
+    let image = match cached {
+        Some(image) => extract_cached(image, settings, store, target_dir)?,
+        None => {
+            let client = RegistryClient::connect(&reference, settings.debug_http).await?;
+            let unchanged = match settings.policy {
+                PullPolicy::Always => {
+                    pull::resolve_unchanged(&client, store, &reference, settings.platform, true)
+                        .await?
+                }
+                _ => None,
+            };
+            match unchanged {
+                Some(image) => extract_cached(image, settings, store, target_dir)?,
+                None => pull_and_extract(&client, reference, settings, store, target_dir).await?,
+            }
+        }
+    };
+    if settings.reproducible {
+        unpack::pin_mtimes(store, &image.manifest.layers, target_dir)?;
+    }
+
+    Ok((image.digest.clone(), pull::load_config(&image, store)?))
+}
+
+// An image whose blobs are all in the store already
+fn extract_cached(
+    image: pull::ResolvedImage,
+    settings: &PullSettings<'_>,
+    store: &Store,
+    target_dir: &Path,
+) -> Result<pull::ResolvedImage> {
+    if let Some(lockfile) = settings.lockfile {
+        lockfile.verify(&image)?;
+    }
+    let space = Space::check(target_dir, &image, store, true);
+    let skipped = unpack::apply_layers(
+        store,
+        &image.manifest.layers,
+        target_dir,
+        settings.max_layer_size,
+        settings.entry_policy(),
+    )
+    .map_err(|error| space.explain(error, target_dir, &image))?;
+    report_skipped(&image, skipped);
+    Ok(image)
+}
+
+async fn pull_and_extract(
+    client: &RegistryClient,
+    reference: Reference,
+    settings: &PullSettings<'_>,
+    store: &Store,
+    target_dir: &Path,
+) -> Result<pull::ResolvedImage> {
+    let (max_layer_size, policy) = (settings.max_layer_size, settings.entry_policy());
+    let image = pull::resolve(client, reference, settings.platform).await?;
+    // Before any blob is fetched, so a moved tag costs nothing but the manifests
+    if let Some(lockfile) = settings.lockfile {
+        lockfile.verify(&image)?;
+    }
+    let space = Space::check(target_dir, &image, store, true);
+    let skipped = if settings.pipeline {
+        // The extractor runs on a thread of its own, with its own copies
+        let (extract_store, layers, rootfs) = (
+            store.clone(),
+            image.manifest.layers.clone(),
+            target_dir.to_path_buf(),
+        );
+        let skipped = Arc::new(AtomicU64::new(0));
+        let extract_skipped = skipped.clone();
+        pull::pull_pipelined(client, &image, store, move |ready| {
+            let skipped = unpack::apply_layers(
+                &extract_store,
+                &layers[..ready],
+                &rootfs,
+                max_layer_size,
+                policy,
+            )?;
+            extract_skipped.fetch_add(skipped, Ordering::Relaxed);
+            Ok(())
+        })
+        .await
+        .map_err(|error| space.explain(error, target_dir, &image))?;
+        skipped.load(Ordering::Relaxed)
+    } else {
+        pull::pull(client, &image, store)
+            .await
+            .and_then(|_| {
+                unpack::apply_layers(
+                    store,
+                    &image.manifest.layers,
+                    target_dir,
+                    max_layer_size,
+                    policy,
+                )
+            })
+            .map_err(|error| space.explain(error, target_dir, &image))?
+    };
+    report_skipped(&image, skipped);
+    Ok(image)
+}
+
+// The per-entry warnings scroll by during extraction, this is the one to notice
+fn report_skipped(image: &pull::ResolvedImage, skipped: u64) {
+    if skipped > 0 {
+        eprintln!(
+            "warning: {} entries of {} couldn't be extracted and were left out; \
+             --strict-unpack fails instead",
+            skipped, image.reference
+        );
+    }
 }
-#[derive(Deserialize)]
-struct Manifest {
-    layers: Vec<Layer>,
+
+// Running out of space halfway through a pull fails with ENOSPC somewhere deep in a layer. The
+// estimate is the layers still to download, plus as much again when they're extracted too, a
+// lower bound since the manifest only has compressed sizes.
+struct Space {
+    free: Option<u64>,
+    needed: u64,
+}
+
+impl Space {
+    // Warns up front when the estimate is already more than there is
+    fn check(
+        target_dir: &Path,
+        image: &pull::ResolvedImage,
+        store: &Store,
+        extract: bool,
+    ) -> Space {
+        let layers = image.manifest.unique_layers();
+        let extracted: u64 = if extract {
+            layers.iter().map(|layer| layer.size).sum()
+        } else {
+            0
+        };
+        let downloads: u64 = layers
+            .iter()
+            .filter(|layer| !store.has_blob(&layer.digest))
+            .map(|layer| layer.size)
+            .sum();
+        let space = Space {
+            free: data_root::available_space(target_dir),
+            needed: downloads + extracted,
+        };
+        match space.free {
+            Some(free) if free < space.needed => eprintln!(
+                "warning: only {} free at {}, {} needs about {}; --data-root moves everything to \
+                 a bigger filesystem",
+                pull::human_size(free),
+                target_dir.display(),
+                image.reference,
+                pull::human_size(space.needed)
+            ),
+            _ => {}
+        }
+        space
+    }
+
+    // Says which filesystem filled up and by how much it fell short. By then the layer being
+    // extracted and any blob being written are gone again; the blobs already in the store are
+    // complete and stay for the next attempt, and the caller removes the rootfs.
+    fn explain(
+        &self,
+        error: anyhow::Error,
+        target_dir: &Path,
+        image: &pull::ResolvedImage,
+    ) -> anyhow::Error {
+        if !data_root::is_out_of_space(&error) {
+            return error;
+        }
+        let filesystem = data_root::filesystem(target_dir)
+            .unwrap_or_else(|| format!("the filesystem of {}", target_dir.display()));
+        let free = self
+            .free
+            .map(pull::human_size)
+            .unwrap_or_else(|| "an unknown amount".to_string());
+        error.context(format!(
+            "Ran out of space on {} pulling {}: {} was free at the start, it needs about {}; \
+             `system prune` frees cache space, --data-root moves everything to a bigger \
+             filesystem",
+            filesystem,
+            image.reference,
+            free,
+            pull::human_size(self.needed)
+        ))
+    }
 }
-#[derive(Deserialize)]
-struct Layer {
-    mediaType: String,
-    digest: String,
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+
+    // A container whose rootfs has these files, each with the given contents
+    fn container_fs(files: &[(&str, &[u8])]) -> (tempfile::TempDir, copy::ContainerFs) {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, contents) in files {
+            let host = dir.path().join(path.trim_start_matches('/'));
+            std::fs::create_dir_all(host.parent().unwrap()).unwrap();
+            std::fs::write(host, contents).unwrap();
+        }
+        let state: container::ContainerState = serde_json::from_value(serde_json::json!({
+            "id": "0123456789abcdef",
+            "image": "test",
+            "rootfs": dir.path(),
+            "created": 0,
+            "status": "running",
+        }))
+        .unwrap();
+        let fs = copy::ContainerFs::new(&state).unwrap();
+        (dir, fs)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn sh_uses_the_declared_shell_then_the_usual_ones() {
+        let (_dir, fs) = container_fs(&[("/bin/sh", b""), ("/bin/bash", b"")]);
+        let declared = strings(&["/bin/bash", "-euc"]);
+        assert_eq!(
+            shell_form(Some(&declared), &fs, "test").unwrap(),
+            ["/bin/bash", "-euc"]
+        );
+        assert_eq!(shell_form(None, &fs, "test").unwrap(), ["/bin/sh", "-c"]);
+        assert_eq!(
+            shell_form(Some(&[]), &fs, "test").unwrap(),
+            ["/bin/sh", "-c"]
+        );
+        let missing = strings(&["/bin/zsh", "-c"]);
+        assert_eq!(
+            shell_form(Some(&missing), &fs, "test").unwrap(),
+            ["/bin/sh", "-c"]
+        );
+        // Found on the container's PATH, if at all
+        let by_name = strings(&["pwsh", "-Command"]);
+        assert_eq!(
+            shell_form(Some(&by_name), &fs, "test").unwrap(),
+            ["pwsh", "-Command"]
+        );
+
+        let (_dir, fs) = container_fs(&[("/bin/ash", b"")]);
+        assert_eq!(shell_form(None, &fs, "test").unwrap(), ["/bin/ash", "-c"]);
+        let (_dir, fs) = container_fs(&[("/app", b"")]);
+        let error = shell_form(None, &fs, "scratch").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--sh needs a shell, but scratch has none of /bin/sh, /bin/ash, /bin/bash"
+        );
+    }
+
+    #[test]
+    fn a_missing_command_is_explained() {
+        let (_dir, fs) = container_fs(&[
+            ("/usr/bin/hello", b"\x7fELF"),
+            ("/opt/tool/run", b"#!/usr/bin/python3 -u\n"),
+        ]);
+        let env = strings(&["PATH=/nowhere", "PATH=/opt/tool:/usr/bin"]);
+        assert_eq!(
+            explain_not_found("/bin/nope", &env, &fs),
+            " (/bin/nope isn't in the image)"
+        );
+        assert_eq!(
+            explain_not_found("nope", &env, &fs),
+            " (nope isn't on the image's PATH)"
+        );
+        assert_eq!(
+            explain_not_found("run", &env, &fs),
+            " (/opt/tool/run is a script for /usr/bin/python3, which isn't in the image)"
+        );
+        assert!(explain_not_found("hello", &env, &fs).contains("dynamic loader"));
+        assert!(explain_not_found("hello", &[], &fs).contains("/usr/bin/hello is there"));
+    }
+
+    // Everything written to any of its clones, in the order it was written
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn service(script: &str) -> tokio::process::Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn relayed_lines_stay_whole_and_in_order() {
+        let output = Captured::default();
+        writeln!(output.clone(), "starting web").unwrap();
+        let mut child = service("i=1; while [ $i -le 1000 ]; do echo $i; i=$((i + 1)); done");
+        let relayed = [
+            forward_lines(child.stdout.take(), "web | ".to_string(), output.clone()),
+            forward_lines(child.stderr.take(), "web | ".to_string(), output.clone()),
+        ];
+        assert!(child.wait().await.unwrap().success());
+        for relay in relayed {
+            relay.await.unwrap();
+        }
+        writeln!(output.clone(), "web exited").unwrap();
+
+        let mut expected = vec!["starting web".to_string()];
+        expected.extend((1..=1000).map(|number| format!("web | {}", number)));
+        expected.push("web exited".to_string());
+        assert_eq!(output.text().lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[tokio::test]
+    async fn a_partial_line_is_relayed_before_the_rest_arrives() {
+        let output = Captured::default();
+        let mut child = service("printf 'name? '; read name; echo hi $name");
+        let relay = forward_lines(child.stdout.take(), "web | ".to_string(), output.clone());
+        let started = Instant::now();
+        while output.text().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(output.text(), "web | name? ");
+
+        // The prompt's answer: none at all
+        drop(child.stdin.take());
+        child.wait().await.unwrap();
+        relay.await.unwrap();
+        assert_eq!(output.text(), "web | name? hi\n");
+    }
+
+    #[tokio::test]
+    async fn a_service_outlives_the_reader_of_our_output() {
+        supervise::ignore_sigpipe();
+        // `up | head -1` once head has gone: a pipe with nothing left at the other end
+        let mut ends = [0; 2];
+        assert_eq!(unsafe { libc::pipe(ends.as_mut_ptr()) }, 0);
+        unsafe { libc::close(ends[0]) };
+        let closed = unsafe { std::fs::File::from_raw_fd(ends[1]) };
+
+        // Far more than a pipe holds, so it only finishes if someone keeps reading
+        let mut child =
+            service("i=0; while [ $i -lt 20000 ]; do echo line $i; i=$((i + 1)); done; exit 7");
+        let relay = forward_lines(child.stdout.take(), "web | ".to_string(), closed);
+        let status = tokio::time::timeout(Duration::from_secs(30), child.wait())
+            .await
+            .expect("the service blocked on its output")
+            .unwrap();
+        assert_eq!(supervise::exit_code(status), 7);
+        relay.await.unwrap();
+    }
 }
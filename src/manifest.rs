@@ -0,0 +1,482 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+pub const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+pub fn is_index(media_type: &str) -> bool {
+    media_type == DOCKER_MANIFEST_LIST || media_type == OCI_INDEX
+}
+
+// For documents read back from the store, where there's no Content-Type header to go by
+pub fn document_media_type(document: &[u8]) -> Option<String> {
+    let document = serde_json::from_slice::<serde_json::Value>(document).ok()?;
+    match document.get("mediaType").and_then(|value| value.as_str()) {
+        Some(media_type) => Some(media_type.to_string()),
+        // mediaType is optional in OCI documents, but only an index has "manifests"
+        None if document.get("manifests").is_some() => Some(OCI_INDEX.to_string()),
+        None if document.get("layers").is_some() => Some(OCI_MANIFEST.to_string()),
+        None => None,
+    }
+}
+
+pub fn is_index_document(document: &[u8]) -> bool {
+    document_media_type(document)
+        .map(|media_type| is_index(&media_type))
+        .unwrap_or(false)
+}
+
+// An image manifest, docker v2 schema 2 or OCI. Fields we don't model are ignored, registries
+// and build tools add their own.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    // Optional in OCI, where the Content-Type header is all there is
+    #[serde(default)]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn parse(document: &[u8]) -> Result<Manifest> {
+        let value = serde_json::from_slice::<serde_json::Value>(document)?;
+        // The signed schema 1 has neither config nor layers, which would make a confusing error
+        if value
+            .get("schemaVersion")
+            .and_then(|version| version.as_u64())
+            == Some(1)
+        {
+            bail!("Schema 1 manifests are not supported, the image needs pushing again with a recent tool");
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    // Layers with repeated digests removed, keeping first-occurrence order. Some older tooling
+    // lists the same blob more than once; it only needs downloading once, but extraction must
+    // still happen at every position so whiteouts apply in the right order.
+    pub fn unique_layers(&self) -> Vec<&Descriptor> {
+        let mut seen = HashSet::new();
+        self.layers
+            .iter()
+            .filter(|layer| seen.insert(layer.digest.as_str()))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    // Where a foreign (non-distributable) layer can be fetched from if the registry doesn't
+    // have it
+    #[serde(default)]
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+// The image config blob. Only the parts that affect how the container is started are modelled.
+#[derive(Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub config: Option<ContainerConfig>,
+    // What the binaries in the image were built for
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub architecture: Option<String>,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl ImageConfig {
+    // None for images that don't say, which we give the benefit of the doubt
+    pub fn platform(&self) -> Option<Platform> {
+        match (&self.os, &self.architecture) {
+            (Some(os), Some(architecture)) if !os.is_empty() && !architecture.is_empty() => {
+                Some(Platform {
+                    os: os.clone(),
+                    architecture: architecture.clone(),
+                    variant: self.variant.clone().filter(|variant| !variant.is_empty()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerConfig {
+    pub env: Option<Vec<String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+    // "80/tcp" -> {}, the values carry nothing
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+    // "SIGQUIT", "QUIT" or a number
+    pub stop_signal: Option<String>,
+    // "name", "uid", "name:group" or "uid:gid"
+    pub user: Option<String>,
+    // Paths that should get a volume rather than live in the rootfs: "/data" -> {}
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+    // Build instructions for images built FROM this one; nothing for us to run
+    pub on_build: Option<Vec<String>>,
+    // What shell-form commands run through, from the Dockerfile's SHELL: ["/bin/bash", "-c"]
+    pub shell: Option<Vec<String>>,
+    pub healthcheck: Option<Healthcheck>,
+}
+
+// The Dockerfile's HEALTHCHECK. Test is ["CMD", <argv>...], ["CMD-SHELL", <line>] or ["NONE"];
+// the durations are nanoseconds, and 0 (like a missing field) means docker's default.
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Healthcheck {
+    pub test: Option<Vec<String>>,
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub start_period: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+// A manifest list (docker) or image index (OCI), pointing at one manifest per platform
+#[derive(Deserialize)]
+pub struct Index {
+    pub manifests: Vec<IndexEntry>,
+}
+
+impl Index {
+    pub fn select(&self, platform: &Platform) -> Option<&IndexEntry> {
+        self.manifests.iter().find(|entry| {
+            entry
+                .platform
+                .as_ref()
+                .map(|candidate| candidate.matches(platform))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IndexEntry {
+    pub digest: String,
+    pub platform: Option<Platform>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    // Parse the "os/arch[/variant]" form used by --platform
+    pub fn parse(value: &str) -> Result<Platform> {
+        let parts: Vec<&str> = value.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|part| part.is_empty()) {
+            bail!("Invalid platform '{}', expected os/arch[/variant]", value);
+        }
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|variant| variant.to_string()),
+        })
+    }
+
+    // The platform matching the machine we're running on, using the GOARCH names registries use
+    pub fn host() -> Platform {
+        let (architecture, variant) = match std::env::consts::ARCH {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", None),
+            "arm" => ("arm", Some("v7")),
+            // Registries only know the little-endian flavour as ppc64le
+            "powerpc64" if cfg!(target_endian = "little") => ("ppc64le", None),
+            "powerpc64" => ("ppc64", None),
+            "riscv64" => ("riscv64", None),
+            "s390x" => ("s390x", None),
+            other => (other, None),
+        };
+        Platform {
+            os: std::env::consts::OS.to_string(),
+            architecture: architecture.to_string(),
+            variant: variant.map(str::to_string),
+        }
+    }
+
+    // A missing variant on either side matches any variant, since registries are inconsistent
+    // about filling it in (e.g. arm64 entries may or may not say "v8")
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && match (&self.variant, &other.variant) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+
+    // Whether binaries built for this platform run natively on host. Like matches(), except
+    // that ARM is backwards compatible: a v7 machine runs v6 and v5 code, but not the reverse.
+    pub fn runs_on(&self, host: &Platform) -> bool {
+        if self.os != host.os || self.architecture != host.architecture {
+            return false;
+        }
+        match (&self.variant, &host.variant) {
+            (Some(image), Some(host)) if self.architecture == "arm" => {
+                match (arm_version(image), arm_version(host)) {
+                    (Some(image), Some(host)) => image <= host,
+                    _ => image == host,
+                }
+            }
+            (Some(image), Some(host)) => image == host,
+            _ => true,
+        }
+    }
+}
+
+// "v7" -> 7
+fn arm_version(variant: &str) -> Option<u32> {
+    variant.strip_prefix('v')?.parse().ok()
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // alpine:3.19 for linux/amd64 on Docker Hub, a docker v2 schema 2 manifest
+    const DOCKER_HUB: &str = r#"{
+   "schemaVersion": 2,
+   "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+   "config": {
+      "mediaType": "application/vnd.docker.container.image.v1+json",
+      "size": 1471,
+      "digest": "sha256:05455a08881ea9cf0e752bc48e61bbd71a34c029bb13df01e40e3e70e0d007bd"
+   },
+   "layers": [
+      {
+         "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+         "size": 3408729,
+         "digest": "sha256:4abcf20661432fb2d719aaf90656f55c287f8ca915dc1c92ec14ff61e67fbaf8"
+      }
+   ]
+}"#;
+
+    // An image pushed to GHCR by buildx: OCI, with annotations on the manifest and its layers
+    const GHCR: &str = r#"{
+  "schemaVersion": 2,
+  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+  "config": {
+    "mediaType": "application/vnd.oci.image.config.v1+json",
+    "digest": "sha256:a5b6f3d2a3d1c61b12c10eb3fb8f7cd0a8e6ef0ba0c5bbd0d6b1c3d1bd2a2f41",
+    "size": 2103
+  },
+  "layers": [
+    {
+      "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+      "digest": "sha256:c926b61bad3b94ae7351bafd0c184c159ebf0643b085f7ef1d47ecdc7316833c",
+      "size": 29150574,
+      "annotations": {
+        "org.opencontainers.image.title": "base"
+      }
+    }
+  ],
+  "annotations": {
+    "org.opencontainers.image.created": "2024-01-15T10:21:33Z",
+    "org.opencontainers.image.source": "https://github.com/example/app"
+  }
+}"#;
+
+    // An OCI manifest from Quay without a mediaType, with fields we don't model. The Windows
+    // base layer is foreign and only available from its urls.
+    const QUAY: &str = r#"{
+  "schemaVersion": 2,
+  "config": {
+    "mediaType": "application/vnd.oci.image.config.v1+json",
+    "digest": "sha256:8e1f5cf4d9e0b5d3ce4a1fd66e0ba1c0e0f6c2f2e7a6b1a1dbd2a0e5f7bcb4a1",
+    "size": 904,
+    "platform": {"architecture": "amd64", "os": "linux"}
+  },
+  "layers": [
+    {
+      "mediaType": "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+      "digest": "sha256:3889bb8d808bbae6fa5a33e07093e65c31371bcf9e4c38ef5d1a0a7e0e80c69c",
+      "size": 1532637846,
+      "urls": ["https://mcr.microsoft.com/v2/windows/nanoserver/blobs/sha256:3889bb8d808bbae6fa5a33e07093e65c31371bcf9e4c38ef5d1a0a7e0e80c69c"]
+    }
+  ],
+  "subject": null,
+  "quay.expires-after": "2w"
+}"#;
+
+    fn platform(value: &str) -> Platform {
+        Platform::parse(value).unwrap()
+    }
+
+    #[test]
+    fn what_runs_where() {
+        let cases = [
+            ("linux/amd64", "linux/amd64", true),
+            ("linux/amd64", "linux/arm64", false),
+            ("linux/arm64", "linux/amd64", false),
+            ("linux/amd64", "windows/amd64", false),
+            // Older ARM code runs on newer ARM machines, not the other way round
+            ("linux/arm/v6", "linux/arm/v7", true),
+            ("linux/arm/v5", "linux/arm/v7", true),
+            ("linux/arm/v7", "linux/arm/v7", true),
+            ("linux/arm/v7", "linux/arm/v6", false),
+            ("linux/arm/v8", "linux/arm/v7", false),
+            ("linux/arm/v7", "linux/arm64/v8", false),
+            ("linux/arm64/v8", "linux/arm64/v8", true),
+            // A variant missing on either side is taken on trust
+            ("linux/arm", "linux/arm/v6", true),
+            ("linux/arm/v7", "linux/arm", true),
+            ("linux/arm64", "linux/arm64/v8", true),
+            ("linux/arm64/v8", "linux/arm64", true),
+            // Variants that aren't versions have to be the same
+            ("linux/arm/vfp", "linux/arm/v7", false),
+            ("linux/arm/vfp", "linux/arm/vfp", true),
+            ("linux/amd64/v3", "linux/amd64/v2", false),
+        ];
+        for (image, host, runs) in cases {
+            assert_eq!(
+                platform(image).runs_on(&platform(host)),
+                runs,
+                "{} on {}",
+                image,
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn matching_is_symmetric_and_ignores_arm_versions() {
+        assert!(platform("linux/arm64").matches(&platform("linux/arm64/v8")));
+        assert!(platform("linux/arm64/v8").matches(&platform("linux/arm64")));
+        assert!(!platform("linux/arm/v6").matches(&platform("linux/arm/v7")));
+        assert!(!platform("linux/arm/v7").matches(&platform("linux/arm/v6")));
+        assert!(!platform("linux/amd64").matches(&platform("linux/386")));
+    }
+
+    #[test]
+    fn the_host_uses_registry_names() {
+        let host = Platform::host();
+        assert_eq!(host.os, std::env::consts::OS);
+        let expected = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "big") => "ppc64",
+            "powerpc64" => "ppc64le",
+            other => other,
+        };
+        assert_eq!(host.architecture, expected);
+        assert!(host.runs_on(&host));
+        assert!(Platform::parse(&host.to_string()).unwrap() == host);
+    }
+
+    #[test]
+    fn platforms_parse_with_an_optional_variant() {
+        assert_eq!(platform("linux/arm/v7").variant.as_deref(), Some("v7"));
+        assert_eq!(platform("linux/amd64").variant, None);
+        for bad in ["linux", "linux/", "/amd64", "linux/arm/v7/x", ""] {
+            assert!(Platform::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn docker_hub_manifest() {
+        let manifest = Manifest::parse(DOCKER_HUB.as_bytes()).unwrap();
+        assert_eq!(manifest.media_type.as_deref(), Some(DOCKER_MANIFEST));
+        assert_eq!(manifest.config.size, 1471);
+        assert_eq!(manifest.layers.len(), 1);
+        let layer = &manifest.layers[0];
+        assert_eq!(
+            layer.media_type,
+            "application/vnd.docker.image.rootfs.diff.tar.gzip"
+        );
+        assert_eq!(layer.size, 3408729);
+        assert!(layer.urls.is_empty() && layer.annotations.is_empty());
+        assert!(manifest.annotations.is_empty());
+    }
+
+    #[test]
+    fn ghcr_manifest_keeps_annotations() {
+        let manifest = Manifest::parse(GHCR.as_bytes()).unwrap();
+        assert_eq!(manifest.media_type.as_deref(), Some(OCI_MANIFEST));
+        assert_eq!(
+            manifest.annotations["org.opencontainers.image.source"],
+            "https://github.com/example/app"
+        );
+        assert_eq!(
+            manifest.layers[0].annotations["org.opencontainers.image.title"],
+            "base"
+        );
+        assert_eq!(manifest.layers[0].size, 29150574);
+    }
+
+    #[test]
+    fn quay_manifest_tolerates_unknown_fields() {
+        let manifest = Manifest::parse(QUAY.as_bytes()).unwrap();
+        assert_eq!(manifest.media_type, None);
+        assert_eq!(manifest.config.size, 904);
+        let layer = &manifest.layers[0];
+        assert_eq!(layer.size, 1532637846);
+        assert_eq!(layer.urls.len(), 1);
+        assert!(layer.urls[0].starts_with("https://mcr.microsoft.com/"));
+        assert_eq!(
+            document_media_type(QUAY.as_bytes()).as_deref(),
+            Some(OCI_MANIFEST)
+        );
+    }
+
+    #[test]
+    fn descriptors_need_a_size() {
+        let document = r#"{"schemaVersion": 2, "config": {"mediaType": "x", "digest": "sha256:00"},
+            "layers": []}"#;
+        assert!(Manifest::parse(document.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn schema_1_is_turned_down() {
+        let document = r#"{"schemaVersion": 1, "name": "library/alpine", "fsLayers": []}"#;
+        let error = Manifest::parse(document.as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("Schema 1"), "{}", error);
+    }
+
+    #[test]
+    fn indexes_are_recognised_with_or_without_a_media_type() {
+        let index = r#"{"schemaVersion": 2, "manifests": []}"#;
+        assert_eq!(
+            document_media_type(index.as_bytes()).as_deref(),
+            Some(OCI_INDEX)
+        );
+        assert!(is_index_document(index.as_bytes()));
+        let list = format!(
+            r#"{{"mediaType": "{}", "manifests": []}}"#,
+            DOCKER_MANIFEST_LIST
+        );
+        assert!(is_index_document(list.as_bytes()));
+        assert!(!is_index_document(DOCKER_HUB.as_bytes()));
+        assert_eq!(document_media_type(b"not json"), None);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::manifest::{document_media_type, OCI_INDEX};
+use crate::pull::ResolvedImage;
+use crate::store::{write_atomic, Store};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs::{copy, create_dir_all, hard_link, read, rename};
+use std::path::Path;
+
+const LAYOUT_VERSION: &str = "1.0.0";
+// What skopeo's oci:<dir>:<name> and umoci's --image <dir>:<name> look an image up by
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+// containerd's, for tools that want the registry and repository as well as the name
+const IMAGE_NAME: &str = "io.containerd.image.name";
+
+// Adds a pulled image to an OCI image layout (image-layout.md in the image spec), creating the
+// layout if dir doesn't have one yet. The entry in index.json is the platform's manifest rather
+// than the registry's index, whose other platforms we never pulled. Blobs are hardlinked from
+// the store where they can be, so a layout on the same filesystem costs next to nothing; either
+// way they're the bytes the registry sent, which is what their digests are of. Other images
+// already in the layout stay, one with the same reference is replaced.
+pub fn write(dir: &Path, image: &ResolvedImage, store: &Store) -> Result<()> {
+    let blobs = dir.join("blobs/sha256");
+    create_dir_all(&blobs).with_context(|| format!("Failed to create {}", blobs.display()))?;
+    check_layout_file(dir)?;
+
+    let manifest_bytes = image
+        .documents
+        .iter()
+        .find(|(digest, _)| *digest == image.manifest_digest)
+        .map(|(_, bytes)| bytes)
+        .ok_or_else(|| anyhow!("No manifest document for {}", image.reference))?;
+    let descriptors = std::iter::once(&image.manifest.config).chain(&image.manifest.layers);
+    for digest in std::iter::once(&image.manifest_digest)
+        .chain(descriptors.map(|descriptor| &descriptor.digest))
+    {
+        link_blob(store, digest, dir)?;
+    }
+
+    let media_type = image
+        .manifest
+        .media_type
+        .clone()
+        .or_else(|| document_media_type(manifest_bytes))
+        .ok_or_else(|| anyhow!("Can't tell what kind of manifest {} has", image.reference))?;
+    let name = image.reference.to_string();
+    let mut entry = json!({
+        "mediaType": media_type,
+        "digest": image.manifest_digest,
+        "size": manifest_bytes.len(),
+        "annotations": {
+            REF_NAME: name,
+            IMAGE_NAME: name,
+        },
+    });
+    if let Some(platform) = &image.platform {
+        let mut value = json!({"architecture": platform.architecture, "os": platform.os});
+        if let Some(variant) = &platform.variant {
+            value["variant"] = json!(variant);
+        }
+        entry["platform"] = value;
+    }
+
+    let index_path = dir.join("index.json");
+    let mut index = if index_path.exists() {
+        let bytes = read(&index_path)
+            .with_context(|| format!("Failed to read {}", index_path.display()))?;
+        serde_json::from_slice::<Value>(&bytes)
+            .with_context(|| format!("{} isn't valid JSON", index_path.display()))?
+    } else {
+        json!({"schemaVersion": 2, "mediaType": OCI_INDEX, "manifests": []})
+    };
+    let manifests = index
+        .get_mut("manifests")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| anyhow!("{} has no manifests list", index_path.display()))?;
+    manifests.retain(|existing| existing["annotations"][REF_NAME].as_str() != Some(name.as_str()));
+    manifests.push(entry);
+    write_atomic(&index_path, &serde_json::to_vec_pretty(&index)?)
+        .with_context(|| format!("Failed to write {}", index_path.display()))
+}
+
+fn check_layout_file(dir: &Path) -> Result<()> {
+    let path = dir.join("oci-layout");
+    if !path.exists() {
+        let layout = json!({ "imageLayoutVersion": LAYOUT_VERSION });
+        return write_atomic(&path, &serde_json::to_vec(&layout)?)
+            .with_context(|| format!("Failed to write {}", path.display()));
+    }
+    let layout = serde_json::from_slice::<Value>(&read(&path)?)
+        .with_context(|| format!("{} isn't valid JSON", path.display()))?;
+    match layout["imageLayoutVersion"].as_str() {
+        Some(LAYOUT_VERSION) => Ok(()),
+        version => bail!(
+            "{} is an image layout of version {}, only {} is supported",
+            dir.display(),
+            version.unwrap_or("(none)"),
+            LAYOUT_VERSION
+        ),
+    }
+}
+
+// A blob the layout already has is left alone: the name is its digest, so it's the same bytes
+fn link_blob(store: &Store, digest: &str, dir: &Path) -> Result<()> {
+    let source = store.blob_path(digest)?;
+    let target = dir.join("blobs/sha256").join(source.file_name().unwrap());
+    if target.exists() {
+        return Ok(());
+    }
+    if !source.is_file() {
+        bail!("Blob {} is not in the store", digest);
+    }
+    // Another filesystem, or one that doesn't do hardlinks
+    if hard_link(&source, &target).is_err() {
+        let staging = target.with_extension(format!("{}.partial", std::process::id()));
+        copy(&source, &staging)
+            .with_context(|| format!("Failed to copy {} into the layout", digest))?;
+        rename(&staging, &target)?;
+    }
+    Ok(())
+}
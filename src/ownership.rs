@@ -0,0 +1,116 @@
+use crate::privileges::{self, SubordinateIds};
+use crate::store::write_atomic;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{read, remove_file, symlink_metadata};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+// Written next to the rootfs by apply_layers: every path the layers give to someone other than
+// root, as container path -> (uid, gid). Extraction never chowns, whoever we are, so this is
+// what root chowns from, what the container's user namespace applies with subordinate ids, and
+// all that's left of the image's intentions when neither is possible.
+const RECORD: &str = "ownership.json";
+
+const CHOWN_BATCH: usize = 256;
+
+pub type Owners = BTreeMap<String, (u32, u32)>;
+
+// A tar entry's path the way it ends up in the rootfs, "./usr/bin" and "usr/bin/" alike
+pub fn container_path(path: &Path) -> String {
+    let mut result = String::new();
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            result.push('/');
+            result.push_str(&part.to_string_lossy());
+        }
+    }
+    result
+}
+
+pub fn load(rootfs: &Path) -> Result<Owners> {
+    let path = rootfs.parent().unwrap().join(RECORD);
+    match read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Owners::new()),
+        Err(error) => Err(error).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+pub fn save(rootfs: &Path, owners: &Owners) -> Result<()> {
+    let path = rootfs.parent().unwrap().join(RECORD);
+    write_atomic(&path, &serde_json::to_vec_pretty(owners)?)
+}
+
+pub fn remove(rootfs: &Path) -> Result<()> {
+    match remove_file(rootfs.parent().unwrap().join(RECORD)) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
+
+// Fold the owners of every entry in a layer into what the layers below it said, keeping only
+// what still exists and isn't root's
+pub fn merge(owners: &mut Owners, layer: Owners, rootfs: &Path) {
+    owners.extend(layer);
+    owners.retain(|path, &mut ids| {
+        ids != (0, 0) && symlink_metadata(host_path(rootfs, path)).is_ok()
+    });
+}
+
+// Apply the recorded ownership directly, which takes root (or CAP_CHOWN)
+pub fn chown_all(rootfs: &Path, owners: &Owners) -> Result<()> {
+    for (path, &(uid, gid)) in owners {
+        let host = host_path(rootfs, path);
+        let c_path = CString::new(host.as_os_str().as_bytes())?;
+        if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to chown {}", host.display()));
+        }
+    }
+    Ok(())
+}
+
+// Apply it from a user namespace that maps container ids 1 and up to our subordinate ranges,
+// the same mapping the container gets, using the host's chown. Owners beyond the ranges can't
+// be represented and stay root; how many there were is returned.
+pub fn chown_mapped(rootfs: &Path, owners: &Owners, ids: SubordinateIds) -> Result<usize> {
+    let mut groups: BTreeMap<(u32, u32), Vec<PathBuf>> = BTreeMap::new();
+    let mut skipped = 0;
+    for (path, &(uid, gid)) in owners {
+        if uid > ids.uids.count || gid > ids.gids.count {
+            skipped += 1;
+            continue;
+        }
+        groups
+            .entry((uid, gid))
+            .or_default()
+            .push(host_path(rootfs, path));
+    }
+
+    for ((uid, gid), paths) in groups {
+        // Well clear of ARG_MAX whatever the paths look like
+        for chunk in paths.chunks(CHOWN_BATCH) {
+            let mut command = Command::new("chown");
+            command
+                .arg("-h")
+                .arg(format!("{}:{}", uid, gid))
+                .arg("--")
+                .args(chunk);
+            let status = privileges::run_mapped(&mut command, ids)
+                .context("Failed to chown the rootfs to our subordinate ids")?;
+            if !status.success() {
+                bail!("chown {}:{} failed in the user namespace", uid, gid);
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+fn host_path(rootfs: &Path, path: &str) -> PathBuf {
+    rootfs.join(path.trim_start_matches('/'))
+}
@@ -0,0 +1,352 @@
+use crate::rootfs::create_dir;
+use anyhow::{bail, Context, Result};
+use std::fs::{read_to_string, remove_file, set_permissions, symlink_metadata, write, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// What a synthesized account gets: nowhere in particular to live and no login
+const SYNTHESIZED_HOME: &str = "/";
+const SYNTHESIZED_SHELL: &str = "/sbin/nologin";
+
+// One line of /etc/passwd: name:password:uid:gid:gecos:home:shell
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+// One line of /etc/group: name:password:gid:member,member
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+    pub members: Vec<String>,
+}
+
+// Who the container's process runs as
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    // Supplementary groups, from the memberships in /etc/group
+    pub groups: Vec<u32>,
+    // The account's home directory, if it has an account
+    pub home: Option<String>,
+}
+
+// Lines that don't parse are skipped rather than failing the lookup: comments, NIS "+" and "-"
+// entries, blank lines, and whatever an image's build left half-written. Busybox and glibc
+// agree on the format, busybox just leaves gecos empty more often.
+pub fn parse_passwd(text: &str) -> Vec<Account> {
+    records(text)
+        .filter_map(|fields| {
+            if fields.len() < 4 {
+                return None;
+            }
+            Some(Account {
+                name: fields[0].to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok()?,
+                home: fields.get(5).unwrap_or(&"").to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn parse_group(text: &str) -> Vec<Group> {
+    records(text)
+        .filter_map(|fields| {
+            if fields.len() < 3 {
+                return None;
+            }
+            Some(Group {
+                name: fields[0].to_string(),
+                gid: fields[2].parse().ok()?,
+                members: fields
+                    .get(3)
+                    .unwrap_or(&"")
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|member| !member.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn records(text: &str) -> impl Iterator<Item = Vec<&str>> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.starts_with('#') && !line.starts_with('+') && !line.starts_with('-'))
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|fields| !fields[0].trim().is_empty())
+}
+
+pub fn accounts(rootfs: &Path) -> Vec<Account> {
+    parse_passwd(&read_image_file(rootfs, "etc/passwd").unwrap_or_default())
+}
+
+pub fn groups(rootfs: &Path) -> Vec<Group> {
+    parse_group(&read_image_file(rootfs, "etc/group").unwrap_or_default())
+}
+
+// The image's own file, never the host's: a symlink there would resolve against the host's
+// filesystem from out here, so it counts as missing
+fn read_image_file(rootfs: &Path, path: &str) -> Option<String> {
+    let path = rootfs.join(path);
+    if symlink_metadata(&path).ok()?.file_type().is_symlink() {
+        return None;
+    }
+    read_to_string(path).ok()
+}
+
+// A User like docker's --user: "", "name", "uid", "name:group" or "uid:gid". Names are looked
+// up in the image's own /etc/passwd and /etc/group; a uid or gid doesn't need to be there.
+pub fn resolve(rootfs: &Path, user: &str) -> Result<User> {
+    if user.is_empty() {
+        return resolve(rootfs, "0");
+    }
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+
+    let accounts = accounts(rootfs);
+    let account = match user.parse::<u32>() {
+        Ok(uid) => accounts.iter().find(|account| account.uid == uid),
+        Err(_) => accounts.iter().find(|account| account.name == user),
+    };
+    let uid = match (user.parse::<u32>(), account) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(account)) => account.uid,
+        (Err(_), None) => bail!("User {} is not in the image's /etc/passwd", user),
+    };
+
+    let groups = groups(rootfs);
+    let gid = match group {
+        None => account.map_or(0, |account| account.gid),
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => match groups.iter().find(|entry| entry.name == group) {
+                Some(entry) => entry.gid,
+                None => bail!("Group {} is not in the image's /etc/group", group),
+            },
+        },
+    };
+    // An explicit group replaces the account's memberships, as in docker
+    let supplementary = match (account, group) {
+        (Some(account), None) => groups
+            .iter()
+            .filter(|entry| entry.gid != gid && entry.members.contains(&account.name))
+            .map(|entry| entry.gid)
+            .collect(),
+        _ => vec![],
+    };
+
+    Ok(User {
+        uid,
+        gid,
+        groups: supplementary,
+        home: account.map(|account| account.home.clone()),
+    })
+}
+
+// For --synthesize-user: give an image without /etc/passwd or /etc/group (scratch, distroless)
+// entries for root and the numeric user it's run as, so lookups of the current user (Go's
+// os/user, ssh) find something. Files the image has are left alone. Returns what was written.
+pub fn synthesize(rootfs: &Path, uid: u32, gid: u32) -> Result<Vec<PathBuf>> {
+    let mut written = vec![];
+    let mut passwd = format!("root:x:0:0:root:/root:{}\n", SYNTHESIZED_SHELL);
+    if uid != 0 {
+        passwd.push_str(&format!(
+            "user:x:{}:{}::{}:{}\n",
+            uid, gid, SYNTHESIZED_HOME, SYNTHESIZED_SHELL
+        ));
+    }
+    if write_missing(rootfs, "etc/passwd", &passwd)? {
+        written.push(PathBuf::from("/etc/passwd"));
+    }
+    let mut group = "root:x:0:\n".to_string();
+    if gid != 0 {
+        group.push_str(&format!("user:x:{}:\n", gid));
+    }
+    if write_missing(rootfs, "etc/group", &group)? {
+        written.push(PathBuf::from("/etc/group"));
+    }
+    Ok(written)
+}
+
+fn write_missing(rootfs: &Path, path: &str, contents: &str) -> Result<bool> {
+    let path = rootfs.join(path);
+    match symlink_metadata(&path) {
+        // Same as reading: a symlink is as good as missing, and not to be written through
+        Ok(metadata) if metadata.file_type().is_symlink() => remove_file(&path)?,
+        Ok(_) => return Ok(false),
+        Err(_) => {}
+    }
+    create_dir(path.parent().unwrap())?;
+    write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+    set_permissions(&path, Permissions::from_mode(0o644))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use std::os::unix::fs::symlink;
+
+    // Trimmed from alpine's, with the things images get wrong added
+    const BUSYBOX_PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/ash
+# added by the Dockerfile
+
+daemon:x:2:2:daemon:/sbin:/sbin/nologin
+nobody:x:65534:65534:nobody:/:/sbin/nologin
+app:x:1000:1000:Linux User,,,:/home/app:/bin/sh
+1001:x:1001:1001::/home/1001:/bin/sh
+short:x:5
+nouid:x::100::/:/bin/sh
+";
+
+    // Trimmed from debian's, CRLF and all
+    const GLIBC_PASSWD: &str = "root:x:0:0:root:/root:/bin/bash\r\n\
+www-data:x:33:33:www-data:/var/www:/usr/sbin/nologin\r\n\
++nisuser::::::\r\n\
+-excluded\r\n\
+postgres:x:999:999::/var/lib/postgresql\r\n\
+brief:x:500:500\r\n";
+
+    const BUSYBOX_GROUP: &str = "\
+root:x:0:root
+wheel:x:10:root,app
+# comment
+audio:x:18: app , , other
+app:x:1000:
+1001:x:1001:
+broken:x
+nogid:x::app
+";
+
+    const GLIBC_GROUP: &str = "root:x:0:\r\n\
+sudo:x:27:postgres\r\n\
++:::\r\n\
+postgres:x:999:\r\n\
+ssl-cert:x:101:postgres\r\n";
+
+    fn names(accounts: &[Account]) -> Vec<&str> {
+        accounts
+            .iter()
+            .map(|account| account.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn busybox_passwd() {
+        let accounts = parse_passwd(BUSYBOX_PASSWD);
+        assert_eq!(
+            names(&accounts),
+            ["root", "daemon", "nobody", "app", "1001"]
+        );
+        let app = &accounts[3];
+        assert_eq!(
+            (app.uid, app.gid, app.home.as_str()),
+            (1000, 1000, "/home/app")
+        );
+        assert_eq!(accounts[4].uid, 1001);
+    }
+
+    #[test]
+    fn glibc_passwd() {
+        let accounts = parse_passwd(GLIBC_PASSWD);
+        assert_eq!(names(&accounts), ["root", "www-data", "postgres", "brief"]);
+        assert_eq!(accounts[0].home, "/root");
+        assert_eq!(accounts[2].home, "/var/lib/postgresql");
+        // Four fields are enough, the home is just empty
+        assert_eq!((accounts[3].uid, accounts[3].home.as_str()), (500, ""));
+    }
+
+    #[test]
+    fn groups_from_either() {
+        let groups = parse_group(BUSYBOX_GROUP);
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|group| (group.name.as_str(), group.gid, group.members.join(",")))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("root", 0, "root".to_string()),
+                ("wheel", 10, "root,app".to_string()),
+                ("audio", 18, "app,other".to_string()),
+                ("app", 1000, String::new()),
+                ("1001", 1001, String::new()),
+            ]
+        );
+
+        let groups = parse_group(GLIBC_GROUP);
+        let names: Vec<_> = groups.iter().map(|group| group.name.as_str()).collect();
+        assert_eq!(names, ["root", "sudo", "postgres", "ssl-cert"]);
+        assert_eq!(groups[3].members, ["postgres"]);
+    }
+
+    fn rootfs(passwd: &str, group: &str) -> tempfile::TempDir {
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir_all(rootfs.path().join("etc")).unwrap();
+        write(rootfs.path().join("etc/passwd"), passwd).unwrap();
+        write(rootfs.path().join("etc/group"), group).unwrap();
+        rootfs
+    }
+
+    #[test]
+    fn users_resolve_by_name_or_number() {
+        let rootfs = rootfs(BUSYBOX_PASSWD, BUSYBOX_GROUP);
+        let user = resolve(rootfs.path(), "app").unwrap();
+        assert_eq!((user.uid, user.gid), (1000, 1000));
+        assert_eq!(user.groups, [10, 18]);
+        assert_eq!(user.home.as_deref(), Some("/home/app"));
+
+        // A number is always a uid, even when an account is named after one
+        let user = resolve(rootfs.path(), "1001").unwrap();
+        assert_eq!((user.uid, user.gid), (1001, 1001));
+        let user = resolve(rootfs.path(), "4242").unwrap();
+        assert_eq!((user.uid, user.gid, user.home), (4242, 0, None));
+
+        let user = resolve(rootfs.path(), "app:wheel").unwrap();
+        assert_eq!((user.uid, user.gid), (1000, 10));
+        assert!(user.groups.is_empty());
+        let user = resolve(rootfs.path(), "").unwrap();
+        assert_eq!((user.uid, user.gid), (0, 0));
+
+        assert!(resolve(rootfs.path(), "short").is_err());
+        assert!(resolve(rootfs.path(), "app:nogroup").is_err());
+    }
+
+    #[test]
+    fn glibc_users_resolve_with_their_groups() {
+        let rootfs = rootfs(GLIBC_PASSWD, GLIBC_GROUP);
+        let user = resolve(rootfs.path(), "postgres").unwrap();
+        assert_eq!((user.uid, user.gid), (999, 999));
+        assert_eq!(user.groups, [27, 101]);
+        assert!(resolve(rootfs.path(), "nisuser").is_err());
+    }
+
+    #[test]
+    fn symlinked_files_count_as_missing() {
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir_all(rootfs.path().join("etc")).unwrap();
+        symlink("/etc/passwd", rootfs.path().join("etc/passwd")).unwrap();
+        assert!(accounts(rootfs.path()).is_empty());
+        assert!(resolve(rootfs.path(), "root").is_err());
+
+        let written = synthesize(rootfs.path(), 1000, 1000).unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(!symlink_metadata(rootfs.path().join("etc/passwd"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        let user = resolve(rootfs.path(), "user").unwrap();
+        assert_eq!((user.uid, user.gid), (1000, 1000));
+        assert!(synthesize(rootfs.path(), 1000, 1000).unwrap().is_empty());
+    }
+}
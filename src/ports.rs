@@ -0,0 +1,183 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+// One -p [hostip:][hostport:]containerport[/tcp] mapping
+#[derive(Clone, Copy)]
+pub struct PortMapping {
+    pub host_ip: IpAddr,
+    // 0 asks the kernel for a free port, like docker's `-p 80`
+    pub host_port: u16,
+    pub container_port: u16,
+}
+
+impl PortMapping {
+    pub fn parse(value: &str) -> Result<PortMapping> {
+        let (spec, protocol) = value.split_once('/').unwrap_or((value, "tcp"));
+        if protocol != "tcp" {
+            bail!("Only tcp ports can be published, got '{}'", value);
+        }
+
+        // An IPv6 host address is bracketed so its colons don't get mistaken for separators
+        let (host_ip, rest) = match spec.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once("]:") {
+                Some((ip, rest)) => (Some(ip), rest),
+                None => bail!(
+                    "Invalid -p '{}', expected [ip]:hostport:containerport",
+                    value
+                ),
+            },
+            None => (None, spec),
+        };
+        let parts: Vec<&str> = rest.split(':').collect();
+        let (host_ip, host_port, container_port) = match (host_ip, parts.as_slice()) {
+            (None, [container]) => (None, "0", *container),
+            (None, [host, container]) => (None, *host, *container),
+            (None, [ip, host, container]) => (Some(*ip), *host, *container),
+            (Some(ip), [host, container]) => (Some(ip), *host, *container),
+            _ => bail!(
+                "Invalid -p '{}', expected [hostip:][hostport:]containerport",
+                value
+            ),
+        };
+
+        let host_ip = match host_ip {
+            Some(ip) => ip
+                .parse()
+                .with_context(|| format!("Invalid host address '{}' in -p", ip))?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        // "8080::80" style empty host ports mean "pick one" too
+        let host_port = if host_port.is_empty() {
+            0
+        } else {
+            parse_port(host_port, value)?
+        };
+        let container_port = parse_port(container_port, value)?;
+        if container_port == 0 {
+            bail!("Container port in -p '{}' must not be 0", value);
+        }
+
+        Ok(PortMapping {
+            host_ip,
+            host_port,
+            container_port,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}->{}/tcp",
+            SocketAddr::new(self.host_ip, self.host_port),
+            self.container_port
+        )
+    }
+}
+
+fn parse_port(port: &str, value: &str) -> Result<u16> {
+    port.parse()
+        .with_context(|| format!("Invalid port '{}' in -p '{}'", port, value))
+}
+
+// Point out mappings for ports the image never declared; usually a typo in the container port
+pub fn check_exposed(mappings: &[PortMapping], exposed: &BTreeMap<String, serde_json::Value>) {
+    if exposed.is_empty() {
+        return;
+    }
+    for mapping in mappings {
+        let key = format!("{}/tcp", mapping.container_port);
+        if !exposed.contains_key(&key) {
+            eprintln!(
+                "warning: -p {} publishes a port the image doesn't expose (it exposes {})",
+                mapping,
+                exposed.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+}
+
+// Mention declared ports nothing publishes, since that's usually what the image is for
+pub fn hint_unpublished(mappings: &[PortMapping], exposed: &BTreeMap<String, serde_json::Value>) {
+    for key in exposed.keys() {
+        let port = match key.strip_suffix("/tcp").unwrap_or(key).parse::<u16>() {
+            Ok(port) if !key.ends_with("/udp") => port,
+            _ => continue,
+        };
+        if !mappings
+            .iter()
+            .any(|mapping| mapping.container_port == port)
+        {
+            eprintln!(
+                "hint: the image exposes {}, publish it with -p <host port>:{}",
+                key, port
+            );
+        }
+    }
+}
+
+// Userspace TCP forwarders from host ports into the container. The container shares the host's
+// network namespace for now, so "into the container" means its port on loopback. Dropping the
+// proxy closes the listeners; connections already being relayed end when we exit.
+pub struct PortProxy {
+    tasks: Vec<JoinHandle<()>>,
+    // The mappings actually bound, with kernel-assigned host ports filled in
+    pub published: Vec<PortMapping>,
+}
+
+impl PortProxy {
+    // Binds everything up front so a busy port fails the run before the container starts
+    pub async fn start(mappings: &[PortMapping]) -> Result<PortProxy> {
+        let mut proxy = PortProxy {
+            tasks: vec![],
+            published: vec![],
+        };
+        for mapping in mappings {
+            let address = SocketAddr::new(mapping.host_ip, mapping.host_port);
+            if mapping.host_port == mapping.container_port {
+                // Same port on the same network: the container's own socket is already
+                // reachable, and our listener would only steal its bind
+                proxy.published.push(*mapping);
+                continue;
+            }
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Failed to publish port {}", address))?;
+            let published = PortMapping {
+                host_port: listener.local_addr()?.port(),
+                ..*mapping
+            };
+            proxy.published.push(published);
+            proxy
+                .tasks
+                .push(tokio::spawn(accept_loop(listener, mapping.container_port)));
+        }
+        Ok(proxy)
+    }
+}
+
+impl Drop for PortProxy {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn accept_loop(listener: TcpListener, container_port: u16) {
+    let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), container_port);
+    while let Ok((mut client, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            // Nothing listening in the container yet just drops the client, like docker-proxy
+            if let Ok(mut upstream) = TcpStream::connect(target).await {
+                let _ = copy_bidirectional(&mut client, &mut upstream).await;
+            }
+        });
+    }
+}
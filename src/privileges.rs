@@ -0,0 +1,796 @@
+use crate::cgroup::CGROUP_ROOT;
+use crate::features::FeatureSet;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::{read_to_string, File};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::thread::JoinHandle;
+
+const CAP_CHOWN: u32 = 0;
+const CAP_SYS_CHROOT: u32 = 18;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_MKNOD: u32 = 27;
+
+// What we could find out about our privileges, gathered once at startup. Deciding what to do
+// with it is plan()'s job, which only looks at this struct so it doesn't care who we really are.
+pub struct Snapshot {
+    pub euid: u32,
+    pub egid: u32,
+    // CapEff from /proc/self/status
+    pub effective: u64,
+    // What the kernel supports, whoever we are
+    pub features: FeatureSet,
+    // Directories we need to write to (store, containers) but can't
+    pub unwritable: Vec<PathBuf>,
+    // We may create groups in the cgroup v2 hierarchy, if there is one
+    pub cgroup_writable: bool,
+    // Our ranges in /etc/subuid and /etc/subgid, or why we can't use any
+    pub subordinate: std::result::Result<SubordinateIds, String>,
+}
+
+impl Snapshot {
+    pub fn probe(directories: &[&Path]) -> Snapshot {
+        let status = read_to_string("/proc/self/status").unwrap_or_default();
+        let effective = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok())
+            .unwrap_or(0);
+
+        Snapshot {
+            euid: unsafe { libc::geteuid() },
+            egid: unsafe { libc::getegid() },
+            effective,
+            features: FeatureSet::probe(Path::new("/proc"), Path::new("/sys")),
+            unwritable: directories
+                .iter()
+                .filter(|directory| !writable(directory))
+                .map(|directory| directory.to_path_buf())
+                .collect(),
+            cgroup_writable: writable(Path::new(CGROUP_ROOT)),
+            subordinate: subordinate_ids(unsafe { libc::geteuid() }),
+        }
+    }
+
+    fn has(&self, capability: u32) -> bool {
+        self.effective & (1 << capability) != 0
+    }
+}
+
+// The closest existing ancestor decides whether we can create the directory
+fn writable(path: &Path) -> bool {
+    let existing = match path.ancestors().find(|ancestor| ancestor.exists()) {
+        Some(existing) => existing,
+        None => return false,
+    };
+    let path = match CString::new(existing.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+// A range of host ids from /etc/subuid or /etc/subgid
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SubordinateIds {
+    pub uids: IdRange,
+    pub gids: IdRange,
+}
+
+// Our ranges, looked up by user name or uid like shadow-utils does, and the setuid helpers
+// that are the only way to use them
+fn subordinate_ids(euid: u32) -> std::result::Result<SubordinateIds, String> {
+    if euid == 0 {
+        return Err("running as root".to_string());
+    }
+    let name = unsafe {
+        let entry = libc::getpwuid(euid);
+        if entry.is_null() {
+            None
+        } else {
+            Some(
+                CStr::from_ptr((*entry).pw_name)
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    };
+    let range = |file: &str| {
+        let contents = read_to_string(file).unwrap_or_default();
+        contents.lines().find_map(|line| {
+            let fields: Vec<&str> = line.trim().split(':').collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            let owner = fields[0];
+            if owner != euid.to_string() && Some(owner) != name.as_deref() {
+                return None;
+            }
+            match (fields[1].parse(), fields[2].parse()) {
+                (Ok(start), Ok(count)) if count > 0 => Some(IdRange { start, count }),
+                _ => None,
+            }
+        })
+    };
+
+    let uids =
+        range("/etc/subuid").ok_or_else(|| format!("no range for uid {} in /etc/subuid", euid))?;
+    let gids =
+        range("/etc/subgid").ok_or_else(|| format!("no range for uid {} in /etc/subgid", euid))?;
+    for tool in ["newuidmap", "newgidmap"] {
+        if !on_path(tool) {
+            return Err(format!("{} is not installed", tool));
+        }
+    }
+    Ok(SubordinateIds { uids, gids })
+}
+
+fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+// How the rootfs gets the file ownership its layers ask for (tar entries owned by _apt, or
+// postgres, which notice when they aren't)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase", tag = "strategy")]
+pub enum Ownership {
+    // We may chown, so we do
+    Chown,
+    // Rootless, with the container's user namespace mapping ids 1 and up to our subordinate
+    // ranges, in which chown works too
+    Subordinate { uids: IdRange, gids: IdRange },
+    // Rootless without subordinate ids: everything belongs to root in the container, and the
+    // intended owners are only recorded, in ownership.json next to the rootfs
+    Recorded,
+}
+
+impl Ownership {
+    pub fn describe(&self) -> String {
+        match self {
+            Ownership::Chown => "chown as root".to_string(),
+            Ownership::Subordinate { uids, gids } => format!(
+                "subordinate ids (uids {}-{}, gids {}-{}) mapped with newuidmap and newgidmap",
+                uids.start,
+                uids.start as u64 + uids.count as u64 - 1,
+                gids.start,
+                gids.start as u64 + gids.count as u64 - 1
+            ),
+            Ownership::Recorded => "recorded only, in ownership.json".to_string(),
+        }
+    }
+
+    pub fn subordinate(&self) -> Option<SubordinateIds> {
+        match *self {
+            Ownership::Subordinate { uids, gids } => Some(SubordinateIds { uids, gids }),
+            _ => None,
+        }
+    }
+}
+
+// How the container will be isolated given our privileges
+pub struct Plan {
+    // Enter a user namespace first and act as root inside it
+    pub rootless: bool,
+    pub pid_namespace: bool,
+    // Put the container in its own cgroup, for limits and usage accounting
+    pub cgroup: bool,
+    // And make that cgroup the root of its own cgroup namespace, mounted at /sys/fs/cgroup
+    pub cgroup_namespace: bool,
+    pub ownership: Ownership,
+    // Isolation we can't provide, as (feature, reason)
+    pub skipped: Vec<(&'static str, String)>,
+    // Problems no fallback gets around
+    pub fatal: Vec<String>,
+}
+
+pub fn plan(snapshot: &Snapshot, resource_limits: bool) -> Plan {
+    let mut plan = Plan {
+        rootless: false,
+        pid_namespace: true,
+        cgroup: false,
+        cgroup_namespace: false,
+        ownership: Ownership::Chown,
+        skipped: vec![],
+        fatal: vec![],
+    };
+
+    let admin = snapshot.has(CAP_SYS_ADMIN);
+    if !snapshot.has(CAP_SYS_CHROOT) || !admin {
+        if let Err(reason) = &snapshot.features.user_namespaces {
+            plan.fatal.push(format!(
+                "chroot needs CAP_SYS_CHROOT and user namespaces are unavailable ({}); run as root",
+                reason
+            ));
+            return plan;
+        }
+        plan.rootless = true;
+        // unshare(CLONE_NEWPID) only applies to children of the caller, and in rootless mode
+        // the namespace would have to be created after the fork
+        plan.pid_namespace = false;
+        plan.skipped.push((
+            "PID namespace",
+            "rootless mode can't make the container PID 1 of its own namespace".to_string(),
+        ));
+    }
+
+    plan.ownership = match &snapshot.subordinate {
+        _ if !plan.rootless && (snapshot.euid == 0 || snapshot.has(CAP_CHOWN)) => Ownership::Chown,
+        Ok(ids) if plan.rootless => Ownership::Subordinate {
+            uids: ids.uids,
+            gids: ids.gids,
+        },
+        problem => {
+            let reason = match problem {
+                Err(reason) if plan.rootless => reason.clone(),
+                _ => "no CAP_CHOWN".to_string(),
+            };
+            plan.skipped.push((
+                "file ownership",
+                format!(
+                    "{}, so files the image gives to other users belong to root in the container",
+                    reason
+                ),
+            ));
+            Ownership::Recorded
+        }
+    };
+
+    // Inside our own user namespace mknod is still refused, the kernel only trusts the
+    // initial namespace with device numbers
+    if plan.rootless || !snapshot.has(CAP_MKNOD) {
+        plan.skipped.push((
+            "/dev/null device",
+            "no CAP_MKNOD, /dev/null will be an empty regular file".to_string(),
+        ));
+    }
+
+    let cgroup_problem = if !snapshot.features.cgroup_v2() {
+        Some("no cgroup v2 hierarchy at /sys/fs/cgroup (v1 and hybrid setups aren't supported)")
+    } else if plan.rootless || !snapshot.cgroup_writable {
+        Some("no permission to create cgroups (delegate a subtree to this user)")
+    } else {
+        None
+    };
+    match cgroup_problem {
+        None => plan.cgroup = true,
+        Some(reason) if resource_limits => plan
+            .fatal
+            .push(format!("--memory and --cpus need a cgroup: {}", reason)),
+        Some(reason) => plan
+            .skipped
+            .push(("cgroup", format!("{}, so no resource accounting", reason))),
+    }
+    // Before 4.6 the container can only be put in its cgroup, not shown it
+    if plan.cgroup {
+        if snapshot.features.cgroup_namespace {
+            plan.cgroup_namespace = true;
+        } else {
+            plan.skipped.push((
+                "cgroup namespace",
+                format!(
+                    "kernel {} has no cgroup namespaces, the container gets no /sys/fs/cgroup",
+                    snapshot.features.kernel
+                ),
+            ));
+        }
+    }
+
+    for directory in &snapshot.unwritable {
+        plan.fatal.push(format!(
+            "uid {} can't write to {}",
+            snapshot.euid,
+            directory.display()
+        ));
+    }
+
+    plan
+}
+
+impl Plan {
+    // Tell the user what they're not getting, or refuse to run with --strict (or
+    // --require-isolation). verbose also says what they are getting.
+    pub fn report(&self, strict: bool, verbose: bool) -> Result<()> {
+        if !self.fatal.is_empty() {
+            bail!("Can't run the container: {}", self.fatal.join("; "));
+        }
+        if verbose {
+            eprintln!("rootless: {}", if self.rootless { "yes" } else { "no" });
+            eprintln!(
+                "PID namespace: {}",
+                if self.pid_namespace { "yes" } else { "no" }
+            );
+            eprintln!("cgroup: {}", if self.cgroup { "yes" } else { "no" });
+            eprintln!(
+                "cgroup namespace: {}",
+                if self.cgroup_namespace { "yes" } else { "no" }
+            );
+            eprintln!("file ownership: {}", self.ownership.describe());
+        }
+        if self.skipped.is_empty() {
+            return Ok(());
+        }
+        let details: Vec<String> = self
+            .skipped
+            .iter()
+            .map(|(feature, reason)| format!("  {}: {}", feature, reason))
+            .collect();
+        if strict {
+            bail!(
+                "--strict was given but some isolation is unavailable:\n{}",
+                details.join("\n")
+            );
+        }
+        if self.rootless {
+            eprintln!("Not running as root, using a user namespace (rootless mode)");
+        }
+        eprintln!("warning: running with reduced isolation:");
+        for line in details {
+            eprintln!("{}", line);
+        }
+        Ok(())
+    }
+}
+
+// Files written from the forked child to become root of a fresh user namespace, mapped to
+// the uid/gid we really have. Prepared before the fork since pre_exec can't allocate.
+pub struct UserMapping {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    // With subordinate ids the maps can only be written by the setuid newuidmap and newgidmap,
+    // from outside. The child sends its pid down the first pipe and waits for a status byte on
+    // the second, 0 meaning it's mapped.
+    handshake: Option<(RawFd, RawFd)>,
+}
+
+impl UserMapping {
+    pub fn new(snapshot: &Snapshot) -> UserMapping {
+        UserMapping {
+            uid_map: format!("0 {} 1\n", snapshot.euid).into_bytes(),
+            gid_map: format!("0 {} 1\n", snapshot.egid).into_bytes(),
+            handshake: None,
+        }
+    }
+
+    // Container root is us, ids 1 and up are the subordinate ranges. The returned Mapper runs
+    // the helpers from a thread, since spawn() doesn't return until the child has exec'd.
+    pub fn subordinate(ids: SubordinateIds) -> Result<(UserMapping, Mapper)> {
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let (ready_read, ready_write) = pipe()?;
+        let (done_read, done_write) = pipe()?;
+        let mapping = UserMapping {
+            uid_map: vec![],
+            gid_map: vec![],
+            handshake: Some((ready_write.as_raw_fd(), done_read.as_raw_fd())),
+        };
+        let thread =
+            std::thread::spawn(move || map_child(ready_read, done_write, (euid, egid), ids));
+        let mapper = Mapper {
+            child_ends: (ready_write, done_read),
+            thread,
+        };
+        Ok((mapping, mapper))
+    }
+
+    // Runs in the forked child; plain syscalls only
+    pub fn enter(&self) -> std::io::Result<()> {
+        unsafe {
+            if libc::unshare(libc::CLONE_NEWUSER) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some((ready, done)) = self.handshake {
+            let pid = unsafe { libc::getpid() }.to_ne_bytes();
+            let mut status = 1u8;
+            unsafe {
+                if libc::write(ready, pid.as_ptr().cast(), pid.len()) != pid.len() as isize {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if libc::read(done, (&mut status as *mut u8).cast(), 1) != 1 || status != 0 {
+                    return Err(std::io::Error::from_raw_os_error(libc::EPERM));
+                }
+            }
+            return Ok(());
+        }
+        // setgroups has to be denied before an unprivileged process may write gid_map
+        write_proc(b"/proc/self/setgroups\0", b"deny")?;
+        write_proc(b"/proc/self/uid_map\0", &self.uid_map)?;
+        write_proc(b"/proc/self/gid_map\0", &self.gid_map)
+    }
+}
+
+// The parent's side of a subordinate mapping
+pub struct Mapper {
+    // Kept open until the child has its copies, then closed so a child that dies early can't
+    // leave the thread waiting
+    child_ends: (File, File),
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Mapper {
+    // Once the child is spawned, or failed to be: why mapping it failed, if it did
+    pub fn finish(self) -> Result<()> {
+        drop(self.child_ends);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => bail!("The thread mapping the user namespace panicked"),
+        }
+    }
+}
+
+fn map_child(mut ready: File, mut done: File, ours: (u32, u32), ids: SubordinateIds) -> Result<()> {
+    let mut pid = [0; 4];
+    if ready.read_exact(&mut pid).is_err() {
+        // The child never got as far as unsharing, and spawn() says why
+        return Ok(());
+    }
+    let pid = i32::from_ne_bytes(pid);
+    let result = write_map("newuidmap", pid, ours.0, ids.uids)
+        .and_then(|_| write_map("newgidmap", pid, ours.1, ids.gids));
+    let _ = done.write_all(&[result.is_err() as u8]);
+    result
+}
+
+fn write_map(tool: &str, pid: i32, ours: u32, range: IdRange) -> Result<()> {
+    let output = Command::new(tool)
+        .args([
+            pid.to_string(),
+            "0".to_string(),
+            ours.to_string(),
+            "1".to_string(),
+            "1".to_string(),
+            range.start.to_string(),
+            range.count.to_string(),
+        ])
+        .output()
+        .with_context(|| format!("Failed to run {}", tool))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            tool,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create a pipe");
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// Run a host command as root of a namespace with our subordinate mapping, which is what it
+// takes to chown to those ids or delete what belongs to them
+pub fn run_mapped(command: &mut Command, ids: SubordinateIds) -> Result<ExitStatus> {
+    let (mapping, mapper) = UserMapping::subordinate(ids)?;
+    unsafe {
+        command.pre_exec(move || mapping.enter());
+    }
+    let status = command.status();
+    mapper.finish()?;
+    Ok(status?)
+}
+
+// Write a /proc or /sys file with raw syscalls, which is safe between fork and exec.
+// The path must be nul-terminated.
+pub fn write_proc(path: &[u8], contents: &[u8]) -> std::io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr().cast(), libc::O_WRONLY);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::CgroupVersion;
+
+    const ALL: u64 = u64::MAX;
+    const RANGE: IdRange = IdRange {
+        start: 100000,
+        count: 65536,
+    };
+
+    fn features() -> FeatureSet {
+        FeatureSet {
+            kernel: "6.1.0".to_string(),
+            cgroup: CgroupVersion::V2 {
+                controllers: vec!["cpu".to_string(), "memory".to_string()],
+            },
+            cgroup_namespace: true,
+            user_namespaces: Ok(()),
+            overlayfs: true,
+            seccomp: true,
+            pidfd: true,
+            clone3: true,
+        }
+    }
+
+    fn root() -> Snapshot {
+        Snapshot {
+            euid: 0,
+            egid: 0,
+            effective: ALL,
+            features: features(),
+            unwritable: vec![],
+            cgroup_writable: true,
+            subordinate: Err("running as root".to_string()),
+        }
+    }
+
+    fn rootless() -> Snapshot {
+        Snapshot {
+            euid: 1000,
+            egid: 1000,
+            effective: 0,
+            cgroup_writable: false,
+            subordinate: Ok(SubordinateIds {
+                uids: RANGE,
+                gids: RANGE,
+            }),
+            ..root()
+        }
+    }
+
+    fn without(capability: u32) -> Snapshot {
+        Snapshot {
+            effective: ALL & !(1 << capability),
+            ..root()
+        }
+    }
+
+    struct Expected {
+        rootless: bool,
+        pid_namespace: bool,
+        cgroup: bool,
+        cgroup_namespace: bool,
+        ownership: &'static str,
+        skipped: &'static [&'static str],
+        fatal: usize,
+    }
+
+    const FULL: Expected = Expected {
+        rootless: false,
+        pid_namespace: true,
+        cgroup: true,
+        cgroup_namespace: true,
+        ownership: "Chown",
+        skipped: &[],
+        fatal: 0,
+    };
+
+    const ROOTLESS: Expected = Expected {
+        rootless: true,
+        pid_namespace: false,
+        cgroup: false,
+        cgroup_namespace: false,
+        ownership: "Subordinate",
+        skipped: &["PID namespace", "/dev/null device", "cgroup"],
+        fatal: 0,
+    };
+
+    #[test]
+    fn plans_follow_the_snapshot() {
+        let cases = vec![
+            ("root", root(), false, FULL),
+            (
+                "root on an old kernel",
+                Snapshot {
+                    features: FeatureSet {
+                        cgroup_namespace: false,
+                        ..features()
+                    },
+                    ..root()
+                },
+                false,
+                Expected {
+                    cgroup_namespace: false,
+                    skipped: &["cgroup namespace"],
+                    ..FULL
+                },
+            ),
+            (
+                "root with cgroup v1",
+                Snapshot {
+                    features: FeatureSet {
+                        cgroup: CgroupVersion::V1,
+                        ..features()
+                    },
+                    ..root()
+                },
+                false,
+                Expected {
+                    cgroup: false,
+                    cgroup_namespace: false,
+                    skipped: &["cgroup"],
+                    ..FULL
+                },
+            ),
+            (
+                "root with cgroup v1 and --memory",
+                Snapshot {
+                    features: FeatureSet {
+                        cgroup: CgroupVersion::V1,
+                        ..features()
+                    },
+                    ..root()
+                },
+                true,
+                Expected {
+                    cgroup: false,
+                    cgroup_namespace: false,
+                    fatal: 1,
+                    ..FULL
+                },
+            ),
+            (
+                "root that can't write the store",
+                Snapshot {
+                    unwritable: vec![PathBuf::from("/var/lib/store")],
+                    ..root()
+                },
+                false,
+                Expected { fatal: 1, ..FULL },
+            ),
+            (
+                "no CAP_MKNOD",
+                without(CAP_MKNOD),
+                false,
+                Expected {
+                    skipped: &["/dev/null device"],
+                    ..FULL
+                },
+            ),
+            (
+                "non-root with every capability but CAP_CHOWN",
+                Snapshot {
+                    euid: 1000,
+                    ..without(CAP_CHOWN)
+                },
+                false,
+                Expected {
+                    ownership: "Recorded",
+                    skipped: &["file ownership"],
+                    ..FULL
+                },
+            ),
+            ("root without CAP_CHOWN", without(CAP_CHOWN), false, FULL),
+            (
+                "no CAP_SYS_ADMIN",
+                Snapshot {
+                    subordinate: rootless().subordinate,
+                    ..without(CAP_SYS_ADMIN)
+                },
+                false,
+                ROOTLESS,
+            ),
+            ("rootless", rootless(), false, ROOTLESS),
+            (
+                "rootless with --memory",
+                rootless(),
+                true,
+                Expected {
+                    skipped: &["PID namespace", "/dev/null device"],
+                    fatal: 1,
+                    ..ROOTLESS
+                },
+            ),
+            (
+                "rootless without subordinate ids",
+                Snapshot {
+                    subordinate: Err("no range for uid 1000 in /etc/subuid".to_string()),
+                    ..rootless()
+                },
+                false,
+                Expected {
+                    ownership: "Recorded",
+                    skipped: &[
+                        "PID namespace",
+                        "file ownership",
+                        "/dev/null device",
+                        "cgroup",
+                    ],
+                    ..ROOTLESS
+                },
+            ),
+            (
+                "rootless without user namespaces",
+                Snapshot {
+                    features: FeatureSet {
+                        user_namespaces: Err("user.max_user_namespaces is 0".to_string()),
+                        ..features()
+                    },
+                    ..rootless()
+                },
+                false,
+                Expected {
+                    rootless: false,
+                    pid_namespace: true,
+                    ownership: "Chown",
+                    skipped: &[],
+                    fatal: 1,
+                    ..ROOTLESS
+                },
+            ),
+        ];
+
+        for (name, snapshot, resource_limits, expected) in cases {
+            let plan = plan(&snapshot, resource_limits);
+            assert_eq!(plan.rootless, expected.rootless, "{}", name);
+            assert_eq!(plan.pid_namespace, expected.pid_namespace, "{}", name);
+            assert_eq!(plan.cgroup, expected.cgroup, "{}", name);
+            assert_eq!(plan.cgroup_namespace, expected.cgroup_namespace, "{}", name);
+            assert!(
+                format!("{:?}", plan.ownership).starts_with(expected.ownership),
+                "{}: {:?}",
+                name,
+                plan.ownership
+            );
+            let skipped: Vec<_> = plan.skipped.iter().map(|(feature, _)| *feature).collect();
+            assert_eq!(skipped, expected.skipped, "{}", name);
+            assert_eq!(
+                plan.fatal.len(),
+                expected.fatal,
+                "{}: {:?}",
+                name,
+                plan.fatal
+            );
+        }
+    }
+
+    #[test]
+    fn reasons_reach_the_report() {
+        let plan = plan(
+            &Snapshot {
+                subordinate: Err("newuidmap is not installed".to_string()),
+                ..rootless()
+            },
+            false,
+        );
+        let error = plan.report(true, false).unwrap_err().to_string();
+        assert!(
+            error.contains("file ownership: newuidmap is not installed"),
+            "{}",
+            error
+        );
+        assert!(plan.report(false, false).is_ok());
+
+        let fatal = super::plan(
+            &Snapshot {
+                unwritable: vec![PathBuf::from("/var/lib/store")],
+                ..rootless()
+            },
+            false,
+        );
+        let error = fatal.report(false, false).unwrap_err().to_string();
+        assert!(
+            error.contains("uid 1000 can't write to /var/lib/store"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::manifest::{is_index_document, Index, Manifest};
+use crate::store::Store;
+use anyhow::Result;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    // No tagged image refers to it any more
+    Unreferenced,
+    // Still part of an image, but the least recently used layer over the size budget
+    Evicted,
+}
+
+pub struct Candidate {
+    pub digest: String,
+    pub size: u64,
+    pub reason: Reason,
+}
+
+// The blobs an image needs, split out so eviction can tell layers (big, downloadable again)
+// from manifests and configs (tiny, and what says which layers an image has)
+#[derive(Default)]
+pub struct Reachable {
+    pub all: HashSet<String>,
+    pub layers: HashSet<String>,
+}
+
+impl Reachable {
+    // Follow a tag's digest through the index (every platform we have) to manifests, configs
+    // and layers. Blobs that are missing or don't parse simply end the walk there.
+    pub fn add(&mut self, store: &Store, digest: &str) {
+        if !self.all.insert(digest.to_string()) {
+            return;
+        }
+        let document = match store.read_blob(digest) {
+            Ok(document) => document,
+            Err(_) => return,
+        };
+        if is_index_document(&document) {
+            if let Ok(index) = serde_json::from_slice::<Index>(&document) {
+                for entry in &index.manifests {
+                    self.add(store, &entry.digest);
+                }
+            }
+        } else if let Ok(manifest) = Manifest::parse(&document) {
+            self.all.insert(manifest.config.digest);
+            for layer in manifest.layers {
+                self.all.insert(layer.digest.clone());
+                self.layers.insert(layer.digest);
+            }
+        }
+    }
+}
+
+// Decide what `system prune` deletes: every blob no tag reaches, then, while the cache is still
+// over max_size, referenced layers from least to most recently used. Layers of pinned images
+// (those containers are using) are never evicted.
+pub fn plan(store: &Store, pinned: &Reachable, max_size: Option<u64>) -> Result<Vec<Candidate>> {
+    let mut referenced = Reachable::default();
+    for digest in store.tags()?.values() {
+        referenced.add(store, digest);
+    }
+
+    let mut candidates = vec![];
+    let mut kept = vec![];
+    let mut remaining = 0;
+    for (digest, size, used) in store.blobs()? {
+        if referenced.all.contains(&digest) {
+            remaining += size;
+            kept.push((digest, size, used));
+        } else {
+            candidates.push(Candidate {
+                digest,
+                size,
+                reason: Reason::Unreferenced,
+            });
+        }
+    }
+
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return Ok(candidates),
+    };
+    let mut evictable: Vec<_> = kept
+        .into_iter()
+        .filter(|(digest, _, _)| referenced.layers.contains(digest) && !pinned.all.contains(digest))
+        .collect();
+    // Oldest first, biggest first among equally old ones
+    evictable.sort_by_key(|(_, size, used)| (*used, Reverse(*size)));
+    for (digest, size, _) in evictable {
+        if remaining <= max_size {
+            break;
+        }
+        remaining -= size;
+        candidates.push(Candidate {
+            digest,
+            size,
+            reason: Reason::Evicted,
+        });
+    }
+    Ok(candidates)
+}
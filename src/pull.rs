@@ -0,0 +1,945 @@
+use crate::data_root;
+use crate::manifest::{
+    document_media_type, is_index, is_index_document, Descriptor, ImageConfig, Index, Manifest,
+    Platform,
+};
+use crate::registry::{Reference, RegistryClient};
+use crate::store::Store;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashSet};
+
+// Everything we learn about an image from the registry before touching any blobs
+pub struct ResolvedImage {
+    pub reference: Reference,
+    // Digest the tag points at: the index for multi-platform images, otherwise the manifest
+    pub digest: String,
+    pub manifest_digest: String,
+    pub platform: Option<Platform>,
+    pub manifest: Manifest,
+    // The index (if any) and manifest documents as fetched, keyed by digest, so a pull can keep
+    // them in the store for offline use
+    pub documents: Vec<(String, Bytes)>,
+}
+
+// When `run` asks the registry about an image it already has, from --pull
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PullPolicy {
+    // Check whether the tag moved every time, with a HEAD request when it's cached
+    Always,
+    // Use the cached image without asking, only pulling what isn't there
+    Missing,
+    // Never touch the network, the same as --offline
+    Never,
+}
+
+impl PullPolicy {
+    pub fn parse(value: &str) -> Result<PullPolicy> {
+        match value {
+            "always" => Ok(PullPolicy::Always),
+            "missing" => Ok(PullPolicy::Missing),
+            "never" => Ok(PullPolicy::Never),
+            _ => bail!(
+                "Unknown --pull '{}', expected always, missing or never",
+                value
+            ),
+        }
+    }
+}
+
+// The cached image, if the registry confirms the tag still points where our store says with
+// one HEAD request, so an unchanged tag costs no manifest downloads and no blob checks. None
+// when it moved, the registry won't say, or the store doesn't have all of the image; a full
+// resolve works out what's new then. A digest reference can't move, so it's never checked.
+// With record, the confirmation and the blobs' use are noted in the store, see resolve_local;
+// without, nothing is written.
+pub async fn resolve_unchanged(
+    client: &RegistryClient,
+    store: &Store,
+    reference: &Reference,
+    platform: Option<&Platform>,
+    record: bool,
+) -> Result<Option<ResolvedImage>> {
+    if reference.digest.is_none() {
+        let tag = reference.tag_key();
+        let cached = match store.tag_digest(&tag)? {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        match client.manifest_digest(&reference.tag).await? {
+            Some(current) if current == cached => {
+                if record {
+                    store.mark_checked(&tag)?;
+                }
+            }
+            Some(current) => {
+                eprintln!("{} moved from {} to {}", reference, cached, current);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(resolve_local(store, reference.clone(), platform, record).ok())
+}
+
+// platform picks the image out of an index, the host's when it's None
+pub async fn resolve(
+    client: &RegistryClient,
+    reference: Reference,
+    platform: Option<&Platform>,
+) -> Result<ResolvedImage> {
+    let top = client.manifest(reference.target()).await?;
+
+    // Some registries serve everything as application/json, the document's own mediaType
+    // (or its shape) settles it then
+    let media_type = if top.content_type.starts_with("application/vnd.") {
+        top.content_type.clone()
+    } else {
+        document_media_type(&top.bytes).unwrap_or_default()
+    };
+    if !is_index(&media_type) {
+        let manifest = Manifest::parse(&top.bytes)
+            .with_context(|| format!("Failed to parse manifest for {}", reference))?;
+        return Ok(ResolvedImage {
+            reference,
+            manifest_digest: top.digest.clone(),
+            digest: top.digest.clone(),
+            platform: None,
+            manifest,
+            documents: vec![(top.digest, top.bytes)],
+        });
+    }
+
+    let index = serde_json::from_slice::<Index>(&top.bytes)
+        .with_context(|| format!("Failed to parse image index for {}", reference))?;
+    let wanted = platform.cloned().unwrap_or_else(Platform::host);
+    let entry = match index.select(&wanted) {
+        Some(entry) => entry,
+        None => bail!("{} has no image for platform {}", reference, wanted),
+    };
+
+    let child = client.manifest(&entry.digest).await?;
+    let manifest = Manifest::parse(&child.bytes)
+        .with_context(|| format!("Failed to parse manifest {}", entry.digest))?;
+
+    Ok(ResolvedImage {
+        reference,
+        digest: top.digest.clone(),
+        manifest_digest: child.digest.clone(),
+        platform: entry.platform.clone(),
+        manifest,
+        documents: vec![(top.digest, top.bytes), (child.digest, child.bytes)],
+    })
+}
+
+// Offline counterpart of `resolve`: the tag, manifests and every blob must already be in the store.
+// record is for an image that's about to be used: its blobs are hashed and count as used for
+// cache eviction. Without it (a dry run) nothing in the store is written, not even a lock file,
+// and a blob only has to be there.
+pub fn resolve_local(
+    store: &Store,
+    reference: Reference,
+    platform: Option<&Platform>,
+    record: bool,
+) -> Result<ResolvedImage> {
+    let digest = match &reference.digest {
+        Some(digest) => digest.clone(),
+        None => match store.tag_digest(&reference.tag_key())? {
+            Some(digest) => digest,
+            None => bail!("{} is not in the local store", reference),
+        },
+    };
+    let top = store
+        .read_blob(&digest)
+        .with_context(|| format!("Manifest {} for {} is not cached", digest, reference))?;
+
+    let (manifest_digest, manifest_bytes, platform, mut documents) = if is_index_document(&top) {
+        let index = serde_json::from_slice::<Index>(&top)
+            .with_context(|| format!("Failed to parse image index for {}", reference))?;
+        let wanted = platform.cloned().unwrap_or_else(Platform::host);
+        let entry = match index.select(&wanted) {
+            Some(entry) => entry,
+            None => bail!("{} has no image for platform {}", reference, wanted),
+        };
+        let child = store
+            .read_blob(&entry.digest)
+            .with_context(|| format!("Manifest {} for {} is not cached", entry.digest, wanted))?;
+        let documents = vec![(digest.clone(), top)];
+        (
+            entry.digest.clone(),
+            child,
+            entry.platform.clone(),
+            documents,
+        )
+    } else {
+        (digest.clone(), top.clone(), None, vec![])
+    };
+
+    let manifest = Manifest::parse(&manifest_bytes)
+        .with_context(|| format!("Failed to parse manifest {}", manifest_digest))?;
+    documents.push((manifest_digest.clone(), manifest_bytes));
+
+    // Hashed before they're reused: a corrupt blob is quarantined and counts as missing
+    let mut missing = vec![];
+    for descriptor in std::iter::once(&manifest.config).chain(manifest.unique_layers()) {
+        let present = if record {
+            let _lock = store.lock_blob(&descriptor.digest)?;
+            store.has_intact_blob(&descriptor.digest)?
+        } else {
+            store.has_blob(&descriptor.digest)
+        };
+        if !present {
+            missing.push(descriptor.digest.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "{} is only partially cached, missing blobs:\n  {}",
+            reference,
+            missing.join("\n  ")
+        );
+    }
+    let used: Vec<&str> = documents
+        .iter()
+        .map(|(digest, _)| digest.as_str())
+        .chain(std::iter::once(manifest.config.digest.as_str()))
+        .chain(manifest.layers.iter().map(|layer| layer.digest.as_str()))
+        .collect();
+    if record {
+        store.touch(&used)?;
+    }
+
+    Ok(ResolvedImage {
+        reference,
+        digest,
+        manifest_digest,
+        platform,
+        manifest,
+        documents,
+    })
+}
+
+// How many downloaded layers may be waiting for the extractor before the downloader waits too
+const PIPELINE_DEPTH: usize = 2;
+
+// Download the config and every layer not already in the store
+pub async fn pull(client: &RegistryClient, image: &ResolvedImage, store: &Store) -> Result<()> {
+    // Blobs we didn't have to download, which count as used for cache eviction
+    let mut hits = vec![];
+    if fetch_config(client, image, store).await? {
+        hits.push(image.manifest.config.digest.as_str());
+    }
+    for layer in image.manifest.unique_layers() {
+        if fetch_layer(client, layer, store).await? {
+            hits.push(layer.digest.as_str());
+        }
+    }
+    finish(image, store, hits)
+}
+
+// `pull` with the disk work overlapped: the config downloads alongside the first layer, and
+// each layer is handed to extract (on a blocking thread) as soon as it's in the store, while
+// the next one downloads. extract gets how many of the manifest's layers are ready, so it still
+// sees them strictly in order, which whiteouts depend on.
+pub async fn pull_pipelined<F>(
+    client: &RegistryClient,
+    image: &ResolvedImage,
+    store: &Store,
+    mut extract: F,
+) -> Result<()>
+where
+    F: FnMut(usize) -> Result<()> + Send + 'static,
+{
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(PIPELINE_DEPTH);
+    let extractor = tokio::task::spawn_blocking(move || {
+        while let Some(ready) = receiver.blocking_recv() {
+            extract(ready)?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    let layers = async {
+        let sender = sender;
+        let mut hits = vec![];
+        let mut fetched = HashSet::new();
+        for (index, layer) in image.manifest.layers.iter().enumerate() {
+            if fetched.insert(layer.digest.as_str()) && fetch_layer(client, layer, store).await? {
+                hits.push(layer.digest.as_str());
+            }
+            // Only a failed extractor hangs up, and its error is the one to report
+            if sender.send(index + 1).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, anyhow::Error>(hits)
+    };
+    let (config, layers) = tokio::join!(fetch_config(client, image, store), layers);
+
+    // Extraction first: when it fails the downloads stop early, and the image mustn't be tagged
+    // as if it were complete
+    extractor.await.context("Layer extraction panicked")??;
+    let mut hits = layers?;
+    if config? {
+        hits.push(image.manifest.config.digest.as_str());
+    }
+    finish(image, store, hits)
+}
+
+// The config decides what actually runs, so it gets the same checks as layers. True if it was
+// already in the store.
+async fn fetch_config(
+    client: &RegistryClient,
+    image: &ResolvedImage,
+    store: &Store,
+) -> Result<bool> {
+    let config = &image.manifest.config;
+    let _lock = store.lock_blob(&config.digest)?;
+    if store.has_intact_blob(&config.digest)? {
+        return Ok(true);
+    }
+    let mut blob = store.blob_writer(&config.digest)?;
+    client.blob(&config.digest, &mut blob).await?;
+    check_size(config, blob.written())?;
+    blob.commit().with_context(|| {
+        format!(
+            "Config blob for {} doesn't match the manifest's config descriptor",
+            image.reference
+        )
+    })?;
+    Ok(false)
+}
+
+// True if the layer was already in the store
+async fn fetch_layer(client: &RegistryClient, layer: &Descriptor, store: &Store) -> Result<bool> {
+    // Check again once we hold the lock, another pull may have just finished this blob
+    let _lock = store.lock_blob(&layer.digest)?;
+    if store.has_intact_blob(&layer.digest)? {
+        return Ok(true);
+    }
+    let mut blob = store.blob_writer(&layer.digest)?;
+    match client.blob(&layer.digest, &mut blob).await {
+        Ok(()) => {}
+        // Foreign layers (Windows base images, mostly) needn't be in the registry at all
+        Err(error) if !layer.urls.is_empty() && !data_root::is_out_of_space(&error) => {
+            eprintln!("warning: {:#}, trying the layer's own URLs", error);
+            client
+                .foreign_blob(&layer.digest, &layer.urls, &mut blob)
+                .await?
+        }
+        Err(error) => return Err(error),
+    }
+    check_size(layer, blob.written())?;
+    blob.commit()
+        .with_context(|| format!("Failed to store layer {}", layer.digest))?;
+    Ok(false)
+}
+
+// Manifests last and the tag after them, so the tag only ever points at a complete image
+fn finish<'a>(image: &'a ResolvedImage, store: &Store, mut hits: Vec<&'a str>) -> Result<()> {
+    for (digest, bytes) in &image.documents {
+        if store.has_blob(digest) {
+            hits.push(digest);
+        } else {
+            store.put_blob(digest, bytes)?;
+        }
+    }
+    store.touch(&hits)?;
+    if image.reference.digest.is_none() {
+        store.set_tag(&image.reference.tag_key(), &image.digest)?;
+    }
+    Ok(())
+}
+
+// Before the digest, since a wrong size says more about what went wrong: a truncated download,
+// or a manifest that doesn't describe its blobs
+fn check_size(descriptor: &Descriptor, size: u64) -> Result<()> {
+    if size != descriptor.size {
+        bail!(
+            "Blob {} is {} bytes, the manifest says {}",
+            descriptor.digest,
+            size,
+            descriptor.size
+        );
+    }
+    Ok(())
+}
+
+pub fn load_config(image: &ResolvedImage, store: &Store) -> Result<ImageConfig> {
+    let digest = &image.manifest.config.digest;
+    let data = std::fs::read(store.blob_path(digest)?)
+        .with_context(|| format!("Config blob {} is not in the store", digest))?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse image config {}", digest))
+}
+
+// Refuse to run an image built for another machine, which would otherwise only fail once the
+// command is exec'd, with ENOEXEC. An explicit --platform means the user knows (binfmt_misc
+// may well be set up to emulate it), so that only gets a warning.
+pub fn check_platform(config: &ImageConfig, requested: Option<&Platform>) -> Result<()> {
+    check_platform_on(config, requested, &Platform::host())
+}
+
+fn check_platform_on(
+    config: &ImageConfig,
+    requested: Option<&Platform>,
+    host: &Platform,
+) -> Result<()> {
+    let image = match config.platform() {
+        Some(platform) => platform,
+        None => return Ok(()),
+    };
+    if image.runs_on(host) {
+        return Ok(());
+    }
+    if requested.is_none() {
+        bail!(
+            "image platform {} does not match host {}, pass --platform {} to run it anyway",
+            image,
+            host,
+            image
+        );
+    }
+    eprintln!(
+        "warning: image platform {} does not match host {}, it will only run under emulation",
+        image, host
+    );
+    Ok(())
+}
+
+// What `pull --dry-run` prints: the resolution result and what a real pull would download
+pub fn print_plan(image: &ResolvedImage, store: &Store) {
+    println!("Reference: {}", image.reference);
+    println!("Digest:    {}", image.digest);
+    if image.manifest_digest != image.digest {
+        println!("Manifest:  {}", image.manifest_digest);
+    }
+    if let Some(media_type) = &image.manifest.media_type {
+        println!("Type:      {}", media_type);
+    }
+    match &image.platform {
+        Some(platform) => println!("Platform:  {}", platform),
+        None => println!("Platform:  (single-platform manifest)"),
+    }
+    print_annotations(&image.manifest.annotations, "");
+    println!(
+        "Config:    {} ({})",
+        image.manifest.config.digest,
+        human_size(image.manifest.config.size)
+    );
+
+    println!("Layers:");
+    let mut seen = HashSet::new();
+    let mut download = 0;
+    let mut missing = 0;
+    for layer in &image.manifest.layers {
+        let status = if !seen.insert(layer.digest.as_str()) {
+            "repeated"
+        } else if store.has_blob(&layer.digest) {
+            "cached"
+        } else {
+            download += layer.size;
+            missing += 1;
+            "missing"
+        };
+        println!(
+            "  {}  {}  {:>10}  {}",
+            layer.digest,
+            layer.media_type,
+            human_size(layer.size),
+            status
+        );
+        for url in &layer.urls {
+            println!("    from {}", url);
+        }
+        print_annotations(&layer.annotations, "    ");
+    }
+    println!(
+        "Total download: {} ({} of {} unique layers)",
+        human_size(download),
+        missing,
+        seen.len()
+    );
+}
+
+fn print_annotations(annotations: &BTreeMap<String, String>, indent: &str) {
+    for (key, value) in annotations {
+        println!("{}{}={}", indent, key, value);
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_digest;
+    use crate::fake_registry::{FakeRegistry, Response};
+    use crate::unpack;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    // A single-layer image, tagged localhost/test:1
+    fn cache_image(store: &Store) -> Reference {
+        cache_image_as(store, "localhost/test:1")
+    }
+
+    fn cache_image_as(store: &Store, tag: &str) -> Reference {
+        let put = |data: &[u8]| {
+            let digest = sha256_digest(data);
+            store.put_blob(&digest, data).unwrap();
+            (digest, data.len())
+        };
+        let (config, config_size) = put(br#"{"architecture":"amd64","os":"linux"}"#);
+        let (layer, layer_size) = put(b"not really a tar");
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config,
+                "size": config_size,
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": layer,
+                "size": layer_size,
+            }],
+        });
+        let reference = Reference::parse(tag).unwrap();
+        let (manifest, _) = put(manifest.to_string().as_bytes());
+        store.set_tag(&reference.tag_key(), &manifest).unwrap();
+        reference
+    }
+
+    // Every path under root with its content, directories as None
+    fn snapshot(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+        let mut entries = vec![];
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path.clone());
+                    entries.push((path, None));
+                } else {
+                    let content = std::fs::read(&path).unwrap();
+                    entries.push((path, Some(content)));
+                }
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn resolving_without_record_writes_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let reference = cache_image(&store);
+        let before = snapshot(root.path());
+
+        let image = resolve_local(&store, reference, None, false).unwrap();
+        assert_eq!(image.manifest.layers.len(), 1);
+        assert_eq!(snapshot(root.path()), before);
+    }
+
+    #[test]
+    fn resolving_with_record_counts_the_blobs_as_used() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let reference = cache_image(&store);
+        let before = std::fs::read(root.path().join("repositories.json")).unwrap();
+
+        let image = resolve_local(&store, reference, None, true).unwrap();
+        let after = std::fs::read(root.path().join("repositories.json")).unwrap();
+        assert_ne!(after, before);
+        let repositories: serde_json::Value = serde_json::from_slice(&after).unwrap();
+        assert!(repositories["last_used"]
+            .get(&image.manifest.layers[0].digest)
+            .is_some());
+    }
+
+    #[test]
+    fn a_missing_blob_fails_either_way() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let reference = cache_image(&store);
+        let image = resolve_local(&store, reference.clone(), None, false).unwrap();
+        std::fs::remove_file(store.blob_path(&image.manifest.layers[0].digest).unwrap()).unwrap();
+
+        for record in [false, true] {
+            match resolve_local(&store, reference.clone(), None, record) {
+                Ok(_) => panic!("resolved an image with a layer missing"),
+                Err(error) => assert!(error.to_string().contains("only partially cached")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unchanged_tag_is_only_recorded_when_asked() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        // The port isn't known until the registry is up, and the registry has to know the digest
+        let digest = Arc::new(Mutex::new(String::new()));
+        let current = digest.clone();
+        let registry = FakeRegistry::start(move |_| {
+            Response::new(200).header("Docker-Content-Digest", &current.lock().unwrap())
+        })
+        .await;
+        let reference = cache_image_as(&store, &format!("{}/test:1", registry.address));
+        *digest.lock().unwrap() = store.tag_digest(&reference.tag_key()).unwrap().unwrap();
+        let client = RegistryClient::connect(&reference, false).await.unwrap();
+        let before = snapshot(root.path());
+
+        let image = resolve_unchanged(&client, &store, &reference, None, false)
+            .await
+            .unwrap();
+        assert!(image.is_some());
+        assert_eq!(snapshot(root.path()), before);
+        assert!(registry
+            .requests()
+            .contains(&"HEAD /v2/test/manifests/1".to_string()));
+
+        let image = resolve_unchanged(&client, &store, &reference, None, true)
+            .await
+            .unwrap();
+        assert!(image.is_some());
+        let repositories: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(root.path().join("repositories.json")).unwrap())
+                .unwrap();
+        assert!(repositories["checked"].get(reference.tag_key()).is_some());
+    }
+
+    #[tokio::test]
+    async fn a_moved_tag_needs_a_full_resolve() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let moved = sha256_digest(b"somewhere else");
+        let registry = FakeRegistry::start(move |_| {
+            Response::new(200).header("Docker-Content-Digest", &moved)
+        })
+        .await;
+        let reference = cache_image_as(&store, &format!("{}/test:1", registry.address));
+        let client = RegistryClient::connect(&reference, false).await.unwrap();
+        let before = snapshot(root.path());
+
+        for record in [false, true] {
+            let image = resolve_unchanged(&client, &store, &reference, None, record)
+                .await
+                .unwrap();
+            assert!(image.is_none());
+        }
+        assert_eq!(snapshot(root.path()), before);
+    }
+
+    const LAYER: &[u8] = b"a layer that arrives in more than one piece";
+
+    fn layer_descriptor(data: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+            digest: sha256_digest(data),
+            size: data.len() as u64,
+            urls: vec![],
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    // Serves LAYER as whatever blob is asked for; respond decides what happens to each request
+    // by the Range it asked for
+    async fn layer_registry(
+        respond: impl Fn(Option<&str>) -> Response + Send + Sync + 'static,
+    ) -> (FakeRegistry, RegistryClient) {
+        let registry = FakeRegistry::start(move |request| respond(request.header("Range"))).await;
+        let reference = Reference::parse(&format!("{}/test:1", registry.address)).unwrap();
+        let client = RegistryClient::connect(&reference, false).await.unwrap();
+        (registry, client)
+    }
+
+    fn blob_dir_names(store: &Store, digest: &str) -> Vec<String> {
+        let dir = store
+            .blob_path(digest)
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn a_layer_is_streamed_into_the_store() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let layer = layer_descriptor(LAYER);
+        let (_registry, client) = layer_registry(|_| Response::new(200).body(LAYER)).await;
+
+        assert!(!fetch_layer(&client, &layer, &store).await.unwrap());
+        assert_eq!(&store.read_blob(&layer.digest).unwrap()[..], LAYER);
+        assert!(!blob_dir_names(&store, &layer.digest)
+            .iter()
+            .any(|name| name.ends_with(".partial")));
+        // Already there the second time
+        assert!(fetch_layer(&client, &layer, &store).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_broken_download_resumes_where_it_stopped() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let layer = layer_descriptor(LAYER);
+        let (registry, client) = layer_registry(|range| match range {
+            None => Response::new(200).body(LAYER).cut_off(10),
+            Some("bytes=10-") => Response::new(206).body(&LAYER[10..]),
+            Some(range) => panic!("unexpected range {}", range),
+        })
+        .await;
+
+        fetch_layer(&client, &layer, &store).await.unwrap();
+        assert_eq!(&store.read_blob(&layer.digest).unwrap()[..], LAYER);
+        let blob = format!("GET /v2/test/blobs/{}", layer.digest);
+        assert_eq!(
+            registry
+                .requests()
+                .iter()
+                .filter(|request| **request == blob)
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn a_server_that_ignores_range_starts_the_blob_over() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let layer = layer_descriptor(LAYER);
+        let (_registry, client) = layer_registry(|range| match range {
+            None => Response::new(200).body(LAYER).cut_off(10),
+            Some(_) => Response::new(200).body(LAYER),
+        })
+        .await;
+
+        fetch_layer(&client, &layer, &store).await.unwrap();
+        assert_eq!(&store.read_blob(&layer.digest).unwrap()[..], LAYER);
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_layer_leaves_nothing_behind() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let layer = layer_descriptor(LAYER);
+        let mut corrupt = LAYER.to_vec();
+        corrupt[0] ^= 1;
+        let (_registry, client) =
+            layer_registry(move |_| Response::new(200).body(&corrupt[..])).await;
+
+        let error = fetch_layer(&client, &layer, &store).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Digest mismatch"));
+        assert!(!store.has_blob(&layer.digest));
+        assert_eq!(
+            blob_dir_names(&store, &layer.digest),
+            [format!("{}.lock", &layer.digest["sha256:".len()..])]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_short_layer_is_not_stored() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let mut layer = layer_descriptor(LAYER);
+        layer.size += 1;
+        let (_registry, client) = layer_registry(|_| Response::new(200).body(LAYER)).await;
+
+        let error = fetch_layer(&client, &layer, &store).await.unwrap_err();
+        assert!(error.to_string().contains("the manifest says"));
+        assert!(!store.has_blob(&layer.digest));
+    }
+
+    #[test]
+    fn images_for_another_machine_need_platform() {
+        let config = |json: &str| serde_json::from_str::<ImageConfig>(json).unwrap();
+        let platform = |value: &str| Platform::parse(value).unwrap();
+        let amd64 = platform("linux/amd64");
+        let armv7 = platform("linux/arm/v7");
+        let cases = [
+            (r#"{"os":"linux","architecture":"amd64"}"#, &amd64, true),
+            (r#"{"os":"linux","architecture":"arm64"}"#, &amd64, false),
+            (r#"{"os":"linux","architecture":"amd64"}"#, &armv7, false),
+            (
+                r#"{"os":"linux","architecture":"arm","variant":"v6"}"#,
+                &armv7,
+                true,
+            ),
+            (
+                r#"{"os":"linux","architecture":"arm","variant":"v8"}"#,
+                &armv7,
+                false,
+            ),
+            (r#"{"os":"linux","architecture":"arm"}"#, &armv7, true),
+            (
+                r#"{"os":"linux","architecture":"arm","variant":""}"#,
+                &armv7,
+                true,
+            ),
+            // Images that don't say are given the benefit of the doubt
+            (r#"{}"#, &amd64, true),
+            (r#"{"os":"","architecture":"arm64"}"#, &amd64, true),
+        ];
+        for (json, host, runs) in cases {
+            let result = check_platform_on(&config(json), None, host);
+            assert_eq!(result.is_ok(), runs, "{} on {}", json, host);
+            // Asking for it explicitly only warns
+            check_platform_on(&config(json), Some(&platform("linux/arm64")), host).unwrap();
+        }
+
+        let error = check_platform_on(
+            &config(r#"{"os":"linux","architecture":"arm64"}"#),
+            None,
+            &amd64,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("--platform linux/arm64"), "{}", error);
+    }
+
+    const POLICY: unpack::EntryPolicy = unpack::EntryPolicy {
+        strict: true,
+        allow_devices: false,
+    };
+
+    // A manifest and the blobs it refers to
+    type Documents = (Vec<u8>, Vec<Vec<u8>>);
+
+    // An image for the fake registry to serve: its manifest and config, with layers as given
+    fn image_documents(config: &[u8], layers: &[&[u8]]) -> Documents {
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": sha256_digest(config),
+                "size": config.len(),
+            },
+            "layers": layers
+                .iter()
+                .map(|layer| serde_json::json!({
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                    "digest": sha256_digest(layer),
+                    "size": layer.len(),
+                }))
+                .collect::<Vec<_>>(),
+        });
+        let mut blobs = vec![config.to_vec()];
+        blobs.extend(layers.iter().map(|layer| layer.to_vec()));
+        (manifest.to_string().into_bytes(), blobs)
+    }
+
+    // Serves each (tag, manifest, blobs) under test:tag, and the blobs by digest
+    async fn image_registry(images: Vec<(&'static str, Documents)>) -> FakeRegistry {
+        FakeRegistry::start(move |request| {
+            for (tag, (manifest, blobs)) in &images {
+                if request.target == format!("/v2/test/manifests/{}", tag) {
+                    return Response::new(200)
+                        .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+                        .body(manifest.clone());
+                }
+                for blob in blobs {
+                    if request.target == format!("/v2/test/blobs/{}", sha256_digest(blob)) {
+                        return Response::new(200).body(blob.clone());
+                    }
+                }
+            }
+            Response::new(if request.target == "/v2/" { 200 } else { 404 })
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn scratch_and_single_layer_images_pull_and_extract() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o755);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, "hello", &b"\x7fELF!"[..])
+            .unwrap();
+        let layer = builder.into_inner().unwrap();
+        // Only what a scratch image built FROM nothing has to say
+        let config = br#"{"architecture":"amd64","os":"linux"}"#;
+        let registry = image_registry(vec![
+            ("scratch", image_documents(config, &[])),
+            ("single", image_documents(config, &[&layer])),
+        ])
+        .await;
+        let expected = |tag| match tag {
+            "scratch" => vec![],
+            _ => vec![(PathBuf::from("hello"), Some(b"\x7fELF!".to_vec()))],
+        };
+
+        for tag in ["scratch", "single"] {
+            for pipeline in [false, true] {
+                let root = tempfile::tempdir().unwrap();
+                let store = Store::new(&root.path().join("store"));
+                let rootfs = root.path().join("container").join("rootfs");
+                std::fs::create_dir_all(&rootfs).unwrap();
+                let reference =
+                    Reference::parse(&format!("{}/test:{}", registry.address, tag)).unwrap();
+                let client = RegistryClient::connect(&reference, false).await.unwrap();
+
+                let image = resolve(&client, reference, None).await.unwrap();
+                if pipeline {
+                    let (extract_store, layers, target) =
+                        (store.clone(), image.manifest.layers.clone(), rootfs.clone());
+                    pull_pipelined(&client, &image, &store, move |ready| {
+                        unpack::apply_layers(
+                            &extract_store,
+                            &layers[..ready],
+                            &target,
+                            None,
+                            POLICY,
+                        )
+                        .map(drop)
+                    })
+                    .await
+                    .unwrap();
+                } else {
+                    pull(&client, &image, &store).await.unwrap();
+                    unpack::apply_layers(&store, &image.manifest.layers, &rootfs, None, POLICY)
+                        .unwrap();
+                }
+
+                let files: Vec<_> = snapshot(&rootfs)
+                    .into_iter()
+                    .map(|(path, content)| (path.strip_prefix(&rootfs).unwrap().into(), content))
+                    .collect();
+                assert_eq!(files, expected(tag), "{} pipelined: {}", tag, pipeline);
+                let config = load_config(&image, &store).unwrap();
+                assert!(config.config.is_none());
+                assert_eq!(config.platform().unwrap().to_string(), "linux/amd64");
+                assert!(store
+                    .tag_digest(&image.reference.tag_key())
+                    .unwrap()
+                    .is_some());
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::pull::ResolvedImage;
+use crate::registry::{Reference, RegistryClient};
+use crate::store::Store;
+use anyhow::{anyhow, bail, Context, Result};
+
+pub struct Pushed {
+    pub uploaded: usize,
+    // Blobs the registry linked in from the repository the image came from
+    pub mounted: usize,
+    // Blobs the repository had already
+    pub skipped: usize,
+}
+
+// Uploads an image from the store: the config and the layers the repository doesn't have yet,
+// then the manifest under the target's tag, so the tag never points at anything incomplete.
+// The manifest goes up byte for byte, so it keeps its digest. Foreign layers are left to the
+// URLs they come from, like docker leaves them. A client connected with a repository to mount
+// from gets the blobs that repository has without uploading them.
+pub async fn push(
+    client: &RegistryClient,
+    store: &Store,
+    image: &ResolvedImage,
+    target: &Reference,
+) -> Result<Pushed> {
+    if target.digest.is_some() {
+        bail!("Can't push to {}, the target needs a tag", target);
+    }
+    let mut pushed = Pushed {
+        uploaded: 0,
+        mounted: 0,
+        skipped: 0,
+    };
+    let layers = image
+        .manifest
+        .layers
+        .iter()
+        .filter(|layer| layer.urls.is_empty());
+    for descriptor in layers.chain(std::iter::once(&image.manifest.config)) {
+        if client.has_blob(&descriptor.digest).await? {
+            pushed.skipped += 1;
+            continue;
+        }
+        let mut blob = store
+            .open_blob(&descriptor.digest)
+            .with_context(|| format!("Can't push {}", image.reference))?;
+        let size = blob.metadata()?.len();
+        if size != descriptor.size {
+            bail!(
+                "Blob {} in the store is {} bytes, the manifest says {}",
+                descriptor.digest,
+                size,
+                descriptor.size
+            );
+        }
+        if client
+            .upload_blob(&descriptor.digest, &mut blob, size)
+            .await?
+        {
+            pushed.mounted += 1;
+        } else {
+            pushed.uploaded += 1;
+        }
+    }
+
+    let bytes = image
+        .documents
+        .iter()
+        .find(|(digest, _)| *digest == image.manifest_digest)
+        .map(|(_, bytes)| bytes)
+        .ok_or_else(|| anyhow!("No manifest document for {}", image.reference))?;
+    let media_type = image
+        .manifest
+        .media_type
+        .clone()
+        .or_else(|| crate::manifest::document_media_type(bytes))
+        .ok_or_else(|| anyhow!("Can't tell what kind of manifest {} has", image.reference))?;
+    client.put_manifest(&target.tag, &media_type, bytes).await?;
+    Ok(pushed)
+}
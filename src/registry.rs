@@ -0,0 +1,926 @@
+use crate::auth::{Challenge, CredentialStore, TokenCache};
+use crate::digest;
+use crate::http::HttpClient;
+use crate::manifest::{DOCKER_MANIFEST, DOCKER_MANIFEST_LIST, OCI_INDEX, OCI_MANIFEST};
+use crate::store::BlobWriter;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+pub const DOCKER_HUB: &str = "docker.io";
+
+// How many times a blob download that breaks off is picked up again
+const BLOB_ATTEMPTS: usize = 3;
+
+// Blobs up to this size are uploaded with a single PUT, bigger ones in PATCHes of this size
+const UPLOAD_CHUNK: u64 = 8 << 20;
+
+// What a client's token has to allow in its repository
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Pull,
+    Push,
+}
+
+// An image reference like "ubuntu", "ubuntu:22.04", "someuser/app:1.0",
+// "ghcr.io/owner/app@sha256:..." or "localhost:5000/app"
+#[derive(Clone)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    pub fn parse(image: &str) -> Result<Reference> {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => {
+                digest::validate(digest)?;
+                (name, Some(digest.to_string()))
+            }
+            None => (image, None),
+        };
+
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, tag),
+            _ => (name, "latest"),
+        };
+        if name.is_empty() || tag.is_empty() {
+            bail!("Invalid image reference '{}'", image);
+        }
+
+        // Like docker, the first component is a registry host only if it looks like one
+        let (registry, path) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host, path)
+            }
+            _ => (DOCKER_HUB, name),
+        };
+        // Official images live under the "library" namespace on Docker Hub
+        let repository = if registry == DOCKER_HUB && !path.contains('/') {
+            format!("library/{}", path)
+        } else {
+            path.to_string()
+        };
+        if repository.is_empty() || repository.chars().any(|c| c.is_ascii_uppercase()) {
+            bail!("Invalid repository name in image reference '{}'", image);
+        }
+
+        Ok(Reference {
+            registry: registry.to_string(),
+            repository,
+            tag: tag.to_string(),
+            digest,
+        })
+    }
+
+    // How the tag is recorded in the local store
+    pub fn tag_key(&self) -> String {
+        format!("{}/{}:{}", self.registry, self.repository, self.tag)
+    }
+
+    // What to ask the registry for: an explicit digest wins over the tag
+    pub fn target(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.tag)
+    }
+
+    // Host serving the registry API, which for Docker Hub isn't the name people type
+    pub fn api_host(&self) -> &str {
+        if self.registry == DOCKER_HUB {
+            "registry-1.docker.io"
+        } else {
+            &self.registry
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)?;
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+// A manifest or index exactly as the registry sent it
+pub struct RawManifest {
+    pub bytes: Bytes,
+    pub content_type: String,
+    pub digest: String,
+    // The Docker-Content-Digest header, which registries aren't required to send
+    pub header_digest: Option<String>,
+}
+
+pub struct RegistryClient {
+    client: HttpClient,
+    base_url: String,
+    registry: String,
+    repository: String,
+    access: Access,
+    // A repository of the same registry that blobs may be mounted from while pushing
+    mount_from: Option<String>,
+    credentials: CredentialStore,
+    tokens: TokenCache,
+    // None until a challenge asks for a token; registries that don't ask get anonymous requests
+    access_token: Mutex<Option<String>>,
+}
+
+impl RegistryClient {
+    // Probe `/v2/` so the registry tells us where its token service lives instead of assuming
+    // Docker Hub's. A 200 means no auth is needed at all. debug_http traces every request.
+    pub async fn connect(reference: &Reference, debug_http: bool) -> Result<RegistryClient> {
+        RegistryClient::open(reference, Access::Pull, None, debug_http).await
+    }
+
+    // The same, with a token that may also upload to the repository. With mount_from, the
+    // token also covers pulling from that repository, so upload_blob can mount blobs from it
+    // instead of uploading them; it only counts when it's on the same registry.
+    pub async fn connect_for_push(
+        reference: &Reference,
+        mount_from: Option<&Reference>,
+        debug_http: bool,
+    ) -> Result<RegistryClient> {
+        let mount_from = mount_from
+            .filter(|source| {
+                source.registry == reference.registry && source.repository != reference.repository
+            })
+            .map(|source| source.repository.clone());
+        RegistryClient::open(reference, Access::Push, mount_from, debug_http).await
+    }
+
+    async fn open(
+        reference: &Reference,
+        access: Access,
+        mount_from: Option<String>,
+        debug_http: bool,
+    ) -> Result<RegistryClient> {
+        let mut registry = RegistryClient {
+            client: HttpClient::new(debug_http),
+            base_url: format!("https://{}/v2", reference.api_host()),
+            registry: reference.registry.clone(),
+            repository: reference.repository.clone(),
+            access,
+            mount_from,
+            credentials: CredentialStore::default(),
+            tokens: TokenCache::default(),
+            access_token: Mutex::new(None),
+        };
+
+        let probe = registry.client.get(&format!("{}/", registry.base_url));
+        let response = match registry.client.send(probe).await {
+            Ok(response) => response,
+            // Like docker, a registry on this machine (a registry:2 container, say) may speak
+            // plain HTTP. Anywhere else that would put credentials on the wire unprotected.
+            Err(_) if is_loopback(&reference.registry) => {
+                registry.base_url = format!("http://{}/v2", reference.api_host());
+                let probe = registry.client.get(&format!("{}/", registry.base_url));
+                registry
+                    .client
+                    .send(probe)
+                    .await
+                    .with_context(|| format!("Failed to reach registry {}", reference.registry))?
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to reach registry {}", reference.registry))
+            }
+        };
+        if response.status() == StatusCode::UNAUTHORIZED {
+            registry.authenticate(&response).await?;
+        }
+
+        Ok(registry)
+    }
+
+    // What a tag points at now, from a HEAD request: no body, and Docker Hub doesn't count it
+    // against the pull rate limit. None when the registry doesn't send Docker-Content-Digest,
+    // which it needn't, and only a GET can tell then.
+    pub async fn manifest_digest(&self, reference: &str) -> Result<Option<String>> {
+        let response = self
+            .send(
+                Method::HEAD,
+                &self.manifest_url(reference),
+                &[("Accept", &manifest_accept())],
+                None,
+            )
+            .await?;
+        let response = self.client.check(response).await.with_context(|| {
+            format!("Failed to check manifest {}:{}", self.repository, reference)
+        })?;
+        Ok(response
+            .headers()
+            .get("Docker-Content-Digest")
+            .and_then(|value| value.to_str().ok())
+            .filter(|digest| digest::validate(digest).is_ok())
+            .map(str::to_string))
+    }
+
+    // Fetch a manifest or index by tag or digest, accepting every format we know how to handle
+    pub async fn manifest(&self, reference: &str) -> Result<RawManifest> {
+        let response = self
+            .get(&self.manifest_url(reference), Some(&manifest_accept()))
+            .await?;
+        let response = self.client.check(response).await.with_context(|| {
+            format!("Failed to fetch manifest {}:{}", self.repository, reference)
+        })?;
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header("Content-Type").unwrap_or_default();
+        let header_digest = header("Docker-Content-Digest");
+        let bytes = response.bytes().await?;
+
+        // When asking by digest we know what we should get, otherwise trust the content itself
+        // over the header so a registry can't point us at bytes that don't match the digest
+        let digest = digest::sha256_digest(&bytes);
+        if reference.starts_with("sha256:") {
+            digest::verify(reference, &bytes)?;
+        } else if let Some(header_digest) = &header_digest {
+            if *header_digest != digest {
+                bail!(
+                    "Registry reported digest {} for {}:{} but the manifest hashes to {}",
+                    header_digest,
+                    self.repository,
+                    reference,
+                    digest
+                );
+            }
+        }
+
+        Ok(RawManifest {
+            bytes,
+            content_type,
+            digest,
+            header_digest,
+        })
+    }
+
+    // A foreign layer from the first of its URLs that has it. These are plain downloads,
+    // nothing of ours is sent along.
+    pub async fn foreign_blob(
+        &self,
+        digest: &str,
+        urls: &[String],
+        blob: &mut BlobWriter,
+    ) -> Result<()> {
+        let mut errors = vec![];
+        for url in urls {
+            let response = match self.client.send(self.client.get(url)).await {
+                Ok(response) => response,
+                Err(error) => {
+                    errors.push(format!("{:#}", error));
+                    continue;
+                }
+            };
+            let mut response = match self.client.check(response).await {
+                Ok(response) => response,
+                Err(error) => {
+                    errors.push(format!("{:#}", error));
+                    continue;
+                }
+            };
+            blob.restart()?;
+            let error = loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => blob.write(&chunk)?,
+                    Ok(None) => return Ok(()),
+                    Err(error) => break error,
+                }
+            };
+            errors.push(format!("{} broke off: {}", url, error));
+        }
+        bail!(
+            "Failed to fetch foreign layer {} from any of its URLs:\n  {}",
+            digest,
+            errors.join("\n  ")
+        )
+    }
+
+    // Blobs usually come from a CDN the registry redirects to. If the download breaks off, it
+    // resumes with a Range request, starting again from the registry: the CDN URL is signed and
+    // may well have expired by then. A failed write isn't retried, a full disk stays full.
+    pub async fn blob(&self, digest: &str, blob: &mut BlobWriter) -> Result<()> {
+        let url = format!("{}/{}/blobs/{}", self.base_url, self.repository, digest);
+        let mut attempt = 1;
+        loop {
+            let range = if blob.written() == 0 {
+                None
+            } else {
+                Some(format!("bytes={}-", blob.written()))
+            };
+            let response = self.get_with(&url, None, range.as_deref()).await?;
+            let mut response = self
+                .client
+                .check(response)
+                .await
+                .with_context(|| format!("Failed to fetch blob {}", digest))?;
+            // A server that ignores Range sends the whole blob again
+            if range.is_some() && response.status() != StatusCode::PARTIAL_CONTENT {
+                blob.restart()?;
+            }
+
+            let error = loop {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => blob.write(&chunk)?,
+                    Ok(None) => return Ok(()),
+                    Err(error) => break error,
+                }
+            };
+            if attempt == BLOB_ATTEMPTS {
+                return Err(error).with_context(|| {
+                    format!(
+                        "Download of blob {} broke off {} times, giving up",
+                        digest, BLOB_ATTEMPTS
+                    )
+                });
+            }
+            attempt += 1;
+            eprintln!(
+                "warning: download of {} broke off after {} bytes ({}), resuming",
+                digest,
+                blob.written(),
+                error
+            );
+        }
+    }
+
+    // Whether the repository has the blob already, so a push can skip it
+    pub async fn has_blob(&self, digest: &str) -> Result<bool> {
+        let url = format!("{}/{}/blobs/{}", self.base_url, self.repository, digest);
+        let response = self.send(Method::HEAD, &url, &[], None).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        self.client
+            .check(response)
+            .await
+            .with_context(|| format!("Failed to check for blob {}", digest))?;
+        Ok(true)
+    }
+
+    // The upload protocol from the distribution spec: a POST opens a session, and a PUT with
+    // the digest closes it. A small blob goes in that PUT; a bigger one goes first in PATCHes,
+    // each sent to the Location the previous answer gave, picking up from wherever the
+    // session's Range says the registry has got to.
+    //
+    // With a repository to mount from, the POST first asks for the blob to be mounted from
+    // there, and true means the registry did so and nothing was uploaded. One that can't (it
+    // doesn't mount, or the blob isn't there, or our token doesn't reach that repository)
+    // opens an ordinary session instead, or fails the POST, and the upload goes ahead as usual.
+    pub async fn upload_blob(&self, digest: &str, blob: &mut File, size: u64) -> Result<bool> {
+        let url = Url::parse(&format!(
+            "{}/{}/blobs/uploads/",
+            self.base_url, self.repository
+        ))?;
+        let mut response = None;
+        if let Some(from) = &self.mount_from {
+            let mut mount = url.clone();
+            mount
+                .query_pairs_mut()
+                .append_pair("mount", digest)
+                .append_pair("from", from);
+            let answer = self
+                .send(Method::POST, mount.as_str(), &[], Some(&Bytes::new()))
+                .await?;
+            match answer.status() {
+                StatusCode::CREATED => return Ok(true),
+                StatusCode::ACCEPTED => response = Some(answer),
+                _ => {}
+            }
+        }
+        let response = match response {
+            Some(response) => response,
+            // An empty body rather than none, some registries insist on a Content-Length
+            None => {
+                let response = self
+                    .send(Method::POST, url.as_str(), &[], Some(&Bytes::new()))
+                    .await?;
+                self.client
+                    .check(response)
+                    .await
+                    .with_context(|| format!("Failed to start uploading blob {}", digest))?
+            }
+        };
+        let mut location = upload_location(&response)?;
+
+        // A blob that fits in one chunk goes up with the closing PUT. Bigger ones are read from
+        // the file a chunk at a time as they're sent, so only one chunk is ever in memory.
+        let read = |blob: &mut File, offset: u64, end: u64| {
+            read_chunk(blob, offset, end)
+                .with_context(|| format!("Failed to read blob {} to upload it", digest))
+        };
+        let mut body = Bytes::new();
+        if size <= UPLOAD_CHUNK {
+            body = read(blob, 0, size)?;
+        } else {
+            let mut offset = 0;
+            while offset < size {
+                let end = size.min(offset + UPLOAD_CHUNK);
+                let range = format!("{}-{}", offset, end - 1);
+                let headers = [
+                    ("Content-Type", "application/octet-stream"),
+                    ("Content-Range", range.as_str()),
+                ];
+                let chunk = read(blob, offset, end)?;
+                let response = self
+                    .send(Method::PATCH, location.as_str(), &headers, Some(&chunk))
+                    .await?;
+                let response = self.client.check(response).await.with_context(|| {
+                    format!("Failed to upload bytes {} of blob {}", range, digest)
+                })?;
+                location = upload_location(&response)?;
+                let received = received_up_to(&response).unwrap_or(end);
+                if received <= offset {
+                    bail!(
+                        "Registry took none of bytes {} of blob {}, giving up",
+                        range,
+                        digest
+                    );
+                }
+                offset = received;
+            }
+        }
+
+        location.query_pairs_mut().append_pair("digest", digest);
+        let headers = [("Content-Type", "application/octet-stream")];
+        let response = self
+            .send(Method::PUT, location.as_str(), &headers, Some(&body))
+            .await?;
+        self.client
+            .check(response)
+            .await
+            .with_context(|| format!("Failed to finish uploading blob {}", digest))?;
+        Ok(false)
+    }
+
+    // Tags the manifest in the repository. These are the bytes it's identified by, so they go
+    // up exactly as they are.
+    pub async fn put_manifest(&self, tag: &str, media_type: &str, bytes: &Bytes) -> Result<()> {
+        let response = self
+            .send(
+                Method::PUT,
+                &self.manifest_url(tag),
+                &[("Content-Type", media_type)],
+                Some(bytes),
+            )
+            .await?;
+        let response = self
+            .client
+            .check(response)
+            .await
+            .with_context(|| format!("Failed to push manifest {}:{}", self.repository, tag))?;
+        let expected = digest::sha256_digest(bytes);
+        if let Some(reported) = response
+            .headers()
+            .get("Docker-Content-Digest")
+            .and_then(|value| value.to_str().ok())
+        {
+            if reported != expected {
+                bail!(
+                    "Registry stored manifest {}:{} as {}, but it hashes to {}",
+                    self.repository,
+                    tag,
+                    reported,
+                    expected
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // GET with whatever token we hold. A 401 means the registry wants a token (or a different
+    // one), so answer its challenge and retry once.
+    async fn get(&self, url: &str, accept: Option<&str>) -> Result<Response> {
+        self.get_with(url, accept, None).await
+    }
+
+    async fn get_with(
+        &self,
+        url: &str,
+        accept: Option<&str>,
+        range: Option<&str>,
+    ) -> Result<Response> {
+        let headers: Vec<(&str, &str)> = [("Accept", accept), ("Range", range)]
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value)))
+            .collect();
+        self.send(Method::GET, url, &headers, None).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&Bytes>,
+    ) -> Result<Response> {
+        let response = self
+            .client
+            .send(self.request(method.clone(), url, headers, body))
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        self.authenticate(&response).await?;
+        self.client
+            .send(self.request(method, url, headers, body))
+            .await
+    }
+
+    fn manifest_url(&self, reference: &str) -> String {
+        format!(
+            "{}/{}/manifests/{}",
+            self.base_url, self.repository, reference
+        )
+    }
+
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&Bytes>,
+    ) -> RequestBuilder {
+        let mut request = self.client.request(method, url);
+        if let Some(token) = self.access_token.lock().unwrap().as_ref() {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(body) = body {
+            request = request.body(body.clone());
+        }
+        request
+    }
+
+    async fn authenticate(&self, response: &Response) -> Result<()> {
+        let header = match response
+            .headers()
+            .get("WWW-Authenticate")
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(header) => header,
+            None => bail!("Registry answered 401 without a WWW-Authenticate challenge"),
+        };
+        let challenge = Challenge::parse(header)?;
+        if !challenge.is_bearer() {
+            bail!(
+                "Registry asks for {} authentication, which needs credentials",
+                challenge.scheme
+            );
+        }
+
+        let actions = match self.access {
+            Access::Pull => "pull",
+            Access::Push => "pull,push",
+        };
+        let mut scopes = vec![format!("repository:{}:{}", self.repository, actions)];
+        if let Some(from) = &self.mount_from {
+            scopes.push(format!("repository:{}:pull", from));
+        }
+        let credentials = self.credentials.get(&self.registry).await?;
+        let token = self
+            .tokens
+            .token(&self.client, &challenge, &scopes, credentials.as_ref())
+            .await?;
+        *self.access_token.lock().unwrap() = Some(token);
+
+        Ok(())
+    }
+}
+
+// localhost, 127.0.0.1 or [::1], with or without a port
+fn is_loopback(registry: &str) -> bool {
+    let host = match registry.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => registry,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+// Where the upload session continues; registries usually send it relative to themselves
+fn upload_location(response: &Response) -> Result<Url> {
+    let location = response
+        .headers()
+        .get("Location")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("Registry didn't say where to continue the upload"))?;
+    response
+        .url()
+        .join(location)
+        .with_context(|| format!("Registry sent an invalid upload location '{}'", location))
+}
+
+// Range: 0-<last byte> is how much of the blob the session holds, as an offset to go on from
+fn received_up_to(response: &Response) -> Option<u64> {
+    let range = response.headers().get("Range")?.to_str().ok()?;
+    let (_, last) = range.trim_start_matches("bytes=").split_once('-')?;
+    last.parse::<u64>().ok().map(|last| last + 1)
+}
+
+// Bytes offset..end of the blob
+fn read_chunk(blob: &mut File, offset: u64, end: u64) -> std::io::Result<Bytes> {
+    let mut chunk = vec![0; (end - offset) as usize];
+    blob.seek(SeekFrom::Start(offset))?;
+    blob.read_exact(&mut chunk)?;
+    Ok(Bytes::from(chunk))
+}
+
+// Every manifest format we know how to handle
+fn manifest_accept() -> String {
+    [
+        DOCKER_MANIFEST,
+        DOCKER_MANIFEST_LIST,
+        OCI_MANIFEST,
+        OCI_INDEX,
+    ]
+    .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_registry::{FakeRegistry, Response};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn parse(image: &str) -> (String, String, String, Option<String>) {
+        let reference = Reference::parse(image).unwrap();
+        (
+            reference.registry,
+            reference.repository,
+            reference.tag,
+            reference.digest,
+        )
+    }
+
+    #[test]
+    fn official_images_are_under_library_on_docker_hub() {
+        assert_eq!(
+            parse("ubuntu"),
+            (
+                "docker.io".into(),
+                "library/ubuntu".into(),
+                "latest".into(),
+                None
+            )
+        );
+        assert_eq!(parse("ubuntu:22.04").2, "22.04");
+        assert_eq!(parse("someuser/app:1.0").1, "someuser/app");
+    }
+
+    #[test]
+    fn registry_hosts_are_told_apart_from_namespaces() {
+        assert_eq!(parse("ghcr.io/owner/app").0, "ghcr.io");
+        assert_eq!(parse("localhost/app").0, "localhost");
+        let (registry, repository, tag, _) = parse("localhost:5000/app");
+        assert_eq!(
+            (registry.as_str(), repository.as_str()),
+            ("localhost:5000", "app")
+        );
+        // The port isn't a tag
+        assert_eq!(tag, "latest");
+        assert_eq!(parse("localhost:5000/app:2").2, "2");
+    }
+
+    #[test]
+    fn digests_pin_the_reference() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let (_, repository, tag, pinned) = parse(&format!("ghcr.io/o/app:1@{}", digest));
+        assert_eq!((repository.as_str(), tag.as_str()), ("o/app", "1"));
+        assert_eq!(pinned.as_deref(), Some(digest.as_str()));
+        let reference = Reference::parse(&format!("alpine@{}", digest)).unwrap();
+        assert_eq!(reference.target(), digest);
+        assert!(Reference::parse("alpine@sha256:short").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        for image in [
+            "docker.io/library/alpine:3.19",
+            "ghcr.io/o/app:1",
+            "localhost:5000/a/b:c",
+        ] {
+            let reference = Reference::parse(image).unwrap();
+            assert_eq!(reference.to_string(), image);
+            assert_eq!(reference.tag_key(), image);
+            assert_eq!(
+                Reference::parse(&reference.to_string())
+                    .unwrap()
+                    .to_string(),
+                image
+            );
+        }
+    }
+
+    #[test]
+    fn docker_hub_is_reached_through_its_api_host() {
+        assert_eq!(
+            Reference::parse("alpine").unwrap().api_host(),
+            "registry-1.docker.io"
+        );
+        assert_eq!(
+            Reference::parse("quay.io/a/b").unwrap().api_host(),
+            "quay.io"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        assert!(Reference::parse("").is_err());
+        assert!(Reference::parse("alpine:").is_err());
+        assert!(Reference::parse("Alpine").is_err());
+        assert!(Reference::parse("ghcr.io/").is_err());
+    }
+
+    const BLOB: &[u8] = b"a blob to push";
+
+    // A registry taking pushes to localhost/<port>/dest; mount decides how it answers a
+    // cross-repository mount. Returns the bodies of the PUTs that finished an upload.
+    async fn push_registry(mount: u16) -> (FakeRegistry, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let finished = Arc::new(Mutex::new(vec![]));
+        let puts = finished.clone();
+        let registry = FakeRegistry::start(move |request| {
+            let target = request.target.as_str();
+            if target.contains("mount=") {
+                let response = Response::new(mount);
+                return match mount {
+                    202 => response.header("Location", "/v2/dest/blobs/uploads/mounted"),
+                    _ => response,
+                };
+            }
+            match request.method.as_str() {
+                "POST" => Response::new(202).header("Location", "/v2/dest/blobs/uploads/fresh"),
+                "PUT" => {
+                    puts.lock().unwrap().push(request.body.clone());
+                    Response::new(201)
+                }
+                _ => Response::new(200),
+            }
+        })
+        .await;
+        (registry, finished)
+    }
+
+    async fn push_client(registry: &FakeRegistry, mount_from: Option<&str>) -> RegistryClient {
+        let reference = Reference::parse(&format!("{}/dest:1", registry.address)).unwrap();
+        let source = mount_from
+            .map(|repository| Reference::parse(&format!("{}/{}", registry.address, repository)))
+            .transpose()
+            .unwrap();
+        RegistryClient::connect_for_push(&reference, source.as_ref(), false)
+            .await
+            .unwrap()
+    }
+
+    async fn upload(client: &RegistryClient, data: &[u8]) -> bool {
+        let mut blob = tempfile::tempfile().unwrap();
+        blob.write_all(data).unwrap();
+        let digest = digest::sha256_digest(data);
+        client
+            .upload_blob(&digest, &mut blob, data.len() as u64)
+            .await
+            .unwrap()
+    }
+
+    // The blob requests so far, with digests as <digest>
+    fn uploads(registry: &FakeRegistry) -> Vec<String> {
+        let digest = regex::Regex::new("sha256(:|%3A)[0-9a-f]{64}").unwrap();
+        registry
+            .requests()
+            .into_iter()
+            .filter(|request| request.contains("/blobs/"))
+            .map(|request| digest.replace_all(&request, "<digest>").into_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_blob_the_registry_has_elsewhere_is_mounted() {
+        let (registry, finished) = push_registry(201).await;
+        let client = push_client(&registry, Some("source")).await;
+        assert!(upload(&client, BLOB).await);
+        assert_eq!(
+            uploads(&registry),
+            ["POST /v2/dest/blobs/uploads/?mount=<digest>&from=source"]
+        );
+        assert!(finished.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_refused_mount_goes_on_as_an_upload_in_the_session_it_opened() {
+        let (registry, finished) = push_registry(202).await;
+        let client = push_client(&registry, Some("source")).await;
+        assert!(!upload(&client, BLOB).await);
+        assert_eq!(
+            uploads(&registry),
+            [
+                "POST /v2/dest/blobs/uploads/?mount=<digest>&from=source",
+                "PUT /v2/dest/blobs/uploads/mounted?digest=<digest>",
+            ]
+        );
+        assert_eq!(*finished.lock().unwrap(), [BLOB]);
+    }
+
+    #[tokio::test]
+    async fn a_mount_the_registry_doesnt_know_falls_back_to_a_fresh_upload() {
+        let (registry, finished) = push_registry(404).await;
+        let client = push_client(&registry, Some("source")).await;
+        assert!(!upload(&client, BLOB).await);
+        assert_eq!(
+            uploads(&registry),
+            [
+                "POST /v2/dest/blobs/uploads/?mount=<digest>&from=source",
+                "POST /v2/dest/blobs/uploads/",
+                "PUT /v2/dest/blobs/uploads/fresh?digest=<digest>",
+            ]
+        );
+        assert_eq!(*finished.lock().unwrap(), [BLOB]);
+    }
+
+    #[tokio::test]
+    async fn mounts_only_come_from_another_repository_on_the_same_registry() {
+        let (registry, _) = push_registry(201).await;
+        for source in [None, Some("dest")] {
+            let client = push_client(&registry, source).await;
+            assert!(!upload(&client, BLOB).await);
+        }
+        let other = Reference::parse("ghcr.io/source/app").unwrap();
+        let reference = Reference::parse(&format!("{}/dest:1", registry.address)).unwrap();
+        let client = RegistryClient::connect_for_push(&reference, Some(&other), false)
+            .await
+            .unwrap();
+        assert!(!upload(&client, BLOB).await);
+        assert!(!uploads(&registry)
+            .iter()
+            .any(|request| request.contains("mount=")));
+    }
+
+    #[tokio::test]
+    async fn big_blobs_go_up_in_chunks_from_where_the_registry_got_to() {
+        let size = UPLOAD_CHUNK as usize * 2 + 1000;
+        let data: Vec<u8> = (0..size).map(|index| (index % 251) as u8).collect();
+        let received = Arc::new(Mutex::new(vec![]));
+        let ranges = Arc::new(Mutex::new(vec![]));
+        let (state, seen) = (received.clone(), ranges.clone());
+        let registry = FakeRegistry::start(move |request| {
+            let mut received = state.lock().unwrap();
+            match request.method.as_str() {
+                "PATCH" => {
+                    let range = request.header("Content-Range").unwrap().to_string();
+                    let start: usize = range.split('-').next().unwrap().parse().unwrap();
+                    assert_eq!(start, received.len(), "{}", range);
+                    // The first PATCH only half gets through
+                    let take = if start == 0 {
+                        request.body.len() / 2
+                    } else {
+                        request.body.len()
+                    };
+                    received.extend_from_slice(&request.body[..take]);
+                    seen.lock().unwrap().push(range);
+                    Response::new(202)
+                        .header("Location", "/v2/dest/blobs/uploads/session")
+                        .header("Range", &format!("0-{}", received.len() - 1))
+                }
+                "POST" => Response::new(202).header("Location", "/v2/dest/blobs/uploads/session"),
+                "PUT" => {
+                    assert!(request.body.is_empty());
+                    Response::new(201)
+                }
+                _ => Response::new(200),
+            }
+        })
+        .await;
+        let client = push_client(&registry, None).await;
+        assert!(!upload(&client, &data).await);
+
+        assert!(*received.lock().unwrap() == data);
+        let half = UPLOAD_CHUNK / 2;
+        let chunk = UPLOAD_CHUNK;
+        let expected = [
+            format!("0-{}", chunk - 1),
+            format!("{}-{}", half, half + chunk - 1),
+            format!("{}-{}", half + chunk, size - 1),
+        ];
+        assert_eq!(*ranges.lock().unwrap(), expected);
+        assert_eq!(
+            uploads(&registry).last().unwrap(),
+            "PUT /v2/dest/blobs/uploads/session?digest=<digest>"
+        );
+    }
+}
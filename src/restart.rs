@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+// Created in the container directory by `stop` before it signals anything, so the supervisor
+// knows the exit it's about to see was asked for
+const NO_RESTART: &str = "no-restart";
+
+// Doubled for every restart, up to MAX_BACKOFF, like docker
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// What the run process supervising the container does when the command exits, from
+// --restart. Nothing restarts a container once that process is gone.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(tag = "name", rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    // Also what containers from before restart policies have
+    #[default]
+    No,
+    // Only after a non-zero exit, at most max_retries times if that's given
+    OnFailure {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_retries: Option<u32>,
+    },
+    Always,
+}
+
+impl RestartPolicy {
+    // no, always, on-failure or on-failure:<max-retries>
+    pub fn parse(value: &str) -> Result<RestartPolicy> {
+        match value.split_once(':') {
+            None if value == "no" => Ok(RestartPolicy::No),
+            None if value == "always" => Ok(RestartPolicy::Always),
+            None if value == "on-failure" => Ok(RestartPolicy::OnFailure { max_retries: None }),
+            Some(("on-failure", count)) => match count.parse::<u32>() {
+                Ok(count) => Ok(RestartPolicy::OnFailure {
+                    max_retries: Some(count),
+                }),
+                Err(_) => bail!(
+                    "Invalid --restart '{}', the maximum retry count must be a number",
+                    value
+                ),
+            },
+            _ => bail!(
+                "Unknown --restart '{}', expected no, always or on-failure[:max-retries]",
+                value
+            ),
+        }
+    }
+
+    // docker's RestartPolicy.Name
+    pub fn name(&self) -> &'static str {
+        match self {
+            RestartPolicy::No => "no",
+            RestartPolicy::OnFailure { .. } => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+
+    // docker's MaximumRetryCount, 0 meaning no limit
+    pub fn max_retries(&self) -> u32 {
+        match self {
+            RestartPolicy::OnFailure {
+                max_retries: Some(count),
+            } => *count,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Exit,
+    // Start the command again once the delay is up
+    Restart(Duration),
+}
+
+// The whole policy, kept free of processes and files: given how the command exited, how often
+// it has been restarted already and whether someone stopped it, what happens next. A stopped
+// container stays stopped whatever the policy says.
+pub fn decide(policy: RestartPolicy, exit_code: i32, restarts: u32, stopped: bool) -> Action {
+    if stopped {
+        return Action::Exit;
+    }
+    match policy {
+        RestartPolicy::No => Action::Exit,
+        RestartPolicy::OnFailure { .. } if exit_code == 0 => Action::Exit,
+        RestartPolicy::OnFailure {
+            max_retries: Some(count),
+        } if restarts >= count => Action::Exit,
+        RestartPolicy::OnFailure { .. } | RestartPolicy::Always => {
+            Action::Restart(backoff(restarts))
+        }
+    }
+}
+
+fn backoff(restarts: u32) -> Duration {
+    FIRST_BACKOFF
+        .checked_mul(2u32.saturating_pow(restarts))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+// Tell the supervisor of the container in dir not to restart it again
+pub fn disable(dir: &Path) -> Result<()> {
+    let path = dir.join(NO_RESTART);
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(())
+}
+
+pub fn disabled(dir: &Path) -> bool {
+    dir.join(NO_RESTART).exists()
+}
+
+// A PID namespace ends with its init, and unshare() refuses a second new one while the first
+// is still what our children are created in. Going back to our own namespace in between
+// (which needs the same privileges the first unshare did) gives every attempt a fresh one.
+pub struct PidNamespaces {
+    ours: File,
+}
+
+impl PidNamespaces {
+    // Before the first unshare, while /proc/self/ns/pid_for_children is still ours
+    pub fn open() -> Result<PidNamespaces> {
+        let ours = File::open("/proc/self/ns/pid").context("Failed to open our PID namespace")?;
+        Ok(PidNamespaces { ours })
+    }
+
+    pub fn fresh(&self) -> Result<()> {
+        unsafe {
+            if libc::setns(self.ours.as_raw_fd(), libc::CLONE_NEWPID) != 0
+                || libc::unshare(libc::CLONE_NEWPID) != 0
+            {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to create a PID namespace for the restart");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ON_FAILURE: RestartPolicy = RestartPolicy::OnFailure { max_retries: None };
+
+    #[test]
+    fn parses_every_policy() {
+        assert_eq!(RestartPolicy::parse("no").unwrap(), RestartPolicy::No);
+        assert_eq!(
+            RestartPolicy::parse("always").unwrap(),
+            RestartPolicy::Always
+        );
+        assert_eq!(RestartPolicy::parse("on-failure").unwrap(), ON_FAILURE);
+        assert_eq!(
+            RestartPolicy::parse("on-failure:3").unwrap(),
+            RestartPolicy::OnFailure {
+                max_retries: Some(3)
+            }
+        );
+        for value in [
+            "",
+            "yes",
+            "Always",
+            "always:3",
+            "no:1",
+            "on-failure:",
+            "on-failure:-1",
+            "on-failure:x",
+            "unless-stopped",
+        ] {
+            assert!(RestartPolicy::parse(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_state_file() {
+        for policy in [
+            RestartPolicy::No,
+            RestartPolicy::Always,
+            ON_FAILURE,
+            RestartPolicy::OnFailure {
+                max_retries: Some(5),
+            },
+        ] {
+            let json = serde_json::to_string(&policy).unwrap();
+            assert_eq!(
+                serde_json::from_str::<RestartPolicy>(&json).unwrap(),
+                policy
+            );
+            let flag = match policy.max_retries() {
+                0 => policy.name().to_string(),
+                count => format!("{}:{}", policy.name(), count),
+            };
+            assert_eq!(RestartPolicy::parse(&flag).unwrap(), policy);
+        }
+        assert_eq!(
+            serde_json::to_string(&ON_FAILURE).unwrap(),
+            r#"{"name":"on-failure"}"#
+        );
+    }
+
+    #[test]
+    fn no_never_restarts() {
+        for exit_code in [0, 1, 137] {
+            assert_eq!(decide(RestartPolicy::No, exit_code, 0, false), Action::Exit);
+        }
+    }
+
+    #[test]
+    fn always_restarts_whatever_the_exit() {
+        for exit_code in [0, 1, 137] {
+            assert_eq!(
+                decide(RestartPolicy::Always, exit_code, 0, false),
+                Action::Restart(FIRST_BACKOFF)
+            );
+        }
+        assert!(matches!(
+            decide(RestartPolicy::Always, 0, 1000, false),
+            Action::Restart(_)
+        ));
+    }
+
+    #[test]
+    fn on_failure_restarts_only_failures_up_to_the_limit() {
+        assert_eq!(decide(ON_FAILURE, 0, 0, false), Action::Exit);
+        assert!(matches!(
+            decide(ON_FAILURE, 1, 50, false),
+            Action::Restart(_)
+        ));
+
+        let limited = RestartPolicy::OnFailure {
+            max_retries: Some(2),
+        };
+        assert!(matches!(decide(limited, 1, 0, false), Action::Restart(_)));
+        assert!(matches!(decide(limited, 1, 1, false), Action::Restart(_)));
+        assert_eq!(decide(limited, 1, 2, false), Action::Exit);
+        assert_eq!(decide(limited, 0, 0, false), Action::Exit);
+        // on-failure:0 means what it says
+        let never = RestartPolicy::OnFailure {
+            max_retries: Some(0),
+        };
+        assert_eq!(decide(never, 1, 0, false), Action::Exit);
+    }
+
+    #[test]
+    fn a_stopped_container_stays_stopped() {
+        for policy in [RestartPolicy::No, RestartPolicy::Always, ON_FAILURE] {
+            assert_eq!(decide(policy, 1, 0, true), Action::Exit);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        assert_eq!(backoff(0), Duration::from_millis(100));
+        assert_eq!(backoff(1), Duration::from_millis(200));
+        assert_eq!(backoff(5), Duration::from_millis(3200));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn stop_disables_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!disabled(dir.path()));
+        disable(dir.path()).unwrap();
+        disable(dir.path()).unwrap();
+        assert!(disabled(dir.path()));
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{
+    copy, read_to_string, remove_file, set_permissions, symlink_metadata, write, DirBuilder, File,
+    Permissions,
+};
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path};
+
+pub const DEFAULT_UMASK: libc::mode_t = 0o022;
+
+const DEFAULT_HOSTS: &str = "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n";
+
+// Scaffolding we add to the rootfs gets explicit modes rather than whatever the umask allows:
+// directories 0755, regular config files 0644 and device nodes 0666, like docker.
+
+pub fn create_dir(path: &Path) -> Result<()> {
+    // An image's absolute symlink (say /etc -> /somewhere) would resolve against the host's
+    // filesystem from out here, so replace it rather than write through it
+    let is_symlink = symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false);
+    if is_symlink {
+        remove_file(path)?;
+    }
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))
+}
+
+// docker creates a WorkingDir the image doesn't have, which is common with scratch images. One
+// reached through a symlink is left to chdir: creating it from out here would follow the link
+// on the host's side.
+pub fn create_working_dir(rootfs: &Path, dir: &str) -> Result<()> {
+    let dir = Path::new(dir);
+    if !dir.is_absolute() || dir.components().any(|part| part == Component::ParentDir) {
+        return Ok(());
+    }
+    let mut path = rootfs.to_path_buf();
+    for part in dir.components().skip(1) {
+        path.push(part);
+        match symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return Ok(()),
+            Ok(_) => {}
+            Err(_) => return create_dir(&rootfs.join(dir.strip_prefix("/")?)),
+        }
+    }
+    Ok(())
+}
+
+// Runs in the forked child: a mount namespace of its own, so mounts made for the container (and
+// their removal when it exits) stay away from the host. Slave rather than private, so mounts the
+// host makes can still reach an rslave volume; each mount says for itself whether it takes them.
+pub fn unshare_mounts() -> std::io::Result<()> {
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWNS) != 0
+            || libc::mount(
+                std::ptr::null(),
+                b"/\0".as_ptr().cast(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_SLAVE,
+                std::ptr::null(),
+            ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn copy_command(command: &str, rootfs: &Path) -> Result<()> {
+    // Bare names are looked up on the container's PATH, there's nothing on the host to copy.
+    // Neither is there for a path only the image has, a scratch image's one binary say.
+    if !command.starts_with('/') || !Path::new(command).is_file() {
+        return Ok(());
+    }
+    // Don't want '/usr/local/bin/docker-explorer' sending us back to the root of the file system.
+    // i.e. outside the temp dir we just created. So try to get a relative path
+    let command_path_relative = command.trim_start_matches('/');
+    let target_command = rootfs.join(command_path_relative);
+    create_dir(target_command.parent().unwrap())?;
+    // copy() carries the source's permission bits over, so the binary stays executable
+    copy(command, target_command)?;
+
+    Ok(())
+}
+
+pub fn create_dev(rootfs: &Path) -> Result<()> {
+    let dev = rootfs.join("dev");
+    create_dir(&dev)?;
+    set_permissions(&dev, Permissions::from_mode(0o755))?;
+
+    let null = dev.join("null");
+    if symlink_metadata(&null).is_ok() {
+        remove_file(&null)?;
+    }
+    // A real device node needs CAP_MKNOD; an empty file is the best we can do without it
+    let path = CString::new(null.as_os_str().as_bytes())?;
+    let created = unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o666, libc::makedev(1, 3)) };
+    if created != 0 {
+        File::create(&null)?;
+    }
+    set_permissions(&null, Permissions::from_mode(0o666))?;
+
+    Ok(())
+}
+
+// One --add-host name:ip mapping
+#[derive(Clone)]
+pub struct HostEntry {
+    pub name: String,
+    pub address: HostAddress,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum HostAddress {
+    Ip(IpAddr),
+    // "host-gateway", resolved once we're about to write /etc/hosts
+    Gateway,
+}
+
+impl HostEntry {
+    pub fn parse(value: &str) -> Result<HostEntry> {
+        // Split on the first colon only, IPv6 addresses are full of them
+        let (name, address) = match value.split_once(':') {
+            Some((name, address)) if !name.is_empty() && !address.is_empty() => (name, address),
+            _ => bail!("Invalid --add-host '{}', expected name:ip", value),
+        };
+        if name.chars().any(|c| c.is_whitespace() || c == '#') {
+            bail!("Invalid host name '{}' in --add-host", name);
+        }
+
+        let address = if address == "host-gateway" {
+            HostAddress::Gateway
+        } else {
+            // Accept the bracketed form people copy from URLs too
+            let address = address.trim_start_matches('[').trim_end_matches(']');
+            HostAddress::Ip(
+                address
+                    .parse()
+                    .with_context(|| format!("Invalid IP address '{}' in --add-host", address))?,
+            )
+        };
+
+        Ok(HostEntry {
+            name: name.to_string(),
+            address,
+        })
+    }
+}
+
+// Reject the same name mapped to two different addresses; exact repeats are harmless
+pub fn check_host_conflicts(entries: &[HostEntry]) -> Result<()> {
+    let mut seen: HashMap<&str, &HostAddress> = HashMap::new();
+    for entry in entries {
+        match seen.get(entry.name.as_str()) {
+            Some(address) if **address != entry.address => {
+                bail!("Conflicting --add-host entries for '{}'", entry.name)
+            }
+            _ => {
+                seen.insert(&entry.name, &entry.address);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Write /etc/hosts into the rootfs: whatever the image shipped (or a localhost-only default),
+// with every name given via --add-host taken out of existing lines and mapped as requested
+pub fn write_hosts(rootfs: &Path, entries: &[HostEntry]) -> Result<()> {
+    // First, so an etc that's a symlink out of the rootfs is gone before anything is read
+    // through it
+    create_dir(&rootfs.join("etc"))?;
+    let path = rootfs.join("etc/hosts");
+    // Never follow a symlink out of the rootfs onto the host's own files
+    let is_symlink = symlink_metadata(&path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false);
+    if is_symlink {
+        remove_file(&path)?;
+    }
+    let base = read_to_string(&path).unwrap_or_else(|_| DEFAULT_HOSTS.to_string());
+
+    let overridden: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    let mut hosts = String::new();
+    for line in base.lines() {
+        let content = line.split('#').next().unwrap_or("");
+        let mut fields = content.split_whitespace();
+        let address = match fields.next() {
+            Some(address) => address,
+            None => {
+                hosts.push_str(line);
+                hosts.push('\n');
+                continue;
+            }
+        };
+        let names: Vec<&str> = fields.collect();
+        let kept: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|name| !overridden.contains(name))
+            .collect();
+        if kept.len() == names.len() {
+            hosts.push_str(line);
+            hosts.push('\n');
+        } else if !kept.is_empty() {
+            hosts.push_str(&format!("{}\t{}\n", address, kept.join(" ")));
+        }
+    }
+
+    let mut gateway = None;
+    let mut written = vec![];
+    for entry in entries {
+        if written.contains(&entry.name.as_str()) {
+            continue;
+        }
+        let address = match entry.address {
+            HostAddress::Ip(address) => address,
+            HostAddress::Gateway => match gateway {
+                Some(address) => address,
+                None => {
+                    let address = host_primary_address()?;
+                    gateway = Some(address);
+                    address
+                }
+            },
+        };
+        hosts.push_str(&format!("{}\t{}\n", address, entry.name));
+        written.push(entry.name.as_str());
+    }
+
+    write(&path, hosts).with_context(|| format!("Failed to write {}", path.display()))?;
+    set_permissions(&path, Permissions::from_mode(0o644))?;
+
+    Ok(())
+}
+
+// The address the host would use for outbound traffic. Connecting a UDP socket doesn't send
+// anything, it just makes the kernel pick a route and source address.
+fn host_primary_address() -> Result<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket
+        .connect("192.0.2.1:9")
+        .context("Can't resolve host-gateway: no route from the host")?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &str) -> HostEntry {
+        HostEntry::parse(value).unwrap()
+    }
+
+    fn entries(values: &[&str]) -> Vec<HostEntry> {
+        values.iter().map(|value| entry(value)).collect()
+    }
+
+    #[test]
+    fn host_entries_split_at_the_first_colon() {
+        let parsed = entry("db:10.0.0.5");
+        assert_eq!(parsed.name, "db");
+        assert!(parsed.address == HostAddress::Ip("10.0.0.5".parse().unwrap()));
+        let ipv6: IpAddr = "fe80::1".parse().unwrap();
+        assert!(entry("db:fe80::1").address == HostAddress::Ip(ipv6));
+        assert!(entry("db:[fe80::1]").address == HostAddress::Ip(ipv6));
+        assert!(entry("db:host-gateway").address == HostAddress::Gateway);
+
+        for value in [
+            "db",
+            "db:",
+            ":10.0.0.5",
+            "db:nowhere",
+            "my db:10.0.0.5",
+            "#db:10.0.0.5",
+        ] {
+            assert!(HostEntry::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn only_different_addresses_for_one_name_conflict() {
+        check_host_conflicts(&entries(&["db:10.0.0.5", "db:10.0.0.5", "cache:10.0.0.6"])).unwrap();
+        check_host_conflicts(&entries(&["db:host-gateway", "db:host-gateway"])).unwrap();
+        let error = check_host_conflicts(&entries(&["db:10.0.0.5", "db:10.0.0.6"])).unwrap_err();
+        assert!(error.to_string().contains("'db'"), "{}", error);
+        assert!(check_host_conflicts(&entries(&["db:10.0.0.5", "db:host-gateway"])).is_err());
+    }
+
+    #[test]
+    fn added_hosts_take_their_names_out_of_the_images_lines() {
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir(&rootfs.path().join("etc")).unwrap();
+        write(
+            rootfs.path().join("etc/hosts"),
+            "# the image's\n127.0.0.1\tlocalhost db\n10.0.0.9\tdb\n",
+        )
+        .unwrap();
+
+        write_hosts(rootfs.path(), &entries(&["db:10.0.0.5", "db:10.0.0.5"])).unwrap();
+        assert_eq!(
+            read_to_string(rootfs.path().join("etc/hosts")).unwrap(),
+            "# the image's\n127.0.0.1\tlocalhost\n10.0.0.5\tdb\n"
+        );
+    }
+
+    #[test]
+    fn hosts_are_never_read_or_written_through_a_symlink() {
+        let outside = tempfile::tempdir().unwrap();
+        write(outside.path().join("hosts"), "10.9.9.9\thost-only\n").unwrap();
+
+        // etc itself pointing out of the rootfs, then just the hosts file
+        let rootfs = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), rootfs.path().join("etc")).unwrap();
+        write_hosts(rootfs.path(), &entries(&["db:10.0.0.5"])).unwrap();
+        let hosts = read_to_string(rootfs.path().join("etc/hosts")).unwrap();
+        assert_eq!(hosts, format!("{}10.0.0.5\tdb\n", DEFAULT_HOSTS));
+
+        let rootfs = tempfile::tempdir().unwrap();
+        create_dir(&rootfs.path().join("etc")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("hosts"),
+            rootfs.path().join("etc/hosts"),
+        )
+        .unwrap();
+        write_hosts(rootfs.path(), &entries(&["db:10.0.0.5"])).unwrap();
+        let hosts = read_to_string(rootfs.path().join("etc/hosts")).unwrap();
+        assert_eq!(hosts, format!("{}10.0.0.5\tdb\n", DEFAULT_HOSTS));
+
+        assert_eq!(
+            read_to_string(outside.path().join("hosts")).unwrap(),
+            "10.9.9.9\thost-only\n"
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use crate::data_root::DataRoot;
+use crate::manifest::Platform;
+use crate::pull::{self, ResolvedImage};
+use crate::registry::{Reference, RegistryClient};
+use crate::store::Store;
+use anyhow::{anyhow, bail, Result};
+use docker_starter_rust::Container;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::io::{stdin, stdout, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+// `serve` speaks newline-delimited JSON: one request per line on stdin, one response or event
+// per line on stdout. Every request carries an id of the client's choosing (any JSON value),
+// and everything written about it carries the same id, so requests can overlap freely.
+//   {"id": 1, "op": "pull", "image": "alpine:3.19", "platform": "linux/arm64"}
+//     -> {"id": 1, "ok": true, "reference": "...", "digest": "sha256:..."}
+//   {"id": 2, "op": "run", "image": "alpine:3.19", "command": ["echo", "hi"],
+//    "env": ["KEY=value"], "options": ["--memory", "64m"]}
+//     -> {"id": 2, "ok": true, "event": "started", "pid": 1234}
+//     -> {"id": 2, "ok": true, "event": "exited", "exit_code": 0, "stdout": "hi\n", "stderr": ""}
+//   {"id": 3, "op": "wait", "run": 2}
+//     -> {"id": 3, "ok": true, "exit_code": 0}
+// A request that fails gets {"id": ..., "ok": false, "error": "..."} instead, a run that fails
+// before it starts included. options are `run` options as on the command line. stdout and
+// stderr are captured whole and passed on as text, invalid UTF-8 replaced.
+//
+// Pulls happen in this process with registry clients kept per repository, so their tokens
+// carry over from one request to the next, and a run pulls its image the same way before
+// starting an offline `run` of the cached image. The container itself still gets a `run`
+// process of its own: a run changes process-wide state (the PID namespace new children go
+// into, signal handling) that one container can't share with the next. End of input stops
+// taking requests; serve exits once every run in flight has finished.
+pub async fn serve(data_root: &DataRoot, offline: bool, debug_http: bool) -> Result<()> {
+    serve_over(data_root, offline, debug_http, stdin(), stdout()).await
+}
+
+// serve with the requests read from input and the responses written to output
+async fn serve_over(
+    data_root: &DataRoot,
+    offline: bool,
+    debug_http: bool,
+    input: impl AsyncRead + Unpin,
+    mut output: impl AsyncWrite + Unpin + Send + 'static,
+) -> Result<()> {
+    let state = Arc::new(State {
+        store: Store::new(data_root.path()),
+        data_root: data_root.path().to_path_buf(),
+        executable: std::env::current_exe()?,
+        offline,
+        debug_http,
+        clients: Mutex::new(HashMap::new()),
+        runs: Mutex::new(HashMap::new()),
+    });
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+    // One writer, so lines from concurrent requests never interleave
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let mut line = message.to_string();
+            line.push('\n');
+            // A client that stopped reading gets nothing more, but its runs still finish
+            if output.write_all(line.as_bytes()).await.is_err() || output.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut tasks = JoinSet::new();
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, request) = match parse(&line) {
+            Ok(parsed) => parsed,
+            Err((id, error)) => {
+                let _ = sender.send(failure(&id, &error));
+                continue;
+            }
+        };
+        // Registered before the next line is read, so a wait right behind the run finds it
+        let exited = match &request {
+            Request::Run { .. } => match state.register(&id) {
+                Ok(exited) => Some(exited),
+                Err(error) => {
+                    let _ = sender.send(failure(&id, &error));
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        let (state, sender) = (state.clone(), sender.clone());
+        tasks.spawn(async move {
+            let message = match handle(&state, &id, request, exited, &sender).await {
+                Ok(message) => message,
+                Err(error) => failure(&id, &error),
+            };
+            let _ = sender.send(message);
+        });
+    }
+
+    while tasks.join_next().await.is_some() {}
+    drop(sender);
+    let _ = writer.await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum Request {
+    Pull {
+        image: String,
+        platform: Option<String>,
+    },
+    Run(RunRequest),
+    Wait {
+        run: Value,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunRequest {
+    image: String,
+    #[serde(default)]
+    command: Vec<String>,
+    #[serde(default)]
+    env: Vec<String>,
+    #[serde(default)]
+    options: Vec<String>,
+    platform: Option<String>,
+}
+
+// The exit code, or why the run never got one
+type Outcome = std::result::Result<i32, String>;
+
+struct State {
+    store: Store,
+    data_root: PathBuf,
+    executable: PathBuf,
+    // Pulls only ever look in the store
+    offline: bool,
+    debug_http: bool,
+    // By registry and repository, which is what a token covers
+    clients: Mutex<HashMap<String, Arc<RegistryClient>>>,
+    // By the run request's id: None until it's over
+    runs: Mutex<HashMap<String, watch::Receiver<Option<Outcome>>>>,
+}
+
+impl State {
+    fn register(&self, id: &Value) -> Result<watch::Sender<Option<Outcome>>> {
+        let mut runs = self.runs.lock().unwrap();
+        let key = id.to_string();
+        if let Some(run) = runs.get(&key) {
+            if run.borrow().is_none() {
+                bail!("A run with id {} is still going", key);
+            }
+        }
+        let (sender, receiver) = watch::channel(None);
+        runs.insert(key, receiver);
+        Ok(sender)
+    }
+
+    async fn client(&self, reference: &Reference) -> Result<Arc<RegistryClient>> {
+        let key = format!("{}/{}", reference.registry, reference.repository);
+        if let Some(client) = self.clients.lock().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+        let client = Arc::new(RegistryClient::connect(reference, self.debug_http).await?);
+        self.clients.lock().unwrap().insert(key, client.clone());
+        Ok(client)
+    }
+
+    // What `pull` does for one image, through the warm client
+    async fn pull(&self, image: &str, platform: Option<&Platform>) -> Result<ResolvedImage> {
+        let reference = Reference::parse(image)?;
+        if self.offline {
+            return pull::resolve_local(&self.store, reference, platform, true);
+        }
+        let client = self.client(&reference).await?;
+        // Keeps prune from deleting blobs between our finding and tagging them
+        let _cache = self.store.share_cache()?;
+        if let Some(image) =
+            pull::resolve_unchanged(&client, &self.store, &reference, platform, true).await?
+        {
+            return Ok(image);
+        }
+        let image = pull::resolve(&client, reference, platform).await?;
+        pull::pull(&client, &image, &self.store).await?;
+        Ok(image)
+    }
+}
+
+// The id comes out even when the rest doesn't parse, so the error can still go to the right
+// request
+fn parse(line: &str) -> std::result::Result<(Value, Request), (Value, anyhow::Error)> {
+    let value = serde_json::from_str::<Value>(line)
+        .map_err(|error| (Value::Null, anyhow!("Invalid JSON: {}", error)))?;
+    let mut value = match value {
+        Value::Object(object) => object,
+        _ => return Err((Value::Null, anyhow!("A request has to be a JSON object"))),
+    };
+    let id = value.remove("id").unwrap_or(Value::Null);
+    match serde_json::from_value::<Request>(Value::Object(value)) {
+        Ok(request) => Ok((id, request)),
+        Err(error) => Err((id, anyhow!("Invalid request: {}", error))),
+    }
+}
+
+async fn handle(
+    state: &State,
+    id: &Value,
+    request: Request,
+    exited: Option<watch::Sender<Option<Outcome>>>,
+    events: &mpsc::UnboundedSender<Value>,
+) -> Result<Value> {
+    match request {
+        Request::Pull { image, platform } => {
+            let platform = platform.as_deref().map(Platform::parse).transpose()?;
+            let image = state.pull(&image, platform.as_ref()).await?;
+            Ok(json!({
+                "id": id,
+                "ok": true,
+                "reference": image.reference.to_string(),
+                "digest": image.digest,
+            }))
+        }
+        Request::Run(request) => {
+            let exited = exited.expect("runs are registered before they're handled");
+            let result = run(state, id, request, events).await;
+            let _ = exited.send(Some(match &result {
+                Ok((exit_code, _)) => Ok(*exit_code),
+                Err(error) => Err(format!("{:#}", error)),
+            }));
+            result.map(|(_, message)| message)
+        }
+        Request::Wait { run } => {
+            let receiver = state.runs.lock().unwrap().get(&run.to_string()).cloned();
+            let mut receiver = match receiver {
+                Some(receiver) => receiver,
+                None => bail!("No run with id {}", run),
+            };
+            while receiver.borrow().is_none() {
+                receiver
+                    .changed()
+                    .await
+                    .map_err(|_| anyhow!("Run {} was dropped", run))?;
+            }
+            let outcome = receiver.borrow().clone().unwrap();
+            match outcome {
+                Ok(exit_code) => Ok(json!({"id": id, "ok": true, "exit_code": exit_code})),
+                Err(error) => bail!("Run {} failed: {}", run, error),
+            }
+        }
+    }
+}
+
+async fn run(
+    state: &State,
+    id: &Value,
+    request: RunRequest,
+    events: &mpsc::UnboundedSender<Value>,
+) -> Result<(i32, Value)> {
+    let platform = request
+        .platform
+        .as_deref()
+        .map(Platform::parse)
+        .transpose()?;
+    state.pull(&request.image, platform.as_ref()).await?;
+
+    let mut run = Container::builder(&request.image)
+        .executable(&state.executable)
+        .data_root(&state.data_root)
+        .offline(true)
+        .command(request.command);
+    if let Some(platform) = &request.platform {
+        run = run.run_option("--platform").run_option(platform);
+    }
+    for variable in &request.env {
+        run = run.run_option("--env").run_option(variable);
+    }
+    for option in &request.options {
+        run = run.run_option(option);
+    }
+    let mut command = Command::from(run.to_command()?);
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let child = command.spawn()?;
+    let _ = events.send(json!({
+        "id": id,
+        "ok": true,
+        "event": "started",
+        "pid": child.id(),
+    }));
+
+    let output = child.wait_with_output().await?;
+    let exit_code = match (output.status.code(), output.status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    };
+    Ok((
+        exit_code,
+        json!({
+            "id": id,
+            "ok": true,
+            "event": "exited",
+            "exit_code": exit_code,
+            "stdout": String::from_utf8_lossy(&output.stdout),
+            "stderr": String::from_utf8_lossy(&output.stderr),
+        }),
+    ))
+}
+
+fn failure(id: &Value, error: &anyhow::Error) -> Value {
+    json!({"id": id, "ok": false, "error": format!("{:#}", error)})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // Feeds the lines to serve over an in-memory pipe and returns what it wrote back, by id
+    async fn exchange(lines: &str) -> Vec<Value> {
+        let dir = tempfile::tempdir().unwrap();
+        let data_root = DataRoot::resolve(Some(dir.path().to_path_buf())).unwrap();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (input, output) = tokio::io::split(server);
+        client.write_all(lines.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let serving = serve_over(&data_root, true, false, input, output);
+        let mut text = String::new();
+        let (served, read) = tokio::join!(serving, client.read_to_string(&mut text));
+        served.unwrap();
+        read.unwrap();
+
+        let mut messages: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        messages.sort_by_key(|message| message["id"].to_string());
+        messages
+    }
+
+    fn error(message: &Value) -> &str {
+        assert_eq!(message["ok"], false, "{}", message);
+        message["error"].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn bad_lines_get_errors_and_the_rest_carry_on() {
+        let messages = exchange(concat!(
+            "not json\n",
+            "\n",
+            "[1, 2]\n",
+            "{\"id\": \"a\", \"op\": \"build\", \"image\": \"alpine\"}\n",
+            "{\"id\": \"b\", \"op\": \"pull\", \"image\": \"alpine\", \"tag\": \"x\"}\n",
+            "{\"id\": \"c\", \"image\": \"alpine\"}\n",
+            "{\"id\": \"d\", \"op\": \"wait\", \"run\": 42}\n",
+            "{\"id\": {\"any\": [\"json\"]}, \"op\": \"pull\", \"image\": \"Bad Image\"}\n",
+        ))
+        .await;
+        assert_eq!(messages.len(), 7);
+        let by_id = |id: Value| messages.iter().find(|message| message["id"] == id).unwrap();
+
+        let nulls: Vec<_> = messages
+            .iter()
+            .filter(|message| message["id"].is_null())
+            .collect();
+        assert_eq!(nulls.len(), 2);
+        assert!(nulls
+            .iter()
+            .any(|message| error(message).starts_with("Invalid JSON")));
+        assert!(nulls
+            .iter()
+            .any(|message| error(message).contains("JSON object")));
+        assert!(error(by_id(json!("a"))).contains("unknown variant `build`"));
+        assert!(error(by_id(json!("b"))).contains("unknown field `tag`"));
+        assert!(error(by_id(json!("c"))).contains("missing field `op`"));
+        assert_eq!(error(by_id(json!("d"))), "No run with id 42");
+        error(by_id(json!({"any": ["json"]})));
+    }
+
+    #[tokio::test]
+    async fn a_failed_run_is_reported_to_its_waiters() {
+        let messages = exchange(concat!(
+            "{\"id\": 1, \"op\": \"run\", \"image\": \"localhost/missing:1\"}\n",
+            "{\"id\": 2, \"op\": \"wait\", \"run\": 1}\n",
+            "{\"id\": 3, \"op\": \"pull\", \"image\": \"localhost/missing:1\"}\n",
+        ))
+        .await;
+        assert_eq!(messages.len(), 3);
+        let run = error(&messages[0]);
+        assert_eq!(messages[0]["id"], 1);
+        assert!(error(&messages[1]).starts_with("Run 1 failed: "));
+        assert!(error(&messages[1]).ends_with(run));
+        assert_eq!(error(&messages[2]), run);
+    }
+}
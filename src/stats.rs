@@ -0,0 +1,142 @@
+use crate::cgroup::{cpu_usage, read_number};
+use crate::container::ContainerState;
+use crate::pull::human_size;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Instant;
+
+// One reading of a container's cgroup files. Anything the kernel doesn't provide stays None.
+pub struct Sample {
+    pub taken: Instant,
+    pub cpu_usec: Option<u64>,
+    pub memory_current: Option<u64>,
+    pub memory_peak: Option<u64>,
+    pub pids: Option<u64>,
+}
+
+pub fn sample(cgroup: &Path) -> Result<Sample> {
+    if !cgroup.is_dir() {
+        bail!("cgroup {} no longer exists", cgroup.display());
+    }
+    Ok(Sample {
+        taken: Instant::now(),
+        cpu_usec: cpu_usage(cgroup),
+        memory_current: read_number(&cgroup.join("memory.current")),
+        memory_peak: read_number(&cgroup.join("memory.peak")),
+        pids: read_number(&cgroup.join("pids.current")),
+    })
+}
+
+// CPU time used between two samples as a share of wall time; 100% is one full core
+pub fn cpu_percent(previous: &Sample, current: &Sample) -> Option<f64> {
+    let used = current.cpu_usec?.checked_sub(previous.cpu_usec?)?;
+    let elapsed = current.taken.duration_since(previous.taken).as_micros();
+    if elapsed == 0 {
+        return None;
+    }
+    Some(used as f64 * 100.0 / elapsed as f64)
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    id: &'a str,
+    cpu_percent: Option<f64>,
+    memory_current: Option<u64>,
+    memory_peak: Option<u64>,
+    pids: Option<u64>,
+}
+
+pub fn header() -> String {
+    format!(
+        "{:<14}{:<10}{:<14}{:<14}PIDS",
+        "CONTAINER ID", "CPU %", "MEM USAGE", "MEM PEAK"
+    )
+}
+
+pub fn format_row(state: &ContainerState, previous: &Sample, current: &Sample) -> String {
+    let size = |bytes: Option<u64>| bytes.map(human_size).unwrap_or_else(|| "-".to_string());
+    format!(
+        "{:<14}{:<10}{:<14}{:<14}{}",
+        state.short_id(),
+        cpu_percent(previous, current)
+            .map(|percent| format!("{:.2}%", percent))
+            .unwrap_or_else(|| "-".to_string()),
+        size(current.memory_current),
+        size(current.memory_peak),
+        current
+            .pids
+            .map(|pids| pids.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    )
+}
+
+pub fn format_json(state: &ContainerState, previous: &Sample, current: &Sample) -> Result<String> {
+    Ok(serde_json::to_string(&JsonLine {
+        id: &state.id,
+        cpu_percent: cpu_percent(previous, current),
+        memory_current: current.memory_current,
+        memory_peak: current.memory_peak,
+        pids: current.pids,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_dir, write};
+    use std::time::Duration;
+
+    #[test]
+    fn samples_a_cgroup_directory() {
+        let cgroup = tempfile::tempdir().unwrap();
+        write(
+            cgroup.path().join("cpu.stat"),
+            "usage_usec 1500000\nuser_usec 1000000\n",
+        )
+        .unwrap();
+        write(cgroup.path().join("memory.current"), "4096\n").unwrap();
+        write(cgroup.path().join("pids.current"), "3\n").unwrap();
+
+        let sample = sample(cgroup.path()).unwrap();
+        assert_eq!(sample.cpu_usec, Some(1_500_000));
+        assert_eq!(sample.memory_current, Some(4096));
+        // Kernels before 5.19 have no memory.peak
+        assert_eq!(sample.memory_peak, None);
+        assert_eq!(sample.pids, Some(3));
+    }
+
+    #[test]
+    fn a_removed_cgroup_is_an_error() {
+        let cgroup = tempfile::tempdir().unwrap();
+        let path = cgroup.path().join("gone");
+        std::fs::create_dir(&path).unwrap();
+        remove_dir(&path).unwrap();
+        assert!(sample(&path).is_err());
+    }
+
+    fn at(taken: Instant, cpu_usec: Option<u64>) -> Sample {
+        Sample {
+            taken,
+            cpu_usec,
+            memory_current: None,
+            memory_peak: None,
+            pids: None,
+        }
+    }
+
+    #[test]
+    fn cpu_percent_is_usage_over_wall_time() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(1);
+        // Half a core for a second, then two full cores
+        let percent = cpu_percent(&at(start, Some(0)), &at(later, Some(500_000))).unwrap();
+        assert!((percent - 50.0).abs() < 1e-9);
+        let percent = cpu_percent(&at(start, Some(0)), &at(later, Some(2_000_000))).unwrap();
+        assert!((percent - 200.0).abs() < 1e-9);
+        assert_eq!(cpu_percent(&at(start, None), &at(later, Some(1))), None);
+        assert_eq!(cpu_percent(&at(start, Some(5)), &at(start, Some(5))), None);
+        // A counter that went backwards (a restarted cgroup) has no meaningful delta
+        assert_eq!(cpu_percent(&at(start, Some(5)), &at(later, Some(1))), None);
+    }
+}
@@ -0,0 +1,765 @@
+use crate::digest;
+use crate::lock::{lock_path, pid_alive, FileLock};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_dir, remove_file, rename, File};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Where blobs whose content doesn't match their digest are moved, out of the way of the download
+// that replaces them but still there to look at, until `cache verify --fix` deletes them
+const QUARANTINE: &str = "quarantine";
+
+// Bumped whenever repositories.json changes shape. Files without a version are version 1.
+const REPOSITORIES_VERSION: u32 = 4;
+
+// Content-addressed blob cache: blobs/sha256/<hex>, written only after their digest checks out.
+// Manifests are stored as blobs too, and repositories.json maps tags to the digest they pointed
+// at when last pulled, and when the registry last confirmed that. Several invocations can share
+// one store, so writers take a per-blob lock and a short-held lock around metadata updates.
+// Anything that could delete blobs (prune) holds the cache lock exclusively, while pulls and
+// runs hold it shared for as long as they use them.
+//
+// Every file is written to a temporary name, synced and renamed into place, so a crash leaves
+// either the old or the new version. Each tag also gets its own file under refs/, which is what
+// `store repair` rebuilds repositories.json from if that ever gets damaged.
+#[derive(Clone)]
+pub struct Store {
+    root: PathBuf,
+    lock_timeout: Duration,
+}
+
+impl Store {
+    pub fn new(root: &Path) -> Store {
+        Store {
+            root: root.to_path_buf(),
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Store {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let hex = digest::validate(digest)?;
+        Ok(self.root.join("blobs/sha256").join(hex))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest)
+            .map(|path| path.is_file())
+            .unwrap_or(false)
+    }
+
+    // has_blob, but the content is hashed as well: a blob that rotted on disk (or was
+    // half-written before writes were atomic) counts as missing, and is quarantined so it gets
+    // downloaded again instead of failing somewhere in extraction
+    pub fn has_intact_blob(&self, digest: &str) -> Result<bool> {
+        let path = self.blob_path(digest)?;
+        if !path.is_file() {
+            return Ok(false);
+        }
+        let actual = digest::sha256_file(&path)
+            .with_context(|| format!("Failed to read blob {}", digest))?;
+        if actual == digest {
+            return Ok(true);
+        }
+        let quarantined = self.quarantine(&path)?;
+        eprintln!(
+            "warning: cached blob {} is corrupt (its content hashes to {}), moved it to {}",
+            digest,
+            actual,
+            quarantined.display()
+        );
+        Ok(false)
+    }
+
+    fn quarantine(&self, path: &Path) -> Result<PathBuf> {
+        let dir = self.root.join(QUARANTINE);
+        create_dir_all(&dir)?;
+        let target = dir.join(format!(
+            "{}.{}",
+            path.file_name().unwrap().to_string_lossy(),
+            unix_time(Some(SystemTime::now()))
+        ));
+        match rename(path, &target) {
+            // Someone else moved or replaced it first
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            result => result.with_context(|| format!("Failed to quarantine {}", path.display()))?,
+        }
+        Ok(target)
+    }
+
+    // Hold this around checking for, downloading and writing a blob so concurrent pulls of the
+    // same layer wait for each other instead of both downloading it. Different blobs have
+    // different locks and can proceed in parallel.
+    pub fn lock_blob(&self, digest: &str) -> Result<FileLock> {
+        let path = self.blob_path(digest)?;
+        create_blob_dir(&path)?;
+        FileLock::acquire(&lock_path(&path), self.lock_timeout)
+    }
+
+    // For reading a blob a piece at a time, like an upload does
+    pub fn open_blob(&self, digest: &str) -> Result<File> {
+        let path = self.blob_path(digest)?;
+        File::open(&path).with_context(|| format!("Blob {} is not in the store", digest))
+    }
+
+    pub fn read_blob(&self, digest: &str) -> Result<Bytes> {
+        let path = self.blob_path(digest)?;
+        let data = read(&path).with_context(|| format!("Blob {} is not in the store", digest))?;
+        Ok(Bytes::from(data))
+    }
+
+    pub fn lock_cache(&self) -> Result<FileLock> {
+        create_dir_all(&self.root)?;
+        FileLock::acquire(&self.root.join("cache.lock"), self.lock_timeout)
+    }
+
+    pub fn share_cache(&self) -> Result<FileLock> {
+        create_dir_all(&self.root)?;
+        FileLock::acquire_shared(&self.root.join("cache.lock"), self.lock_timeout)
+    }
+
+    pub fn tag_digest(&self, tag: &str) -> Result<Option<String>> {
+        Ok(self.read_repositories()?.tags.get(tag).cloned())
+    }
+
+    pub fn tags(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self.read_repositories()?.tags)
+    }
+
+    // Every complete blob as (digest, size, when it was last used). Blobs never used since
+    // they were written count from their modification time.
+    pub fn blobs(&self) -> Result<Vec<(String, u64, u64)>> {
+        let dir = self.root.join("blobs/sha256");
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error).context("Failed to list blobs"),
+        };
+        let last_used = self.read_repositories()?.last_used;
+        let mut blobs = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.contains('.') {
+                // Locks and staging files
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let digest = format!("sha256:{}", name);
+            let used = last_used
+                .get(&digest)
+                .copied()
+                .unwrap_or_else(|| unix_time(metadata.modified().ok()));
+            blobs.push((digest, metadata.len(), used));
+        }
+        Ok(blobs)
+    }
+
+    // Record a cache hit on these blobs, for least-recently-used eviction
+    pub fn touch(&self, digests: &[&str]) -> Result<()> {
+        if digests.is_empty() {
+            return Ok(());
+        }
+        let _lock = self.lock_metadata()?;
+        let mut repositories = self.read_repositories()?;
+        let now = unix_time(Some(SystemTime::now()));
+        for digest in digests {
+            repositories.last_used.insert(digest.to_string(), now);
+        }
+        self.write_repositories(repositories)
+    }
+
+    // Delete blobs, each under its own lock so a pull that's just about to use one either
+    // finishes with it first or finds it gone and downloads it again
+    pub fn remove_blobs(&self, digests: &[&str]) -> Result<()> {
+        for digest in digests {
+            let _lock = self.lock_blob(digest)?;
+            match remove_file(self.blob_path(digest)?) {
+                Err(error) if error.kind() != ErrorKind::NotFound => {
+                    return Err(error).with_context(|| format!("Failed to delete blob {}", digest))
+                }
+                _ => {}
+            }
+        }
+        let _lock = self.lock_metadata()?;
+        let mut repositories = self.read_repositories()?;
+        for digest in digests {
+            repositories.last_used.remove(*digest);
+        }
+        self.write_repositories(repositories)
+    }
+
+    // When the registry last said where each tag points; tags it never has are missing
+    pub fn checked(&self) -> Result<BTreeMap<String, u64>> {
+        Ok(self.read_repositories()?.checked)
+    }
+
+    // The registry says the tag still points where we have it pointing
+    pub fn mark_checked(&self, tag: &str) -> Result<()> {
+        let _lock = self.lock_metadata()?;
+        let mut repositories = self.read_repositories()?;
+        repositories
+            .checked
+            .insert(tag.to_string(), unix_time(Some(SystemTime::now())));
+        self.write_repositories(repositories)
+    }
+
+    pub fn set_tag(&self, tag: &str, digest: &str) -> Result<()> {
+        let _lock = self.lock_metadata()?;
+        let mut repositories = self.read_repositories()?;
+        repositories
+            .tags
+            .insert(tag.to_string(), digest.to_string());
+        repositories
+            .checked
+            .insert(tag.to_string(), unix_time(Some(SystemTime::now())));
+
+        let refs = self.root.join("refs");
+        create_dir_all(&refs)?;
+        write_atomic(
+            &refs.join(ref_file_name(tag)),
+            format!("{}\n{}\n", tag, digest).as_bytes(),
+        )?;
+        self.write_repositories(repositories)
+    }
+
+    fn write_repositories(&self, mut repositories: Repositories) -> Result<()> {
+        repositories.version = REPOSITORIES_VERSION;
+        write_atomic(
+            &self.repositories_path(),
+            &serde_json::to_vec_pretty(&repositories)?,
+        )
+        .context("Failed to update repositories.json")
+    }
+
+    fn lock_metadata(&self) -> Result<FileLock> {
+        create_dir_all(&self.root)?;
+        FileLock::acquire(&lock_path(&self.repositories_path()), self.lock_timeout)
+    }
+
+    fn repositories_path(&self) -> PathBuf {
+        self.root.join("repositories.json")
+    }
+
+    fn read_repositories(&self) -> Result<Repositories> {
+        let data = match read(self.repositories_path()) {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Repositories::default()),
+            Err(error) => return Err(error).context("Failed to read repositories.json"),
+        };
+        let repositories: Repositories = serde_json::from_slice(&data)
+            .context("repositories.json is damaged, run `store repair` to rebuild it")?;
+        if repositories.version > REPOSITORIES_VERSION {
+            bail!(
+                "repositories.json is version {}, newer than this build understands ({})",
+                repositories.version,
+                REPOSITORIES_VERSION
+            );
+        }
+        Ok(repositories)
+    }
+
+    // Bring the store back into a consistent state after a crash or a damaged index: drop
+    // staging files left by dead processes and blobs whose content doesn't match their name,
+    // then rebuild repositories.json from refs/ (plus whatever still parses of the old index),
+    // keeping only tags that point at blobs we have.
+    pub fn repair(&self) -> Result<RepairReport> {
+        let _lock = self.lock_metadata()?;
+        let mut report = RepairReport::default();
+
+        let refs = self.root.join("refs");
+        let blobs = self.root.join("blobs/sha256");
+        for dir in [&self.root, &refs, &blobs] {
+            report.removed_partial += remove_abandoned(dir)?;
+        }
+        if blobs.is_dir() {
+            for entry in read_dir(&blobs)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                if name.ends_with(".partial") || name.ends_with(".lock") {
+                    continue;
+                }
+                let digest = format!("sha256:{}", name);
+                let _blob_lock = self.lock_blob(&digest)?;
+                let intact = read(&path)
+                    .map(|data| digest::verify(&digest, &data).is_ok())
+                    .unwrap_or(false);
+                if !intact {
+                    remove_file(&path)?;
+                    report.corrupt_blobs.push(digest);
+                }
+            }
+        }
+
+        // The old index first, so refs (written before it on every update) win
+        let mut tags = BTreeMap::new();
+        let mut last_used = BTreeMap::new();
+        let mut checked = BTreeMap::new();
+        if let Ok(data) = read(self.repositories_path()) {
+            if let Ok(old) = serde_json::from_slice::<Repositories>(&data) {
+                tags.extend(old.tags);
+                last_used = old.last_used;
+                checked = old.checked;
+            } else {
+                report.index_was_damaged = true;
+            }
+        }
+        if refs.is_dir() {
+            for entry in read_dir(&refs)? {
+                let contents = std::fs::read_to_string(entry?.path()).unwrap_or_default();
+                let mut lines = contents.lines();
+                if let (Some(tag), Some(digest)) = (lines.next(), lines.next()) {
+                    tags.insert(tag.to_string(), digest.to_string());
+                }
+            }
+        }
+        // Stores from before refs/ existed get them now, so the next repair has them too
+        create_dir_all(&refs)?;
+        for (tag, digest) in tags {
+            let ref_path = refs.join(ref_file_name(&tag));
+            if self.has_blob(&digest) {
+                write_atomic(&ref_path, format!("{}\n{}\n", tag, digest).as_bytes())?;
+                report.tags.insert(tag, digest);
+            } else {
+                let _ = remove_file(&ref_path);
+                report.dropped_tags.push(tag);
+            }
+        }
+
+        last_used.retain(|digest, _| self.has_blob(digest));
+        checked.retain(|tag, _| report.tags.contains_key(tag));
+
+        self.write_repositories(Repositories {
+            version: REPOSITORIES_VERSION,
+            tags: report.tags.clone(),
+            last_used,
+            checked,
+        })?;
+        Ok(report)
+    }
+
+    // Hash every blob, on up to jobs threads since a big cache is mostly waiting on the disk.
+    // Corrupt blobs and stray files (staging files whose writer is gone, names that aren't
+    // digests, quarantined blobs) are reported, and deleted with fix, each blob under its lock.
+    pub fn verify(&self, jobs: usize, fix: bool) -> Result<VerifyReport> {
+        let started = Instant::now();
+        let mut report = VerifyReport::default();
+        let mut queue = vec![];
+        let blobs = self.root.join("blobs/sha256");
+        if blobs.is_dir() {
+            for entry in read_dir(&blobs)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                if name.ends_with(".lock") {
+                    continue;
+                }
+                match partial_owner(&name) {
+                    Some(pid) if pid_alive(pid) => continue,
+                    Some(_) => report.stray.push(path),
+                    None if digest::validate(&format!("sha256:{}", name)).is_ok() => {
+                        queue.push((format!("sha256:{}", name), path))
+                    }
+                    None => report.stray.push(path),
+                }
+            }
+        }
+        let quarantine = self.root.join(QUARANTINE);
+        if quarantine.is_dir() {
+            for entry in read_dir(&quarantine)? {
+                report.stray.push(entry?.path());
+            }
+        }
+
+        let queue = Arc::new(Mutex::new(queue));
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    let mut checked = vec![];
+                    loop {
+                        let next = queue.lock().unwrap().pop();
+                        let (digest, path) = match next {
+                            Some(next) => next,
+                            None => return checked,
+                        };
+                        let size = std::fs::metadata(&path).map(|metadata| metadata.len());
+                        let intact = digest::sha256_file(&path).map(|actual| actual == digest);
+                        checked.push((digest, size.unwrap_or(0), intact.unwrap_or(false)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let checked = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("A verification thread panicked"))?;
+            for (digest, size, intact) in checked {
+                report.blobs += 1;
+                report.bytes += size;
+                if !intact {
+                    report.corrupt.push(digest);
+                }
+            }
+        }
+        report.corrupt.sort();
+        report.elapsed = started.elapsed();
+
+        if fix {
+            let corrupt: Vec<&str> = report.corrupt.iter().map(String::as_str).collect();
+            self.remove_blobs(&corrupt)?;
+            for path in &report.stray {
+                remove_file(path)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+            }
+        }
+        Ok(report)
+    }
+
+    // For blobs too big to hold in memory, which is to say layers
+    pub fn blob_writer(&self, digest: &str) -> Result<BlobWriter> {
+        let path = self.blob_path(digest)?;
+        create_blob_dir(&path)?;
+        let staging = staging_path(&path);
+        let file = File::create(&staging)
+            .with_context(|| format!("Failed to create {}", staging.display()))?;
+        Ok(BlobWriter {
+            digest: digest.to_string(),
+            path,
+            staging,
+            file,
+            hasher: digest::Sha256::new(),
+            written: 0,
+            committed: false,
+        })
+    }
+
+    pub fn put_blob(&self, digest: &str, data: &[u8]) -> Result<PathBuf> {
+        digest::verify(digest, data)?;
+
+        let path = self.blob_path(digest)?;
+        create_blob_dir(&path)?;
+        write_atomic(&path, data)?;
+        Ok(path)
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyReport {
+    // Blobs hashed, and their total size
+    pub blobs: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub corrupt: Vec<String>,
+    pub stray: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct RepairReport {
+    pub removed_partial: usize,
+    pub corrupt_blobs: Vec<String>,
+    pub index_was_damaged: bool,
+    // What repositories.json holds now
+    pub tags: BTreeMap<String, String>,
+    // Tags whose image is no longer in the store
+    pub dropped_tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Repositories {
+    #[serde(default = "first_version")]
+    version: u32,
+    // "registry/repository:tag" -> digest of the manifest or index
+    tags: BTreeMap<String, String>,
+    // Blob digest -> unix time a pull or run last found it in the cache
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    last_used: BTreeMap<String, u64>,
+    // Tag -> unix time the registry last confirmed (or set) its digest
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    checked: BTreeMap<String, u64>,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+fn unix_time(time: Option<SystemTime>) -> u64 {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// Write next to the final location and rename so readers never see a partial file. The pid
+// keeps staging files unique even if a caller forgot to take the relevant lock, and lets
+// `store repair` tell abandoned ones from those still being written. Syncing the directory
+// afterwards makes the rename itself survive a power cut.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    // "images.lock" on its own lives in the current directory
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let staging = staging_path(path);
+    let written = File::create(&staging).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    // Out of space, most likely, and a half-written file would only make that worse
+    if let Err(error) = written {
+        let _ = remove_file(&staging);
+        return Err(error.into());
+    }
+    rename(&staging, path)
+        .with_context(|| format!("Failed to move {} into place", path.display()))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// "<name>.<pid>.partial", next to the file it becomes
+fn staging_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        "{}.{}.partial",
+        path.file_name().unwrap().to_string_lossy(),
+        std::process::id()
+    ))
+}
+
+// A blob downloaded straight into the store: written to its staging file and hashed as it
+// arrives, so a layer never has to fit in memory and a full disk fails the write that hits it.
+// commit moves it into place once the digest checks out; dropped before that, the staging file
+// goes with it. The caller holds the blob's lock.
+pub struct BlobWriter {
+    digest: String,
+    path: PathBuf,
+    staging: PathBuf,
+    file: File,
+    hasher: digest::Sha256,
+    written: u64,
+    committed: bool,
+}
+
+impl BlobWriter {
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all(data)
+            .with_context(|| format!("Failed to write blob {}", self.digest))?;
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    // Bytes so far, which is where a resumed download picks up
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    // Back to nothing, for a server that sends the whole blob again
+    pub fn restart(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.hasher = digest::Sha256::new();
+        self.written = 0;
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<PathBuf> {
+        let hasher = std::mem::take(&mut self.hasher);
+        let actual = hasher.digest();
+        if actual != self.digest {
+            bail!(
+                "Digest mismatch: expected {} but content hashes to {}",
+                self.digest,
+                actual
+            );
+        }
+        self.file
+            .sync_all()
+            .with_context(|| format!("Failed to write blob {}", self.digest))?;
+        rename(&self.staging, &self.path)
+            .with_context(|| format!("Failed to move {} into place", self.path.display()))?;
+        self.committed = true;
+        File::open(self.path.parent().unwrap())?.sync_all()?;
+        Ok(self.path.clone())
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = remove_file(&self.staging);
+        }
+    }
+}
+
+// "<name>.<pid>.partial" -> pid
+fn partial_owner(name: &str) -> Option<i32> {
+    let stem = name.strip_suffix(".partial")?;
+    stem.rsplit_once('.')?.1.parse().ok()
+}
+
+// Staging files whose writer has died, which are never going to be renamed into place
+fn remove_abandoned(dir: &Path) -> Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if let Some(pid) = partial_owner(&name) {
+            if !pid_alive(pid) {
+                remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+// Tags contain slashes, which can't appear in a file name
+fn ref_file_name(tag: &str) -> String {
+    tag.replace('%', "%25").replace('/', "%2F")
+}
+
+fn create_blob_dir(blob: &Path) -> Result<&Path> {
+    let dir = blob.parent().unwrap();
+    create_dir_all(dir)
+        .with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_digest;
+
+    // No process has this pid: it's above the kernel's limit
+    const DEAD_PID: i32 = 1 << 30;
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.ends_with(".lock"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn a_dead_writers_staging_file_is_never_taken_for_the_blob() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let data = b"layer contents";
+        let digest = sha256_digest(data);
+        let path = store.blob_path(&digest).unwrap();
+        create_blob_dir(&path).unwrap();
+        // Complete, even: it just never got renamed
+        let leftover = path.with_file_name(format!(
+            "{}.{}.partial",
+            path.file_name().unwrap().to_string_lossy(),
+            DEAD_PID
+        ));
+        std::fs::write(&leftover, data).unwrap();
+
+        assert!(!store.has_blob(&digest));
+        assert!(store.read_blob(&digest).is_err());
+        let report = store.verify(1, false).unwrap();
+        assert_eq!((report.blobs, report.stray.len()), (0, 1));
+
+        store.put_blob(&digest, data).unwrap();
+        assert_eq!(&store.read_blob(&digest).unwrap()[..], data);
+        let report = store.repair().unwrap();
+        assert_eq!(report.removed_partial, 1);
+        assert!(!leftover.exists());
+        assert_eq!(
+            names(path.parent().unwrap()),
+            [path.file_name().unwrap().to_str().unwrap()]
+        );
+    }
+
+    #[test]
+    fn a_live_writers_staging_file_is_left_alone() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let digest = sha256_digest(b"whole");
+        let mut writer = store.blob_writer(&digest).unwrap();
+        writer.write(b"who").unwrap();
+        // As if we'd crashed mid-download, but we're still here
+        std::mem::forget(writer);
+
+        assert!(!store.has_blob(&digest));
+        let report = store.verify(1, true).unwrap();
+        assert!(report.stray.is_empty());
+        assert_eq!(store.repair().unwrap().removed_partial, 0);
+
+        // The next writer in this process starts the staging file over
+        let mut writer = store.blob_writer(&digest).unwrap();
+        writer.write(b"whole").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(&store.read_blob(&digest).unwrap()[..], b"whole");
+        let dir = store.blob_path(&digest).unwrap();
+        assert_eq!(names(dir.parent().unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn a_digest_mismatch_is_never_published() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path());
+        let digest = sha256_digest(b"expected");
+
+        assert!(store.put_blob(&digest, b"something else").is_err());
+        assert!(!store.has_blob(&digest));
+
+        let mut writer = store.blob_writer(&digest).unwrap();
+        writer.write(b"expected but longer").unwrap();
+        let error = writer.commit().unwrap_err().to_string();
+        assert!(error.starts_with("Digest mismatch"), "{}", error);
+        assert!(!store.has_blob(&digest));
+        let dir = store.blob_path(&digest).unwrap();
+        assert!(names(dir.parent().unwrap()).is_empty());
+
+        // Starting over after a bad attempt gets it right
+        let mut writer = store.blob_writer(&digest).unwrap();
+        writer.write(b"junk").unwrap();
+        writer.restart().unwrap();
+        writer.write(b"expected").unwrap();
+        writer.commit().unwrap();
+        assert!(store.has_blob(&digest));
+    }
+
+    #[test]
+    fn write_atomic_replaces_whatever_staging_file_is_there() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repositories.json");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::write(staging_path(&path), "half of somethi").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(names(dir.path()), ["repositories.json"]);
+
+        assert!(write_atomic(&dir.path().join("missing/file"), b"x").is_err());
+        assert_eq!(names(dir.path()), ["repositories.json"]);
+    }
+
+    #[test]
+    fn staging_files_name_their_writer() {
+        let path = Path::new("/store/blobs/sha256/abc");
+        let staging = staging_path(path);
+        let name = staging.file_name().unwrap().to_str().unwrap();
+        assert_eq!(partial_owner(name), Some(std::process::id() as i32));
+        assert_eq!(partial_owner("abc.partial"), None);
+        assert_eq!(partial_owner("abc"), None);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::cgroup;
+use crate::lock::pid_alive;
+use crate::restart;
+use anyhow::{bail, Context, Result};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tokio::process::Child;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
+
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often a restart's backoff checks whether `stop` has called it off
+const BACKOFF_POLL: Duration = Duration::from_millis(100);
+
+// How often a container without an init checks whether its cgroup has emptied
+const CGROUP_POLL: Duration = Duration::from_millis(100);
+
+const SIGNALS: [(&str, i32); 31] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("STKFLT", libc::SIGSTKFLT),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("PWR", libc::SIGPWR),
+    ("SYS", libc::SIGSYS),
+];
+
+// "SIGQUIT", "quit" and "3" all mean the same signal, as in an image's StopSignal
+pub fn parse_signal(value: &str) -> Result<i32> {
+    if let Ok(number) = value.parse::<i32>() {
+        if number > 0 && number < 65 {
+            return Ok(number);
+        }
+        bail!("Invalid signal number {}", number);
+    }
+    let upper = value.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    match SIGNALS.iter().find(|(known, _)| *known == name) {
+        Some((_, number)) => Ok(*number),
+        None => bail!("Unknown signal '{}'", value),
+    }
+}
+
+pub fn signal_name(signal: i32) -> String {
+    match SIGNALS.iter().find(|(_, number)| *number == signal) {
+        Some((name, _)) => format!("SIG{}", name),
+        None => format!("signal {}", signal),
+    }
+}
+
+// Exit status of run when --timeout cut the container short, the same as timeout(1)
+pub const TIMED_OUT: i32 = 124;
+
+// How the container's process came to an end
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Ending {
+    Exited,
+    // We shut it down on a signal aimed at us, which no restart policy should undo
+    Stopped,
+    // Its --timeout ran out
+    TimedOut,
+}
+
+// Wait for the container while it's attached to our terminal. Ctrl-C is passed on once so the
+// workload can handle it; a second Ctrl-C, or SIGTERM/SIGHUP aimed at us, shuts the container
+// down with a bounded grace period so we never leave it orphaned. Reaching the deadline does
+// the same, unless a Ctrl-C came first: someone is dealing with the container by hand then,
+// and the deadline is dropped for good.
+pub async fn supervise(
+    child: &mut Child,
+    stop_signal: i32,
+    stop_timeout: Duration,
+    deadline: &mut Option<tokio::time::Instant>,
+) -> Result<(ExitStatus, Ending)> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupted = false;
+
+    let (status, ending) = loop {
+        let timer = async {
+            match *deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            status = child.wait() => return Ok((status?, Ending::Exited)),
+            _ = timer => break (stop(child, stop_signal, stop_timeout).await, Ending::TimedOut),
+            _ = interrupt.recv() => {
+                if interrupted {
+                    break (stop(child, stop_signal, stop_timeout).await, Ending::Stopped);
+                }
+                interrupted = true;
+                *deadline = None;
+                send_signal(child, libc::SIGINT);
+            }
+            _ = terminate.recv() => {
+                break (stop(child, stop_signal, stop_timeout).await, Ending::Stopped)
+            }
+            _ = hangup.recv() => break (stop(child, stop_signal, stop_timeout).await, Ending::Stopped),
+        }
+    };
+    Ok((status?, ending))
+}
+
+// After the command has exited, for a container with a cgroup but no PID namespace: there's
+// no init to outlive the command, so what it left behind is waited for through the cgroup
+// instead, the way init waits for its namespace to empty. Signals are handled as supervise
+// does, passed on to everything in the cgroup or stopping all of it.
+pub async fn linger(
+    cgroup: &Path,
+    stop_signal: i32,
+    stop_timeout: Duration,
+    deadline: &mut Option<tokio::time::Instant>,
+) -> Result<Ending> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupted = false;
+
+    let ending = loop {
+        if cgroup::pids(cgroup).is_empty() {
+            return Ok(Ending::Exited);
+        }
+        let timer = async {
+            match *deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(CGROUP_POLL) => {}
+            _ = timer => break Ending::TimedOut,
+            _ = interrupt.recv() => {
+                if interrupted {
+                    break Ending::Stopped;
+                }
+                interrupted = true;
+                *deadline = None;
+                signal_cgroup(cgroup, libc::SIGINT);
+            }
+            _ = terminate.recv() => break Ending::Stopped,
+            _ = hangup.recv() => break Ending::Stopped,
+        }
+    };
+    stop_leftovers(cgroup, stop_signal, stop_timeout).await?;
+    Ok(ending)
+}
+
+// stop for whatever the command left in its cgroup, once it's gone itself
+pub async fn stop_leftovers(cgroup: &Path, stop_signal: i32, stop_timeout: Duration) -> Result<()> {
+    let cgroup = cgroup.to_path_buf();
+    let killed =
+        tokio::task::spawn_blocking(move || stop_cgroup(&cgroup, stop_signal, stop_timeout))
+            .await?;
+    if killed {
+        eprintln!(
+            "Container didn't stop within {}s of {}, sent SIGKILL",
+            stop_timeout.as_secs(),
+            signal_name(stop_signal)
+        );
+    }
+    Ok(())
+}
+
+// Sit out a restart's backoff, cut short (returning false) by the signals that would have
+// stopped the container or by `stop` disabling the restart
+pub async fn backoff(delay: Duration, dir: &Path) -> Result<bool> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let deadline = tokio::time::Instant::now() + delay;
+
+    while tokio::time::Instant::now() < deadline {
+        if restart::disabled(dir) {
+            return Ok(false);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(BACKOFF_POLL.min(deadline - tokio::time::Instant::now())) => {}
+            _ = interrupt.recv() => return Ok(false),
+            _ = terminate.recv() => return Ok(false),
+            _ = hangup.recv() => return Ok(false),
+        }
+    }
+    Ok(!restart::disabled(dir))
+}
+
+// The stop signal (SIGTERM unless the image or --stop-signal says otherwise), then SIGKILL if
+// the container is still around once the grace period is up
+pub async fn stop(
+    child: &mut Child,
+    stop_signal: i32,
+    stop_timeout: Duration,
+) -> Result<ExitStatus> {
+    send_signal(child, stop_signal);
+    match timeout(stop_timeout, child.wait()).await {
+        Ok(status) => {
+            eprintln!("Container stopped after {}", signal_name(stop_signal));
+            Ok(status?)
+        }
+        Err(_) => {
+            eprintln!(
+                "Container didn't stop within {}s of {}, sending SIGKILL",
+                stop_timeout.as_secs(),
+                signal_name(stop_signal)
+            );
+            send_signal(child, libc::SIGKILL);
+            child
+                .wait()
+                .await
+                .context("Failed to collect the container's exit status after SIGKILL")
+        }
+    }
+}
+
+// The child called setsid(), so its pid is also the process group id and a negative pid
+// reaches grandchildren like the `sleep` in `sh -c 'sleep 100 & wait'` too
+fn send_signal(child: &Child, signal: i32) {
+    // No pid means the child has already been reaped, so there's nobody left to signal
+    if let Some(pid) = child.id() {
+        unsafe {
+            libc::kill(-(pid as i32), signal);
+        }
+    }
+}
+
+// The same escalation for a container some other process is supervising, which we can only
+// reach through its pid. Returns whether it took a SIGKILL.
+pub fn stop_pid(pid: u32, stop_signal: i32, stop_timeout: Duration) -> bool {
+    let pid = pid as i32;
+    unsafe {
+        libc::kill(-pid, stop_signal);
+    }
+    let started = Instant::now();
+    while started.elapsed() < stop_timeout {
+        if !pid_alive(pid) {
+            return false;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    unsafe {
+        libc::kill(-pid, libc::SIGKILL);
+    }
+    true
+}
+
+// The same for everything in a cgroup, which is all a container without a PID namespace can
+// be reached through once its command is gone. SIGKILL goes out until nothing is left, in case
+// something forked in between. Returns whether it took a SIGKILL.
+pub fn stop_cgroup(cgroup: &Path, stop_signal: i32, stop_timeout: Duration) -> bool {
+    if !signal_cgroup(cgroup, stop_signal) {
+        return false;
+    }
+    let started = Instant::now();
+    while started.elapsed() < stop_timeout {
+        if cgroup::pids(cgroup).is_empty() {
+            return false;
+        }
+        sleep(CGROUP_POLL);
+    }
+    let mut killed = false;
+    while signal_cgroup(cgroup, libc::SIGKILL) {
+        killed = true;
+        sleep(CGROUP_POLL);
+    }
+    killed
+}
+
+// Whether there was anyone to signal
+fn signal_cgroup(cgroup: &Path, signal: i32) -> bool {
+    let pids = cgroup::pids(cgroup);
+    for pid in &pids {
+        unsafe {
+            libc::kill(*pid, signal);
+        }
+    }
+    !pids.is_empty()
+}
+
+// Runs in the forked child. Ignored signals survive exec, so a supervisor started from a
+// script (where SIGINT and SIGQUIT are ignored for background jobs) would otherwise hand a
+// container that can never see its own stop signal, since shells can't trap what they were
+// started ignoring.
+pub fn reset_signal_dispositions() {
+    for (_, signal) in SIGNALS.iter() {
+        if *signal != libc::SIGKILL && *signal != libc::SIGSTOP {
+            unsafe {
+                libc::signal(*signal, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+// Signals that only mean something to the process they happen in, and the ones an init has
+// no business passing on
+const NOT_FORWARDED: [i32; 10] = [
+    libc::SIGKILL,
+    libc::SIGSTOP,
+    libc::SIGCHLD,
+    libc::SIGILL,
+    libc::SIGTRAP,
+    libc::SIGABRT,
+    libc::SIGBUS,
+    libc::SIGFPE,
+    libc::SIGSEGV,
+    libc::SIGSYS,
+];
+
+// Runs in the forked child once it's in the rootfs, when it's PID 1 of a namespace of its own.
+// The child forks again: the new process goes on to exec the command, and the child stays
+// behind as the namespace's init. A command that daemonizes (forks and exits, leaving the fork
+// to do the work) then doesn't end the container, which it would as PID 1, since the kernel
+// kills everything in a namespace whose init is gone. init exits only once nothing is left in
+// the namespace, with the command's exit status.
+pub fn become_init() -> std::io::Result<()> {
+    let command = unsafe { libc::fork() };
+    if command < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if command == 0 {
+        // A process group of its own: init passes on what's sent to its group, and the
+        // command would get it twice otherwise
+        if unsafe { libc::setpgid(0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+    unsafe { init(command) }
+}
+
+// Only async-signal-safe calls here too, this is still a fork of a threaded process
+unsafe fn init(command: libc::pid_t) -> ! {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    libc::sigemptyset(&mut action.sa_mask);
+    for signal in 1..=libc::SIGRTMAX() {
+        // The realtime signals libc keeps for itself refuse a handler, which is fine
+        if !NOT_FORWARDED.contains(&signal) {
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+    // spawn() returns once the pipe std reports exec errors through is closed, and only the
+    // command's exec closes its end of it. Ours goes with everything else init doesn't need.
+    if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+        for fd in 3..libc::sysconf(libc::_SC_OPEN_MAX) as i32 {
+            libc::close(fd);
+        }
+    }
+
+    let mut code = 1;
+    loop {
+        let mut status = 0;
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == command {
+            code = exit_code(ExitStatus::from_raw(status));
+        } else if pid < 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            // ECHILD: everything in the namespace has exited
+            libc::_exit(code);
+        }
+    }
+}
+
+// init's handler: whatever reaches it from outside goes on to every other process in the
+// namespace, a daemon in a session of its own included
+extern "C" fn forward(signal: libc::c_int) {
+    unsafe {
+        libc::kill(-1, signal);
+    }
+}
+
+// Writing to a stdout whose reader has gone (`run ... | head -1`) has to fail with EPIPE, which
+// the relays shrug off, rather than raise a SIGPIPE that takes us down with the container still
+// running. Rust's runtime happens to ignore it already; this doesn't leave it to that. The
+// container gets the default back from reset_signal_dispositions.
+pub fn ignore_sigpipe() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
+
+// Shell convention: a process killed by signal N exits with 128 + N
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_by_name_or_number() {
+        for value in ["SIGQUIT", "quit", "Sigquit", "QUIT", "3"] {
+            assert_eq!(parse_signal(value).unwrap(), libc::SIGQUIT, "{}", value);
+        }
+        assert_eq!(parse_signal("SIGRTMIN").ok(), None);
+        assert_eq!(parse_signal("64").unwrap(), 64);
+        for value in ["0", "65", "-9", "SIGNOPE", "", "SIG"] {
+            assert!(parse_signal(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for (name, number) in SIGNALS {
+            assert_eq!(signal_name(number), format!("SIG{}", name));
+            assert_eq!(parse_signal(&signal_name(number)).unwrap(), number);
+        }
+        assert_eq!(signal_name(40), "signal 40");
+    }
+
+    #[test]
+    fn exit_codes_follow_the_shell() {
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGKILL)), 137);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGTERM)), 143);
+    }
+
+    // A command that forks a daemon and exits straight away
+    const DAEMONIZES: &str = "sleep 2 & exit 3";
+
+    #[test]
+    fn init_waits_for_what_the_command_left_behind() {
+        // Only children of the thread that unshares go into the new namespace
+        let ran = std::thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWPID) } != 0 {
+                return None;
+            }
+            let mut command = std::process::Command::new("sh");
+            command.args(["-c", DAEMONIZES]);
+            unsafe {
+                std::os::unix::process::CommandExt::pre_exec(&mut command, become_init);
+            }
+            let started = Instant::now();
+            let status = command.status().unwrap();
+            Some((status, started.elapsed()))
+        })
+        .join()
+        .unwrap();
+        let (status, elapsed) = match ran {
+            Some(ran) => ran,
+            None => return eprintln!("skipped: can't create a PID namespace"),
+        };
+        assert_eq!(status.code(), Some(3));
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    // A cgroup of our own on whichever cgroup2 hierarchy we can write to
+    fn test_cgroup(name: &str) -> Option<cgroup::Cgroup> {
+        let id = format!("test-{}-{}", std::process::id(), name);
+        [cgroup::CGROUP_ROOT, "/sys/fs/cgroup/unified"]
+            .iter()
+            .map(Path::new)
+            .filter(|root| cgroup::available(root))
+            .find_map(|root| cgroup::Cgroup::create(root, &id, None, None).ok())
+    }
+
+    async fn start_in(cgroup: &cgroup::Cgroup, script: &str) -> ExitStatus {
+        let procs = cgroup.procs_file();
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", script]);
+        unsafe {
+            command.pre_exec(move || {
+                libc::setsid();
+                cgroup::join(&procs)
+            });
+        }
+        command.status().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn linger_waits_for_what_the_command_left_in_its_cgroup() {
+        let cgroup = match test_cgroup("linger") {
+            Some(cgroup) => cgroup,
+            None => return eprintln!("skipped: no cgroup2 hierarchy to create a cgroup in"),
+        };
+        let started = Instant::now();
+        assert_eq!(start_in(&cgroup, DAEMONIZES).await.code(), Some(3));
+        assert_eq!(cgroup::pids(cgroup.path()).len(), 1);
+
+        let ending = linger(
+            cgroup.path(),
+            libc::SIGTERM,
+            DEFAULT_STOP_TIMEOUT,
+            &mut None,
+        )
+        .await
+        .unwrap();
+        assert!(ending == Ending::Exited);
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        assert!(cgroup::pids(cgroup.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_deadline_stops_what_the_command_left_behind() {
+        let cgroup = match test_cgroup("deadline") {
+            Some(cgroup) => cgroup,
+            None => return eprintln!("skipped: no cgroup2 hierarchy to create a cgroup in"),
+        };
+        let started = Instant::now();
+        assert_eq!(start_in(&cgroup, "sleep 30 &").await.code(), Some(0));
+
+        let mut deadline = Some(tokio::time::Instant::now() + Duration::from_millis(200));
+        let ending = linger(
+            cgroup.path(),
+            libc::SIGTERM,
+            DEFAULT_STOP_TIMEOUT,
+            &mut deadline,
+        )
+        .await
+        .unwrap();
+        assert!(ending == Ending::TimedOut);
+        assert!(started.elapsed() < DEFAULT_STOP_TIMEOUT);
+        assert!(cgroup::pids(cgroup.path()).is_empty());
+    }
+
+    #[test]
+    fn an_empty_cgroup_needs_no_stopping() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cgroup.procs"), "").unwrap();
+        assert!(!stop_cgroup(
+            dir.path(),
+            libc::SIGTERM,
+            DEFAULT_STOP_TIMEOUT
+        ));
+        // Gone altogether
+        assert!(!stop_cgroup(
+            &dir.path().join("missing"),
+            libc::SIGTERM,
+            DEFAULT_STOP_TIMEOUT
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::rootfs::create_dir;
+use crate::volume::{Mount, MountPoint, Propagation};
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fs::{canonicalize, read_link, remove_file, symlink_metadata, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+const LOCALTIME: &str = "/etc/localtime";
+
+// --tz: the host's timezone, or one by its IANA name
+#[derive(Clone, PartialEq, Eq)]
+pub enum Timezone {
+    Host,
+    Named(String),
+}
+
+// How the container gets its timezone: TZ when the image has tzdata of its own, otherwise the
+// zone's file mounted from the host. The mount goes in with the volumes, before chroot.
+pub struct Plan {
+    pub env: Option<String>,
+    pub mount: Option<Mount>,
+}
+
+impl Timezone {
+    pub fn parse(value: &str) -> Result<Timezone> {
+        if value == "host" {
+            return Ok(Timezone::Host);
+        }
+        // A path into the zoneinfo tree, and nothing that could climb out of it
+        let valid = !value.is_empty()
+            && !value.starts_with('/')
+            && value.split('/').all(|part| {
+                !part.is_empty()
+                    && part != "."
+                    && part != ".."
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_+-".contains(c))
+            });
+        if !valid {
+            bail!(
+                "Invalid --tz '{}', expected host or a name like Europe/Berlin",
+                value
+            );
+        }
+        Ok(Timezone::Named(value.to_string()))
+    }
+
+    // Where the zone's data is on the host, and its name if it has one. The host's is whatever
+    // /etc/localtime is; its name only comes with it when that's the usual symlink into
+    // zoneinfo.
+    fn on_host(&self) -> Result<(PathBuf, Option<String>)> {
+        match self {
+            Timezone::Host => {
+                let file = canonicalize(LOCALTIME)
+                    .with_context(|| format!("--tz host needs the host's {}", LOCALTIME))?;
+                let name = read_link(LOCALTIME).ok().and_then(|target| {
+                    let target = target.to_string_lossy().into_owned();
+                    let (_, name) = target.split_once("zoneinfo/")?;
+                    Timezone::parse(name).ok().map(|_| name.to_string())
+                });
+                Ok((file, name))
+            }
+            Timezone::Named(name) => Ok((Path::new(ZONEINFO).join(name), Some(name.clone()))),
+        }
+    }
+}
+
+// Decided on the assembled rootfs, since whether TZ works depends on the image having the
+// zone. Without tzdata the zone's file is mounted at its zoneinfo path with /etc/localtime a
+// symlink to it, the way distributions lay it out, so tools that read the zone's name off the
+// symlink find it, and TZ=<name> works as well. -e TZ= still has the last word: it's applied
+// after this.
+pub fn plan(timezone: &Timezone, rootfs: &Path) -> Result<Plan> {
+    let (source, name) = timezone.on_host()?;
+    let in_image = |path: &str| symlink_metadata(rootfs.join(path.trim_start_matches('/')));
+    if let Some(name) = &name {
+        let has_tzdata = in_image(ZONEINFO)
+            .map(|metadata| metadata.is_dir())
+            .unwrap_or(false);
+        if has_tzdata && in_image(&format!("{}/{}", ZONEINFO, name)).is_ok() {
+            return Ok(Plan {
+                env: Some(format!("TZ={}", name)),
+                mount: None,
+            });
+        }
+    }
+
+    if !source.is_file() {
+        bail!(
+            "Unknown timezone {}, neither the image nor the host has zoneinfo for it",
+            name.as_deref().unwrap_or("")
+        );
+    }
+    let destination = match &name {
+        Some(name) => {
+            let destination = PathBuf::from(format!("{}/{}", ZONEINFO, name));
+            replace_with(&rootfs.join(&LOCALTIME[1..]), |localtime| {
+                symlink(&destination, localtime)
+            })?;
+            destination
+        }
+        None => PathBuf::from(LOCALTIME),
+    };
+    let target = rootfs.join(destination.strip_prefix("/")?);
+    replace_with(&target, |target| File::create(target).map(|_| ()))?;
+    Ok(Plan {
+        env: name.map(|name| format!("TZ={}", name)),
+        mount: Some(Mount {
+            source: CString::new(source.as_os_str().as_bytes())?,
+            target: CString::new(target.as_os_str().as_bytes())?,
+            read_only: true,
+            propagation: Propagation::Private,
+            submounts: vec![],
+            point: MountPoint {
+                source,
+                destination,
+                read_only: true,
+            },
+        }),
+    })
+}
+
+// Whatever the image has at path goes, a symlink above all: mounting onto one would follow it
+// from out here, against the host's filesystem
+fn replace_with(path: &Path, create: impl Fn(&Path) -> std::io::Result<()>) -> Result<()> {
+    create_dir(path.parent().unwrap())?;
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            bail!("The image has a directory at {}", path.display())
+        }
+        Ok(_) => remove_file(path)?,
+        Err(_) => {}
+    }
+    create(path).with_context(|| format!("Failed to create {}", path.display()))
+}
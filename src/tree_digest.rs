@@ -0,0 +1,239 @@
+use crate::digest::Sha256;
+use crate::ownership::{self, Owners};
+use anyhow::{Context, Result};
+use std::fs::{read_dir, read_link, symlink_metadata, File, Metadata};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+// A canonical hash of an assembled rootfs: the same tree gives the same hash wherever and
+// whenever it was extracted. It's Merkle-style, each directory hashing its own metadata with
+// the names and hashes of its entries in byte order, so the whole tree comes down to the
+// root's. What each kind of entry contributes:
+//   directory  mode, uid:gid, entries
+//   file       mode, uid:gid, sha256 of the content
+//   symlink    uid:gid, target
+//   device     mode, uid:gid, major:minor
+//   fifo       mode, uid:gid
+// mtimes, inode numbers and hardlinks are left out, they say how the tree was put together
+// rather than what's in it. Owners come from the ownership record, which holds what the layers
+// asked for no matter who extracted them.
+pub fn digest(rootfs: &Path) -> Result<String> {
+    let owners = ownership::load(rootfs)?;
+    let hash = node(rootfs, Path::new(""), &owners)?;
+    Ok(format!("sha256:{}", hex(&hash)))
+}
+
+// path is relative to the rootfs, "" for the rootfs itself
+fn node(rootfs: &Path, path: &Path, owners: &Owners) -> Result<[u8; 32]> {
+    let host = rootfs.join(path);
+    let metadata =
+        symlink_metadata(&host).with_context(|| format!("Failed to stat {}", host.display()))?;
+    let mut record = header(path, &metadata, owners).into_bytes();
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        let mut names = read_dir(&host)
+            .with_context(|| format!("Failed to list {}", host.display()))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // By bytes, not by whatever a locale would make of them
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in names {
+            let hash = node(rootfs, &path.join(&name), owners)?;
+            // Length-prefixed, so no name can pass for another entry
+            record.extend_from_slice(format!("\n{} ", name.len()).as_bytes());
+            record.extend_from_slice(name.as_bytes());
+            record.extend_from_slice(format!(" {}", hex(&hash)).as_bytes());
+        }
+    } else if file_type.is_file() {
+        record.extend_from_slice(format!(" {}", hex(&content(&host)?)).as_bytes());
+    } else if file_type.is_symlink() {
+        let target = read_link(&host)?;
+        record.extend_from_slice(format!(" {} ", target.as_os_str().len()).as_bytes());
+        record.extend_from_slice(target.as_os_str().as_bytes());
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        let device = metadata.rdev();
+        let (major, minor) = unsafe { (libc::major(device), libc::minor(device)) };
+        record.extend_from_slice(format!(" {}:{}", major, minor).as_bytes());
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(&record);
+    Ok(hasher.finalize())
+}
+
+// The kind, mode and owner. A symlink's mode is always 0777 on Linux, so it's left out.
+fn header(path: &Path, metadata: &Metadata, owners: &Owners) -> String {
+    let (uid, gid) = owners
+        .get(&ownership::container_path(path))
+        .copied()
+        .unwrap_or((0, 0));
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_file() {
+        "file"
+    } else if file_type.is_symlink() {
+        return format!("symlink {}:{}", uid, gid);
+    } else if file_type.is_char_device() {
+        "char"
+    } else if file_type.is_block_device() {
+        "block"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else {
+        "socket"
+    };
+    format!("{} {:o} {}:{}", kind, metadata.mode() & 0o7777, uid, gid)
+}
+
+fn content(path: &Path) -> Result<[u8; 32]> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 << 10];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, create_dir_all, hard_link, set_permissions, write, Permissions};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    // A rootfs inside a scratch directory, where the ownership record goes next to it
+    fn rootfs() -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        create_dir(&rootfs).unwrap();
+        (dir, rootfs)
+    }
+
+    // The same small tree every time, modes pinned so the umask doesn't matter
+    fn populate(rootfs: &Path, order: &[&str]) {
+        for name in order {
+            let path = rootfs.join(name);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, format!("contents of {}", name)).unwrap();
+            set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        }
+        symlink("../lib/libc.so", rootfs.join("bin/libc")).unwrap();
+        for dir in ["bin", "lib", "etc"] {
+            set_permissions(rootfs.join(dir), Permissions::from_mode(0o755)).unwrap();
+        }
+        set_permissions(rootfs, Permissions::from_mode(0o755)).unwrap();
+    }
+
+    const FILES: [&str; 4] = ["bin/sh", "lib/libc.so", "etc/passwd", "etc/group"];
+
+    fn tree() -> (tempfile::TempDir, std::path::PathBuf) {
+        let (dir, rootfs) = rootfs();
+        populate(&rootfs, &FILES);
+        (dir, rootfs)
+    }
+
+    #[test]
+    fn the_same_tree_hashes_the_same_however_it_was_made() {
+        let (_first_dir, first) = tree();
+        let (_second_dir, second) = rootfs();
+        let mut reversed = FILES;
+        reversed.reverse();
+        populate(&second, &reversed);
+        // Made at another time, as far as the mtimes go
+        let old = libc::timespec {
+            tv_sec: 1,
+            tv_nsec: 0,
+        };
+        let path =
+            std::ffi::CString::new(second.join("etc/passwd").as_os_str().as_bytes()).unwrap();
+        assert_eq!(
+            unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), [old, old].as_ptr(), 0) },
+            0
+        );
+
+        let hash = digest(&first).unwrap();
+        assert!(hash.starts_with("sha256:") && hash.len() == 71);
+        assert_eq!(digest(&second).unwrap(), hash);
+        assert_eq!(digest(&first).unwrap(), hash);
+    }
+
+    #[test]
+    fn hardlinks_hash_like_copies() {
+        let (_first_dir, first) = tree();
+        let (_second_dir, second) = tree();
+        std::fs::copy(first.join("bin/sh"), first.join("bin/ash")).unwrap();
+        hard_link(second.join("bin/sh"), second.join("bin/ash")).unwrap();
+        assert_eq!(digest(&first).unwrap(), digest(&second).unwrap());
+    }
+
+    type Change = fn(&Path);
+
+    #[test]
+    fn every_kind_of_change_shows() {
+        let (_dir, base) = tree();
+        let unchanged = digest(&base).unwrap();
+        let changes: [(&str, Change); 8] = [
+            ("content", |rootfs| {
+                write(rootfs.join("etc/passwd"), "x").unwrap()
+            }),
+            ("mode", |rootfs| {
+                set_permissions(rootfs.join("bin/sh"), Permissions::from_mode(0o755)).unwrap()
+            }),
+            ("directory mode", |rootfs| {
+                set_permissions(rootfs.join("etc"), Permissions::from_mode(0o700)).unwrap()
+            }),
+            ("owner", |rootfs| {
+                let owners = [("/etc/passwd".to_string(), (1000, 1000))]
+                    .into_iter()
+                    .collect();
+                ownership::save(rootfs, &owners).unwrap()
+            }),
+            ("symlink target", |rootfs| {
+                std::fs::remove_file(rootfs.join("bin/libc")).unwrap();
+                symlink("/lib/libc.so", rootfs.join("bin/libc")).unwrap();
+            }),
+            ("name", |rootfs| {
+                std::fs::rename(rootfs.join("etc/group"), rootfs.join("etc/groups")).unwrap()
+            }),
+            ("new empty file", |rootfs| {
+                write(rootfs.join("etc/empty"), "").unwrap()
+            }),
+            ("new empty directory", |rootfs| {
+                create_dir(rootfs.join("tmp")).unwrap();
+                set_permissions(rootfs.join("tmp"), Permissions::from_mode(0o755)).unwrap()
+            }),
+        ];
+        for (what, change) in changes {
+            let (_dir, rootfs) = tree();
+            assert_eq!(digest(&rootfs).unwrap(), unchanged);
+            change(&rootfs);
+            assert_ne!(digest(&rootfs).unwrap(), unchanged, "{}", what);
+        }
+    }
+
+    #[test]
+    fn a_file_and_a_directory_never_collide() {
+        // The same names, once as a file holding "" and once as an empty directory
+        let (_first_dir, first) = rootfs();
+        write(first.join("x"), "").unwrap();
+        set_permissions(first.join("x"), Permissions::from_mode(0o755)).unwrap();
+        let (_second_dir, second) = rootfs();
+        create_dir(second.join("x")).unwrap();
+        set_permissions(second.join("x"), Permissions::from_mode(0o755)).unwrap();
+        for rootfs in [&first, &second] {
+            set_permissions(rootfs, Permissions::from_mode(0o755)).unwrap();
+        }
+        assert_ne!(digest(&first).unwrap(), digest(&second).unwrap());
+    }
+}
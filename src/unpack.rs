@@ -0,0 +1,1351 @@
+use crate::manifest::Descriptor;
+use crate::ownership::{self, Owners};
+use crate::rootfs::create_dir;
+use crate::store::{write_atomic, Store};
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{
+    read_dir, read_to_string, remove_dir, remove_dir_all, remove_file, rename, set_permissions,
+    symlink_metadata, File, Permissions,
+};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use tar::{Archive, Entry, EntryType};
+
+// Digests of the layers already merged into a rootfs, in order, one per line. It lives next to
+// the rootfs rather than in it so the container never sees it.
+const LEDGER: &str = "layers.applied";
+const STAGING: &str = "layer.partial";
+// How a layer deletes what the layers below it have, see merge
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+// A layer may expand to this many times its compressed size before we call it a decompression
+// bomb, unless --max-layer-size sets the ceiling instead. Text and binaries rarely get past 5x,
+// but tiny layers can have big ratios, hence the floor.
+const EXPANSION_RATIO: u64 = 20;
+const MIN_EXPANDED_SIZE: u64 = 64 << 20;
+// Real layers have tens of thousands of entries at most
+const MAX_LAYER_ENTRIES: u64 = 1_000_000;
+
+// What to do about layer entries we can't or won't create
+#[derive(Clone, Copy)]
+pub struct EntryPolicy {
+    // Fail on entries that can't be decoded or created instead of leaving them out
+    pub strict: bool,
+    // Create the layers' device nodes. Only root can, and an untrusted image has no business
+    // putting devices in the rootfs, so by default they're left out.
+    pub allow_devices: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_media_type(media_type: &str) -> Option<Compression> {
+        match media_type {
+            "application/vnd.oci.image.layer.v1.tar"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar"
+            | "application/vnd.docker.image.rootfs.diff.tar" => Some(Compression::None),
+            "application/vnd.docker.image.rootfs.diff.tar.gzip"
+            | "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
+            | "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip" => {
+                Some(Compression::Gzip)
+            }
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd" => {
+                Some(Compression::Zstd)
+            }
+            _ => None,
+        }
+    }
+
+    // Work out the format from the blob's first bytes. Tar has no magic at the start, but
+    // ustar/GNU archives carry "ustar" at offset 257 of the first header.
+    pub fn sniff(header: &[u8]) -> Option<Compression> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+            Some(Compression::None)
+        } else {
+            None
+        }
+    }
+}
+
+// Apply an image's layers to rootfs in order. Each one is extracted into a staging directory
+// and only merged into the rootfs once the whole archive extracted cleanly, then recorded in
+// the ledger. A failed layer therefore leaves nothing behind, and calling this again on the same
+// rootfs carries on from the first layer that isn't recorded instead of reapplying the rest.
+// Who the layers say should own what is recorded alongside, see ownership.rs.
+pub fn apply_layers(
+    store: &Store,
+    layers: &[Descriptor],
+    rootfs: &Path,
+    max_layer_size: Option<u64>,
+    policy: EntryPolicy,
+) -> Result<u64> {
+    let dir = rootfs.parent().unwrap();
+    let ledger = dir.join(LEDGER);
+    let mut applied = read_ledger(&ledger)?;
+    if !layers
+        .iter()
+        .map(|layer| &layer.digest)
+        .take(applied.len())
+        .eq(applied.iter())
+    {
+        bail!(
+            "{} was partly assembled from a different image",
+            rootfs.display()
+        );
+    }
+
+    let mut owners = ownership::load(rootfs)?;
+    let mut skipped = 0;
+    for layer in &layers[applied.len()..] {
+        let staging = dir.join(STAGING);
+        if symlink_metadata(&staging).is_ok() {
+            remove_dir_all(&staging)?;
+        }
+        create_dir(&staging)?;
+        let limit = expansion_limit(layer.size, max_layer_size);
+        let unpacked = unpack_layer(
+            &store.blob_path(&layer.digest)?,
+            &layer.media_type,
+            &staging,
+            limit,
+            policy,
+        );
+        let extracted = match unpacked {
+            Ok(extracted) => extracted,
+            Err(error) => {
+                // Whatever a bomb managed to write shouldn't sit on the disk until the next run
+                let _ = remove_dir_all(&staging);
+                return Err(error)
+                    .with_context(|| format!("Failed to extract layer {}", layer.digest));
+            }
+        };
+        if let Err(error) = merge(&staging, rootfs, Path::new(""), &extracted.directories) {
+            let _ = remove_dir_all(&staging);
+            return Err(error).with_context(|| format!("Failed to apply layer {}", layer.digest));
+        }
+        remove_dir(&staging)?;
+        // Before the ledger: a layer that's applied again records the same owners again
+        ownership::merge(&mut owners, extracted.owners, rootfs);
+        if extracted.skipped > 0 {
+            eprintln!(
+                "warning: left {} entries of layer {} out",
+                extracted.skipped, layer.digest
+            );
+            skipped += extracted.skipped;
+        }
+        // Left out on purpose or for want of privileges, never a reason to fail
+        if extracted.devices > 0 {
+            eprintln!(
+                "warning: left {} device nodes of layer {} out, {}",
+                extracted.devices,
+                layer.digest,
+                if policy.allow_devices {
+                    "creating them takes root"
+                } else {
+                    "--allow-devices creates them"
+                }
+            );
+        }
+        if extracted.fifos > 0 {
+            eprintln!(
+                "warning: left {} fifos of layer {} out, they couldn't be created",
+                extracted.fifos, layer.digest
+            );
+        }
+        ownership::save(rootfs, &owners)?;
+
+        applied.push(layer.digest.clone());
+        let mut contents = applied.join("\n");
+        contents.push('\n');
+        write_atomic(&ledger, contents.as_bytes())?;
+    }
+    Ok(skipped)
+}
+
+// How far a layer of size compressed bytes may expand before extraction gives up on it
+fn expansion_limit(size: u64, max_layer_size: Option<u64>) -> u64 {
+    max_layer_size.unwrap_or_else(|| size.saturating_mul(EXPANSION_RATIO).max(MIN_EXPANDED_SIZE))
+}
+
+// For a rootfs that's handed over rather than kept in a container directory, where the ledger
+// and ownership record would otherwise outlive the rootfs they describe
+pub fn remove_bookkeeping(rootfs: &Path) -> Result<()> {
+    match remove_file(rootfs.parent().unwrap().join(LEDGER)) {
+        Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+        _ => {}
+    }
+    ownership::remove(rootfs)
+}
+
+fn read_ledger(path: &Path) -> Result<Vec<String>> {
+    match read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(error) => Err(error).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+// Move everything from a freshly extracted layer into the tree below it, the way an overlay
+// would show it: directories present in both are merged, anything else in the layer replaces
+// what was there. A .wh.<name> marker deletes <name> from the tree below and a .wh..wh..opq
+// marker empties its directory before the layer's own entries go in; the markers themselves
+// never reach the tree. Symlinks are never followed, they belong to the image. If this is
+// interrupted the layer simply gets extracted and merged again, which overwrites the same
+// entries. path is where layer sits relative to the layer root, directories what the layer had
+// entries for.
+fn merge(layer: &Path, target: &Path, path: &Path, directories: &HashSet<PathBuf>) -> Result<()> {
+    // The markers speak about the layers below, so they go first
+    let mut entries = vec![];
+    for entry in read_dir(layer)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.as_bytes() == OPAQUE.as_bytes() {
+            for below in read_dir(target)? {
+                remove_entry(&below?.path())?;
+            }
+            remove_entry(&entry.path())?;
+        } else if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT.as_bytes()) {
+            // extract leaves these out, this is no place to find out otherwise
+            if is_bad_whiteout(name.as_bytes()) {
+                bail!("whiteout {} names no entry", entry.path().display());
+            }
+            match remove_entry(&target.join(OsStr::from_bytes(hidden))) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+            remove_entry(&entry.path())?;
+        } else {
+            entries.push(entry);
+        }
+    }
+
+    for entry in entries {
+        let source = entry.path();
+        let destination = target.join(entry.file_name());
+        let relative = path.join(entry.file_name());
+        let source_is_dir = entry.file_type()?.is_dir();
+        let existing = symlink_metadata(&destination).ok();
+        match existing {
+            Some(existing) if existing.is_dir() && source_is_dir => {
+                merge(&source, &destination, &relative, directories)?;
+                // The layer's entry for the directory carries its mode. Without one the
+                // directory was only made to hold what's below it and the one below stays.
+                if directories.contains(&relative) {
+                    set_permissions(&destination, symlink_metadata(&source)?.permissions())?;
+                }
+                remove_dir(&source)?;
+                continue;
+            }
+            Some(_) => remove_entry(&destination)?,
+            None => {}
+        }
+        rename(&source, &destination)?;
+        // Nothing is below a new directory, but its markers still mustn't show
+        if source_is_dir {
+            remove_markers(&destination)?;
+        }
+    }
+    Ok(())
+}
+
+// .wh. on its own, or one for . or .., which would take the directory it's in or the one above
+// it: for a marker at the layer's root, whatever the rootfs sits in
+fn is_bad_whiteout(name: &[u8]) -> bool {
+    match name.strip_prefix(WHITEOUT.as_bytes()) {
+        Some(hidden) => {
+            hidden.is_empty() || hidden == b"." || hidden == b".." || hidden.contains(&b'/')
+        }
+        None => false,
+    }
+}
+
+fn remove_markers(dir: &Path) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .as_bytes()
+            .starts_with(WHITEOUT.as_bytes())
+        {
+            remove_entry(&entry.path())?;
+        } else if entry.file_type()?.is_dir() {
+            remove_markers(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Whatever is at path, without following a symlink
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    if symlink_metadata(path)?.is_dir() {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    }
+}
+
+// What came out of one layer
+pub struct Extracted {
+    // The owner of every entry, which isn't applied here
+    pub owners: Owners,
+    // Entries left out for encoding problems or types we can't create
+    pub skipped: u64,
+    // Device nodes and fifos left out, which --strict-unpack doesn't mind
+    pub devices: u64,
+    pub fifos: u64,
+    // The directories the layer has an entry for, relative to its root
+    pub directories: HashSet<PathBuf>,
+}
+
+// Extract one layer blob into target_dir. Extraction stops once the decompressed archive passes
+// limit bytes. An entry that can't be decoded or created is left out with a warning, unless
+// policy is strict; one that would land outside target_dir always fails the layer. Device
+// nodes are only created if the policy allows them, and they and fifos are counted rather than
+// failed on when they can't be.
+pub fn unpack_layer(
+    blob: &Path,
+    media_type: &str,
+    target_dir: &Path,
+    limit: u64,
+    policy: EntryPolicy,
+) -> Result<Extracted> {
+    let (compression, file) = open_layer(blob, media_type)?;
+    let exceeded = Rc::new(Cell::new(false));
+    let extracted = match compression {
+        Compression::None => extract(Limited::new(file, limit, &exceeded), target_dir, policy),
+        Compression::Gzip => extract(
+            Limited::new(GzDecoder::new(file), limit, &exceeded),
+            target_dir,
+            policy,
+        ),
+        Compression::Zstd => bail!("zstd-compressed layers are not supported"),
+    };
+    // The limit surfaces as an I/O error somewhere inside tar, this says what it was
+    if exceeded.get() {
+        bail!(
+            "layer exceeds expected decompressed size of {} bytes, use --max-layer-size to allow \
+             more",
+            limit
+        );
+    }
+    extracted
+}
+
+// The blob, rewound, and what it's compressed with. The declared media type picks the decoder,
+// but registries occasionally mislabel blobs so the content gets the final say.
+fn open_layer(blob: &Path, media_type: &str) -> Result<(Compression, File)> {
+    let mut file = File::open(blob)?;
+    let mut header = Vec::with_capacity(512);
+    (&mut file).take(512).read_to_end(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let declared = Compression::from_media_type(media_type);
+    let compression = match (declared, Compression::sniff(&header)) {
+        (Some(declared), Some(sniffed)) if declared != sniffed => {
+            eprintln!(
+                "warning: layer is labelled {} but looks like {:?}, using {:?}",
+                media_type, sniffed, sniffed
+            );
+            sniffed
+        }
+        (_, Some(sniffed)) => sniffed,
+        // Pre-POSIX tar headers (and empty archives) have no magic to find
+        (Some(Compression::None), None) => Compression::None,
+        (_, None) => bail!(
+            "Layer with media type {} is not in a known format, first bytes: {}",
+            media_type,
+            hex_prefix(&header, 16)
+        ),
+    };
+    Ok((compression, file))
+}
+
+// For --reproducible. Extraction leaves a directory's mtime at whenever a later layer last
+// added to it, and directories no layer has an entry for at whenever they were created. This
+// gives every path in rootfs the mtime of the last layer entry for it, or the epoch when there's
+// none, so the same image extracts to the same tree on any machine and at any time.
+pub fn pin_mtimes(store: &Store, layers: &[Descriptor], rootfs: &Path) -> Result<()> {
+    let mut mtimes = HashMap::new();
+    for layer in layers {
+        let blob = store.blob_path(&layer.digest)?;
+        let (compression, file) = open_layer(&blob, &layer.media_type)?;
+        match compression {
+            Compression::None => read_mtimes(file, &mut mtimes),
+            Compression::Gzip => read_mtimes(GzDecoder::new(file), &mut mtimes),
+            Compression::Zstd => bail!("zstd-compressed layers are not supported"),
+        }
+        .with_context(|| format!("Failed to read layer {}", layer.digest))?;
+    }
+    set_mtimes(rootfs, Path::new(""), &mtimes)
+}
+
+fn read_mtimes<R: Read>(reader: R, mtimes: &mut HashMap<PathBuf, (i64, i64)>) -> Result<()> {
+    for entry in Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        // Extraction already reported whatever it left out
+        let described = match describe(&mut entry) {
+            Ok(described) => described,
+            Err(_) => continue,
+        };
+        let mtime = match described.mtime {
+            Some(mtime) => mtime,
+            None => (entry.header().mtime()? as i64, 0),
+        };
+        mtimes.insert(described.path, mtime);
+    }
+    Ok(())
+}
+
+// path is relative to the rootfs, "" for the rootfs itself. Setting a child's mtime doesn't
+// touch its parent's, so the order doesn't matter.
+fn set_mtimes(rootfs: &Path, path: &Path, mtimes: &HashMap<PathBuf, (i64, i64)>) -> Result<()> {
+    let host = rootfs.join(path);
+    set_mtime(&host, mtimes.get(path).copied().unwrap_or((0, 0)))?;
+    if symlink_metadata(&host)?.is_dir() {
+        for entry in read_dir(&host)? {
+            set_mtimes(rootfs, &path.join(entry?.file_name()), mtimes)?;
+        }
+    }
+    Ok(())
+}
+
+// What Archive::unpack does, counting entries and noting their owners on the way. The tar
+// crate reads GNU long names and PAX paths itself, but not the PAX uid, gid and mtime that
+// stand in for values too big for the header, so those are applied here.
+fn extract<R: Read>(reader: R, target_dir: &Path, policy: EntryPolicy) -> Result<Extracted> {
+    let mut archive = Archive::new(reader);
+    // Keep modes exactly as the layer recorded them (setuid binaries, sticky /tmp) instead of
+    // filtering them through our umask
+    archive.set_preserve_permissions(true);
+
+    // Directories go last so their modes can't stop us from creating what's inside them
+    let mut directories = vec![];
+    let mut extracted = Extracted {
+        owners: Owners::new(),
+        skipped: 0,
+        devices: 0,
+        fifos: 0,
+        directories: HashSet::new(),
+    };
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        count += 1;
+        if count > MAX_LAYER_ENTRIES {
+            bail!("layer has more than {} entries", MAX_LAYER_ENTRIES);
+        }
+        if entry.header().entry_type() == EntryType::XGlobalHeader {
+            continue;
+        }
+        let described = match describe(&mut entry) {
+            Ok(described) => described,
+            Err(Rejected::Escapes(path)) => {
+                bail!("entry {} leads out of the layer", path.display())
+            }
+            Err(Rejected::Undecodable(reason)) => {
+                skip(&display_path(&entry), &reason, policy.strict)?;
+                extracted.skipped += 1;
+                continue;
+            }
+        };
+
+        let marker = described.path.file_name().unwrap_or_default();
+        if is_bad_whiteout(marker.as_bytes()) {
+            skip(
+                &display_path(&entry),
+                "a whiteout has to name an entry",
+                policy.strict,
+            )?;
+            extracted.skipped += 1;
+            continue;
+        }
+
+        let kind = entry.header().entry_type();
+        if is_device(kind) && !policy.allow_devices {
+            extracted.devices += 1;
+            continue;
+        }
+        if kind == EntryType::Directory {
+            extracted.directories.insert(described.path.clone());
+            directories.push((entry, described.mtime));
+        } else {
+            if !entry.unpack_in(target_dir)? {
+                // No parent to put it in, the path was nothing but /
+                bail!("entry {} is outside the layer", display_path(&entry));
+            }
+            let mut mtime = described.mtime;
+            if is_device(kind) || kind == EntryType::Fifo {
+                match make_node(&entry, &target_dir.join(&described.path)) {
+                    // The time tar gave the file went with it
+                    Ok(()) => mtime = mtime.or(Some((entry.header().mtime()? as i64, 0))),
+                    // mknod takes privileges we may not have, and a filesystem may not do fifos
+                    Err(error)
+                        if is_device(kind) && error.kind() == ErrorKind::PermissionDenied =>
+                    {
+                        extracted.devices += 1;
+                        continue;
+                    }
+                    Err(_) if kind == EntryType::Fifo => {
+                        extracted.fifos += 1;
+                        continue;
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            if let Some(mtime) = mtime {
+                set_mtime(&target_dir.join(&described.path), mtime)?;
+            }
+        }
+        match described.path.to_str() {
+            Some("") => {}
+            Some(path) => {
+                extracted
+                    .owners
+                    .insert(format!("/{}", path), described.owner);
+            }
+            // ownership.json can only hold UTF-8; such a file stays root's
+            None if described.owner != (0, 0) => eprintln!(
+                "warning: can't record that {} belongs to {}:{}, its name isn't UTF-8",
+                described.path.display(),
+                described.owner.0,
+                described.owner.1
+            ),
+            None => {}
+        }
+    }
+    for (mut directory, mtime) in directories {
+        directory.unpack_in(target_dir)?;
+        if let Some(mtime) = mtime {
+            let path = relative_path(&directory.path()?);
+            set_mtime(&target_dir.join(path), mtime)?;
+        }
+    }
+    Ok(extracted)
+}
+
+// An entry as we'll extract it
+struct Described {
+    // Relative to the layer root, without "." or leading /
+    path: PathBuf,
+    owner: (u32, u32),
+    // From PAX, which can say what the header can't (before 1970, after 2242, sub-second)
+    mtime: Option<(i64, i64)>,
+}
+
+enum Rejected {
+    // Encoding problems and types we can't create, the entry can be left out
+    Undecodable(String),
+    // A .. in the path: the layer is broken or malicious, either way none of it is trusted
+    Escapes(PathBuf),
+}
+
+impl From<String> for Rejected {
+    fn from(reason: String) -> Rejected {
+        Rejected::Undecodable(reason)
+    }
+}
+
+// Everything about an entry that has to be decoded before it can be extracted
+fn describe<R: Read>(entry: &mut Entry<R>) -> std::result::Result<Described, Rejected> {
+    let kind = entry.header().entry_type();
+    if !is_supported(kind) {
+        return Err(format!("entry type '{}' isn't supported", kind.as_byte() as char).into());
+    }
+    let path = entry
+        .path()
+        .map_err(|error| format!("unreadable path: {}", error))?;
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(Rejected::Escapes(path.into_owned()));
+    }
+    let path = relative_path(&path);
+
+    let (mut uid, mut gid, mut mtime) = (None, None, None);
+    if let Some(extensions) = entry
+        .pax_extensions()
+        .map_err(|error| format!("unreadable PAX header: {}", error))?
+    {
+        for extension in extensions {
+            let extension =
+                extension.map_err(|error| format!("unreadable PAX header: {}", error))?;
+            let value = extension
+                .value()
+                .map_err(|_| "PAX value isn't UTF-8".to_string());
+            match extension.key() {
+                Ok("uid") => uid = Some(parse_id(value?, "uid")?),
+                Ok("gid") => gid = Some(parse_id(value?, "gid")?),
+                Ok("mtime") => mtime = Some(parse_pax_time(value?)?),
+                _ => {}
+            }
+        }
+    }
+    let header = entry.header();
+    let uid = match uid {
+        Some(uid) => uid,
+        None => header_id(header.uid(), "uid")?,
+    };
+    let gid = match gid {
+        Some(gid) => gid,
+        None => header_id(header.gid(), "gid")?,
+    };
+    Ok(Described {
+        path,
+        owner: (uid, gid),
+        mtime,
+    })
+}
+
+// Leave an entry out with a warning, or with strict, fail the layer over it
+fn skip(path: &str, reason: &str, strict: bool) -> Result<()> {
+    if strict {
+        bail!("can't extract {}: {} (--strict-unpack)", path, reason);
+    }
+    eprintln!("warning: skipped {}: {}", path, reason);
+    Ok(())
+}
+
+// For messages, whatever the name is made of
+fn display_path<R: Read>(entry: &Entry<R>) -> String {
+    String::from_utf8_lossy(&entry.path_bytes()).into_owned()
+}
+
+// What unpack_in can create; anything else (volume labels, multi-volume continuations, types
+// from other tars' extensions) would be silently ignored by it
+fn is_supported(kind: EntryType) -> bool {
+    matches!(
+        kind,
+        EntryType::Regular
+            | EntryType::Continuous
+            | EntryType::GNUSparse
+            | EntryType::Directory
+            | EntryType::Symlink
+            | EntryType::Link
+            | EntryType::Fifo
+    ) || is_device(kind)
+}
+
+// tar writes device nodes and fifos out as empty files, with the checks that keep every entry
+// inside the layer; this swaps the file for the real thing. Either way nothing is left at path
+// when it fails. Sockets never get this far: tar has no entry type for them, so whatever
+// built the layer already left them out.
+fn make_node<R: Read>(entry: &Entry<R>, path: &Path) -> std::io::Result<()> {
+    let header = entry.header();
+    let kind = match header.entry_type() {
+        EntryType::Char => libc::S_IFCHR,
+        EntryType::Block => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    // A fifo's device fields are often left blank rather than zero
+    let (major, minor) = if kind == libc::S_IFIFO {
+        (0, 0)
+    } else {
+        (
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        )
+    };
+    let mode = header.mode()? & 0o7777;
+    remove_file(path)?;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(c_path.as_ptr(), kind | mode, libc::makedev(major, minor)) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // mknod's mode goes through the umask
+    set_permissions(path, Permissions::from_mode(mode))
+}
+
+fn is_device(kind: EntryType) -> bool {
+    matches!(kind, EntryType::Char | EntryType::Block)
+}
+
+// "./usr/bin", "/usr/bin" and "usr/bin/" are all usr/bin
+fn relative_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+fn parse_id(value: &str, what: &str) -> std::result::Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("PAX {} '{}' isn't a valid id", what, value))
+}
+
+fn header_id(id: std::io::Result<u64>, what: &str) -> std::result::Result<u32, String> {
+    let id = id.map_err(|error| format!("unreadable {}: {}", what, error))?;
+    u32::try_from(id).map_err(|_| format!("{} {} is too large", what, id))
+}
+
+// Seconds since the epoch with an optional fraction, possibly negative: "-1.5", "1700000000.25"
+fn parse_pax_time(value: &str) -> std::result::Result<(i64, i64), String> {
+    let invalid = || format!("PAX mtime '{}' isn't a valid time", value);
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let mut seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let digits: String = fraction
+        .chars()
+        .chain("000000000".chars())
+        .take(9)
+        .collect();
+    let mut nanoseconds: i64 = digits.parse().map_err(|_| invalid())?;
+    // -1.5 is half a second before -1
+    if value.starts_with('-') && nanoseconds > 0 {
+        seconds -= 1;
+        nanoseconds = 1_000_000_000 - nanoseconds;
+    }
+    Ok((seconds, nanoseconds))
+}
+
+// Without following a symlink, which belongs to the image
+fn set_mtime(path: &Path, (seconds, nanoseconds): (i64, i64)) -> Result<()> {
+    let time = libc::timespec {
+        tv_sec: seconds as libc::time_t,
+        tv_nsec: nanoseconds as libc::c_long,
+    };
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path_c.as_ptr(),
+            [time, time].as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to set the mtime of {}", path.display()));
+    }
+    Ok(())
+}
+
+// A reader that fails once more than limit bytes have come out of it, and says so in exceeded
+struct Limited<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl<R> Limited<R> {
+    fn new(inner: R, limit: u64, exceeded: &Rc<Cell<bool>>) -> Limited<R> {
+        Limited {
+            inner,
+            remaining: limit,
+            exceeded: exceeded.clone(),
+        }
+    }
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // One byte past the limit tells a stream that's exactly limit long from a longer one
+        let allowed = self.remaining.saturating_add(1).min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..allowed])?;
+        if read as u64 > self.remaining {
+            self.exceeded.set(true);
+            return Err(std::io::Error::new(
+                ErrorKind::Other,
+                "decompressed size limit exceeded",
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+pub fn hex_prefix(data: &[u8], count: usize) -> String {
+    if data.is_empty() {
+        return "(empty)".to_string();
+    }
+    data.iter()
+        .take(count)
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use std::os::unix::fs::MetadataExt;
+
+    const NO_POLICY: EntryPolicy = EntryPolicy {
+        strict: false,
+        allow_devices: false,
+    };
+
+    // Every path below root, with a / after directories
+    fn tree(root: &Path) -> Vec<String> {
+        fn walk(root: &Path, dir: &Path, paths: &mut Vec<String>) {
+            for entry in read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let relative = path.strip_prefix(root).unwrap().to_string_lossy();
+                if symlink_metadata(&path).unwrap().is_dir() {
+                    paths.push(format!("{}/", relative));
+                    walk(root, &path, paths);
+                } else {
+                    paths.push(relative.to_string());
+                }
+            }
+        }
+        let mut paths = vec![];
+        walk(root, root, &mut paths);
+        paths.sort();
+        paths
+    }
+
+    fn files(root: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = root.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, "").unwrap();
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        symlink_metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn a_whiteout_deletes_the_entry_below() {
+        let (layer, rootfs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        files(
+            rootfs.path(),
+            &["etc/passwd", "etc/shadow", "var/cache/apt/pkgcache.bin"],
+        );
+        files(
+            layer.path(),
+            &["etc/.wh.shadow", "var/cache/.wh.apt", "etc/.wh.missing"],
+        );
+
+        merge(layer.path(), rootfs.path(), Path::new(""), &HashSet::new()).unwrap();
+        assert_eq!(
+            tree(rootfs.path()),
+            ["etc/", "etc/passwd", "var/", "var/cache/"]
+        );
+    }
+
+    #[test]
+    fn a_whiteout_never_reaches_past_its_directory() {
+        let base = tempfile::tempdir().unwrap();
+        let (layer, rootfs) = (base.path().join("layer"), base.path().join("rootfs"));
+        files(&rootfs, &["etc/passwd"]);
+        files(base.path(), &["next-to-the-rootfs"]);
+        for marker in [".wh...", ".wh..", ".wh."] {
+            files(&layer, &[marker]);
+            assert!(merge(&layer, &rootfs, Path::new(""), &HashSet::new()).is_err());
+            remove_file(layer.join(marker)).unwrap();
+        }
+        assert_eq!(
+            tree(base.path()),
+            [
+                "layer/",
+                "next-to-the-rootfs",
+                "rootfs/",
+                "rootfs/etc/",
+                "rootfs/etc/passwd"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_opaque_directory_hides_everything_below() {
+        let (layer, rootfs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        files(rootfs.path(), &["opt/old", "opt/app/bin", "srv/kept"]);
+        files(layer.path(), &["opt/.wh..wh..opq", "opt/new"]);
+
+        merge(layer.path(), rootfs.path(), Path::new(""), &HashSet::new()).unwrap();
+        assert_eq!(tree(rootfs.path()), ["opt/", "opt/new", "srv/", "srv/kept"]);
+    }
+
+    #[test]
+    fn markers_in_a_new_directory_never_show() {
+        let (layer, rootfs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        files(
+            layer.path(),
+            &["app/.wh..wh..opq", "app/lib/.wh.gone", "app/lib/kept"],
+        );
+
+        merge(layer.path(), rootfs.path(), Path::new(""), &HashSet::new()).unwrap();
+        assert_eq!(tree(rootfs.path()), ["app/", "app/lib/", "app/lib/kept"]);
+    }
+
+    #[test]
+    fn an_entry_replaces_whatever_was_there() {
+        let (layer, rootfs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        files(rootfs.path(), &["bin/sh/oops", "lib"]);
+        files(layer.path(), &["bin/sh", "lib/libc.so"]);
+
+        merge(layer.path(), rootfs.path(), Path::new(""), &HashSet::new()).unwrap();
+        assert_eq!(
+            tree(rootfs.path()),
+            ["bin/", "bin/sh", "lib/", "lib/libc.so"]
+        );
+    }
+
+    #[test]
+    fn only_a_directory_entry_changes_the_mode_below() {
+        let (layer, rootfs) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        files(rootfs.path(), &["root/.profile", "tmp/.keep"]);
+        set_permissions(rootfs.path().join("root"), Permissions::from_mode(0o700)).unwrap();
+        set_permissions(rootfs.path().join("tmp"), Permissions::from_mode(0o755)).unwrap();
+        // root/ is only there to hold .bashrc, tmp/ has an entry of its own
+        files(layer.path(), &["root/.bashrc", "tmp/.keep"]);
+        set_permissions(layer.path().join("tmp"), Permissions::from_mode(0o1777)).unwrap();
+
+        let directories = [PathBuf::from("tmp")].into_iter().collect();
+        merge(layer.path(), rootfs.path(), Path::new(""), &directories).unwrap();
+        assert_eq!(mode(&rootfs.path().join("root")), 0o700);
+        assert_eq!(mode(&rootfs.path().join("tmp")), 0o1777);
+    }
+
+    fn header(kind: EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    #[test]
+    fn extraction_records_the_directory_entries() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut directory = header(EntryType::Directory, 0o750, 0);
+        builder
+            .append_data(&mut directory, "./etc/", std::io::empty())
+            .unwrap();
+        let mut file = header(EntryType::Regular, 0o755, 2);
+        builder
+            .append_data(&mut file, "usr/bin/tool", &b"#!"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let layer = tempfile::tempdir().unwrap();
+        let extracted = extract(&archive[..], layer.path(), NO_POLICY).unwrap();
+        assert_eq!(extracted.skipped, 0);
+        let expected: HashSet<_> = [PathBuf::from("etc")].into_iter().collect();
+        assert_eq!(extracted.directories, expected);
+        assert_eq!(mode(&layer.path().join("etc")), 0o750);
+        assert_eq!(
+            tree(layer.path()),
+            ["etc/", "usr/", "usr/bin/", "usr/bin/tool"]
+        );
+    }
+
+    #[test]
+    fn layers_may_expand_by_the_ratio_above_a_floor() {
+        assert_eq!(expansion_limit(1 << 30, None), 20 << 30);
+        assert_eq!(expansion_limit(1000, None), MIN_EXPANDED_SIZE);
+        assert_eq!(expansion_limit(u64::MAX, None), u64::MAX);
+        // --max-layer-size replaces both
+        assert_eq!(expansion_limit(1 << 30, Some(4096)), 4096);
+        assert_eq!(expansion_limit(1000, Some(1 << 40)), 1 << 40);
+    }
+
+    #[test]
+    fn limited_fails_one_byte_past_the_limit() {
+        let exceeded = Rc::new(Cell::new(false));
+        let mut exact = vec![];
+        Limited::new(&[7u8; 100][..], 100, &exceeded)
+            .read_to_end(&mut exact)
+            .unwrap();
+        assert_eq!(exact.len(), 100);
+        assert!(!exceeded.get());
+
+        let error = Limited::new(&[7u8; 101][..], 100, &exceeded)
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+        assert!(exceeded.get());
+    }
+
+    #[test]
+    fn a_layer_past_its_limit_is_a_bomb() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut file = header(EntryType::Regular, 0o644, 1 << 20);
+        builder
+            .append_data(&mut file, "zeros", std::io::repeat(0).take(1 << 20))
+            .unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        std::io::Write::write_all(&mut encoder, &builder.into_inner().unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let blob = dir.path().join("blob");
+        write(&blob, encoder.finish().unwrap()).unwrap();
+        let media_type = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+        let target = dir.path().join("bomb");
+        create_dir(&target).unwrap();
+        let error = unpack_layer(&blob, media_type, &target, 64 << 10, NO_POLICY)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("--max-layer-size"), "{}", error);
+
+        let target = dir.path().join("fine");
+        create_dir(&target).unwrap();
+        unpack_layer(&blob, media_type, &target, 2 << 20, NO_POLICY).unwrap();
+        assert_eq!(
+            symlink_metadata(target.join("zeros")).unwrap().len(),
+            1 << 20
+        );
+    }
+
+    fn add_file(builder: &mut tar::Builder<Vec<u8>>, path: impl AsRef<Path>, data: &[u8]) {
+        let mut file = header(EntryType::Regular, 0o644, data.len() as u64);
+        builder.append_data(&mut file, path, data).unwrap();
+    }
+
+    // A PAX extended header for the entry that follows it
+    fn add_pax(builder: &mut tar::Builder<Vec<u8>>, records: &[(&str, &str)]) {
+        let mut data = vec![];
+        for (key, value) in records {
+            // The length counts itself
+            let rest = format!(" {}={}\n", key, value);
+            let mut length = rest.len() + 1;
+            while length.to_string().len() + rest.len() > length {
+                length += 1;
+            }
+            data.extend_from_slice(format!("{}{}", length, rest).as_bytes());
+        }
+        let mut pax = header(EntryType::XHeader, 0o644, data.len() as u64);
+        builder
+            .append_data(&mut pax, "PaxHeaders/entry", &data[..])
+            .unwrap();
+    }
+
+    // A header carrying exactly these name bytes, which append_data would refuse or rewrite
+    fn add_raw(builder: &mut tar::Builder<Vec<u8>>, kind: EntryType, name: &[u8]) {
+        let mut raw = header(kind, 0o644, 0);
+        raw.as_old_mut().name[..name.len()].copy_from_slice(name);
+        raw.set_cksum();
+        builder.append(&raw, std::io::empty()).unwrap();
+    }
+
+    fn extract_archive(
+        builder: tar::Builder<Vec<u8>>,
+        policy: EntryPolicy,
+    ) -> (tempfile::TempDir, Result<Extracted>) {
+        let archive = builder.into_inner().unwrap();
+        let layer = tempfile::tempdir().unwrap();
+        let extracted = extract(&archive[..], layer.path(), policy);
+        (layer, extracted)
+    }
+
+    #[test]
+    fn long_names_come_through_gnu_and_pax() {
+        let deep = format!(
+            "app/{}/index.js",
+            ["node_modules/@scope/package"; 6].join("/")
+        );
+        assert!(deep.len() > 100);
+        let mut builder = tar::Builder::new(vec![]);
+        // Too long for the header, so the tar crate writes a GNU longname entry
+        add_file(&mut builder, &deep, b"gnu");
+        let pax_path = deep.replace("index.js", "pax.js");
+        add_pax(&mut builder, &[("path", &pax_path)]);
+        add_file(&mut builder, "short-name-in-header", b"pax");
+
+        let (layer, extracted) = extract_archive(builder, NO_POLICY);
+        assert_eq!(extracted.unwrap().skipped, 0);
+        assert_eq!(read_to_string(layer.path().join(&deep)).unwrap(), "gnu");
+        assert_eq!(read_to_string(layer.path().join(&pax_path)).unwrap(), "pax");
+        assert!(!layer.path().join("short-name-in-header").exists());
+    }
+
+    #[test]
+    fn pax_ids_and_mtimes_win_over_the_header() {
+        let mut builder = tar::Builder::new(vec![]);
+        add_pax(
+            &mut builder,
+            &[
+                ("uid", "4000000000"),
+                ("gid", "70000"),
+                ("mtime", "1700000000.25"),
+            ],
+        );
+        add_file(&mut builder, "big-ids", b"");
+        add_pax(&mut builder, &[("mtime", "-1.5")]);
+        add_file(&mut builder, "before-1970", b"");
+
+        let (layer, extracted) = extract_archive(builder, NO_POLICY);
+        let extracted = extracted.unwrap();
+        assert_eq!(extracted.owners["/big-ids"], (4_000_000_000, 70000));
+        let metadata = symlink_metadata(layer.path().join("big-ids")).unwrap();
+        assert_eq!(
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (1_700_000_000, 250_000_000)
+        );
+        let metadata = symlink_metadata(layer.path().join("before-1970")).unwrap();
+        assert_eq!((metadata.mtime(), metadata.mtime_nsec()), (-2, 500_000_000));
+    }
+
+    #[test]
+    fn pax_times() {
+        assert_eq!(parse_pax_time("1700000000"), Ok((1_700_000_000, 0)));
+        assert_eq!(parse_pax_time("1.5"), Ok((1, 500_000_000)));
+        assert_eq!(parse_pax_time("1.000000001999"), Ok((1, 1)));
+        assert_eq!(parse_pax_time("-1.25"), Ok((-2, 750_000_000)));
+        assert_eq!(parse_pax_time("-3"), Ok((-3, 0)));
+        for value in ["", "x", "1.x", "1.-5", "1e9"] {
+            assert!(parse_pax_time(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn non_utf8_names_are_extracted() {
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        let mut builder = tar::Builder::new(vec![]);
+        add_file(&mut builder, name, b"latin-1");
+
+        let (layer, extracted) = extract_archive(builder, NO_POLICY);
+        assert_eq!(extracted.unwrap().skipped, 0);
+        assert_eq!(read_to_string(layer.path().join(name)).unwrap(), "latin-1");
+    }
+
+    #[test]
+    fn undecodable_entries_are_skipped_unless_strict() {
+        let build = || {
+            let mut builder = tar::Builder::new(vec![]);
+            add_pax(&mut builder, &[("uid", "root")]);
+            add_file(&mut builder, "bad-uid", b"");
+            // A volume label, which there's nothing to make of
+            add_raw(&mut builder, EntryType::new(b'V'), b"label");
+            add_file(&mut builder, "fine", b"");
+            builder
+        };
+
+        let (layer, extracted) = extract_archive(build(), NO_POLICY);
+        assert_eq!(extracted.unwrap().skipped, 2);
+        assert_eq!(tree(layer.path()), ["fine"]);
+
+        let strict = EntryPolicy {
+            strict: true,
+            ..NO_POLICY
+        };
+        let (_, extracted) = extract_archive(build(), strict);
+        let error = extracted.err().unwrap();
+        assert!(error.to_string().contains("--strict-unpack"), "{}", error);
+    }
+
+    #[test]
+    fn whiteouts_that_name_nothing_are_skipped_unless_strict() {
+        let build = || {
+            let mut builder = tar::Builder::new(vec![]);
+            for name in [".wh...", "etc/.wh..", "etc/.wh.", "etc/.wh.shadow", "fine"] {
+                add_file(&mut builder, name, b"");
+            }
+            builder
+        };
+
+        let (layer, extracted) = extract_archive(build(), NO_POLICY);
+        assert_eq!(extracted.unwrap().skipped, 3);
+        assert_eq!(tree(layer.path()), ["etc/", "etc/.wh.shadow", "fine"]);
+
+        let strict = EntryPolicy {
+            strict: true,
+            ..NO_POLICY
+        };
+        let (_, extracted) = extract_archive(build(), strict);
+        let error = extracted.err().unwrap();
+        assert!(error.to_string().contains("whiteout"), "{}", error);
+    }
+
+    #[test]
+    fn leaving_the_layer_always_fails() {
+        for name in [&b"../evil"[..], b"a/../../evil"] {
+            let mut builder = tar::Builder::new(vec![]);
+            add_raw(&mut builder, EntryType::Regular, name);
+            let (layer, extracted) = extract_archive(builder, NO_POLICY);
+            let error = extracted.err().unwrap();
+            assert!(
+                error.to_string().contains("leads out of the layer"),
+                "{}",
+                error
+            );
+            assert!(tree(layer.path()).is_empty());
+        }
+    }
+
+    // The layers in the store as an image would list them
+    fn store_layers(store: &Store, archives: Vec<Vec<u8>>) -> Vec<Descriptor> {
+        archives
+            .into_iter()
+            .map(|archive| {
+                let digest = crate::digest::sha256_digest(&archive);
+                store.put_blob(&digest, &archive).unwrap();
+                Descriptor {
+                    media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+                    digest,
+                    size: archive.len() as u64,
+                    urls: vec![],
+                    annotations: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    fn mtimes(root: &Path) -> Vec<(String, i64, i64)> {
+        let mut paths = vec![(String::new(), root.to_path_buf())];
+        paths.extend(
+            tree(root)
+                .into_iter()
+                .map(|path| (path.clone(), root.join(path.trim_end_matches('/')))),
+        );
+        paths
+            .into_iter()
+            .map(|(path, host)| {
+                let metadata = symlink_metadata(host).unwrap();
+                (path, metadata.mtime(), metadata.mtime_nsec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pinned_mtimes_come_from_the_last_layer_entry() {
+        let mut first = tar::Builder::new(vec![]);
+        let mut etc = header(EntryType::Directory, 0o755, 0);
+        etc.set_mtime(1000);
+        first
+            .append_data(&mut etc, "etc/", std::io::empty())
+            .unwrap();
+        let mut passwd = header(EntryType::Regular, 0o644, 1);
+        passwd.set_mtime(2000);
+        first
+            .append_data(&mut passwd, "etc/passwd", &b"a"[..])
+            .unwrap();
+        let mut second = tar::Builder::new(vec![]);
+        let mut passwd = header(EntryType::Regular, 0o644, 1);
+        passwd.set_mtime(3000);
+        second
+            .append_data(&mut passwd, "etc/passwd", &b"b"[..])
+            .unwrap();
+        // No entry for usr/ or usr/bin/, they only hold the tool
+        add_pax(&mut second, &[("mtime", "4000.5")]);
+        add_file(&mut second, "usr/bin/tool", b"");
+        let archives = vec![first.into_inner().unwrap(), second.into_inner().unwrap()];
+
+        let data = tempfile::tempdir().unwrap();
+        let store = Store::new(&data.path().join("store"));
+        let layers = store_layers(&store, archives);
+        let mut extractions = vec![];
+        for name in ["one", "two"] {
+            let rootfs = data.path().join(name).join("rootfs");
+            create_dir_all(&rootfs).unwrap();
+            apply_layers(&store, &layers, &rootfs, None, NO_POLICY).unwrap();
+            pin_mtimes(&store, &layers, &rootfs).unwrap();
+            extractions.push(mtimes(&rootfs));
+        }
+        assert_eq!(extractions[0], extractions[1]);
+        assert_eq!(
+            extractions[0],
+            [
+                ("".to_string(), 0, 0),
+                ("etc/".to_string(), 1000, 0),
+                ("etc/passwd".to_string(), 3000, 0),
+                ("usr/".to_string(), 0, 0),
+                ("usr/bin/".to_string(), 0, 0),
+                ("usr/bin/tool".to_string(), 4000, 500_000_000),
+            ]
+        );
+    }
+
+    fn special(builder: &mut tar::Builder<Vec<u8>>, kind: EntryType, path: &str, mode: u32) {
+        let mut node = header(kind, mode, 0);
+        if kind != EntryType::Fifo {
+            // /dev/null
+            node.set_device_major(1).unwrap();
+            node.set_device_minor(3).unwrap();
+        }
+        builder
+            .append_data(&mut node, path, std::io::empty())
+            .unwrap();
+    }
+
+    fn special_layer() -> tar::Builder<Vec<u8>> {
+        let mut builder = tar::Builder::new(vec![]);
+        special(&mut builder, EntryType::Char, "dev/null", 0o666);
+        special(&mut builder, EntryType::Block, "dev/loop0", 0o660);
+        special(&mut builder, EntryType::Fifo, "run/initctl", 0o600);
+        add_file(&mut builder, "etc/hostname", b"");
+        builder
+    }
+
+    #[test]
+    fn device_nodes_are_left_out_by_default() {
+        let (layer, extracted) = extract_archive(special_layer(), NO_POLICY);
+        let extracted = extracted.unwrap();
+        assert_eq!((extracted.devices, extracted.skipped), (2, 0));
+        assert!(symlink_metadata(layer.path().join("dev/null")).is_err());
+        assert!(symlink_metadata(layer.path().join("dev/loop0")).is_err());
+        assert!(layer.path().join("etc/hostname").exists());
+    }
+
+    #[test]
+    fn allowed_device_nodes_are_created_or_counted() {
+        use std::os::unix::fs::FileTypeExt;
+        let allowed = EntryPolicy {
+            allow_devices: true,
+            ..NO_POLICY
+        };
+        let (layer, extracted) = extract_archive(special_layer(), allowed);
+        let extracted = extracted.unwrap();
+        // Whether mknod works depends on who and where we are, but either way the layer goes on
+        // and nothing half-made is left behind
+        let mut created = 0;
+        for path in ["dev/null", "dev/loop0"] {
+            if let Ok(metadata) = symlink_metadata(layer.path().join(path)) {
+                let file_type = metadata.file_type();
+                assert!(file_type.is_char_device() || file_type.is_block_device());
+                let device = metadata.rdev();
+                assert_eq!(
+                    unsafe { (libc::major(device), libc::minor(device)) },
+                    (1, 3)
+                );
+                created += 1;
+            }
+        }
+        assert_eq!(created + extracted.devices, 2);
+        assert!(layer.path().join("etc/hostname").exists());
+    }
+
+    #[test]
+    fn fifos_are_created_for_real() {
+        use std::os::unix::fs::FileTypeExt;
+        let strict = EntryPolicy {
+            strict: true,
+            ..NO_POLICY
+        };
+        // Devices being left out doesn't trouble --strict-unpack
+        let (layer, extracted) = extract_archive(special_layer(), strict);
+        let extracted = extracted.unwrap();
+        assert_eq!(extracted.fifos, 0);
+        let fifo = layer.path().join("run/initctl");
+        assert!(symlink_metadata(&fifo).unwrap().file_type().is_fifo());
+        assert_eq!(mode(&fifo), 0o600);
+    }
+}
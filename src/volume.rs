@@ -0,0 +1,713 @@
+use crate::copy::ContainerFs;
+use crate::data_root::unescape;
+use crate::rootfs::create_dir;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{
+    copy, create_dir_all, read_dir, read_link, remove_dir_all, rename, set_permissions,
+    symlink_metadata, File,
+};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+// Where a -v mount gets its content from
+#[derive(Clone)]
+pub enum Source {
+    // An existing host path
+    Bind(PathBuf),
+    // A volume we manage, created on first use
+    Named(String),
+}
+
+// How mounts made under a bind's source after it's mounted carry over into the container.
+// Either way nothing the container mounts reaches the host.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    // Neither way, docker's default
+    Private,
+    // Host to container, for trees like /var/lib/docker whose mounts come and go
+    Slave,
+}
+
+// One -v source:target[:options], the options a comma-separated mix of ro or rw and rprivate or
+// rslave
+#[derive(Clone)]
+pub struct VolumeSpec {
+    pub source: Source,
+    // Absolute path inside the container
+    pub target: String,
+    pub read_only: bool,
+    pub propagation: Propagation,
+}
+
+impl VolumeSpec {
+    pub fn parse(value: &str) -> Result<VolumeSpec> {
+        let parts: Vec<&str> = value.split(':').collect();
+        let (source, target, options) = match parts.as_slice() {
+            [source, target] => (*source, *target, ""),
+            [source, target, options] => (*source, *target, *options),
+            _ => bail!(
+                "Invalid -v '{}', expected source:target[:ro][,rslave]",
+                value
+            ),
+        };
+        let (mut read_only, mut propagation) = (false, Propagation::Private);
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => read_only = true,
+                "rw" => read_only = false,
+                "rprivate" => propagation = Propagation::Private,
+                "rslave" => propagation = Propagation::Slave,
+                _ => bail!(
+                    "Unknown option '{}' in -v '{}', expected ro, rw, rprivate or rslave",
+                    option,
+                    value
+                ),
+            }
+        }
+        if !target.starts_with('/') {
+            bail!("Mount target '{}' in -v must be an absolute path", target);
+        }
+
+        // Paths start with / or . (docker's own rule); anything else names a volume
+        let source = if source.starts_with('/') || source.starts_with('.') {
+            let path = Path::new(source)
+                .canonicalize()
+                .with_context(|| format!("Bind mount source {} does not exist", source))?;
+            Source::Bind(path)
+        } else {
+            validate_name(source)?;
+            Source::Named(source.to_string())
+        };
+
+        Ok(VolumeSpec {
+            source,
+            target: target.to_string(),
+            read_only,
+            propagation,
+        })
+    }
+
+    // A fresh volume for a path in the image config's Volumes, which docker calls anonymous
+    pub fn anonymous(name: String, target: &str) -> VolumeSpec {
+        VolumeSpec {
+            source: Source::Named(name),
+            target: target.to_string(),
+            read_only: false,
+            propagation: Propagation::Private,
+        }
+    }
+
+    pub fn volume_name(&self) -> Option<&str> {
+        match &self.source {
+            Source::Named(name) => Some(name),
+            Source::Bind(_) => None,
+        }
+    }
+}
+
+// Two mounts on one path would leave the first hidden under the second, so that's refused before
+// anything is created. Targets are compared as paths: /data and /data/ are the same.
+pub fn check_targets(specs: &[VolumeSpec]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for spec in specs {
+        let target: PathBuf = Path::new(&spec.target).components().collect();
+        if !seen.insert(target) {
+            bail!("More than one -v mounts onto {}", spec.target);
+        }
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().map_or(false, |c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        bail!(
+            "Invalid volume name '{}', use letters, digits, '_', '.' and '-'",
+            name
+        );
+    }
+    Ok(())
+}
+
+// A mount the child sets up right before chroot, with paths already converted for libc
+#[derive(Clone)]
+pub struct Mount {
+    pub source: CString,
+    // Absolute on the host, inside the rootfs
+    pub target: CString,
+    pub read_only: bool,
+    pub propagation: Propagation,
+    // For read-only mounts, what's mounted under the source on the host: each mount point
+    // under target and the flags it has to keep. Kernels without mount_setattr can only make
+    // those read-only one at a time.
+    pub submounts: Vec<(CString, libc::c_ulong)>,
+    // What the container state records about it
+    pub point: MountPoint,
+}
+
+// A mount as seen from outside, in container state, so `cp` can find the real files behind a
+// path inside the container
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MountPoint {
+    pub source: PathBuf,
+    // Absolute path inside the container
+    pub destination: PathBuf,
+    pub read_only: bool,
+}
+
+// Turn the -v specs into concrete mounts: create named volumes (seeding new ones from the
+// image), and make sure every mount point exists in the rootfs
+pub fn prepare(specs: &[VolumeSpec], base: &Path, rootfs: &Path) -> Result<Vec<Mount>> {
+    let mut mounts = vec![];
+    let fs = ContainerFs::bare(rootfs);
+    for spec in specs {
+        // The image's symlinks on the way are followed inside the rootfs: created or seeded
+        // from out here, /data -> /etc would otherwise be the host's /etc
+        let target = fs.host(&spec.target, true)?;
+        if !target.starts_with(rootfs) {
+            bail!("Mount target {} leads out of the container", spec.target);
+        }
+        let source = match &spec.source {
+            Source::Bind(path) => path.clone(),
+            Source::Named(name) => open_volume(base, name, &target)?,
+        };
+
+        if source.is_dir() {
+            create_dir(&target)?;
+        } else {
+            create_dir(target.parent().unwrap())?;
+            if symlink_metadata(&target).is_err() {
+                File::create(&target)?;
+            }
+        }
+
+        mounts.push(Mount {
+            source: CString::new(source.as_os_str().as_bytes())?,
+            target: CString::new(target.as_os_str().as_bytes())?,
+            read_only: spec.read_only,
+            propagation: spec.propagation,
+            submounts: if spec.read_only {
+                submounts(&source, &target)?
+            } else {
+                vec![]
+            },
+            point: MountPoint {
+                source,
+                destination: PathBuf::from(&spec.target),
+                read_only: spec.read_only,
+            },
+        });
+    }
+    Ok(mounts)
+}
+
+// Puts mounts in the order they have to be made: a mount inside another's target goes after it,
+// whatever order the flags came in, and a stable sort keeps the rest as they were. A nested
+// mount's mount point has to exist in the outer mount's source, since that's what its path
+// leads into once the outer one is mounted, so it's created there (in the host directory of a
+// bind, like docker does).
+pub fn arrange(mounts: &mut [Mount]) -> Result<()> {
+    mounts.sort_by_key(|mount| mount.point.destination.components().count());
+    for index in 0..mounts.len() {
+        let (outer, rest) = mounts.split_at(index);
+        let inner = &rest[0];
+        let enclosing = outer.iter().rev().find(|outer| {
+            inner
+                .point
+                .destination
+                .starts_with(&outer.point.destination)
+        });
+        let outer = match enclosing {
+            Some(outer) => outer,
+            None => continue,
+        };
+        if !outer.point.source.is_dir() {
+            bail!(
+                "Can't mount onto {}, it's inside the file mounted at {}",
+                inner.point.destination.display(),
+                outer.point.destination.display()
+            );
+        }
+        let relative = inner
+            .point
+            .destination
+            .strip_prefix(&outer.point.destination)?;
+        let point = outer.point.source.join(relative);
+        let created = if inner.point.source.is_dir() {
+            create_dir_all(&point)
+        } else {
+            point.parent().map_or(Ok(()), create_dir_all).and_then(|_| {
+                match symlink_metadata(&point) {
+                    Ok(_) => Ok(()),
+                    Err(_) => File::create(&point).map(|_| ()),
+                }
+            })
+        };
+        created.with_context(|| {
+            format!(
+                "Failed to create the mount point for {} in {}",
+                inner.point.destination.display(),
+                outer.point.source.display()
+            )
+        })?;
+    }
+    Ok(())
+}
+
+// The volume's data directory, created if needed. A brand new volume starts out with whatever
+// the image has at the mount point. It's populated under a temporary name and renamed into
+// place, so a container starting concurrently sees either nothing or the complete copy.
+fn open_volume(base: &Path, name: &str, image_content: &Path) -> Result<PathBuf> {
+    let dir = base.join(name);
+    let data = dir.join("_data");
+    if data.is_dir() {
+        return Ok(data);
+    }
+
+    create_dir_all(&dir).with_context(|| format!("Failed to create volume {}", name))?;
+    let staging = dir.join(format!("_data.{}.partial", std::process::id()));
+    create_dir_all(&staging)?;
+    if image_content.is_dir() {
+        copy_tree(image_content, &staging)
+            .with_context(|| format!("Failed to copy image content into volume {}", name))?;
+    }
+    if let Err(error) = rename(&staging, &data) {
+        // Someone else got there first, theirs is as good as ours
+        let _ = remove_dir_all(&staging);
+        if !data.is_dir() {
+            return Err(error).with_context(|| format!("Failed to create volume {}", name));
+        }
+    }
+    Ok(data)
+}
+
+// Recursive copy keeping modes and symlinks (which must not be followed, they're the image's)
+pub fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    set_permissions(to, symlink_metadata(from)?.permissions())?;
+    for entry in read_dir(from)? {
+        let entry = entry?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(read_link(&source)?, &target)?;
+        } else if file_type.is_dir() {
+            create_dir_all(&target)?;
+            copy_tree(&source, &target)?;
+        } else if file_type.is_file() {
+            copy(&source, &target)?;
+        }
+    }
+    Ok(())
+}
+
+// Runs in the forked child before chroot, in a private mount namespace (see
+// rootfs::unshare_mounts), which matters because the supervisor deletes the container
+// directory afterwards and must never recurse into a volume
+pub fn mount_all(mounts: &[Mount]) -> std::io::Result<()> {
+    unsafe {
+        for mount in mounts {
+            let flags = libc::MS_BIND | libc::MS_REC;
+            if libc::mount(
+                mount.source.as_ptr(),
+                mount.target.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null(),
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            let propagation = match mount.propagation {
+                Propagation::Private => libc::MS_PRIVATE,
+                Propagation::Slave => libc::MS_SLAVE,
+            };
+            if libc::mount(
+                std::ptr::null(),
+                mount.target.as_ptr(),
+                std::ptr::null(),
+                propagation | libc::MS_REC,
+                std::ptr::null(),
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            if mount.read_only {
+                read_only(mount)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Not in libc yet
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+// The read-only flag is ignored on the initial bind, it takes a remount, and a remount only
+// ever changes the one mount it names, MS_REC or not. mount_setattr (5.12) reaches everything
+// under the target in one go; before it, each submount is remounted by itself.
+unsafe fn read_only(mount: &Mount) -> std::io::Result<()> {
+    let attr = MountAttr {
+        attr_set: MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let result = libc::syscall(
+        libc::SYS_mount_setattr,
+        libc::AT_FDCWD,
+        mount.target.as_ptr(),
+        AT_RECURSIVE,
+        &attr as *const MountAttr,
+        std::mem::size_of::<MountAttr>(),
+    );
+    if result == 0 {
+        return Ok(());
+    }
+    // ENOSYS, or EPERM from a seccomp filter that doesn't know the call
+    remount_read_only(mount)
+}
+
+unsafe fn remount_read_only(mount: &Mount) -> std::io::Result<()> {
+    let remount = |target: &CString, flags: libc::c_ulong| {
+        let flags = flags | libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+        if libc::mount(
+            std::ptr::null(),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    remount(&mount.target, libc::MS_REC)?;
+    for (target, flags) in &mount.submounts {
+        remount(target, *flags)?;
+    }
+    Ok(())
+}
+
+// The mounts under source in /proc/self/mountinfo, as the paths they'll have under target and
+// the flags a remount has to repeat (in a user namespace, leaving out nosuid or nodev that the
+// mount was made with is refused)
+fn submounts(source: &Path, target: &Path) -> Result<Vec<(CString, libc::c_ulong)>> {
+    let source = match source.canonicalize() {
+        Ok(source) => source,
+        Err(_) => return Ok(vec![]),
+    };
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    mounted_under(&mountinfo, &source, target)
+}
+
+fn mounted_under(
+    mountinfo: &str,
+    source: &Path,
+    target: &Path,
+) -> Result<Vec<(CString, libc::c_ulong)>> {
+    let mut found = vec![];
+    for (mount_point, options) in mountinfo.lines().filter_map(mountinfo_entry) {
+        let relative = match mount_point.strip_prefix(source) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => continue,
+        };
+        let flags = options
+            .split(',')
+            .map(|option| match option {
+                "nosuid" => libc::MS_NOSUID,
+                "nodev" => libc::MS_NODEV,
+                "noexec" => libc::MS_NOEXEC,
+                "noatime" => libc::MS_NOATIME,
+                "nodiratime" => libc::MS_NODIRATIME,
+                "relatime" => libc::MS_RELATIME,
+                _ => 0,
+            })
+            .fold(0, |flags, flag| flags | flag);
+        let path = target.join(relative);
+        found.push((CString::new(path.as_os_str().as_bytes())?, flags));
+    }
+    Ok(found)
+}
+
+// A line of mountinfo as its mount point and per-mount options:
+// <id> <parent> <major:minor> <root> <mount point> <options> ... - <type> <source> ...
+fn mountinfo_entry(line: &str) -> Option<(PathBuf, &str)> {
+    let mut fields = line.split(' ');
+    let mount_point = fields.nth(4)?;
+    let options = fields.next()?;
+    Some((PathBuf::from(unescape(mount_point)), options))
+}
+
+pub fn list(base: &Path) -> Result<Vec<String>> {
+    let entries = match read_dir(base) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to list {}", base.display()))
+        }
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if entry.path().join("_data").is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn remove(base: &Path, name: &str) -> Result<()> {
+    validate_name(name)?;
+    let dir = base.join(name);
+    if !dir.is_dir() {
+        bail!("No such volume: {}", name);
+    }
+    remove_dir_all(&dir).with_context(|| format!("Failed to remove volume {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    fn bind(source: &Path, target: &str) -> VolumeSpec {
+        VolumeSpec {
+            source: Source::Bind(source.to_path_buf()),
+            target: target.to_string(),
+            read_only: false,
+            propagation: Propagation::Private,
+        }
+    }
+
+    fn target(mount: &Mount) -> PathBuf {
+        PathBuf::from(mount.target.to_str().unwrap())
+    }
+
+    #[test]
+    fn mount_points_are_made_through_the_images_symlinks_in_the_rootfs() {
+        let (rootfs, source, outside) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        create_dir_all(rootfs.path().join("srv")).unwrap();
+        symlink(outside.path(), rootfs.path().join("data")).unwrap();
+        symlink("../srv", rootfs.path().join("relative")).unwrap();
+        let file = source.path().join("file");
+        write(&file, "").unwrap();
+
+        let mounts = prepare(
+            &[
+                bind(source.path(), "/data/dir"),
+                bind(&file, "/relative/file"),
+            ],
+            &rootfs.path().join("volumes"),
+            rootfs.path(),
+        )
+        .unwrap();
+        // outside's path, but under the rootfs
+        let inside = rootfs
+            .path()
+            .join(outside.path().strip_prefix("/").unwrap())
+            .join("dir");
+        assert_eq!(target(&mounts[0]), inside);
+        assert!(inside.is_dir());
+        assert_eq!(target(&mounts[1]), rootfs.path().join("srv/file"));
+        assert!(rootfs.path().join("srv/file").is_file());
+        assert_eq!(read_dir(outside.path()).unwrap().count(), 0);
+        assert_eq!(mounts[0].point.destination, Path::new("/data/dir"));
+    }
+
+    #[test]
+    fn a_new_volume_is_seeded_from_the_rootfs_not_the_host() {
+        let (rootfs, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write(outside.path().join("secret"), "host").unwrap();
+        let image_dir = rootfs
+            .path()
+            .join(outside.path().strip_prefix("/").unwrap());
+        create_dir_all(&image_dir).unwrap();
+        write(image_dir.join("seed"), "image").unwrap();
+        symlink(outside.path(), rootfs.path().join("data")).unwrap();
+        let volumes = rootfs.path().join("volumes");
+
+        let mounts = prepare(
+            &[VolumeSpec::anonymous("fresh".to_string(), "/data")],
+            &volumes,
+            rootfs.path(),
+        )
+        .unwrap();
+        let data = volumes.join("fresh/_data");
+        assert_eq!(mounts[0].point.source, data);
+        assert_eq!(read_dir(&data).unwrap().count(), 1);
+        assert!(data.join("seed").is_file());
+    }
+
+    #[test]
+    fn nested_mounts_go_after_what_they_are_in() {
+        let (rootfs, sources) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let source = |name: &str| {
+            let path = sources.path().join(name);
+            create_dir_all(&path).unwrap();
+            path
+        };
+        let (a, ab, abc, x) = (source("a"), source("ab"), source("abc"), source("x"));
+        let mut mounts = prepare(
+            &[
+                bind(&abc, "/a/b/c"),
+                bind(&a, "/a"),
+                bind(&x, "/x"),
+                bind(&ab, "/a/b"),
+            ],
+            &rootfs.path().join("volumes"),
+            rootfs.path(),
+        )
+        .unwrap();
+        arrange(&mut mounts).unwrap();
+        let order: Vec<_> = mounts
+            .iter()
+            .map(|mount| mount.point.destination.to_str().unwrap())
+            .collect();
+        assert_eq!(order, ["/a", "/x", "/a/b", "/a/b/c"]);
+        // Each mount point exists in the source of the mount it lands in
+        assert!(a.join("b").is_dir());
+        assert!(ab.join("c").is_dir());
+        assert!(!a.join("b/c").exists());
+    }
+
+    #[test]
+    fn nothing_mounts_inside_a_file() {
+        let (rootfs, sources) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let file = sources.path().join("file");
+        write(&file, "").unwrap();
+        let mut mounts = prepare(
+            &[
+                bind(sources.path(), "/etc/file/x"),
+                bind(&file, "/etc/file"),
+            ],
+            &rootfs.path().join("volumes"),
+            rootfs.path(),
+        );
+        // The rootfs can't have both; whichever comes second finds the other in the way
+        if let Ok(mounts) = &mut mounts {
+            assert!(arrange(mounts).is_err());
+        }
+    }
+
+    #[test]
+    fn submounts_are_found_under_the_source() {
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime - ext4 /dev/sda1 rw
+30 22 0:30 / /srv/data rw,relatime - ext4 /dev/sdb1 rw
+31 30 0:31 / /srv/data/cache rw,nosuid,nodev,noexec,relatime - tmpfs tmpfs rw
+32 30 0:32 / /srv/data/with\\040space ro,noatime - tmpfs tmpfs rw
+33 22 0:33 / /srv/database rw - tmpfs tmpfs rw
+";
+        let found = mounted_under(
+            mountinfo,
+            Path::new("/srv/data"),
+            Path::new("/root/fs/data"),
+        )
+        .unwrap();
+        let found: Vec<_> = found
+            .iter()
+            .map(|(path, flags)| (path.to_str().unwrap(), *flags))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    "/root/fs/data/cache",
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC | libc::MS_RELATIME
+                ),
+                ("/root/fs/data/with space", libc::MS_NOATIME),
+            ]
+        );
+    }
+
+    // Binds source read-only at target in a mount namespace of a child's own, with a tmpfs on
+    // source/sub, and has the child try to write to both. Without mount_setattr, only the
+    // submounts given are made read-only.
+    fn write_through_read_only_bind(mount_setattr: bool, submounts: bool) -> String {
+        use std::os::unix::process::CommandExt;
+        let (rootfs, source) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        create_dir_all(source.path().join("sub")).unwrap();
+        let mut spec = bind(source.path(), "/data");
+        spec.read_only = true;
+        let mut mounts = prepare(&[spec], &rootfs.path().join("volumes"), rootfs.path()).unwrap();
+        let target = target(&mounts[0]);
+        // The tmpfs only exists in the child, so it isn't in our mountinfo
+        assert!(mounts[0].submounts.is_empty());
+        if submounts {
+            let sub = CString::new(target.join("sub").as_os_str().as_bytes()).unwrap();
+            mounts[0].submounts.push((sub, 0));
+        }
+        let sub = CString::new(source.path().join("sub").as_os_str().as_bytes()).unwrap();
+        let (root, tmpfs) = (CString::new("/").unwrap(), CString::new("tmpfs").unwrap());
+
+        let mut writable = mounts.clone();
+        writable[0].read_only = false;
+
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(format!(
+            "touch {0}/file 2>/dev/null && echo top; touch {0}/sub/file 2>/dev/null && echo sub; true",
+            target.display()
+        ));
+        unsafe {
+            command.pre_exec(move || {
+                let check = |result: libc::c_int| match result {
+                    0 => Ok(()),
+                    _ => Err(std::io::Error::last_os_error()),
+                };
+                check(libc::unshare(libc::CLONE_NEWNS))?;
+                check(libc::mount(
+                    std::ptr::null(),
+                    root.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                check(libc::mount(
+                    tmpfs.as_ptr(),
+                    sub.as_ptr(),
+                    tmpfs.as_ptr(),
+                    0,
+                    std::ptr::null(),
+                ))?;
+                if mount_setattr {
+                    return mount_all(&mounts);
+                }
+                mount_all(&writable)?;
+                remount_read_only(&writable[0])
+            });
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn read_only_reaches_submounts() {
+        assert_eq!(write_through_read_only_bind(true, false), "");
+        assert_eq!(write_through_read_only_bind(false, false), "sub\n");
+        assert_eq!(write_through_read_only_bind(false, true), "");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::container::{find, ContainerState, Status};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time::{sleep, timeout_at, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long after the container's process is gone we give its supervisor to record the exit
+const RECORD_GRACE: Duration = Duration::from_secs(5);
+
+// Watchers hold a shared lock on this file while they wait; a supervisor takes it exclusively
+// before removing the container directory, so the state file outlives everyone still
+// interested in the exit code.
+const WAIT_LOCK: &str = "wait.lock";
+
+pub struct WaitLock {
+    file: File,
+}
+
+impl WaitLock {
+    pub fn shared(dir: &Path) -> Result<WaitLock> {
+        WaitLock::acquire(dir, libc::LOCK_SH)
+    }
+
+    pub fn exclusive(dir: &Path) -> Result<WaitLock> {
+        WaitLock::acquire(dir, libc::LOCK_EX)
+    }
+
+    fn acquire(dir: &Path, operation: i32) -> Result<WaitLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(WAIT_LOCK))?;
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(WaitLock { file })
+    }
+}
+
+impl Drop for WaitLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+// Wait for a container started by any invocation to exit and return its exit code, or None if
+// it's still running when the timeout runs out. We aren't the container's parent, so this
+// watches its pid (with a pidfd where the kernel has them) and then reads the exit code its
+// supervisor records. An exit its restart policy restarts from doesn't count.
+pub async fn wait_for_exit(base: &Path, id: &str, limit: Option<Duration>) -> Result<Option<i32>> {
+    let state = find(base, id)?;
+    let _lock = WaitLock::shared(&base.join(&state.id))
+        .with_context(|| format!("Container {} was removed", state.short_id()))?;
+    let deadline = limit.map(|limit| Instant::now() + limit);
+
+    loop {
+        let state = find(base, &state.id)?;
+        if let Some(code) = exit_code(&state)? {
+            return Ok(Some(code));
+        }
+        if state.status == Status::Restarting {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+            sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        if let Some(pid) = state.pid.filter(|_| state.is_running()) {
+            let exited = watch_pid(pid);
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline, exited).await.is_err() {
+                        return Ok(None);
+                    }
+                }
+                None => exited.await,
+            }
+        }
+
+        // The process is gone; its supervisor writes the exit code (or that it's restarting)
+        // right after reaping it
+        let recorded = Instant::now() + RECORD_GRACE;
+        loop {
+            let current = find(base, &state.id)?;
+            if let Some(code) = exit_code(&current)? {
+                return Ok(Some(code));
+            }
+            if current.status == Status::Restarting || current.pid != state.pid {
+                break;
+            }
+            if Instant::now() >= recorded {
+                bail!(
+                    "Container {} exited but its exit code was never recorded",
+                    state.short_id()
+                );
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn exit_code(state: &ContainerState) -> Result<Option<i32>> {
+    match (state.status, state.exit_code) {
+        (Status::Exited, Some(code)) => Ok(Some(code)),
+        (Status::Exited, None) => Err(anyhow!(
+            "Container {} exited without an exit code",
+            state.short_id()
+        )),
+        _ => Ok(None),
+    }
+}
+
+async fn watch_pid(pid: u32) {
+    if let Some(pidfd) = pidfd_open(pid) {
+        if let Ok(pidfd) = AsyncFd::new(pidfd) {
+            // A pidfd polls readable once the process has exited
+            let _ = pidfd.readable().await;
+            return;
+        }
+    }
+    // Kernels before 5.3: check /proc until the pid disappears
+    let proc_dir = format!("/proc/{}", pid);
+    while Path::new(&proc_dir).exists() && !is_zombie(&proc_dir) {
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+struct PidFd(File);
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+fn pidfd_open(pid: u32) -> Option<PidFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return None;
+    }
+    Some(PidFd(unsafe { File::from_raw_fd(fd as RawFd) }))
+}
+
+// An exited process lingers as a zombie until its supervisor reaps it, which is already
+// "exited" as far as we're concerned
+fn is_zombie(proc_dir: &str) -> bool {
+    std::fs::read_to_string(format!("{}/stat", proc_dir))
+        .ok()
+        .and_then(|stat| {
+            stat.rsplit_once(')')
+                .map(|(_, rest)| rest.trim_start().starts_with('Z'))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    // A container that's running as far as its state goes, with pid as its process
+    fn running_container(base: &Path, id: &str, pid: u32) {
+        write_state(
+            base,
+            id,
+            serde_json::json!({ "status": "running", "pid": pid }),
+        );
+    }
+
+    fn write_state(base: &Path, id: &str, fields: serde_json::Value) {
+        let dir = base.join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut state = serde_json::json!({
+            "id": id,
+            "image": "test",
+            "rootfs": dir.join("rootfs"),
+            "created": 0,
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            state[key] = value.clone();
+        }
+        std::fs::write(dir.join("state.json"), state.to_string()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_pidfd_sees_a_short_lived_child_exit() {
+        let mut child = Command::new("sleep").arg("0.2").spawn().unwrap();
+        assert!(pidfd_open(child.id()).is_some());
+        tokio::time::timeout(Duration::from_secs(10), watch_pid(child.id()))
+            .await
+            .unwrap();
+        // Exited, though not reaped until now
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn the_recorded_exit_code_comes_back() {
+        let base = tempfile::tempdir().unwrap();
+        let mut child = Command::new("sh")
+            .args(["-c", "sleep 0.2; exit 3"])
+            .spawn()
+            .unwrap();
+        running_container(base.path(), "0123456789abcdef", child.id());
+
+        // What the container's supervisor does once it has reaped it
+        let supervisor_base = base.path().to_path_buf();
+        let supervisor = std::thread::spawn(move || {
+            let code = child.wait().unwrap().code().unwrap();
+            let fields = serde_json::json!({ "status": "exited", "exit_code": code });
+            write_state(&supervisor_base, "0123456789abcdef", fields);
+        });
+
+        let code = wait_for_exit(base.path(), "0123", Some(Duration::from_secs(10)))
+            .await
+            .unwrap();
+        assert_eq!(code, Some(3));
+        supervisor.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_container_still_running_at_the_limit_gives_none() {
+        let base = tempfile::tempdir().unwrap();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        running_container(base.path(), "0123456789abcdef", child.id());
+
+        let code = wait_for_exit(base.path(), "0123", Some(Duration::from_millis(200)))
+            .await
+            .unwrap();
+        assert_eq!(code, None);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+// Just enough YAML for compose files: block mappings and sequences, flow [..] and {..} on a
+// single line, plain and quoted scalars, and comments. Anchors, tags, multi-document streams
+// and block scalars (| and >) are rejected rather than misread.
+//
+// Scalars come back as strings (or null for ~, null and nothing at all) whatever they look
+// like, since every field we read out of them gets parsed further anyway: "512m", "0.5" and
+// "5432" all end up where a string is expected.
+pub fn parse(text: &str) -> Result<Value> {
+    let mut lines = vec![];
+    for (index, raw) in text.lines().enumerate() {
+        let number = index + 1;
+        let raw = raw.strip_suffix('\r').unwrap_or(raw);
+        let content = strip_comment(raw);
+        let trimmed = content.trim_start_matches(' ');
+        if trimmed.trim().is_empty() {
+            continue;
+        }
+        if trimmed.starts_with('\t') {
+            bail!("line {}: tabs can't be used for indentation", number);
+        }
+        // A document start marker is fine ahead of the content
+        if trimmed.trim_end() == "---" && lines.is_empty() {
+            continue;
+        }
+        if trimmed.trim_end() == "---" || trimmed.trim_end() == "..." {
+            bail!("line {}: only a single document is supported", number);
+        }
+        lines.push(Line {
+            number,
+            indent: content.len() - trimmed.len(),
+            text: trimmed.trim_end().to_string(),
+        });
+    }
+    if lines.is_empty() {
+        return Ok(Value::Null);
+    }
+
+    let mut parser = Parser { lines, pos: 0 };
+    let indent = parser.lines[0].indent;
+    let value = parser.block(indent)?;
+    if let Some(line) = parser.lines.get(parser.pos) {
+        bail!("line {}: unexpected indentation", line.number);
+    }
+    Ok(value)
+}
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    pos: usize,
+}
+
+impl Parser {
+    // A mapping or sequence whose entries all start at column indent
+    fn block(&mut self, indent: usize) -> Result<Value> {
+        if is_sequence_entry(&self.lines[self.pos].text) {
+            self.sequence(indent)
+        } else {
+            self.mapping(indent)
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Value> {
+        let mut items = vec![];
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent != indent || !is_sequence_entry(&line.text) {
+                break;
+            }
+            let number = line.number;
+            let rest = line.text[1..].trim_start_matches(' ');
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.nested(indent)?);
+            } else if split_key(rest)?.is_some() || is_sequence_entry(rest) {
+                // "- key: value" starts a mapping (or "- - x" a sequence) at the column of
+                // its first character, where the lines after it continue
+                let column = indent + line.text.len() - rest.len();
+                let rest = rest.to_string();
+                self.lines[self.pos].indent = column;
+                self.lines[self.pos].text = rest;
+                items.push(self.block(column)?);
+            } else {
+                let value = inline(rest, number)?;
+                self.pos += 1;
+                items.push(value);
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Value> {
+        let mut entries = Map::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent != indent || is_sequence_entry(&line.text) {
+                break;
+            }
+            let number = line.number;
+            let (key, rest) = match split_key(&line.text)? {
+                Some(split) => split,
+                None => bail!("line {}: expected 'key: value'", number),
+            };
+            let (key, rest) = (key, rest.to_string());
+            self.pos += 1;
+
+            let value = if rest.is_empty() {
+                // A sequence may sit at the same indentation as its key
+                match self.lines.get(self.pos) {
+                    Some(next) if next.indent == indent && is_sequence_entry(&next.text) => {
+                        self.sequence(indent)?
+                    }
+                    _ => self.nested(indent)?,
+                }
+            } else {
+                inline(&rest, number)?
+            };
+            if entries.insert(key.clone(), value).is_some() {
+                bail!("line {}: duplicate key '{}'", number, key);
+            }
+        }
+        Ok(Value::Object(entries))
+    }
+
+    // What an empty "key:" or "-" holds: the more indented block below it, if any
+    fn nested(&mut self, indent: usize) -> Result<Value> {
+        match self.lines.get(self.pos) {
+            Some(next) if next.indent > indent => {
+                let indent = next.indent;
+                self.block(indent)
+            }
+            _ => Ok(Value::Null),
+        }
+    }
+}
+
+fn is_sequence_entry(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+// "key: rest" or "key:" as the key and what follows it, None if the line has no key
+fn split_key(text: &str) -> Result<Option<(String, &str)>> {
+    if text.starts_with('[') || text.starts_with('{') {
+        return Ok(None);
+    }
+    let (key, rest) = if text.starts_with('"') || text.starts_with('\'') {
+        let (key, length) = quoted(text)?;
+        let rest = &text[length..];
+        match rest.strip_prefix(':') {
+            Some(rest) if rest.is_empty() || rest.starts_with(' ') => (key, rest),
+            _ => return Ok(None),
+        }
+    } else {
+        let bytes = text.as_bytes();
+        let separator = (0..bytes.len()).find(|&index| {
+            bytes[index] == b':' && (index + 1 == bytes.len() || bytes[index + 1] == b' ')
+        });
+        match separator {
+            Some(index) => (text[..index].trim_end().to_string(), &text[index + 1..]),
+            None => return Ok(None),
+        }
+    };
+    Ok(Some((key, rest.trim())))
+}
+
+// The value after "key: " or "- " on the same line
+fn inline(text: &str, number: usize) -> Result<Value> {
+    let mut flow = Flow {
+        chars: text.chars().collect(),
+        pos: 0,
+        number,
+    };
+    let value = flow.value(false)?;
+    flow.skip_spaces();
+    if flow.pos != flow.chars.len() {
+        bail!("line {}: unexpected text after the value", number);
+    }
+    Ok(value)
+}
+
+struct Flow {
+    chars: Vec<char>,
+    pos: usize,
+    number: usize,
+}
+
+impl Flow {
+    fn value(&mut self, in_flow: bool) -> Result<Value> {
+        self.skip_spaces();
+        match self.chars.get(self.pos) {
+            Some('[') => self.sequence(),
+            Some('{') => self.mapping(),
+            Some('"') | Some('\'') => {
+                let rest: String = self.chars[self.pos..].iter().collect();
+                let (value, length) = quoted(&rest)
+                    .map_err(|error| anyhow::anyhow!("line {}: {}", self.number, error))?;
+                self.pos += rest[..length].chars().count();
+                Ok(Value::String(value))
+            }
+            Some('&') | Some('*') | Some('!') => {
+                bail!(
+                    "line {}: anchors, aliases and tags aren't supported",
+                    self.number
+                )
+            }
+            Some('|') | Some('>') if !in_flow => {
+                bail!(
+                    "line {}: block scalars (| and >) aren't supported",
+                    self.number
+                )
+            }
+            _ => {
+                let start = self.pos;
+                while let Some(&c) = self.chars.get(self.pos) {
+                    if in_flow && (c == ',' || c == ']' || c == '}') {
+                        break;
+                    }
+                    if in_flow && c == ':' && self.chars.get(self.pos + 1) == Some(&' ') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                Ok(plain(text.trim()))
+            }
+        }
+    }
+
+    fn sequence(&mut self) -> Result<Value> {
+        self.pos += 1;
+        let mut items = vec![];
+        loop {
+            self.skip_spaces();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value(true)?);
+            self.skip_spaces();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            if !self.eat(',') {
+                bail!("line {}: expected ',' or ']'", self.number);
+            }
+        }
+    }
+
+    fn mapping(&mut self) -> Result<Value> {
+        self.pos += 1;
+        let mut entries = Map::new();
+        loop {
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Value::Object(entries));
+            }
+            let key = match self.value(true)? {
+                Value::String(key) => key,
+                _ => bail!("line {}: expected a key", self.number),
+            };
+            self.skip_spaces();
+            let value = if self.eat(':') {
+                self.value(true)?
+            } else {
+                Value::Null
+            };
+            if entries.insert(key.clone(), value).is_some() {
+                bail!("line {}: duplicate key '{}'", self.number, key);
+            }
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Value::Object(entries));
+            }
+            if !self.eat(',') {
+                bail!("line {}: expected ',' or '}}'", self.number);
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.get(self.pos) == Some(&' ') {
+            self.pos += 1;
+        }
+    }
+}
+
+fn plain(text: &str) -> Value {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => Value::Null,
+        _ => Value::String(text.to_string()),
+    }
+}
+
+// A quoted scalar at the start of text and how many bytes it took. Single quotes only know ''
+// for a quote, double quotes take the usual backslash escapes.
+fn quoted(text: &str) -> Result<(String, usize)> {
+    let quote = text.chars().next().unwrap();
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        if c == quote {
+            if quote == '\'' && text[index + 1..].starts_with('\'') {
+                chars.next();
+                value.push('\'');
+                continue;
+            }
+            return Ok((value, index + 1));
+        }
+        if c == '\\' && quote == '"' {
+            match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, 'r')) => value.push('\r'),
+                Some((_, '0')) => value.push('\0'),
+                Some((_, c @ ('"' | '\\' | '/'))) => value.push(c),
+                Some((_, c)) => bail!("unknown escape \\{} in a double-quoted string", c),
+                None => break,
+            }
+            continue;
+        }
+        value.push(c);
+    }
+    bail!(
+        "unterminated {}-quoted string",
+        if quote == '"' { "double" } else { "single" }
+    )
+}
+
+// Everything before a # that starts a comment: at the start of the line or after a space, and
+// outside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut chars = line.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match quote {
+            Some('"') if c == '\\' => {
+                chars.next();
+            }
+            // '' is a quote inside single quotes, not the end of them
+            Some('\'') if c == '\'' && chars.peek().map(|&(_, next)| next) == Some('\'') => {
+                chars.next();
+            }
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '#' && (previous == ' ' || previous == '\t') => return &line[..index],
+            // A quote only opens a quoted scalar where one can start, not inside "it's"
+            None if (c == '"' || c == '\'')
+                && matches!(previous, ' ' | '\t' | '[' | '{' | ',' | ':' | '-') =>
+            {
+                quote = Some(c)
+            }
+            None => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scalars_stay_strings_and_quotes_come_off() {
+        let value = parse(concat!(
+            "plain: 512m\n",
+            "number: 5432\n",
+            "empty:\n",
+            "tilde: ~\n",
+            "single: 'it''s # not a comment'\n",
+            "double: \"a\\tb\\n\\\"c\\\"\"\n",
+            "colon: http://example.com:8080/x\n",
+            "\"quoted key\": value # a comment\n",
+            "apostrophe: it's\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "plain": "512m",
+                "number": "5432",
+                "empty": null,
+                "tilde": null,
+                "single": "it's # not a comment",
+                "double": "a\tb\n\"c\"",
+                "colon": "http://example.com:8080/x",
+                "quoted key": "value",
+                "apostrophe": "it's",
+            })
+        );
+    }
+
+    #[test]
+    fn maps_and_lists_nest() {
+        let value = parse(concat!(
+            "---\n",
+            "# a compose file\n",
+            "services:\n",
+            "  web:\n",
+            "    image: nginx\n",
+            "    command: [\"nginx\", -g, 'daemon off;']\n",
+            "    environment: {A: 1, B: \"two, three\", C}\n",
+            "    volumes:\n",
+            "    - ./html:/usr/share/nginx/html\n",
+            "    - - nested\n",
+            "      - list\n",
+            "    depends_on:\n",
+            "      - db\n",
+            "  db:\n",
+            "    image: postgres\n",
+            "list:\n",
+            "  - name: first\n",
+            "    value: 1\n",
+            "  -\n",
+            "    name: second\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "services": {
+                    "web": {
+                        "image": "nginx",
+                        "command": ["nginx", "-g", "daemon off;"],
+                        "environment": {"A": "1", "B": "two, three", "C": null},
+                        "volumes": ["./html:/usr/share/nginx/html", ["nested", "list"]],
+                        "depends_on": ["db"],
+                    },
+                    "db": {"image": "postgres"},
+                },
+                "list": [{"name": "first", "value": "1"}, {"name": "second"}],
+            })
+        );
+    }
+
+    #[test]
+    fn what_we_dont_understand_is_an_error() {
+        for text in [
+            "key: value\n\tother: value\n",
+            "a: 1\n---\nb: 2\n",
+            "a: &anchor 1\n",
+            "a: *alias\n",
+            "a: !!str 1\n",
+            "a: |\n  text\n",
+            "a: >\n  text\n",
+            "a: 1\na: 2\n",
+            "a: {b: 1, b: 2}\n",
+            "a: [1, 2\n",
+            "a: 'open\n",
+            "a: \"\\q\"\n",
+            "a: 1\n  b: 2\n",
+            "just a scalar\n",
+        ] {
+            assert!(parse(text).is_err(), "{:?}", text);
+        }
+        assert_eq!(parse("# nothing\n\n").unwrap(), Value::Null);
+    }
+}